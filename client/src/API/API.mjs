@@ -1,20 +1,30 @@
 // API functions per la comunicazione con il backend Rust
 const BASE_URL = 'http://localhost:3000';
 
+// Messaggio leggibile da una risposta di errore: LoginError {reason, message} o stringa JSON
+const errorMessage = async (response, fallback) => {
+  const text = await response.text();
+  try {
+    const body = JSON.parse(text);
+    return (typeof body === 'string' ? body : body.message) || fallback;
+  } catch {
+    return text || fallback;
+  }
+};
+
 // Funzioni per la gestione degli utenti
-const signup = async (username) => {
+const signup = async ({ username, password }) => {
   try {
-    const response = await fetch(`${BASE_URL}/api/login`, {
+    const response = await fetch(`${BASE_URL}/api/register`, {
       method: 'POST',
       headers: {
         'Content-Type': 'application/json',
       },
-      body: JSON.stringify({ username }),
+      body: JSON.stringify({ username, password }),
     });
 
     if (!response.ok) {
-      const errorText = await response.text();
-      throw new Error(errorText || 'Signup failed');
+      throw new Error(await errorMessage(response, 'Signup failed'));
     }
 
     return await response.json();
@@ -26,26 +36,21 @@ const signup = async (username) => {
 
 const login = async (credentials) => {
   try {
-    // Verifica direttamente tramite l'endpoint di login
+    // Verifica le credenziali tramite l'endpoint di login
     const response = await fetch(`${BASE_URL}/api/login`, {
       method: 'POST',
       headers: {
         'Content-Type': 'application/json',
       },
-      body: JSON.stringify({ username: credentials.username }),
+      body: JSON.stringify({ username: credentials.username, password: credentials.password }),
     });
 
-    if (response.status === 409) { // Conflict - username già in uso
-      const errorText = await response.json();
-      throw new Error(errorText);
-    }
-
+    // 401: credenziali non valide, 409: utente già connesso
     if (!response.ok) {
-      const errorText = await response.text();
-      throw new Error(errorText || 'Login failed');
+      throw new Error(await errorMessage(response, 'Login failed'));
     }
 
-    // Username disponibile, restituisci i dati utente
+    // Credenziali valide, restituisci i dati utente
     return { username: credentials.username, available: true };
   } catch (error) {
     console.error('Login error:', error);
@@ -68,12 +73,14 @@ const getAllUsers = async () => {
   }
 };
 
-const updateUserAvailability = async (username, available) => {
+// Richiede il token di sessione ricevuto con LoginSuccess: si può aggiornare solo il proprio stato
+const updateUserAvailability = async (username, available, token) => {
   try {
     const response = await fetch(`${BASE_URL}/api/users/${username}/availability`, {
       method: 'POST',
       headers: {
         'Content-Type': 'application/json',
+        Authorization: `Bearer ${token}`,
       },
       body: JSON.stringify(available),
     });
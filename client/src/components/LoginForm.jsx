@@ -1,8 +1,10 @@
 import { useState } from 'react';
-import { login } from '../API/API.mjs';
+import { login, signup } from '../API/API.mjs';
 
 export default function LoginForm({ onLogin }) {
   const [username, setUsername] = useState('');
+  const [password, setPassword] = useState('');
+  const [registering, setRegistering] = useState(false);
   const [error, setError] = useState('');
   const [loading, setLoading] = useState(false);
 
@@ -12,11 +14,15 @@ export default function LoginForm({ onLogin }) {
     setLoading(true);
 
     try {
+      // Nuovo account: prima la registrazione, poi il login con le stesse credenziali
+      if (registering) {
+        await signup({ username, password });
+      }
       // Il login HTTP è solo per validazione
-      await login({ username });
+      await login({ username, password });
 
-      // Passiamo solo l'username, la connessione WebSocket avverrà automaticamente
-      onLogin({ username });
+      // Le credenziali servono anche al login WebSocket, che avverrà automaticamente
+      onLogin({ username, password });
     } catch (err) {
       setError(err.message || 'Errore durante il login');
     } finally {
@@ -67,9 +73,32 @@ export default function LoginForm({ onLogin }) {
             required
             disabled={loading}
           />
-          <small style={{ color: '#b8b8b8ff', fontSize: '0.85rem' }}>
-            💡 Scegli un username unico non ancora in uso
-          </small>
+        </div>
+        <div style={{ display: 'flex', flexDirection: 'column', gap: 4 }}>
+          <label htmlFor="login-password" style={{ color: '#dfdfdf', fontWeight: 500 }}>
+            Password
+          </label>
+          <input
+            id="login-password"
+            type="password"
+            value={password}
+            onChange={e => setPassword(e.target.value)}
+            placeholder="Inserisci la password"
+            autoComplete={registering ? 'new-password' : 'current-password'}
+            style={{
+              padding: '0.5rem',
+              borderRadius: 6,
+              border: '1px solid #bdbdbd',
+              fontSize: 16
+            }}
+            required
+            disabled={loading}
+          />
+          {registering && (
+            <small style={{ color: '#b8b8b8ff', fontSize: '0.85rem' }}>
+              💡 Scegli un username unico e una password di almeno 8 caratteri
+            </small>
+          )}
         </div>
         <button
           type="submit"
@@ -87,7 +116,21 @@ export default function LoginForm({ onLogin }) {
             boxShadow: loading ? 'none' : '0 2px 8px rgba(201, 70, 42, 0.3)'
           }}
         >
-          {loading ? 'Accesso in corso...' : 'Accedi'}
+          {loading ? 'Accesso in corso...' : registering ? 'Registrati e accedi' : 'Accedi'}
+        </button>
+        <button
+          type="button"
+          onClick={() => setRegistering(!registering)}
+          disabled={loading}
+          style={{
+            background: 'none',
+            color: '#dfdfdf',
+            border: 'none',
+            textDecoration: 'underline',
+            cursor: loading ? 'not-allowed' : 'pointer'
+          }}
+        >
+          {registering ? 'Hai già un account? Accedi' : 'Non hai un account? Registrati'}
         </button>
         {error && (
           <div style={{
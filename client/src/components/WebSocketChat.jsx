@@ -166,10 +166,10 @@ function Chat() {
         <Col md={8} className="h-100">
           {/* Avviso quando l'utente è solo (solo se NON c'è abbandono definitivo) */}
           {isAloneInChat && !currentChatAbandoned && (
-            <div className="alert alert-warning d-flex align-items-center m-3 mb-2" role="alert">
-              <i className="bi bi-exclamation-triangle-fill me-2"></i>
+            <div className="alert alert-info d-flex align-items-center m-3 mb-2" role="alert">
+              <i className="bi bi-info-circle-fill me-2"></i>
               <div>
-                <strong>Attenzione:</strong> Sei solo in questa chat. I tuoi messaggi non arriveranno a nessuno.
+                Sei solo in questa chat. Gli altri membri riceveranno i tuoi messaggi quando torneranno.
               </div>
             </div>
           )}
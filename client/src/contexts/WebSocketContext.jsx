@@ -28,6 +28,7 @@ export const WebSocketProvider = ({ children, user, onLogout }) => {
   const chatStateRef = useRef({ inChat: false, chatType: '', members: [], chatId: null });
   const leavingChatRef = useRef(false); // Previene chiamate multiple di leaveChat
   const sessionIdRef = useRef(''); // Sessione utente
+  const sessionTokenRef = useRef(''); // Token di sessione, per le API REST autenticate
   // Pulisci lo stato legato alla sessione quando cambia utente
   useEffect(() => {
     // Reset completo delle notifiche e stati chat per evitare "bleed" tra utenti
@@ -58,7 +59,7 @@ export const WebSocketProvider = ({ children, user, onLogout }) => {
           // Invia immediatamente il messaggio di login
           const loginMessage = {
             message_type: 'Login',
-            data: JSON.stringify({ username: user.username })
+            data: JSON.stringify({ username: user.username, password: user.password })
           };
           ws.send(JSON.stringify(loginMessage));
         };
@@ -70,26 +71,23 @@ export const WebSocketProvider = ({ children, user, onLogout }) => {
 
             switch (wsMessage.message_type) {
               case 'LoginSuccess': {
-                // Estrai session_id dalla risposta del backend
-                if (typeof wsMessage.data === 'string' && wsMessage.data.includes('session_id')) {
-                  const match = wsMessage.data.match(/session_id: ([a-f0-9\-]+)/);
-                  if (match) {
-                    sessionIdRef.current = match[1];
-                  }
-                }
-                break;
-              }
+                // LoginSuccess {username, session_id, token, expires_at, resumed, chat_id}
+                const success = JSON.parse(wsMessage.data);
+                sessionIdRef.current = success.session_id;
+                sessionTokenRef.current = success.token;
                 setLoginError('');
                 break;
+              }
 
-              case 'LoginError':
-                setLoginError(wsMessage.data);
-                if (wsMessage.data.includes('già in uso') || wsMessage.data.includes('Username taken')) {
-                  setTimeout(() => {
-                    handleLogout();
-                  }, 3000);
-                }
+              case 'LoginError': {
+                // LoginError {reason, message}: il login è fallito, si torna al form
+                const loginErr = JSON.parse(wsMessage.data);
+                setLoginError(loginErr.message);
+                setTimeout(() => {
+                  handleLogout();
+                }, 3000);
                 break;
+              }
 
               case 'ChatMessage':
                 const chatMsg = JSON.parse(wsMessage.data);
@@ -121,8 +119,16 @@ export const WebSocketProvider = ({ children, user, onLogout }) => {
                 }
                 break;
 
+              case 'ChatCreated': {
+                // ChatCreated {invite_id, room}: il chat_id è assegnato dal server
+                const created = JSON.parse(wsMessage.data);
+                rememberChatId(created.room.id);
+                break;
+              }
+
               case 'ChatReady':
                 const chatReadyData = JSON.parse(wsMessage.data);
+                rememberChatId(chatReadyData.chat_id);
                 setChatReady(prev => [...prev, chatReadyData]);
                 break;
 
@@ -400,7 +406,7 @@ export const WebSocketProvider = ({ children, user, onLogout }) => {
 
       // Fallback: usa API HTTP per aggiornare lo stato nel backend
       import('../API/API.mjs').then(({ updateUserAvailability }) => {
-        updateUserAvailability(user.username, true)
+        updateUserAvailability(user.username, true, sessionTokenRef.current)
           .catch(err => console.error('Errore aggiornamento API HTTP:', err));
       });
 
@@ -464,14 +470,21 @@ export const WebSocketProvider = ({ children, user, onLogout }) => {
     }
   };
 
+  // Conserva il chat_id ricevuto dal server finché l'utente non entra in una chat
+  const rememberChatId = (chatId) => {
+    if (!chatStateRef.current.inChat) {
+      chatStateRef.current = { ...chatStateRef.current, chatId };
+    }
+  };
+
+  // Il chat_id non viene inviato: lo assegna il server e arriva con ChatCreated
   const sendChatInvite = (chatType, targetUser = '', members = [], message = '') => {
     if (!wsRef.current || !isConnected) return false;
 
-    const chatId = crypto.randomUUID();
+    const inviteId = crypto.randomUUID();
 
     const invite = {
-      id: crypto.randomUUID(),
-      chat_id: chatId,
+      id: inviteId,
       from: user.username,
       from_session_id: sessionIdRef.current,
       chat_type: chatType === 'private'
@@ -488,10 +501,10 @@ export const WebSocketProvider = ({ children, user, onLogout }) => {
 
     try {
       wsRef.current.send(JSON.stringify(wsMessage));
-      return { success: true, chatId: chatId };
+      return { success: true, inviteId };
     } catch (error) {
       console.error('Errore invio invito:', error);
-      return { success: false, chatId: null };
+      return { success: false, inviteId: null };
     }
   };
 
@@ -561,6 +574,7 @@ export const WebSocketProvider = ({ children, user, onLogout }) => {
     removeChatDeclined, //rimuovi notifica rifiuto
     clearAllNotifications,
     handleLogout,
+    getSessionToken: () => sessionTokenRef.current, // per le API REST autenticate (Authorization: Bearer)
     user
   };
 
@@ -0,0 +1,203 @@
+use crate::state::AppState;
+use crate::types::{LoginError, LoginErrorReason, LoginRequest};
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+// Nomi riservati al server (i messaggi di sistema usano "Sistema" come autore)
+const RESERVED_USERNAMES: [&str; 1] = ["Sistema"];
+const MAX_USERNAME_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 8;
+
+// Account registrato: la password è conservata solo come hash argon2 (formato PHC)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Account {
+    pub username: String,
+    pub password_hash: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    InvalidUsername,
+    PasswordTooShort,
+    AlreadyExists,
+    Storage,
+}
+
+impl RegisterError {
+    pub fn message(&self) -> String {
+        match self {
+            RegisterError::InvalidUsername => format!(
+                "Username non valido: deve contenere da 1 a {} caratteri e non essere riservato",
+                MAX_USERNAME_LEN
+            ),
+            RegisterError::PasswordTooShort => format!(
+                "La password deve contenere almeno {} caratteri",
+                MIN_PASSWORD_LEN
+            ),
+            RegisterError::AlreadyExists => "Username già registrato".to_string(),
+            RegisterError::Storage => "Errore nel salvataggio dell'account".to_string(),
+        }
+    }
+}
+
+// Hash verificato quando l'utente non esiste, così la risposta richiede lo stesso tempo
+// e non rivela quali username sono registrati
+fn dummy_password_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(b"password-inesistente", &salt)
+            .map(|hash| hash.to_string())
+            .unwrap_or_default()
+    })
+}
+
+// Elenco degli account registrati. Se creato con `open` viene salvato su file JSON
+// a ogni registrazione, altrimenti vive solo in memoria.
+#[derive(Default)]
+pub struct AccountStore {
+    accounts: Mutex<HashMap<String, Account>>,
+    path: Option<PathBuf>,
+}
+
+impl AccountStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+
+        let mut accounts = HashMap::new();
+        if path.exists() {
+            let content = std::fs::read_to_string(&path)?;
+            if !content.trim().is_empty() {
+                let list: Vec<Account> = serde_json::from_str(&content)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                for account in list {
+                    accounts.insert(account.username.clone(), account);
+                }
+            }
+        }
+
+        Ok(AccountStore {
+            accounts: Mutex::new(accounts),
+            path: Some(path),
+        })
+    }
+
+    pub fn exists(&self, username: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(username)
+    }
+
+    // Registra un nuovo account. L'hashing argon2 è volutamente costoso:
+    // dai contesti async va chiamata tramite `spawn_blocking`.
+    pub fn register(&self, username: &str, password: &str) -> Result<(), RegisterError> {
+        let username_ok = !username.trim().is_empty()
+            && username.trim() == username
+            && username.chars().count() <= MAX_USERNAME_LEN
+            && !RESERVED_USERNAMES.contains(&username);
+        if !username_ok {
+            return Err(RegisterError::InvalidUsername);
+        }
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(RegisterError::PasswordTooShort);
+        }
+        if self.exists(username) {
+            return Err(RegisterError::AlreadyExists);
+        }
+
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|_| RegisterError::Storage)?
+            .to_string();
+
+        let mut accounts = self.accounts.lock().unwrap();
+        // Ricontrolla: due registrazioni concorrenti potrebbero aver superato il controllo iniziale
+        if accounts.contains_key(username) {
+            return Err(RegisterError::AlreadyExists);
+        }
+        accounts.insert(
+            username.to_string(),
+            Account {
+                username: username.to_string(),
+                password_hash,
+                created_at: chrono::Utc::now(),
+            },
+        );
+
+        if let Some(path) = &self.path {
+            let list: Vec<&Account> = accounts.values().collect();
+            let saved = serde_json::to_string_pretty(&list)
+                .map_err(io::Error::from)
+                .and_then(|json| std::fs::write(path, json));
+            if let Err(e) = saved {
+                eprintln!("Errore salvataggio account: {}", e);
+                accounts.remove(username);
+                return Err(RegisterError::Storage);
+            }
+        }
+        Ok(())
+    }
+
+    // Verifica le credenziali. Come `register`, è un'operazione bloccante.
+    // Utente sconosciuto e password errata non sono distinguibili.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        let password_hash = self
+            .accounts
+            .lock()
+            .unwrap()
+            .get(username)
+            .map(|account| account.password_hash.clone());
+
+        let known = password_hash.is_some();
+        let password_hash = password_hash.unwrap_or_else(|| dummy_password_hash().to_string());
+        let Ok(parsed) = PasswordHash::new(&password_hash) else {
+            return false;
+        };
+        let matches = Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok();
+        known && matches
+    }
+}
+
+// Verifica le credenziali e che l'utente non sia già connesso.
+// Condivisa tra /api/login e il login via WebSocket.
+pub async fn check_login(state: &AppState, login_req: &LoginRequest) -> Result<(), LoginError> {
+    let accounts = state.accounts.clone();
+    let username = login_req.username.clone();
+    let password = login_req.password.clone();
+    let verified = tokio::task::spawn_blocking(move || accounts.verify(&username, &password))
+        .await
+        .unwrap_or(false);
+    if !verified {
+        return Err(LoginError::new(
+            LoginErrorReason::InvalidCredentials,
+            &login_req.username,
+        ));
+    }
+
+    // Controlla se l'username è già in uso
+    // Una sessione nel periodo di grazia può essere ripresa con le credenziali
+    if state.users.is_online(&login_req.username).await {
+        return Err(LoginError::new(
+            LoginErrorReason::AlreadyConnected,
+            &login_req.username,
+        ));
+    }
+    Ok(())
+}
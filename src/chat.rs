@@ -1,10 +1,73 @@
+use crate::frame::Frame;
 use crate::performance::update_cpu_time;
+use crate::protocol::ServerMessage;
+use crate::rooms::Delivery;
 use crate::state::AppState;
 use crate::tracking::{check_and_notify_alone_in_chat, remove_user_from_chat_tracking};
-use crate::types::{ChatMessage, MessageType, WebSocketMessage};
+use crate::types::{
+    ChatError, ChatErrorReason, ChatMessage, ChatType, SendChatMessage,
+    TypingRequest,
+};
+use crate::typing::handle_typing_stopped;
+use crate::unread::record_unread;
 use crate::user::broadcast_to_all;
 use std::time::Instant;
-use uuid;
+
+// Messaggio inviato da un client: il mittente deve essere membro della chat di destinazione
+// (quella indicata o, in mancanza, quella in cui si trova). Autore, id, timestamp e tipo di
+// chat sono assegnati dal server; il messaggio registrato e inviato viene restituito.
+pub async fn send_chat_message(
+    state: &AppState,
+    sender_username: &str,
+    request: &SendChatMessage,
+) -> Result<ChatMessage, ChatError> {
+    let start = Instant::now();
+    let chat_id = match &request.chat_id {
+        Some(chat_id) => Some(chat_id.clone()),
+        None => state
+            .users
+            .get(sender_username)
+            .await
+            .and_then(|user| user.chat_id),
+    };
+    let Some(chat_id) = chat_id else {
+        update_cpu_time(state.total_cpu_time.clone(), start);
+        return Err(ChatError::new(ChatErrorReason::UnknownChat, None));
+    };
+
+    let room = match state.rooms.authorize_member(&chat_id, sender_username).await {
+        Ok(room) => room,
+        Err(reason) => {
+            update_cpu_time(state.total_cpu_time.clone(), start);
+            return Err(ChatError::new(reason, Some(&chat_id)));
+        }
+    };
+
+    let chat_msg = ChatMessage {
+        id: uuid::Uuid::new_v4(),
+        chat_id: Some(chat_id),
+        username: sender_username.to_string(),
+        content: request.content.clone(),
+        timestamp: chrono::Utc::now(),
+        chat_type: room.chat_type_for(sender_username),
+    };
+    // Consegna e lettura vengono tracciate per ogni membro della chat
+    state.receipts.track(&chat_msg, &room.members);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    broadcast_chat_message(state, sender_username, &chat_msg).await;
+    // Chi è membro ma sta guardando un'altra chat riceve i non letti aggiornati
+    record_unread(state, &room, &chat_msg);
+    state.hooks.message(&chat_msg);
+
+    // Il messaggio inviato conclude l'eventuale digitazione in corso
+    let typing = TypingRequest {
+        chat_id: room.id,
+    };
+    handle_typing_stopped(state, sender_username, &typing).await;
+    Ok(chat_msg)
+}
 
 pub async fn broadcast_chat_message(
     state: &AppState,
@@ -15,11 +78,7 @@ pub async fn broadcast_chat_message(
     let mut start = Instant::now();
 
     //serializza messaggio
-    let message = WebSocketMessage {
-        message_type: MessageType::ChatMessage,
-        data: serde_json::to_string(chat_msg).unwrap(),
-    };
-    let message_json = serde_json::to_string(&message).unwrap();
+    let frame = Frame::new(ServerMessage::ChatMessage(chat_msg.clone()));
 
     //determina a quale chat appartiene il messaggio
     let target_chat_id = if let Some(explicit_chat_id) = chat_msg.chat_id.clone() {
@@ -30,57 +89,39 @@ pub async fn broadcast_chat_message(
         //aggiorna il tempo di CPU//
         update_cpu_time(state.total_cpu_time.clone(), start);
         // Fallback: prendi chat_id del sender
-        let users = state.connected_users.lock().unwrap();
-        start = Instant::now();
-        let sender_chat_id = users
+        state
+            .users
             .get(sender_username)
-            .and_then(|u| u.user.chat_id.as_ref())
-            .map(|id| id.clone());
-
-        //aggiorna il tempo di CPU//
-        update_cpu_time(state.total_cpu_time.clone(), start);
-        sender_chat_id
+            .await
+            .and_then(|user| user.chat_id)
     };
 
     //invio messaggio
     if let Some(chat_id) = target_chat_id {
-        // FILTRA: Invia solo agli utenti con stesso chatId
-        let users = state.connected_users.lock().unwrap();
+        // Registra il messaggio nella cronologia della chat prima della consegna
         start = Instant::now();
+        state.message_store.append(&chat_id, chat_msg);
+        // Anche gli avvisi di sistema (ingressi, uscite, chiusura) arrivano a tutti i membri,
+        // nella coda offline se disconnessi
+        let delivery = Delivery::Members {
+            users: state.users.clone(),
+            offline: state.offline.clone(),
+        };
 
-        for (_, connected_user) in users.iter() {
-            if let Some(user_chat_id) = &connected_user.user.chat_id {
-                if user_chat_id == &chat_id {
-                    let _ = connected_user.sender.send(message_json.clone());
-                }
-            }
-        }
+        state.rooms.broadcast(&chat_id, frame, None, delivery);
 
         //aggiorna il tempo di CPU//
         update_cpu_time(state.total_cpu_time.clone(), start);
     } else {
         // FALLBACK: Per messaggi di sistema o utenti senza chatId
-        let users = state.connected_users.lock().unwrap();
         start = Instant::now();
 
-        match &chat_msg.chat_type {
-            crate::types::ChatType::Private { target } => {
-                let recipients = vec![sender_username, target.as_str()];
-                for recipient in recipients {
-                    if let Some(connected_user) = users.get(recipient) {
-                        let _ = connected_user.sender.send(message_json.clone());
-                    }
-                }
-            }
-            crate::types::ChatType::Group { members } => {
-                for member in members {
-                    if let Some(connected_user) = users.get(member) {
-                        let _ = connected_user.sender.send(message_json.clone());
-                    }
-                }
-            }
-            _ => {}
-        }
+        let recipients = match &chat_msg.chat_type {
+            ChatType::Private { target } => vec![sender_username.to_string(), target.clone()],
+            ChatType::Group { members } => members.clone(),
+            _ => Vec::new(),
+        };
+        state.users.send_to(recipients, frame);
         //aggiorna il tempo di CPU//
         update_cpu_time(state.total_cpu_time.clone(), start);
     }
@@ -91,15 +132,12 @@ pub async fn broadcast_user_left(state: &AppState, username: &str, chat_id: Opti
     //misura l'inizio di uso di cpu//
     let start = Instant::now();
 
-    let message = WebSocketMessage {
-        message_type: MessageType::UserLeft,
-        data: username.to_string(),
-    };
+    let frame = Frame::new(ServerMessage::UserLeft(username.to_string()));
 
     //aggiorna il tempo di CPU//
     update_cpu_time(state.total_cpu_time.clone(), start);
 
-    broadcast_to_all(state, &message).await;
+    broadcast_to_all(state, frame).await;
 
     //misura l'inizio di uso di cpu//
     let start = Instant::now();
@@ -111,7 +149,7 @@ pub async fn broadcast_user_left(state: &AppState, username: &str, chat_id: Opti
             username: "Sistema".to_string(),
             content: format!("{} ha abbandonato la chat", username),
             timestamp: chrono::Utc::now(),
-            chat_type: crate::types::ChatType::System,
+            chat_type: ChatType::System,
         };
 
         //aggiorna il tempo di CPU//
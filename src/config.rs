@@ -0,0 +1,367 @@
+use axum::http::{header, HeaderValue, Method};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+// File letto se non ne viene indicato un altro con --config o RUGGINE_CONFIG
+pub const DEFAULT_CONFIG_FILE: &str = "ruggine.toml";
+// Prefisso delle variabili d'ambiente (es. RUGGINE_BIND_ADDRESS)
+pub const ENV_PREFIX: &str = "RUGGINE_";
+
+// Opzioni configurabili: stesso nome nel file TOML, in maiuscolo con prefisso
+// nelle variabili d'ambiente e con i trattini sulla riga di comando (--bind-address)
+const KEYS: [&str; 21] = [
+    "bind_address",
+    "allowed_origins",
+    "cpu_log_path",
+    "cpu_log_interval_secs",
+    "messages_path",
+    "rooms_path",
+    "read_markers_path",
+    "accounts_path",
+    "session_key_path",
+    "session_grace_secs",
+    "session_ttl_secs",
+    "typing_timeout_secs",
+    "invite_ttl_secs",
+    "offline_queue_limit",
+    "outbound_queue_limit",
+    "shutdown_timeout_secs",
+    "shutdown_reconnect_after_secs",
+    "metrics_token",
+    "tls_cert_path",
+    "tls_key_path",
+    "tls_reload_interval_secs",
+];
+
+// Configurazione del server. Ogni valore viene preso, in ordine di priorità crescente,
+// dai default, dal file TOML, dalle variabili d'ambiente e dagli argomenti da riga di comando.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind_address: SocketAddr,
+    pub allowed_origins: Vec<String>, // origini ammesse dal CORS (il client web)
+    pub cpu_log_path: PathBuf,
+    pub cpu_log_interval_secs: u64,
+    pub messages_path: PathBuf, // cronologia dei messaggi (JSON lines)
+    pub rooms_path: PathBuf,    // chat con invitati, membri e ruoli (JSON lines)
+    pub read_markers_path: PathBuf, // ultimo messaggio visto per utente e chat (JSON lines)
+    pub accounts_path: PathBuf, // account registrati
+    pub session_key_path: PathBuf, // chiave di firma dei token di sessione (creata se manca)
+    pub session_grace_secs: u64,
+    pub session_ttl_secs: u64,
+    pub typing_timeout_secs: u64,
+    pub invite_ttl_secs: u64, // validità di un invito senza risposta
+    pub offline_queue_limit: usize, // messaggi conservati per ogni utente disconnesso
+    pub outbound_queue_limit: usize, // messaggi in attesa di invio per connessione (oltre: client lento)
+    pub shutdown_timeout_secs: u64, // attesa massima delle connessioni aperte all'arresto
+    pub shutdown_reconnect_after_secs: u64, // suggerimento ai client all'arresto (0: nessuno)
+    pub metrics_token: Option<String>, // token per GET /api/metrics/queues (assente: rotta disattivata)
+    pub tls_cert_path: Option<PathBuf>, // catena di certificati PEM: se presente il server usa HTTPS/WSS
+    pub tls_key_path: Option<PathBuf>,  // chiave privata PEM del certificato
+    pub tls_reload_interval_secs: u64,  // ogni quanto controllare se i file TLS sono cambiati
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            allowed_origins: vec!["http://localhost:5173".to_string()],
+            cpu_log_path: PathBuf::from("Log/cpu_log.txt"),
+            cpu_log_interval_secs: 120,
+            messages_path: PathBuf::from("Data/messages.jsonl"),
+            rooms_path: PathBuf::from("Data/rooms.jsonl"),
+            read_markers_path: PathBuf::from("Data/read_markers.jsonl"),
+            accounts_path: PathBuf::from("Data/accounts.json"),
+            session_key_path: PathBuf::from("Data/session.key"),
+            session_grace_secs: 30,
+            session_ttl_secs: 12 * 60 * 60,
+            typing_timeout_secs: 5,
+            invite_ttl_secs: 24 * 60 * 60,
+            offline_queue_limit: 500,
+            outbound_queue_limit: 1024,
+            shutdown_timeout_secs: 10,
+            shutdown_reconnect_after_secs: 0,
+            metrics_token: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_reload_interval_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    UnknownOption(String),
+    MissingValue(String),
+    InvalidValue { key: String, value: String },
+}
+
+impl ConfigError {
+    pub fn message(&self) -> String {
+        match self {
+            ConfigError::Io(path, e) => format!("Impossibile leggere {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => format!("File {} non valido: {}", path.display(), e),
+            ConfigError::UnknownOption(option) => format!(
+                "Opzione sconosciuta '{}'. Opzioni disponibili: --config, {}",
+                option,
+                KEYS.iter()
+                    .map(|key| format!("--{}", key.replace('_', "-")))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ConfigError::MissingValue(option) => format!("Manca il valore di '{}'", option),
+            ConfigError::InvalidValue { key, value } => {
+                format!("Valore '{}' non valido per {}", value, key)
+            }
+        }
+    }
+}
+
+impl Config {
+    // Carica la configurazione del processo (argomenti e variabili d'ambiente reali)
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::load_from(std::env::args().skip(1), |key| std::env::var(key).ok())
+    }
+
+    // Carica la configurazione da argomenti (`--chiave valore` o `--chiave=valore`)
+    // e variabili d'ambiente forniti dal chiamante.
+    pub fn load_from<I, F>(args: I, env: F) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let cli = parse_args(args)?;
+
+        // File: --config, poi RUGGINE_CONFIG, poi ruggine.toml se esiste
+        let config_file = cli
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| PathBuf::from(value))
+            .or_else(|| env(&format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from));
+        let mut config = match config_file {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+
+        for key in KEYS {
+            if let Some(value) = env(&format!("{}{}", ENV_PREFIX, key.to_uppercase())) {
+                config.set(key, &value)?;
+            }
+        }
+        for (key, value) in cli.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value)?;
+        }
+        config.validate_tls()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let config: Config = toml::from_str(&content)
+            .map_err(|e| ConfigError::Parse(path.to_path_buf(), e.to_string()))?;
+        config.validate()?;
+        config.validate_tls()?;
+        Ok(config)
+    }
+
+    // Imposta un'opzione a partire dal suo valore testuale (env o riga di comando)
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        match key {
+            "bind_address" => self.bind_address = value.parse().map_err(|_| invalid())?,
+            "allowed_origins" => {
+                self.allowed_origins = value
+                    .split(',')
+                    .map(|origin| origin.trim().to_string())
+                    .filter(|origin| !origin.is_empty())
+                    .collect();
+            }
+            "cpu_log_path" => self.cpu_log_path = PathBuf::from(value),
+            "cpu_log_interval_secs" => {
+                self.cpu_log_interval_secs = value.parse().map_err(|_| invalid())?
+            }
+            "messages_path" => self.messages_path = PathBuf::from(value),
+            "rooms_path" => self.rooms_path = PathBuf::from(value),
+            "read_markers_path" => self.read_markers_path = PathBuf::from(value),
+            "accounts_path" => self.accounts_path = PathBuf::from(value),
+            "session_key_path" => self.session_key_path = PathBuf::from(value),
+            "session_grace_secs" => self.session_grace_secs = value.parse().map_err(|_| invalid())?,
+            "session_ttl_secs" => self.session_ttl_secs = value.parse().map_err(|_| invalid())?,
+            "typing_timeout_secs" => {
+                self.typing_timeout_secs = value.parse().map_err(|_| invalid())?
+            }
+            "invite_ttl_secs" => self.invite_ttl_secs = value.parse().map_err(|_| invalid())?,
+            "offline_queue_limit" => {
+                self.offline_queue_limit = value.parse().map_err(|_| invalid())?
+            }
+            "outbound_queue_limit" => {
+                self.outbound_queue_limit = value.parse().map_err(|_| invalid())?
+            }
+            "shutdown_timeout_secs" => {
+                self.shutdown_timeout_secs = value.parse().map_err(|_| invalid())?
+            }
+            "shutdown_reconnect_after_secs" => {
+                self.shutdown_reconnect_after_secs = value.parse().map_err(|_| invalid())?
+            }
+            "metrics_token" => self.metrics_token = Some(value.to_string()),
+            "tls_cert_path" => self.tls_cert_path = Some(PathBuf::from(value)),
+            "tls_key_path" => self.tls_key_path = Some(PathBuf::from(value)),
+            "tls_reload_interval_secs" => {
+                self.tls_reload_interval_secs = value.parse().map_err(|_| invalid())?
+            }
+            _ => return Err(ConfigError::UnknownOption(key.to_string())),
+        }
+        self.validate()
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(origin) = self
+            .allowed_origins
+            .iter()
+            .find(|origin| origin.parse::<HeaderValue>().is_err())
+        {
+            return Err(ConfigError::InvalidValue {
+                key: "allowed_origins".to_string(),
+                value: origin.clone(),
+            });
+        }
+        if self.tls_reload_interval_secs == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "tls_reload_interval_secs".to_string(),
+                value: "0".to_string(),
+            });
+        }
+        if self.cpu_log_interval_secs == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "cpu_log_interval_secs".to_string(),
+                value: "0".to_string(),
+            });
+        }
+        if self.invite_ttl_secs == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "invite_ttl_secs".to_string(),
+                value: "0".to_string(),
+            });
+        }
+        if self.outbound_queue_limit == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "outbound_queue_limit".to_string(),
+                value: "0".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    // Certificato e chiave vanno indicati insieme (controllato a configurazione completa,
+    // perché env e riga di comando li impostano uno alla volta)
+    fn validate_tls(&self) -> Result<(), ConfigError> {
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(_), None) => Err(ConfigError::MissingValue("tls_key_path".to_string())),
+            (None, Some(_)) => Err(ConfigError::MissingValue("tls_cert_path".to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn cpu_log_interval(&self) -> Duration {
+        Duration::from_secs(self.cpu_log_interval_secs)
+    }
+
+    pub fn session_grace(&self) -> Duration {
+        Duration::from_secs(self.session_grace_secs)
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        Duration::from_secs(self.session_ttl_secs)
+    }
+
+    pub fn typing_timeout(&self) -> Duration {
+        Duration::from_secs(self.typing_timeout_secs)
+    }
+
+    pub fn invite_ttl(&self) -> Duration {
+        Duration::from_secs(self.invite_ttl_secs)
+    }
+
+    // Certificato e chiave TLS, se configurati
+    pub fn tls_paths(&self) -> Option<(&Path, &Path)> {
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            _ => None,
+        }
+    }
+
+    pub fn tls_reload_interval(&self) -> Duration {
+        Duration::from_secs(self.tls_reload_interval_secs)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn shutdown_reconnect_after(&self) -> Option<Duration> {
+        (self.shutdown_reconnect_after_secs > 0)
+            .then(|| Duration::from_secs(self.shutdown_reconnect_after_secs))
+    }
+
+    //bypass del blocco del browser per richieste tra origini diverse(client/server)
+    pub fn cors_layer(&self) -> CorsLayer {
+        let origins: Vec<HeaderValue> = self
+            .allowed_origins
+            .iter()
+            .filter_map(|origin| origin.parse().ok())
+            .collect();
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::DELETE,
+                Method::OPTIONS,
+            ])
+            .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION, header::ACCEPT])
+            .allow_credentials(true)
+    }
+}
+
+// Converte gli argomenti in coppie (chiave, valore) con la chiave in snake_case
+fn parse_args<I>(args: I) -> Result<Vec<(String, String)>, ConfigError>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut parsed = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(option) = arg.strip_prefix("--") else {
+            return Err(ConfigError::UnknownOption(arg));
+        };
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                (option.to_string(), value)
+            }
+        };
+        let key = name.replace('-', "_");
+        if key != "config" && !KEYS.contains(&key.as_str()) {
+            return Err(ConfigError::UnknownOption(arg));
+        }
+        parsed.push((key, value));
+    }
+    Ok(parsed)
+}
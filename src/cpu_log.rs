@@ -1,44 +1,43 @@
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 
-pub fn start_log(duration: Arc<Mutex<Duration>>) {
+// Scrive periodicamente il tempo di CPU accumulato nel file `path`, ogni `interval`
+pub fn start_log(duration: Arc<Mutex<Duration>>, path: PathBuf, interval: Duration) {
     tokio::spawn(async move {
         loop {
-            // Copia il valore sotto lock e rilascia subito il mutex
-            let secs = {
-                let d = duration.lock().unwrap();
-                d.as_secs_f64()
-            };
+            if let Err(e) = write_log(&duration, &path).await {
+                eprintln!("Errore scrittura log CPU: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
 
-            let line = format!("Durata corrente: {:.6} s\n", secs);
+// Aggiunge al file `path` una riga con il tempo di CPU accumulato finora
+pub async fn write_log(duration: &Arc<Mutex<Duration>>, path: &Path) -> std::io::Result<()> {
+    // Copia il valore sotto lock e rilascia subito il mutex
+    let secs = {
+        let d = duration.lock().unwrap();
+        d.as_secs_f64()
+    };
 
-            // Crea la directory se non esiste
-            if let Err(e) = tokio::fs::create_dir_all("Log").await {
-                eprintln!("Errore creazione directory: {}", e);
-                continue;
-            }
+    let line = format!("Durata corrente: {:.6} s\n", secs);
 
-            // Apri il file in append e scrivi
-            match OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open("Log/cpu_log.txt")
-                .await
-            {
-                Ok(mut file) => {
-                    if let Err(e) = file.write_all(line.as_bytes()).await {
-                        eprintln!("Errore scrittura file: {}", e);
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Errore apertura file: {}", e);
-                }
-            }
+    // Crea la directory se non esiste
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(dir).await?;
+    }
 
-            tokio::time::sleep(Duration::from_secs(120)).await;
-        }
-    });
+    // Apri il file in append e scrivi
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    file.flush().await
 }
@@ -0,0 +1,103 @@
+use crate::protocol::{ProtocolVersion, ServerMessage};
+use crate::types::MessageType;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+// Messaggio in uscita condiviso tra tutti i destinatari: viene serializzato una sola volta
+// (protocollo 2) e la codifica per il protocollo 1 viene calcolata al primo invio che la richiede.
+// Clonare un Frame costa un contatore di riferimenti.
+#[derive(Clone)]
+pub struct Frame(Arc<Encoded>);
+
+struct Encoded {
+    message_type: MessageType,
+    v2: Arc<str>,
+    v1: OnceLock<Arc<str>>,
+}
+
+// Busta del protocollo 2: il messaggio con l'eventuale id della richiesta a cui risponde
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(flatten)]
+    message: &'a ServerMessage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+// Frame del protocollo 2 diviso in payload e id della richiesta, senza rileggere il payload
+#[derive(Deserialize)]
+struct V2Frame<'a> {
+    #[serde(borrow)]
+    data: &'a RawValue,
+    request_id: Option<String>,
+}
+
+// Codifica V1, identica a quella di WebSocketMessage
+#[derive(Serialize)]
+struct V1Frame<'a> {
+    message_type: &'a MessageType,
+    data: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+impl Frame {
+    pub fn new(message: ServerMessage) -> Self {
+        Frame::reply(message, None)
+    }
+
+    // Risposta a un frame del client: ripete il suo `request_id`, se presente
+    pub fn reply(message: ServerMessage, request_id: Option<&str>) -> Self {
+        let envelope = Envelope {
+            message: &message,
+            request_id,
+        };
+        Frame(Arc::new(Encoded {
+            message_type: message.message_type(),
+            v2: serde_json::to_string(&envelope).unwrap().into(),
+            v1: OnceLock::new(),
+        }))
+    }
+
+    pub fn message_type(&self) -> &MessageType {
+        &self.0.message_type
+    }
+
+    // Testo del frame nella versione del protocollo indicata, calcolato una volta sola
+    pub fn encode(&self, version: ProtocolVersion) -> Arc<str> {
+        let encoded = &self.0;
+        match version {
+            ProtocolVersion::V2 => encoded.v2.clone(),
+            ProtocolVersion::V1 => encoded
+                .v1
+                .get_or_init(|| {
+                    let frame: V2Frame = serde_json::from_str(&encoded.v2).unwrap();
+                    // Un payload testuale (UserLeft) resta così com'è, gli altri vengono racchiusi in una stringa
+                    let text: Option<String> = serde_json::from_str(frame.data.get()).ok();
+                    let frame = V1Frame {
+                        message_type: &encoded.message_type,
+                        data: text.as_deref().unwrap_or(frame.data.get()),
+                        request_id: frame.request_id.as_deref(),
+                    };
+                    serde_json::to_string(&frame).unwrap().into()
+                })
+                .clone(),
+        }
+    }
+}
+
+impl PartialEq for Frame {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.0.v2 == other.0.v2
+    }
+}
+
+impl Eq for Frame {}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Frame").field(&self.0.v2).finish()
+    }
+}
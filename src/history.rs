@@ -0,0 +1,77 @@
+use crate::performance::update_cpu_time;
+use crate::state::AppState;
+use crate::types::{ChatErrorReason, HistoryPage, HistoryRequest};
+use std::time::Instant;
+
+// Numero di messaggi restituiti quando il client non indica un limite
+pub const DEFAULT_HISTORY_LIMIT: usize = 50;
+// Limite massimo per una singola pagina, per evitare risposte enormi
+pub const MAX_HISTORY_LIMIT: usize = 200;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HistoryError {
+    // La chat non esiste
+    UnknownChat,
+    // L'utente non risulta tra gli invitati della chat
+    Forbidden,
+    // `before` non è un messaggio della chat
+    InvalidCursor,
+}
+
+impl HistoryError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            HistoryError::UnknownChat => "La chat indicata non esiste",
+            HistoryError::Forbidden => "Non hai accesso alla cronologia di questa chat",
+            HistoryError::InvalidCursor => "Il messaggio indicato in `before` non fa parte di questa chat",
+        }
+    }
+
+    // Motivo inviato ai client WebSocket nel ChatError
+    pub fn reason(&self) -> ChatErrorReason {
+        match self {
+            HistoryError::UnknownChat => ChatErrorReason::UnknownChat,
+            HistoryError::Forbidden => ChatErrorReason::NotMember,
+            HistoryError::InvalidCursor => ChatErrorReason::InvalidCursor,
+        }
+    }
+}
+
+// Restituisce una pagina di cronologia della chat richiesta.
+// L'accesso è consentito solo agli utenti invitati nella chat (`Room::invited_users`).
+pub async fn load_history_page(
+    state: &AppState,
+    username: &str,
+    request: &HistoryRequest,
+) -> Result<HistoryPage, HistoryError> {
+    let Some(room) = state.rooms.get(&request.chat_id).await else {
+        return Err(HistoryError::UnknownChat);
+    };
+    if !room.is_invited(username) {
+        return Err(HistoryError::Forbidden);
+    }
+
+    let start = Instant::now();
+    let limit = request
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+    let page = state
+        .message_store
+        .page(&request.chat_id, request.before, limit);
+    let Some((messages, has_more)) = page else {
+        //aggiorna il tempo di CPU//
+        update_cpu_time(state.total_cpu_time.clone(), start);
+        return Err(HistoryError::InvalidCursor);
+    };
+    let receipts = state.receipts.for_messages(&messages);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    Ok(HistoryPage {
+        chat_id: request.chat_id.clone(),
+        messages,
+        has_more,
+        receipts,
+    })
+}
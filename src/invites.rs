@@ -1,164 +1,711 @@
 use crate::chat::broadcast_chat_message;
+use crate::frame::Frame;
 use crate::performance::update_cpu_time;
+use crate::protocol::ServerMessage;
 use crate::state::AppState;
-use crate::tracking::{broadcast_chat_users_count, init_chat_tracking, remove_user_from_invited};
+use crate::tracking::{broadcast_chat_users_count, remove_user_from_invited};
 use crate::types::{
-    ChatInvite, ChatInviteResponse, ChatInviteResponseNotify, ChatMessage, ChatReady, MessageType,
-    WebSocketMessage,
+    AddChatMembers, ChatCreated, ChatError, ChatErrorReason, ChatInvite, ChatInviteClosed,
+    ChatInviteResponse, ChatInviteResponseNotify, ChatMessage, ChatReady, ChatType, InviteCancel,
+    InviteState, PendingInvite, RoomKind,
 };
-use std::time::Instant;
-use uuid;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// Validità predefinita di un invito senza risposta
+pub const DEFAULT_INVITE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Invito inviato dal server con la risposta di ciascun destinatario
+struct InviteRecord {
+    invite: ChatInvite, // con chat_id e mittente assegnati dal server
+    expires_at: DateTime<Utc>,
+    ttl: chrono::Duration, // validità dell'invito, usata anche per ricordarlo dopo la chiusura
+    responses: HashMap<String, InviteState>, // Pending, Accepted o Declined
+}
+
+impl InviteRecord {
+    // Stato dell'invito per `username` (None se non ne è destinatario):
+    // la scadenza vale solo per chi non ha ancora risposto
+    fn state_for(&self, username: &str) -> Option<InviteState> {
+        let state = match self.responses.get(username)? {
+            InviteState::Pending if Utc::now() >= self.expires_at => InviteState::Expired,
+            state => state.clone(),
+        };
+        Some(state)
+    }
+
+    // Destinatari che non hanno ancora risposto
+    fn pending(&self) -> Vec<String> {
+        let mut pending: Vec<String> = self
+            .responses
+            .keys()
+            .filter(|u| self.state_for(u) == Some(InviteState::Pending))
+            .cloned()
+            .collect();
+        pending.sort();
+        pending
+    }
+
+    // Motivo per cui un invito non più in attesa non può essere accettato o ritirato
+    fn closed_reason(state: InviteState) -> ChatErrorReason {
+        match state {
+            InviteState::Expired => ChatErrorReason::InviteExpired,
+            InviteState::Cancelled => ChatErrorReason::InviteCancelled,
+            _ => ChatErrorReason::InviteAnswered,
+        }
+    }
+}
+
+// Invito chiuso per tutti (risposto, ritirato o scaduto): resta solo lo stato finale, per
+// rifiutare risposte e ritiri tardivi con l'errore giusto, fino a `forget_at`
+struct ClosedInvite {
+    state: InviteState,
+    forget_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct Invites {
+    open: HashMap<String, InviteRecord>,
+    closed: HashMap<String, ClosedInvite>,
+    sweeping: bool, // è attivo il task che chiude gli inviti scaduti
+}
+
+impl Invites {
+    fn close(&mut self, invite_id: &str, state: InviteState) {
+        if let Some(record) = self.open.remove(invite_id) {
+            let forget_at = Utc::now() + record.ttl;
+            self.closed
+                .insert(invite_id.to_string(), ClosedInvite { state, forget_at });
+        }
+    }
+
+    // Un invito a cui hanno risposto (o da cui sono stati tolti) tutti i destinatari viene chiuso
+    fn close_if_answered(&mut self, invite_id: &str) {
+        if self
+            .open
+            .get(invite_id)
+            .is_some_and(|record| record.pending().is_empty())
+        {
+            self.close(invite_id, InviteState::Accepted);
+        }
+    }
+
+    fn closed_reason(&self, invite_id: &str) -> Option<ChatErrorReason> {
+        self.closed
+            .get(invite_id)
+            .map(|closed| InviteRecord::closed_reason(closed.state.clone()))
+    }
+}
+
+// Inviti inviati dal server, per id, con lo stato di ciascun destinatario.
+// Gli inviti chiusi vengono tolti subito; ne resta lo stato finale per un'altra validità,
+// poi vengono dimenticati dal task di `start_invite_sweeper`.
+#[derive(Clone, Default)]
+pub struct InviteRegistry {
+    invites: Arc<Mutex<Invites>>,
+    registered: Arc<Notify>, // sveglia il task di scadenza quando arriva un nuovo invito
+}
+
+impl InviteRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Id già usato da un invito aperto o chiuso da poco
+    pub fn contains(&self, invite_id: &str) -> bool {
+        let invites = self.invites.lock().unwrap();
+        invites.open.contains_key(invite_id) || invites.closed.contains_key(invite_id)
+    }
+
+    // Inviti ancora aperti
+    pub fn open_count(&self) -> usize {
+        self.invites.lock().unwrap().open.len()
+    }
+
+    // Registra un invito appena inviato ai `recipients`, valido per `ttl`. Le risposte si
+    // riferiscono all'invito tramite il suo id: un id già usato (invito aperto o chiuso da poco)
+    // viene rifiutato, con lo stesso lock che registra l'invito
+    pub fn register(
+        &self,
+        invite: &ChatInvite,
+        recipients: &[String],
+        ttl: Duration,
+    ) -> Result<(), ChatErrorReason> {
+        let ttl = chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::zero());
+        let record = InviteRecord {
+            invite: invite.clone(),
+            expires_at: Utc::now() + ttl,
+            ttl,
+            responses: recipients
+                .iter()
+                .filter(|u| **u != invite.from)
+                .map(|u| (u.clone(), InviteState::Pending))
+                .collect(),
+        };
+        let mut invites = self.invites.lock().unwrap();
+        if invites.open.contains_key(&invite.id) || invites.closed.contains_key(&invite.id) {
+            return Err(ChatErrorReason::DuplicateInvite);
+        }
+        invites.open.insert(invite.id.clone(), record);
+        drop(invites);
+        self.registered.notify_one();
+        Ok(())
+    }
+
+    // Registra la risposta di `username` a un invito ancora in attesa a lui rivolto
+    // (e, se indicata, relativo a `chat_id`). Restituisce l'invito.
+    pub fn respond(
+        &self,
+        invite_id: &str,
+        chat_id: Option<&str>,
+        username: &str,
+        accepted: bool,
+    ) -> Result<ChatInvite, ChatErrorReason> {
+        let mut invites = self.invites.lock().unwrap();
+        let Some(record) = invites.open.get_mut(invite_id) else {
+            return Err(invites
+                .closed_reason(invite_id)
+                .unwrap_or(ChatErrorReason::NotInvited));
+        };
+        if chat_id.is_some() && chat_id != record.invite.chat_id.as_deref() {
+            return Err(ChatErrorReason::UnknownChat);
+        }
+        match record.state_for(username) {
+            None => return Err(ChatErrorReason::NotInvited),
+            Some(InviteState::Pending) => {}
+            Some(state) => return Err(InviteRecord::closed_reason(state)),
+        }
+        let state = if accepted {
+            InviteState::Accepted
+        } else {
+            InviteState::Declined
+        };
+        record.responses.insert(username.to_string(), state);
+        let invite = record.invite.clone();
+        invites.close_if_answered(invite_id);
+        Ok(invite)
+    }
+
+    // L'accettazione di `username` non ha avuto effetto (la chat è stata chiusa nel frattempo):
+    // per lui l'invito risulta scaduto, non accettato
+    pub fn expire_for(&self, invite_id: &str, username: &str) {
+        let mut invites = self.invites.lock().unwrap();
+        if let Some(record) = invites.open.get_mut(invite_id) {
+            record
+                .responses
+                .insert(username.to_string(), InviteState::Expired);
+        } else if let Some(closed) = invites.closed.get_mut(invite_id) {
+            closed.state = InviteState::Expired;
+        }
+    }
+
+    // Ritira un invito di `inviter`: restituisce l'invito e i destinatari che non avevano risposto
+    pub fn cancel(
+        &self,
+        invite_id: &str,
+        inviter: &str,
+    ) -> Result<(ChatInvite, Vec<String>), ChatErrorReason> {
+        let mut invites = self.invites.lock().unwrap();
+        let Some(record) = invites.open.get(invite_id) else {
+            return Err(invites
+                .closed_reason(invite_id)
+                .unwrap_or(ChatErrorReason::UnknownInvite));
+        };
+        if record.invite.from != inviter {
+            return Err(ChatErrorReason::UnknownInvite);
+        }
+        if Utc::now() >= record.expires_at {
+            return Err(ChatErrorReason::InviteExpired);
+        }
+        let pending = record.pending();
+        if pending.is_empty() {
+            return Err(ChatErrorReason::InviteAnswered);
+        }
+        let invite = record.invite.clone();
+        invites.close(invite_id, InviteState::Cancelled);
+        Ok((invite, pending))
+    }
+
+    // Chiude gli inviti scaduti entro `now` e dimentica quelli chiusi da più di una validità.
+    // Restituisce gli inviti scaduti con i destinatari che non hanno risposto
+    pub fn sweep(&self, now: DateTime<Utc>) -> Vec<(ChatInvite, Vec<String>)> {
+        let mut invites = self.invites.lock().unwrap();
+        invites.closed.retain(|_, closed| closed.forget_at > now);
+        let due: Vec<String> = invites
+            .open
+            .iter()
+            .filter(|(_, record)| record.expires_at <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut expired = Vec::new();
+        for invite_id in due {
+            let record = &invites.open[&invite_id];
+            let mut pending: Vec<String> = record
+                .responses
+                .iter()
+                .filter(|(_, state)| **state == InviteState::Pending)
+                .map(|(username, _)| username.clone())
+                .collect();
+            pending.sort();
+            expired.push((record.invite.clone(), pending));
+            invites.close(&invite_id, InviteState::Expired);
+        }
+        expired
+    }
+
+    // Prossima scadenza da controllare. Se non resta nulla il task di scadenza si ferma:
+    // lo decide sotto lo stesso lock di `start_sweeping`, così nessun invito resta scoperto
+    fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        let mut invites = self.invites.lock().unwrap();
+        let next = invites
+            .open
+            .values()
+            .map(|record| record.expires_at)
+            .chain(invites.closed.values().map(|closed| closed.forget_at))
+            .min();
+        if next.is_none() {
+            invites.sweeping = false;
+        }
+        next
+    }
+
+    // True se il task di scadenza va avviato
+    fn start_sweeping(&self) -> bool {
+        let mut invites = self.invites.lock().unwrap();
+        !std::mem::replace(&mut invites.sweeping, true)
+    }
+
+    // Ritira gli inviti della chat ancora in attesa di risposta da parte di `username`
+    // (es. espulso o bandito). Restituisce gli id degli inviti ritirati
+    pub fn revoke(&self, chat_id: &str, username: &str) -> Vec<String> {
+        let mut invites = self.invites.lock().unwrap();
+        let mut revoked = Vec::new();
+        for record in invites.open.values_mut() {
+            if record.invite.chat_id.as_deref() == Some(chat_id)
+                && record.state_for(username) == Some(InviteState::Pending)
+            {
+                record
+                    .responses
+                    .insert(username.to_string(), InviteState::Cancelled);
+                revoked.push(record.invite.id.clone());
+            }
+        }
+        for invite_id in &revoked {
+            invites.close_if_answered(invite_id);
+        }
+        revoked
+    }
+
+    // Inviti ancora in attesa di risposta da parte di `username`, dal meno recente
+    pub fn pending_for(&self, username: &str) -> Vec<PendingInvite> {
+        let invites = self.invites.lock().unwrap();
+        let mut pending: Vec<PendingInvite> = invites
+            .open
+            .values()
+            .filter(|record| record.state_for(username) == Some(InviteState::Pending))
+            .map(|record| PendingInvite {
+                invite: record.invite.clone(),
+                expires_at: record.expires_at,
+            })
+            .collect();
+        pending.sort_by_key(|p| p.expires_at);
+        pending
+    }
+}
+
+// Rifiuta l'invito se qualche destinatario non ha un account: l'errore elenca gli username sconosciuti
+fn check_registered(state: &AppState, usernames: &[String], chat_id: Option<&str>) -> Result<(), ChatError> {
+    let unknown: Vec<&str> = usernames
+        .iter()
+        .filter(|username| !state.accounts.exists(username))
+        .map(String::as_str)
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    Err(ChatError {
+        message: format!("Utenti non registrati: {}", unknown.join(", ")),
+        ..ChatError::new(ChatErrorReason::UnknownUser, chat_id)
+    })
+}
 
 //Gestione inviti chat
-pub async fn send_chat_invite(state: &AppState, from_username: &str, invite: &ChatInvite) {
+// `request_id` è quello del frame ChatInvite, ripetuto nella conferma ChatCreated
+pub async fn send_chat_invite(
+    state: &AppState,
+    from_username: &str,
+    invite: &ChatInvite,
+    request_id: Option<&str>,
+) -> Result<(), ChatError> {
     let mut start = Instant::now();
-    let message = WebSocketMessage {
-        message_type: MessageType::ChatInvite,
-        data: serde_json::to_string(invite).unwrap(),
+
+    //Determina tipo di chat e invitati in base al tipo di invito
+    let (kind, invited_users) = match &invite.chat_type {
+        ChatType::Private { target } => (RoomKind::Private, vec![target.clone()]),
+        ChatType::Group { members } => (RoomKind::Group, members.clone()),
+        ChatType::System => (RoomKind::Group, Vec::new()),
     };
-    let message_json = serde_json::to_string(&message).unwrap();
-    //aggiorna il tempo di CPU//
+    // Serve almeno un destinatario diverso dal mittente
+    if !invited_users.iter().any(|u| u != from_username) {
+        update_cpu_time(state.total_cpu_time.clone(), start);
+        return Err(ChatError::new(ChatErrorReason::InvalidInvite, None));
+    }
+    // Si possono invitare solo account registrati
+    if let Err(error) = check_registered(state, &invited_users, None) {
+        update_cpu_time(state.total_cpu_time.clone(), start);
+        return Err(error);
+    }
     update_cpu_time(state.total_cpu_time.clone(), start);
-    let users = state.connected_users.lock().unwrap();
+
+    // Mittente e sessione sono quelli autenticati, non quelli dichiarati dal client
+    let from_session_id = state
+        .users
+        .session(from_username)
+        .await
+        .map(|(_, session_id)| session_id)
+        .unwrap_or_default();
     start = Instant::now();
+    // Il server genera l'id della chat: quello eventualmente proposto dal client è ignorato.
+    // L'invito viene registrato prima di creare la chat, così un id di invito già usato
+    // non lascia chat orfane
+    let chat_id = uuid::Uuid::new_v4().to_string();
+    let stamped_invite = ChatInvite {
+        chat_id: Some(chat_id.clone()),
+        from: from_username.to_string(),
+        from_session_id,
+        ..invite.clone()
+    };
+    if let Err(reason) = state
+        .invites
+        .register(&stamped_invite, &invited_users, state.invite_ttl)
+    {
+        update_cpu_time(state.total_cpu_time.clone(), start);
+        return Err(ChatError::new(reason, None));
+    }
+    let room = state
+        .rooms
+        .create_with_id(chat_id, from_username, kind, invited_users);
+    let frame = Frame::new(ServerMessage::ChatInvite(stamped_invite));
 
-    //Inizializza tracking utenti per questa chat
-    let invited_users = match &invite.chat_type {
-        crate::types::ChatType::Private { target } => {
-            vec![from_username.to_string(), target.clone()]
-        }
-        crate::types::ChatType::Group { members } => members.clone(),
-        _ => vec![],
+    // Conferma all'invitante la chat appena creata
+    let created = ChatCreated {
+        invite_id: invite.id.clone(),
+        room: room.clone(),
     };
+    let created = Frame::reply(ServerMessage::ChatCreated(created), request_id);
+    state.users.send_to(vec![from_username.to_string()], created);
 
-    if let Some(chat_id) = &invite.chat_id {
-        init_chat_tracking(state, chat_id, invited_users);
+    // Invia l'invito a tutti gli invitati tranne il mittente (chi è disconnesso lo riceverà al login)
+    for member in &room.invited_users {
+        if member != from_username {
+            state
+                .users
+                .deliver_or_queue(member, None, frame.clone(), &state.offline, &state.accounts);
+        }
     }
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
 
-    //Determina i destinatari dell'invito in base al tipo di chat
-    match &invite.chat_type {
-        crate::types::ChatType::Private { target } => {
-            // Invito per chat privata: invia solo al target
-            if let Some(connected_user) = users.get(target) {
-                let _ = connected_user.sender.send(message_json);
-            }
+    start_invite_sweeper(state);
+    Ok(())
+}
+
+// `AddChatMembers`: un membro invita altre persone in una chat di gruppo già creata.
+// Membri e presenti restano invariati; i nuovi invitati ricevono un ChatInvite a cui
+// rispondere come a quello iniziale
+pub async fn add_chat_members(
+    state: &AppState,
+    from_username: &str,
+    request: &AddChatMembers,
+) -> Result<(), ChatError> {
+    let mut start = Instant::now();
+    // Controllo anticipato, prima di modificare la chat: `register` lo ripete in modo atomico
+    if state.invites.contains(&request.invite_id) {
+        update_cpu_time(state.total_cpu_time.clone(), start);
+        return Err(ChatError::new(ChatErrorReason::DuplicateInvite, Some(&request.chat_id)));
+    }
+    let registered = check_registered(state, &request.members, Some(&request.chat_id));
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    if let Err(error) = registered {
+        // Chi non può invitare in questa chat riceve quell'errore, non l'elenco degli sconosciuti
+        state
+            .rooms
+            .check_inviter(&request.chat_id, from_username)
+            .await
+            .map_err(|reason| ChatError::new(reason, Some(&request.chat_id)))?;
+        return Err(error);
+    }
+
+    let (room, added) = state
+        .rooms
+        .invite(&request.chat_id, from_username, request.members.clone())
+        .await
+        .map_err(|reason| ChatError::new(reason, Some(&request.chat_id)))?;
+
+    let from_session_id = state
+        .users
+        .session(from_username)
+        .await
+        .map(|(_, session_id)| session_id)
+        .unwrap_or_default();
+    start = Instant::now();
+    let invite = ChatInvite {
+        id: request.invite_id.clone(),
+        chat_id: Some(room.id.clone()),
+        from: from_username.to_string(),
+        from_session_id,
+        chat_type: ChatType::Group {
+            members: room.invited_users.clone(),
+        },
+        message: request.message.clone(),
+        timestamp: chrono::Utc::now(),
+    };
+    if let Err(reason) = state.invites.register(&invite, &added, state.invite_ttl) {
+        // Un invito con lo stesso id è stato registrato nel frattempo: gli invitati appena aggiunti
+        // vengono tolti dalla chat
+        update_cpu_time(state.total_cpu_time.clone(), start);
+        for member in &added {
+            remove_user_from_invited(state, &room.id, member).await;
         }
-        crate::types::ChatType::Group { members } => {
-            // Invito per chat di gruppo: invia a tutti i membri tranne il mittente
-            for member in members {
-                if member != from_username {
-                    if let Some(connected_user) = users.get(member) {
-                        let _ = connected_user.sender.send(message_json.clone());
-                    }
-                }
+        return Err(ChatError::new(reason, Some(&room.id)));
+    }
+    let frame = Frame::new(ServerMessage::ChatInvite(invite));
+    for member in &added {
+        state
+            .users
+            .deliver_or_queue(member, None, frame.clone(), &state.offline, &state.accounts);
+    }
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    start_invite_sweeper(state);
+
+    // Annuncia i nuovi invitati nella chat e aggiorna il conteggio per tutti
+    let system_message = ChatMessage {
+        id: uuid::Uuid::new_v4(),
+        chat_id: Some(room.id.clone()),
+        username: "Sistema".to_string(),
+        content: format!("{} ha invitato {} nella chat", from_username, added.join(", ")),
+        timestamp: chrono::Utc::now(),
+        chat_type: ChatType::System,
+    };
+    broadcast_chat_message(state, "Sistema", &system_message).await;
+    broadcast_chat_users_count(state, &room.id).await;
+    Ok(())
+}
+
+// Un solo task chiude gli inviti alla scadenza, per chi non ha ancora risposto, e dimentica
+// quelli chiusi da tempo. Termina quando non resta nulla da sorvegliare e riparte con il
+// prossimo invito
+fn start_invite_sweeper(state: &AppState) {
+    if !state.invites.start_sweeping() {
+        return;
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        while let Some(deadline) = state.invites.next_deadline() {
+            let wait = (deadline - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+            // Un nuovo invito può scadere prima (validità cambiata): si ricalcola la scadenza
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => expire_chat_invites(&state).await,
+                _ = state.invites.registered.notified() => {}
             }
         }
-        _ => {}
+    });
+}
+
+// Chiude gli inviti scaduti: avvisa i destinatari che non hanno risposto e l'invitante
+async fn expire_chat_invites(state: &AppState) {
+    let start = Instant::now();
+    let expired = state.invites.sweep(Utc::now());
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    for (invite, pending) in expired {
+        if pending.is_empty() {
+            continue;
+        }
+        let frame = close_chat_invite(state, &invite, &pending, InviteState::Expired).await;
+        send_to_inviter(state, &invite.from, &invite.from_session_id, frame);
+    }
+}
+
+// `ChatInviteCancel`: l'invitante ritira un invito a cui qualcuno non ha ancora risposto
+pub async fn cancel_chat_invite(
+    state: &AppState,
+    username: &str,
+    cancel: &InviteCancel,
+) -> Result<(), ChatError> {
+    let start = Instant::now();
+    let cancelled = state.invites.cancel(&cancel.invite_id, username);
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    let (invite, pending) = cancelled.map_err(|reason| ChatError::new(reason, None))?;
+    close_chat_invite(state, &invite, &pending, InviteState::Cancelled).await;
+    Ok(())
+}
+
+// Notifica ChatInviteClosed ai destinatari in attesa (chi è disconnesso la riceverà al login),
+// li toglie dagli invitati della chat e aggiorna il conteggio. Restituisce il frame inviato.
+async fn close_chat_invite(
+    state: &AppState,
+    invite: &ChatInvite,
+    pending: &[String],
+    invite_state: InviteState,
+) -> Frame {
+    let start = Instant::now();
+    let chat_id = invite.chat_id.clone().unwrap_or_default();
+    let closed = ChatInviteClosed {
+        invite_id: invite.id.clone(),
+        chat_id: chat_id.clone(),
+        state: invite_state,
+    };
+    let frame = Frame::new(ServerMessage::ChatInviteClosed(closed));
+    for member in pending {
+        state
+            .users
+            .deliver_or_queue(member, None, frame.clone(), &state.offline, &state.accounts);
     }
     //aggiorna il tempo di CPU//
     update_cpu_time(state.total_cpu_time.clone(), start);
+
+    for member in pending {
+        remove_user_from_invited(state, &chat_id, member).await;
+    }
+    broadcast_chat_users_count(state, &chat_id).await;
+    frame
 }
 
+// Notifica l'invitante dell'esito: se è connesso solo la sessione che ha invitato la riceve,
+// se è disconnesso viene accodata per il prossimo login
+fn send_to_inviter(state: &AppState, inviter: &str, inviter_session_id: &str, frame: Frame) {
+    state.users.deliver_or_queue(
+        inviter,
+        Some(inviter_session_id),
+        frame,
+        &state.offline,
+        &state.accounts,
+    );
+}
+
+// Tipo della chat dell'invito visto dall'invitante, ricavato dalla chat salvata
+// (con i membri attuali per i gruppi)
+async fn invite_chat_type(state: &AppState, invite: &ChatInvite) -> ChatType {
+    let chat_id = invite.chat_id.as_deref().unwrap_or_default();
+    state
+        .rooms
+        .get(chat_id)
+        .await
+        .map_or(invite.chat_type.clone(), |room| room.chat_type_for(&invite.from))
+}
+
+// `request_id` è quello del frame ChatInviteResponse, ripetuto nella conferma di accettazione
 pub async fn handle_invite_response(
     state: &AppState,
     responding_user: &str,
     response: &ChatInviteResponse,
-) {
+    request_id: Option<&str>,
+) -> Result<(), ChatError> {
     let mut start = Instant::now();
 
+    // La risposta deve riferirsi a un invito inviato all'utente e ancora in attesa
+    let requested_chat_id = response.chat_id.as_deref();
+    let answered = state.invites.respond(
+        &response.invite_id,
+        requested_chat_id,
+        responding_user,
+        response.accepted,
+    );
+    let invite = match answered {
+        Ok(invite) => invite,
+        Err(reason) => {
+            update_cpu_time(state.total_cpu_time.clone(), start);
+            return Err(ChatError::new(reason, requested_chat_id));
+        }
+    };
+    let chat_id = invite.chat_id.clone().unwrap_or_default();
+    // Invitante, sua sessione e tipo di chat sono quelli registrati dal server con l'invito e la chat:
+    // i campi corrispondenti della risposta del client vengono ignorati
+    let inviter = invite.from.clone();
+    let inviter_session_id = invite.from_session_id.clone();
+
     if response.accepted {
+        if !state.rooms.accept(&chat_id, responding_user).await {
+            // Invitato ma la chat non è più aperta: l'invito non risulta accettato
+            state.invites.expire_for(&response.invite_id, responding_user);
+            update_cpu_time(state.total_cpu_time.clone(), start);
+            return Err(ChatError::new(ChatErrorReason::ChatClosed, Some(&chat_id)));
+        }
+        // Chi entra ora non ha messaggi arretrati: la cronologia precedente non risulta non letta
+        state.read_markers.mark_all(responding_user, &chat_id);
+        let chat_type = invite_chat_type(state, &invite).await;
         // Quando qualcuno accetta, invia una notifica al mittente dell'invito
         // che la chat è pronta per essere aperta
         let chat_ready = ChatReady {
-            chat_id: response
-                .chat_id
-                .clone()
-                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
-            inviter: response.from_user.clone(),
-            inviter_session_id: response.from_session_id.clone(),
-            chat_type: response.chat_type.clone(),
+            chat_id: chat_id.clone(),
+            inviter: inviter.clone(),
+            inviter_session_id: inviter_session_id.clone(),
+            chat_type: chat_type.clone(),
             accepted_by: responding_user.to_string(),
         };
 
-        let ready_message = WebSocketMessage {
-            message_type: MessageType::ChatReady,
-            data: serde_json::to_string(&chat_ready).unwrap(),
-        };
-        let ready_message_json = serde_json::to_string(&ready_message).unwrap();
+        let ready_frame = Frame::new(ServerMessage::ChatReady(chat_ready));
 
         let system_message = ChatMessage {
             id: uuid::Uuid::new_v4(),
-            chat_id: response.chat_id.clone(),
+            chat_id: Some(chat_id.clone()),
             username: "Sistema".to_string(),
             content: format!("{} è entrato nella chat", responding_user),
             timestamp: chrono::Utc::now(),
-            chat_type: crate::types::ChatType::System,
+            chat_type: ChatType::System,
         };
         
         update_cpu_time(state.total_cpu_time.clone(), start);
         broadcast_chat_message(state, "Sistema", &system_message).await;
         start = Instant::now(); 
         // Invia la notifica "chat pronta" al mittente dell'invito
-        let users = state.connected_users.lock().unwrap();
-
-        // Cerca il mittente con session_id corrispondente
-        let inviter_user = users
-            .get(&response.from_user)
-            .filter(|cu| cu.session_id == response.from_session_id);
-        if let Some(inviter_user) = inviter_user {
-            let _ = inviter_user.sender.send(ready_message_json);
-        }
+        // Cerca il mittente con session_id corrispondente (se è disconnesso la notifica resta in coda)
+        send_to_inviter(state, &inviter, &inviter_session_id, ready_frame);
 
         // Invia conferma di accettazione a chi ha risposto
-        let response_message = WebSocketMessage {
-            message_type: MessageType::ChatInviteResponse,
-            data: serde_json::to_string(response).unwrap(),
+        let accepted = ChatInviteResponseNotify {
+            invite_id: response.invite_id.clone(),
+            chat_id: Some(chat_id.clone()),
+            accepted: true,
+            from_user: inviter.clone(),
+            from_session_id: inviter_session_id.clone(),
+            chat_type: chat_type.clone(),
+            responding_user: responding_user.to_string(),
         };
-        let response_json = serde_json::to_string(&response_message).unwrap();
+        let response_frame = Frame::reply(ServerMessage::ChatInviteResponse(accepted), request_id);
 
-        if let Some(responding_user_conn) = users.get(responding_user) {
-            let _ = responding_user_conn.sender.send(response_json);
-        }
+        state.users.send_to(vec![responding_user.to_string()], response_frame);
         update_cpu_time(state.total_cpu_time.clone(), start);
     } else {
         // Se rifiutato, invia solo la risposta negativa al mittente
+        let chat_type = invite_chat_type(state, &invite).await;
         // Estende il payload con chi ha rifiutato così il client può mostrarlo
         let notify = ChatInviteResponseNotify {
             invite_id: response.invite_id.clone(),
-            chat_id: response.chat_id.clone(),
+            chat_id: Some(chat_id.clone()),
             accepted: false,
-            from_user: response.from_user.clone(),
-            from_session_id: response.from_session_id.clone(),
-            chat_type: response.chat_type.clone(),
+            from_user: inviter.clone(),
+            from_session_id: inviter_session_id.clone(),
+            chat_type,
             responding_user: responding_user.to_string(),
         };
-        let response_message = WebSocketMessage {
-            message_type: MessageType::ChatInviteResponse,
-            data: serde_json::to_string(&notify).unwrap(),
-        };
-        let response_json = serde_json::to_string(&response_message).unwrap();
+        let response_frame = Frame::new(ServerMessage::ChatInviteResponse(notify));
         update_cpu_time(state.total_cpu_time.clone(), start);
 
-        {
-            let users = state.connected_users.lock().unwrap();
-            start = Instant::now();
-            let inviter_user = users
-                .get(&response.from_user)
-                .filter(|cu| cu.session_id == response.from_session_id);
-            if let Some(inviter_user) = inviter_user {
-                let _ = inviter_user.sender.send(response_json);
-            }
-            update_cpu_time(state.total_cpu_time.clone(), start);
-        }
-        
-        
-        // Aggiorna i partecipanti invitati con il conteggio utenti della chat (se disponibile)
-        if let Some(ref chat_id) = response.chat_id {
-            // Rimuovi chi ha rifiutato dagli invitati di questa chat
-            remove_user_from_invited(state, chat_id, responding_user).await;
-            // Poi notifica il nuovo conteggio ai rimanenti invitati
-            broadcast_chat_users_count(state, chat_id).await;
-            
-        }
+        start = Instant::now();
+        send_to_inviter(state, &inviter, &inviter_session_id, response_frame);
+        update_cpu_time(state.total_cpu_time.clone(), start);
+
+        // Rimuovi chi ha rifiutato dagli invitati di questa chat
+        remove_user_from_invited(state, &chat_id, responding_user).await;
+        // Poi notifica il nuovo conteggio ai rimanenti invitati
+        broadcast_chat_users_count(state, &chat_id).await;
     }
+    Ok(())
 }
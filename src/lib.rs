@@ -1,37 +1,77 @@
 // Libreria del server per riuso in test di integrazione
 
 // Reimportiamo i moduli in modo che siano disponibili anche come crate libreria
+pub mod accounts;
 pub mod chat;
+pub mod config;
 pub mod cpu_log;
+pub mod frame;
+pub mod history;
 pub mod invites;
+pub mod moderation;
 pub mod notifications;
+pub mod offline;
+pub mod outbound;
 pub mod performance;
+pub mod presence;
+pub mod protocol;
+pub mod receipts;
+pub mod rooms;
 pub mod routes;
+pub mod schema;
+pub mod server;
+pub mod session;
+pub mod shutdown;
 pub mod state;
+pub mod storage;
+pub mod tls;
 pub mod tracking;
 pub mod types;
+pub mod typing;
+pub mod unread;
 pub mod user;
 pub mod websocket;
 
-pub use state::{AppState, ConnectedUser};
+pub use config::Config;
+pub use presence::ConnectedUser;
+pub use server::{ServerBuilder, ServerHooks};
+pub use state::AppState;
 pub use types::*;
 
 use axum::{routing::{get, post}, Router};
-use tower_http::cors::CorsLayer;
-use routes::{get_users, login_user, root, update_user_availability};
+use routes::{
+	get_chat_messages, get_message_receipt, get_pending_invites, get_protocol, get_queue_metrics,
+	get_unread, get_users, login_user, register_user, root, update_user_availability,
+};
 use websocket::websocket_handler;
 
-// Costruisce il Router Axum come fa il main
-pub fn create_app(state: AppState, cors: CorsLayer) -> Router {
+// Costruisce il Router Axum con lo stato e la configurazione indicati
+// (scorciatoia per `ServerBuilder::new(config).state(state).build()`)
+pub fn create_app(state: AppState, config: &Config) -> Router {
+	chat_routes()
+		.with_state(state)
+		.layer(config.cors_layer())
+}
+
+// Rotte REST e WebSocket del server di chat, ancora senza stato
+pub(crate) fn chat_routes() -> Router<AppState> {
 	Router::new()
 		.route("/", get(root))
 		.route("/ws", get(websocket_handler))
 		.route("/api/users", get(get_users))
 		.route("/api/login", post(login_user))
+		.route("/api/register", post(register_user))
 		.route(
 			"/api/users/:username/availability",
 			post(update_user_availability),
 		)
-		.with_state(state)
-		.layer(cors)
+		.route("/api/users/:username/unread", get(get_unread))
+		.route("/api/chats/:chat_id/messages", get(get_chat_messages))
+		.route(
+			"/api/chats/:chat_id/messages/:message_id/receipts",
+			get(get_message_receipt),
+		)
+		.route("/api/invites", get(get_pending_invites))
+		.route("/api/metrics/queues", get(get_queue_metrics))
+		.route("/api/protocol", get(get_protocol))
 }
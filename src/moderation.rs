@@ -0,0 +1,101 @@
+use crate::chat::broadcast_chat_message;
+use crate::frame::Frame;
+use crate::performance::update_cpu_time;
+use crate::protocol::ServerMessage;
+use crate::state::AppState;
+use crate::tracking::broadcast_chat_users_count;
+use crate::types::{
+    ChatError, ChatInviteClosed, ChatMessage, ChatType, InviteState, ModerationAction,
+    ModerationNotice, ModerationRequest,
+};
+use std::time::Instant;
+
+// Testo del messaggio di "Sistema" che annuncia l'operazione nella chat
+fn announcement(by: &str, action: ModerationAction, target: &str) -> String {
+    match action {
+        ModerationAction::Kick => format!("{} ha rimosso {} dalla chat", by, target),
+        ModerationAction::Ban => format!("{} ha bandito {} dalla chat", by, target),
+        ModerationAction::Unban => format!("{} ha revocato il ban di {}", by, target),
+        ModerationAction::Promote => format!("{} ha nominato {} amministratore", by, target),
+        ModerationAction::Demote => {
+            format!("{} ha revocato a {} il ruolo di amministratore", by, target)
+        }
+        ModerationAction::TransferOwnership => {
+            format!("{} ha ceduto la chat a {}", by, target)
+        }
+    }
+}
+
+// `ChatModeration`: espulsione, ban e ruoli in una chat di gruppo.
+// Gli invitati della chat e l'utente coinvolto ricevono i ruoli aggiornati,
+// la chat un messaggio di "Sistema"
+pub async fn moderate_chat(
+    state: &AppState,
+    username: &str,
+    request: &ModerationRequest,
+) -> Result<(), ChatError> {
+    let room = state
+        .rooms
+        .moderate(&request.chat_id, username, request.action, &request.target)
+        .await
+        .map_err(|reason| ChatError::new(reason, Some(&request.chat_id)))?;
+
+    let start = Instant::now();
+    let removed = matches!(request.action, ModerationAction::Kick | ModerationAction::Ban);
+    if removed {
+        // Un invito ancora in attesa non può più essere accettato
+        for invite_id in state.invites.revoke(&room.id, &request.target) {
+            let closed = ChatInviteClosed {
+                invite_id,
+                chat_id: room.id.clone(),
+                state: InviteState::Cancelled,
+            };
+            let frame = Frame::new(ServerMessage::ChatInviteClosed(closed));
+            state.users.deliver_or_queue(
+                &request.target,
+                None,
+                frame,
+                &state.offline,
+                &state.accounts,
+            );
+        }
+    }
+
+    let notice = ModerationNotice {
+        chat_id: room.id.clone(),
+        action: request.action,
+        target: request.target.clone(),
+        by: username.to_string(),
+        owner: room.owner.clone(),
+        admins: room.admins.clone(),
+        banned: room.banned.clone(),
+    };
+    let frame = Frame::new(ServerMessage::ChatModeration(notice));
+    state.users.send_to(room.invited_users.clone(), frame.clone());
+    if !room.is_invited(&request.target) {
+        // Chi è stato rimosso lo saprà anche se ora è disconnesso
+        state.users.deliver_or_queue(
+            &request.target,
+            None,
+            frame,
+            &state.offline,
+            &state.accounts,
+        );
+    }
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    let system_message = ChatMessage {
+        id: uuid::Uuid::new_v4(),
+        chat_id: Some(room.id.clone()),
+        username: "Sistema".to_string(),
+        content: announcement(username, request.action, &request.target),
+        timestamp: chrono::Utc::now(),
+        chat_type: ChatType::System,
+    };
+    broadcast_chat_message(state, "Sistema", &system_message).await;
+    if removed {
+        broadcast_chat_users_count(state, &room.id).await;
+    }
+    Ok(())
+}
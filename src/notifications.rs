@@ -1,6 +1,8 @@
+use crate::frame::Frame;
 use crate::performance::update_cpu_time;
+use crate::protocol::ServerMessage;
 use crate::state::AppState;
-use crate::types::{ChatInvalidated, MessageType, WebSocketMessage};
+use crate::types::ChatInvalidated;
 use crate::user::broadcast_to_all;
 use std::time::Instant;
 
@@ -15,13 +17,10 @@ pub async fn invalidate_chat_ready_notifications(state: &AppState, chat_id: &str
         reason: reason.to_string(),
     };
 
-    let invalidation_message = WebSocketMessage {
-        message_type: MessageType::ChatInvalidated,
-        data: serde_json::to_string(&chat_invalidated).unwrap(),
-    };
-    
+    let invalidation_frame = Frame::new(ServerMessage::ChatInvalidated(chat_invalidated));
+
     update_cpu_time(state.total_cpu_time.clone(), start);
     // Invia a tutti gli utenti connessi (le notifiche ChatReady potrebbero essere su qualsiasi client)
-    broadcast_to_all(state, &invalidation_message).await;
+    broadcast_to_all(state, invalidation_frame).await;
 
 }
@@ -0,0 +1,63 @@
+use crate::frame::Frame;
+use crate::outbound::{Closed, OutboundSender};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+// Numero massimo predefinito di messaggi conservati per ogni utente disconnesso
+pub const DEFAULT_OFFLINE_QUEUE_LIMIT: usize = 500;
+
+// Code dei messaggi (Frame già serializzati) destinati ad account
+// registrati ma non connessi. Vengono consegnate in ordine al login successivo.
+// Oltre il limite di conservazione i messaggi più vecchi vengono scartati.
+#[derive(Clone)]
+pub struct OfflineQueues {
+    queues: Arc<Mutex<HashMap<String, VecDeque<Frame>>>>,
+    limit: usize,
+}
+
+impl Default for OfflineQueues {
+    fn default() -> Self {
+        Self::with_limit(DEFAULT_OFFLINE_QUEUE_LIMIT)
+    }
+}
+
+impl OfflineQueues {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limit(limit: usize) -> Self {
+        OfflineQueues {
+            queues: Arc::new(Mutex::new(HashMap::new())),
+            limit,
+        }
+    }
+
+    pub fn push(&self, username: &str, frame: Frame) {
+        if self.limit == 0 {
+            return;
+        }
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(username.to_string()).or_default();
+        queue.push_back(frame);
+        while queue.len() > self.limit {
+            queue.pop_front();
+        }
+    }
+
+    // Svuota la coda dell'utente inviando i messaggi nell'ordine di arrivo.
+    // Se la connessione viene chiusa nel frattempo i messaggi non inviati restano in coda.
+    pub fn flush(&self, username: &str, tx: &OutboundSender) {
+        let mut queues = self.queues.lock().unwrap();
+        let Some(mut queue) = queues.remove(username) else {
+            return;
+        };
+        while let Some(frame) = queue.pop_front() {
+            if let Err(Closed(frame)) = tx.send_backlog(frame) {
+                queue.push_front(frame);
+                queues.insert(username.to_string(), queue);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use crate::frame::Frame;
+use crate::types::MessageType;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+// Numero massimo predefinito di messaggi in attesa di essere scritti su una connessione
+pub const DEFAULT_OUTBOUND_QUEUE_LIMIT: usize = 1024;
+
+// Aggiornamenti di presenza: se la coda è piena si scartano i più vecchi,
+// tanto il client riceve comunque quelli successivi
+pub(crate) fn is_presence_update(frame: &Frame) -> bool {
+    matches!(
+        frame.message_type(),
+        MessageType::UserJoined
+            | MessageType::UserLeft
+            | MessageType::UserStatusChanged
+            | MessageType::UsersList
+            | MessageType::ChatUsersCount
+            | MessageType::TypingStarted
+            | MessageType::TypingStopped
+    )
+}
+
+// Messaggi destinati all'utente e non alla singola connessione (chat, conferme di consegna,
+// inviti, avvisi delle chat): se la connessione si chiude prima di scriverli vanno consegnati
+// al prossimo login. Le risposte alle richieste (LoginSuccess, Ack, Error, HistoryPage,
+// ChatMessageSent, ...) valgono solo per la connessione che le ha chieste
+pub(crate) fn is_deliverable(frame: &Frame) -> bool {
+    matches!(
+        frame.message_type(),
+        MessageType::ChatMessage
+            | MessageType::ReceiptUpdate
+            | MessageType::ChatInvite
+            | MessageType::ChatInviteResponse
+            | MessageType::ChatReady
+            | MessageType::ChatAbandoned
+            | MessageType::ChatInvalidated
+            | MessageType::ChatInviteClosed
+            | MessageType::ChatModeration
+    )
+}
+
+// Statistiche della coda di una connessione, esposte da GET /api/metrics/queues
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct QueueStats {
+    pub queued: usize,           // messaggi in attesa di essere scritti sul socket
+    pub peak: usize,             // massimo raggiunto da `queued`
+    pub dropped_presence: u64,   // aggiornamenti di presenza scartati perché la coda era piena
+    pub evicted: bool,           // connessione chiusa perché non smaltiva i messaggi
+}
+
+struct QueueState {
+    messages: VecDeque<Queued>,
+    backlog: usize, // messaggi in coda provenienti dalla coda offline (non contano per il limite)
+    stats: QueueStats,
+    closed: bool, // la connessione non riceve più messaggi (espulsa o chiusa)
+}
+
+struct Queued {
+    frame: Frame,
+    backlog: bool,
+}
+
+impl QueueState {
+    fn push(&mut self, frame: Frame, backlog: bool) {
+        self.messages.push_back(Queued { frame, backlog });
+        self.backlog += backlog as usize;
+        self.stats.queued = self.messages.len();
+        self.stats.peak = self.stats.peak.max(self.stats.queued);
+    }
+
+    fn pop(&mut self) -> Option<Frame> {
+        let queued = self.messages.pop_front()?;
+        self.backlog -= queued.backlog as usize;
+        self.stats.queued = self.messages.len();
+        Some(queued.frame)
+    }
+}
+
+// La coda è chiusa (o è stata appena chiusa perché il client non smaltiva i messaggi):
+// il messaggio non accodato viene restituito
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Closed(pub Frame);
+
+// Coda limitata dei messaggi in uscita verso un WebSocket.
+// Quando è piena scarta gli aggiornamenti di presenza più vecchi; gli altri messaggi (chat,
+// inviti, notifiche) non vengono mai scartati: se la coda è piena solo di messaggi non scartabili il client è
+// troppo lento e la connessione viene chiusa.
+pub fn channel(limit: usize) -> (OutboundSender, OutboundReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(QueueState {
+            messages: VecDeque::new(),
+            backlog: 0,
+            stats: QueueStats::default(),
+            closed: false,
+        }),
+        notify: Notify::new(),
+        limit: limit.max(1),
+    });
+    (
+        OutboundSender {
+            shared: shared.clone(),
+        },
+        OutboundReceiver { shared },
+    )
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    limit: usize,
+}
+
+#[derive(Clone)]
+pub struct OutboundSender {
+    shared: Arc<Shared>,
+}
+
+impl OutboundSender {
+    pub fn send(&self, frame: Frame) -> Result<(), Closed> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed(frame));
+        }
+        if state.messages.len() - state.backlog >= self.shared.limit {
+            let oldest_presence = state
+                .messages
+                .iter()
+                .position(|m| is_presence_update(&m.frame));
+            match oldest_presence {
+                Some(pos) => {
+                    state.messages.remove(pos);
+                    state.stats.dropped_presence += 1;
+                }
+                // Anche il nuovo messaggio è scartabile: si tiene la coda com'è
+                None if is_presence_update(&frame) => {
+                    state.stats.dropped_presence += 1;
+                    return Ok(());
+                }
+                None => {
+                    // Consumatore lento: la connessione viene chiusa. Il messaggio non viene
+                    // accodato; i messaggi in coda si recuperano con `take_pending`
+                    state.closed = true;
+                    state.stats.evicted = true;
+                    drop(state);
+                    self.shared.notify.notify_one();
+                    return Err(Closed(frame));
+                }
+            }
+        }
+        state.push(frame, false);
+        drop(state);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+
+    // Accoda un messaggio recuperato dalla coda offline al login. Questi messaggi non
+    // contano per il limite: sono già limitati dalla coda offline e vanno tutti consegnati.
+    pub fn send_backlog(&self, frame: Frame) -> Result<(), Closed> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed(frame));
+        }
+        state.push(frame, true);
+        drop(state);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+
+    // Chiude la coda e restituisce, in ordine, i messaggi non ancora scritti sul socket da
+    // consegnare all'utente: presenze (superate da quelle inviate al prossimo login) e risposte
+    // alla connessione vengono scartate
+    pub fn take_pending(&self) -> Vec<Frame> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        let pending = std::mem::take(&mut state.messages);
+        state.backlog = 0;
+        state.stats.queued = 0;
+        drop(state);
+        self.shared.notify.notify_one();
+        pending
+            .into_iter()
+            .map(|m| m.frame)
+            .filter(is_deliverable)
+            .collect()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.shared.state.lock().unwrap().stats.clone()
+    }
+}
+
+pub struct OutboundReceiver {
+    shared: Arc<Shared>,
+}
+
+impl OutboundReceiver {
+    // Prossimo messaggio da scrivere sul socket; None quando la coda è stata chiusa
+    pub async fn recv(&mut self) -> Option<Frame> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                if let Some(frame) = state.pop() {
+                    return Some(frame);
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    // Si completa quando la coda viene chiusa (es. client espulso mentre si scrive sul socket)
+    pub async fn closed(&self) {
+        loop {
+            if self.shared.state.lock().unwrap().closed {
+                return;
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Frame> {
+        self.shared.state.lock().unwrap().pop()
+    }
+}
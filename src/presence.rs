@@ -0,0 +1,599 @@
+use crate::accounts::AccountStore;
+use crate::frame::Frame;
+use crate::offline::OfflineQueues;
+use crate::outbound::{is_deliverable, Closed, OutboundSender, QueueStats};
+use crate::types::User;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+// Canale verso la connessione attuale di un utente. Resta lo stesso per tutta la sessione:
+// la ripresa dopo una disconnessione ricollega il nuovo WebSocket, quindi le chat che
+// conservano l'Outbox non devono essere aggiornate. Mentre l'utente è disconnesso i messaggi
+// da conservare finiscono nella sua coda offline.
+#[derive(Clone)]
+pub struct Outbox {
+    username: Arc<str>,
+    inner: Arc<Mutex<OutboxInner>>,
+    offline: OfflineQueues,
+}
+
+struct OutboxInner {
+    sender: OutboundSender,
+    connected: bool,
+}
+
+impl Outbox {
+    fn new(username: &str, sender: OutboundSender, offline: OfflineQueues) -> Self {
+        Outbox {
+            username: Arc::from(username),
+            // Collegato alla prima connessione da `connect`
+            inner: Arc::new(Mutex::new(OutboxInner {
+                sender,
+                connected: false,
+            })),
+            offline,
+        }
+    }
+
+    // Invia solo se l'utente è connesso (es. notifiche di stato, indicatori di digitazione).
+    // Se la connessione viene espulsa proprio ora, il messaggio segue quelli in coda,
+    // se è da consegnare comunque all'utente
+    pub fn send(&self, frame: Frame) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.connected {
+            return;
+        }
+        if let Err(Closed(frame)) = inner.sender.send(frame) {
+            self.close(&mut inner);
+            if is_deliverable(&frame) {
+                self.offline.push(&self.username, frame);
+            }
+        }
+    }
+
+    // Invia se l'utente è connesso, altrimenti conserva il messaggio per il prossimo login
+    pub fn deliver(&self, frame: Frame) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.connected {
+            // Connessione espulsa perché troppo lenta: il messaggio va in coda offline
+            if let Err(Closed(frame)) = inner.sender.send(frame) {
+                self.close(&mut inner);
+                self.offline.push(&self.username, frame);
+            }
+        } else {
+            self.offline.push(&self.username, frame);
+        }
+    }
+
+    // Statistiche della coda in uscita della connessione attuale
+    pub fn queue_stats(&self) -> QueueStats {
+        self.inner.lock().unwrap().sender.stats()
+    }
+
+    // Ricollega la sessione a una nuova connessione: `welcome` e i messaggi in coda vengono
+    // inviati prima di qualsiasi altro messaggio destinato all'utente
+    fn connect(&self, sender: OutboundSender, welcome: Frame) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.connected {
+            // La connessione precedente è ancora aperta (ripresa della stessa sessione)
+            self.close(&mut inner);
+        }
+        let _ = sender.send(welcome);
+        self.offline.flush(&self.username, &sender);
+        inner.sender = sender;
+        inner.connected = true;
+    }
+
+    fn disconnect(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.connected {
+            self.close(&mut inner);
+        }
+    }
+
+    // La connessione attuale non riceve più nulla: i messaggi non ancora scritti sul socket
+    // destinati all'utente passano nella coda offline, prima di quelli successivi
+    fn close(&self, inner: &mut OutboxInner) {
+        inner.connected = false;
+        for frame in inner.sender.take_pending() {
+            self.offline.push(&self.username, frame);
+        }
+    }
+}
+
+// Sessione di un utente registrata nel UserRegistry
+pub struct ConnectedUser {
+    pub user: User,
+    pub outbox: Outbox,         // canale verso la connessione attuale
+    pub session_id: String,     // Identificatore univoco della sessione
+    pub connection_id: Uuid,    // connessione WebSocket attualmente associata alla sessione
+    pub disconnected_at: Option<Instant>, // Some durante il periodo di grazia dopo una disconnessione
+}
+
+impl ConnectedUser {
+    fn is_online(&self) -> bool {
+        self.disconnected_at.is_none()
+    }
+}
+
+// Coda in uscita di un utente, restituita da GET /api/metrics/queues
+#[derive(Serialize, Debug)]
+pub struct ConnectionQueue {
+    pub username: String,
+    pub online: bool, // false durante il periodo di grazia
+    #[serde(flatten)]
+    pub stats: QueueStats,
+}
+
+// Costruisce il primo messaggio (LoginSuccess) dato l'utente, la sessione e se è stata ripresa
+pub type Welcome = Box<dyn FnOnce(&User, &str, bool) -> Frame + Send>;
+
+// Richiesta di associare una connessione a un utente (login o ripresa della sessione)
+pub struct AttachRequest {
+    pub username: String,
+    pub connection_id: Uuid,
+    pub resume_session: Option<String>,
+    pub sender: OutboundSender,
+    pub offline: OfflineQueues,
+    pub welcome: Welcome,
+}
+
+pub struct AttachOutcome {
+    pub user: User,
+    pub session_id: String,
+    pub resumed: bool,
+}
+
+// Cambio di stato richiesto dal client con UserStatusChanged
+#[derive(Debug, Default)]
+pub struct StatusUpdate {
+    pub available: Option<bool>,
+    pub chat_id: Option<Option<String>>, // Some(None): esce dalla chat corrente
+    pub in_chat: Option<bool>,
+}
+
+// Esito di un cambio di stato: le chat da cui l'utente è uscito o in cui è entrato
+pub struct StatusChange {
+    pub user: User,
+    pub left_chat: Option<String>,
+    pub entered_chat: Option<String>,
+    pub outbox: Outbox,
+}
+
+enum UserCommand {
+    Attach {
+        request: AttachRequest,
+        reply: oneshot::Sender<Option<AttachOutcome>>,
+    },
+    Detach {
+        username: String,
+        connection_id: Uuid,
+        reply: oneshot::Sender<bool>,
+    },
+    Expire {
+        username: String,
+        connection_id: Uuid,
+        reply: oneshot::Sender<Option<User>>,
+    },
+    UpdateStatus {
+        username: String,
+        update: StatusUpdate,
+        reply: oneshot::Sender<Option<StatusChange>>,
+    },
+    Get {
+        username: String,
+        reply: oneshot::Sender<Option<(User, String)>>,
+    },
+    List {
+        reply: oneshot::Sender<Vec<User>>,
+    },
+    IsOnline {
+        username: String,
+        reply: oneshot::Sender<bool>,
+    },
+    QueueStats {
+        reply: oneshot::Sender<Vec<ConnectionQueue>>,
+    },
+    SendTo {
+        usernames: Vec<String>,
+        frame: Frame,
+    },
+    Broadcast {
+        frame: Frame,
+        reply: oneshot::Sender<()>,
+    },
+    DeliverOrQueue {
+        username: String,
+        session_id: Option<String>,
+        frame: Frame,
+        offline: OfflineQueues,
+        accounts: Arc<AccountStore>,
+    },
+    DeliverTo {
+        usernames: Vec<String>,
+        frame: Frame,
+        offline: OfflineQueues,
+    },
+}
+
+// Registro degli utenti connessi. Un unico task possiede le sessioni e le modifica
+// in ordine di arrivo delle richieste: nessun lock condiviso tra le connessioni.
+// Va creato all'interno del runtime tokio.
+#[derive(Clone)]
+pub struct UserRegistry {
+    commands: mpsc::UnboundedSender<UserCommand>,
+}
+
+impl Default for UserRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UserRegistry {
+    pub fn new() -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_registry(receiver));
+        UserRegistry { commands }
+    }
+
+    // Invia una richiesta al task e attende la risposta (default se il task è terminato)
+    async fn request<T: Default>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> UserCommand,
+    ) -> T {
+        let (reply, response) = oneshot::channel();
+        if self.commands.send(command(reply)).is_err() {
+            return T::default();
+        }
+        response.await.unwrap_or_default()
+    }
+
+    // Associa la connessione all'utente: riprende la sessione nel periodo di grazia
+    // (o quella indicata) oppure ne crea una nuova. None se l'utente è già connesso altrove.
+    pub async fn attach(&self, request: AttachRequest) -> Option<AttachOutcome> {
+        self.request(|reply| UserCommand::Attach { request, reply }).await
+    }
+
+    // Segna la sessione come disconnessa (inizio del periodo di grazia).
+    // Ritorna false se la sessione è già passata a un'altra connessione.
+    pub async fn detach(&self, username: &str, connection_id: Uuid) -> bool {
+        let username = username.to_string();
+        self.request(|reply| UserCommand::Detach {
+            username,
+            connection_id,
+            reply,
+        })
+        .await
+    }
+
+    // Rimuove la sessione ancora disconnessa allo scadere del periodo di grazia.
+    // Ritorna l'utente com'era (con la chat in cui si trovava).
+    pub async fn expire(&self, username: &str, connection_id: Uuid) -> Option<User> {
+        let username = username.to_string();
+        self.request(|reply| UserCommand::Expire {
+            username,
+            connection_id,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn update_status(&self, username: &str, update: StatusUpdate) -> Option<StatusChange> {
+        let username = username.to_string();
+        self.request(|reply| UserCommand::UpdateStatus {
+            username,
+            update,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn get(&self, username: &str) -> Option<User> {
+        self.session(username).await.map(|(user, _)| user)
+    }
+
+    // Utente e session_id della sua sessione
+    pub async fn session(&self, username: &str) -> Option<(User, String)> {
+        let username = username.to_string();
+        self.request(|reply| UserCommand::Get { username, reply }).await
+    }
+
+    pub async fn list(&self) -> Vec<User> {
+        self.request(|reply| UserCommand::List { reply }).await
+    }
+
+    // Connesso con una connessione attiva (non nel periodo di grazia)
+    pub async fn is_online(&self, username: &str) -> bool {
+        let username = username.to_string();
+        self.request(|reply| UserCommand::IsOnline { username, reply })
+            .await
+    }
+
+    // Stato della coda in uscita di ogni utente connesso
+    pub async fn queue_stats(&self) -> Vec<ConnectionQueue> {
+        self.request(|reply| UserCommand::QueueStats { reply }).await
+    }
+
+    // Invia agli utenti indicati, se connessi
+    pub fn send_to(&self, usernames: Vec<String>, frame: Frame) {
+        let _ = self.commands.send(UserCommand::SendTo {
+            usernames,
+            frame,
+        });
+    }
+
+    // Invia a tutti gli utenti connessi; si completa quando il messaggio è stato accodato
+    // su ogni connessione
+    pub async fn broadcast(&self, frame: Frame) {
+        self.request(|reply| UserCommand::Broadcast {
+            frame,
+            reply,
+        })
+        .await
+    }
+
+    // Consegna il messaggio all'utente se è connesso (solo alla sessione `session_id`,
+    // se indicata), altrimenti lo accoda se l'account esiste.
+    pub fn deliver_or_queue(
+        &self,
+        username: &str,
+        session_id: Option<&str>,
+        frame: Frame,
+        offline: &OfflineQueues,
+        accounts: &Arc<AccountStore>,
+    ) {
+        let _ = self.commands.send(UserCommand::DeliverOrQueue {
+            username: username.to_string(),
+            session_id: session_id.map(str::to_string),
+            frame,
+            offline: offline.clone(),
+            accounts: accounts.clone(),
+        });
+    }
+
+    // Consegna il messaggio agli utenti indicati: subito se connessi,
+    // altrimenti nella loro coda offline
+    pub fn deliver_to(
+        &self,
+        usernames: Vec<String>,
+        frame: Frame,
+        offline: &OfflineQueues,
+    ) {
+        let _ = self.commands.send(UserCommand::DeliverTo {
+            usernames,
+            frame,
+            offline: offline.clone(),
+        });
+    }
+}
+
+// Task che possiede le sessioni: termina quando tutti gli UserRegistry sono stati rilasciati
+async fn run_registry(mut commands: mpsc::UnboundedReceiver<UserCommand>) {
+    let mut users: HashMap<String, ConnectedUser> = HashMap::new();
+    while let Some(command) = commands.recv().await {
+        match command {
+            UserCommand::Attach { request, reply } => {
+                let _ = reply.send(attach(&mut users, request));
+            }
+            UserCommand::Detach {
+                username,
+                connection_id,
+                reply,
+            } => {
+                let detached = match users.get_mut(&username) {
+                    Some(connected_user) if connected_user.connection_id == connection_id => {
+                        connected_user.disconnected_at = Some(Instant::now());
+                        connected_user.outbox.disconnect();
+                        true
+                    }
+                    _ => false,
+                };
+                let _ = reply.send(detached);
+            }
+            UserCommand::Expire {
+                username,
+                connection_id,
+                reply,
+            } => {
+                let expired = match users.get(&username) {
+                    Some(connected_user)
+                        if connected_user.connection_id == connection_id
+                            && !connected_user.is_online() =>
+                    {
+                        users.remove(&username).map(|connected_user| connected_user.user)
+                    }
+                    // Sessione ripresa (o già rimossa): nulla da fare
+                    _ => None,
+                };
+                let _ = reply.send(expired);
+            }
+            UserCommand::UpdateStatus {
+                username,
+                update,
+                reply,
+            } => {
+                let change = users
+                    .get_mut(&username)
+                    .map(|connected_user| apply_status(connected_user, update));
+                let _ = reply.send(change);
+            }
+            UserCommand::Get { username, reply } => {
+                let session = users
+                    .get(&username)
+                    .map(|cu| (cu.user.clone(), cu.session_id.clone()));
+                let _ = reply.send(session);
+            }
+            UserCommand::List { reply } => {
+                let _ = reply.send(users.values().map(|cu| cu.user.clone()).collect());
+            }
+            UserCommand::IsOnline { username, reply } => {
+                let _ = reply.send(users.get(&username).is_some_and(ConnectedUser::is_online));
+            }
+            UserCommand::QueueStats { reply } => {
+                let stats = users
+                    .iter()
+                    .map(|(username, cu)| ConnectionQueue {
+                        username: username.clone(),
+                        online: cu.is_online(),
+                        stats: cu.outbox.queue_stats(),
+                    })
+                    .collect();
+                let _ = reply.send(stats);
+            }
+            UserCommand::SendTo {
+                usernames,
+                frame,
+            } => {
+                for username in &usernames {
+                    if let Some(connected_user) = users.get(username) {
+                        connected_user.outbox.send(frame.clone());
+                    }
+                }
+            }
+            UserCommand::Broadcast {
+                frame,
+                reply,
+            } => {
+                for connected_user in users.values() {
+                    connected_user.outbox.send(frame.clone());
+                }
+                let _ = reply.send(());
+            }
+            UserCommand::DeliverOrQueue {
+                username,
+                session_id,
+                frame,
+                offline,
+                accounts,
+            } => match users.get(&username) {
+                Some(connected_user) if connected_user.is_online() => {
+                    // Con una sessione indicata, le altre sessioni dello stesso utente non ricevono nulla
+                    if session_id.map_or(true, |id| id == connected_user.session_id) {
+                        connected_user.outbox.deliver(frame);
+                    }
+                }
+                Some(connected_user) => connected_user.outbox.deliver(frame),
+                None => {
+                    if accounts.exists(&username) {
+                        offline.push(&username, frame);
+                    }
+                }
+            },
+            UserCommand::DeliverTo {
+                usernames,
+                frame,
+                offline,
+            } => {
+                for username in &usernames {
+                    match users.get(username) {
+                        Some(connected_user) => connected_user.outbox.deliver(frame.clone()),
+                        None => offline.push(username, frame.clone()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn attach(users: &mut HashMap<String, ConnectedUser>, request: AttachRequest) -> Option<AttachOutcome> {
+    let AttachRequest {
+        username,
+        connection_id,
+        resume_session,
+        sender,
+        offline,
+        welcome,
+    } = request;
+
+    match users.get_mut(&username) {
+        Some(existing) => {
+            let same_session = resume_session.as_deref() == Some(existing.session_id.as_str());
+            if !existing.is_online() || same_session {
+                // Ripresa: la nuova connessione sostituisce la precedente
+                existing.connection_id = connection_id;
+                existing.disconnected_at = None;
+                let welcome = welcome(&existing.user, &existing.session_id, true);
+                existing.outbox.connect(sender, welcome);
+                Some(AttachOutcome {
+                    user: existing.user.clone(),
+                    session_id: existing.session_id.clone(),
+                    resumed: true,
+                })
+            } else {
+                // Un altro socket ha completato il login nel frattempo
+                None
+            }
+        }
+        None => {
+            // Sessione nuova (o ripresa dopo il periodo di grazia: stesso session_id, nessuna chat)
+            let session_id = resume_session.unwrap_or_else(|| Uuid::new_v4().to_string());
+            let user = User {
+                username: username.clone(),
+                is_available: true,
+                chat_id: None,
+            };
+            let outbox = Outbox::new(&username, sender.clone(), offline);
+            outbox.connect(sender, welcome(&user, &session_id, false));
+            users.insert(
+                username,
+                ConnectedUser {
+                    user: user.clone(),
+                    outbox,
+                    session_id: session_id.clone(),
+                    connection_id,
+                    disconnected_at: None,
+                },
+            );
+            Some(AttachOutcome {
+                user,
+                session_id,
+                resumed: false,
+            })
+        }
+    }
+}
+
+// Applica un UserStatusChanged e ricava le chat da lasciare e in cui entrare
+fn apply_status(connected_user: &mut ConnectedUser, update: StatusUpdate) -> StatusChange {
+    let user = &mut connected_user.user;
+    let previous_chat_id = user.chat_id.clone();
+
+    //Gestione stato disponibilità
+    if let Some(available) = update.available {
+        user.is_available = available;
+    }
+    if let Some(chat_id) = update.chat_id {
+        user.chat_id = chat_id;
+    }
+
+    let mut left_chat = None;
+    let mut entered_chat = None;
+    match update.in_chat {
+        // Gestione uscita da chat (torna disponibile)
+        Some(false) => {
+            left_chat = user.chat_id.take();
+            user.is_available = true;
+        }
+        Some(true) => {
+            user.is_available = false;
+            entered_chat = user.chat_id.clone();
+            // Passaggio diretto da un'altra chat: la precedente viene lasciata
+            if previous_chat_id.is_some() && previous_chat_id != user.chat_id {
+                left_chat = previous_chat_id;
+            }
+        }
+        None => {}
+    }
+
+    StatusChange {
+        user: user.clone(),
+        left_chat,
+        entered_chat,
+        outbox: connected_user.outbox.clone(),
+    }
+}
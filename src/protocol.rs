@@ -0,0 +1,228 @@
+use crate::types::{
+    AddChatMembers, AloneInChatNotification, ChatAbandonedNotification, ChatCreated, ChatError,
+    ChatErrorReason, ChatInvalidated, ChatInvite, ChatInviteClosed, ChatInviteResponse,
+    ChatInviteResponseNotify, ChatMessage, ChatMessageSent, ChatReady, ChatUsersCount, HistoryPage,
+    HistoryRequest, InviteCancel, LoginError, LoginRequest, LoginSuccess, MessageReceipt,
+    MessageType, ModerationNotice, ModerationRequest, ReceiptAck, RequestAck, ResumeRequest,
+    SendChatMessage, ServerShutdown, StatusChangeRequest, TypingIndicator, TypingRequest,
+    UnreadCounts, User,
+};
+use serde::{Deserialize, Serialize};
+
+// Versione del protocollo negoziata alla connessione
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolVersion {
+    // `data` è una stringa che contiene il payload serializzato (client esistenti)
+    #[default]
+    V1,
+    // `data` è il payload JSON stesso, senza doppia codifica
+    V2,
+}
+
+// Sottoprotocolli WebSocket (Sec-WebSocket-Protocol), dal più recente: se il client ne
+// propone più d'uno il server sceglie il primo di questa lista
+pub const SUBPROTOCOLS: [&str; 2] = ["ruggine.v2", "ruggine.v1"];
+
+impl ProtocolVersion {
+    pub fn from_number(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(ProtocolVersion::V1),
+            2 => Some(ProtocolVersion::V2),
+            _ => None,
+        }
+    }
+
+    pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+        match subprotocol {
+            "ruggine.v1" => Some(ProtocolVersion::V1),
+            "ruggine.v2" => Some(ProtocolVersion::V2),
+            _ => None,
+        }
+    }
+
+    pub fn number(self) -> u8 {
+        match self {
+            ProtocolVersion::V1 => 1,
+            ProtocolVersion::V2 => 2,
+        }
+    }
+}
+
+// Definisce ClientMessage insieme all'elenco dei message_type accettati, così che non possano divergere
+macro_rules! client_messages {
+    ($($variant:ident($payload:ty),)*) => {
+        // Messaggi che il client può inviare al server. Sul filo:
+        // `{"message_type": "Login", "data": {...}}` (con il protocollo 1 `data` è una stringa JSON)
+        #[derive(Serialize, Deserialize, Debug)]
+        #[serde(tag = "message_type", content = "data")]
+        pub enum ClientMessage {
+            $($variant($payload),)*
+        }
+
+        impl ClientMessage {
+            // Valori di `message_type` che il client può inviare
+            pub const MESSAGE_TYPES: &'static [&'static str] = &[$(stringify!($variant)),*];
+        }
+    };
+}
+
+client_messages! {
+    Login(LoginRequest),
+    ResumeSession(ResumeRequest),
+    SendChatMessage(SendChatMessage),
+    ChatMessage(ChatMessage), // formato precedente di SendChatMessage: si usano solo chat e testo
+    UserStatusChanged(StatusChangeRequest),
+    ChatInvite(ChatInvite),
+    ChatInviteResponse(ChatInviteResponse),
+    ChatInviteCancel(InviteCancel),
+    AddChatMembers(AddChatMembers),
+    ChatModeration(ModerationRequest),
+    Delivered(ReceiptAck),
+    Read(ReceiptAck),
+    TypingStarted(TypingRequest),
+    TypingStopped(TypingRequest),
+    HistoryRequest(HistoryRequest),
+}
+
+impl ClientMessage {
+    // Decodifica un frame testuale del client in entrambe le versioni del protocollo
+    pub fn decode(text: &str) -> Result<Self, serde_json::Error> {
+        let mut frame: serde_json::Value = serde_json::from_str(text)?;
+        // Protocollo 1: il payload è a sua volta una stringa JSON
+        if let Some(data) = frame.get_mut("data") {
+            if let serde_json::Value::String(encoded) = data {
+                *data = serde_json::from_str(encoded)?;
+            }
+        }
+        serde_json::from_value(frame)
+    }
+
+    // Login e ripresa della sessione sono le uniche richieste accettate prima del login
+    pub fn requires_login(&self) -> bool {
+        !matches!(self, ClientMessage::Login(_) | ClientMessage::ResumeSession(_))
+    }
+}
+
+// Frame del client decodificato, con l'id di correlazione facoltativo scelto dal client
+#[derive(Debug)]
+pub struct ClientFrame {
+    pub request_id: Option<String>,
+    pub message: ClientMessage,
+}
+
+// Frame del client rifiutato prima di arrivare ai gestori
+#[derive(Debug)]
+pub struct RejectedFrame {
+    pub request_id: Option<String>, // se è stato possibile leggerlo
+    pub error: ChatError,
+}
+
+impl ClientFrame {
+    // Come ClientMessage::decode, ma distingue i frame illeggibili (Malformed)
+    // dai tipi che il client non può inviare (UnknownType)
+    pub fn decode(text: &str) -> Result<Self, RejectedFrame> {
+        let rejected = |request_id: Option<String>, reason: ChatErrorReason, detail: String| {
+            let error = ChatError::new(reason, None);
+            RejectedFrame {
+                request_id,
+                error: ChatError {
+                    message: format!("{} {}", error.message, detail),
+                    ..error
+                },
+            }
+        };
+        let frame: serde_json::Value = serde_json::from_str(text).map_err(|e| {
+            rejected(None, ChatErrorReason::Malformed, format!("JSON non valido: {}", e))
+        })?;
+        let request_id = frame
+            .get("request_id")
+            .and_then(|id| id.as_str())
+            .map(str::to_string);
+        let message_type = match frame.get("message_type") {
+            Some(serde_json::Value::String(message_type)) => message_type.as_str(),
+            _ => {
+                let detail = "Campo message_type mancante.".to_string();
+                return Err(rejected(request_id, ChatErrorReason::Malformed, detail));
+            }
+        };
+        if !ClientMessage::MESSAGE_TYPES.contains(&message_type) {
+            let detail = format!("'{}' non può essere inviato dal client.", message_type);
+            return Err(rejected(request_id, ChatErrorReason::UnknownType, detail));
+        }
+        match ClientMessage::decode(text) {
+            Ok(message) => Ok(ClientFrame {
+                request_id,
+                message,
+            }),
+            Err(e) => {
+                let detail = format!("Payload di {} non valido: {}", message_type, e);
+                Err(rejected(request_id, ChatErrorReason::Malformed, detail))
+            }
+        }
+    }
+}
+
+// Messaggi che il server invia ai client, con lo stesso formato di ClientMessage
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "message_type", content = "data")]
+pub enum ServerMessage {
+    LoginSuccess(LoginSuccess),
+    LoginError(LoginError),
+    ChatMessage(ChatMessage),
+    ChatMessageSent(ChatMessageSent),
+    ReceiptUpdate(MessageReceipt),
+    TypingStarted(TypingIndicator),
+    TypingStopped(TypingIndicator),
+    UserJoined(User),
+    UserLeft(String), // username; con il protocollo 1 `data` è lo username stesso
+    UserStatusChanged(User),
+    UsersList(Vec<User>),
+    ChatInvite(ChatInvite),
+    ChatInviteResponse(ChatInviteResponseNotify),
+    ChatInviteClosed(ChatInviteClosed),
+    ChatModeration(ModerationNotice),
+    UnreadCounts(UnreadCounts),
+    ChatReady(ChatReady),
+    AloneInChat(AloneInChatNotification),
+    ChatUsersCount(ChatUsersCount),
+    ChatAbandoned(ChatAbandonedNotification),
+    ChatInvalidated(ChatInvalidated),
+    ChatCreated(ChatCreated),
+    HistoryPage(HistoryPage),
+    Error(ChatError),
+    ServerShutdown(ServerShutdown),
+    Ack(RequestAck),
+}
+
+impl ServerMessage {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            ServerMessage::LoginSuccess(_) => MessageType::LoginSuccess,
+            ServerMessage::LoginError(_) => MessageType::LoginError,
+            ServerMessage::ChatMessage(_) => MessageType::ChatMessage,
+            ServerMessage::ChatMessageSent(_) => MessageType::ChatMessageSent,
+            ServerMessage::ReceiptUpdate(_) => MessageType::ReceiptUpdate,
+            ServerMessage::TypingStarted(_) => MessageType::TypingStarted,
+            ServerMessage::TypingStopped(_) => MessageType::TypingStopped,
+            ServerMessage::UserJoined(_) => MessageType::UserJoined,
+            ServerMessage::UserLeft(_) => MessageType::UserLeft,
+            ServerMessage::UserStatusChanged(_) => MessageType::UserStatusChanged,
+            ServerMessage::UsersList(_) => MessageType::UsersList,
+            ServerMessage::ChatInvite(_) => MessageType::ChatInvite,
+            ServerMessage::ChatInviteResponse(_) => MessageType::ChatInviteResponse,
+            ServerMessage::ChatInviteClosed(_) => MessageType::ChatInviteClosed,
+            ServerMessage::ChatModeration(_) => MessageType::ChatModeration,
+            ServerMessage::UnreadCounts(_) => MessageType::UnreadCounts,
+            ServerMessage::ChatReady(_) => MessageType::ChatReady,
+            ServerMessage::AloneInChat(_) => MessageType::AloneInChat,
+            ServerMessage::ChatUsersCount(_) => MessageType::ChatUsersCount,
+            ServerMessage::ChatAbandoned(_) => MessageType::ChatAbandoned,
+            ServerMessage::ChatInvalidated(_) => MessageType::ChatInvalidated,
+            ServerMessage::ChatCreated(_) => MessageType::ChatCreated,
+            ServerMessage::HistoryPage(_) => MessageType::HistoryPage,
+            ServerMessage::Error(_) => MessageType::Error,
+            ServerMessage::ServerShutdown(_) => MessageType::ServerShutdown,
+            ServerMessage::Ack(_) => MessageType::Ack,
+        }
+    }
+}
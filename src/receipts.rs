@@ -0,0 +1,154 @@
+use crate::frame::Frame;
+use crate::performance::update_cpu_time;
+use crate::protocol::ServerMessage;
+use crate::state::AppState;
+use crate::types::{
+    ChatError, ChatErrorReason, ChatMessage, MessageReceipt, ReceiptAck, ReceiptStatus,
+    RecipientReceipt,
+};
+use crate::unread::mark_read_up_to;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use uuid::Uuid;
+
+// Conferma inviata da un destinatario
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+impl MessageReceipt {
+    // Stato complessivo: Delivered/Read solo quando tutti i destinatari hanno confermato
+    fn refresh_status(&mut self) {
+        self.status = if self.recipients.is_empty() {
+            ReceiptStatus::Sent
+        } else if self.recipients.iter().all(|r| r.read_at.is_some()) {
+            ReceiptStatus::Read
+        } else if self.recipients.iter().all(|r| r.delivered_at.is_some()) {
+            ReceiptStatus::Delivered
+        } else {
+            ReceiptStatus::Sent
+        };
+    }
+}
+
+// Stato di consegna e lettura dei messaggi inviati dagli utenti, per destinatario.
+#[derive(Clone, Default)]
+pub struct ReceiptRegistry {
+    receipts: Arc<Mutex<HashMap<Uuid, MessageReceipt>>>,
+}
+
+impl ReceiptRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Inizia a tracciare un messaggio appena inviato ai `recipients` (mittente escluso)
+    pub fn track(&self, message: &ChatMessage, recipients: &[String]) {
+        let mut receipt = MessageReceipt {
+            message_id: message.id,
+            chat_id: message.chat_id.clone().unwrap_or_default(),
+            sender: message.username.clone(),
+            recipients: recipients
+                .iter()
+                .filter(|u| **u != message.username)
+                .map(|u| RecipientReceipt {
+                    username: u.clone(),
+                    delivered_at: None,
+                    read_at: None,
+                })
+                .collect(),
+            status: ReceiptStatus::Sent,
+        };
+        receipt.refresh_status();
+        self.receipts.lock().unwrap().insert(message.id, receipt);
+    }
+
+    pub fn get(&self, message_id: &Uuid) -> Option<MessageReceipt> {
+        self.receipts.lock().unwrap().get(message_id).cloned()
+    }
+
+    // Stato dei messaggi indicati (quelli non tracciati, es. di sistema, vengono saltati)
+    pub fn for_messages(&self, messages: &[ChatMessage]) -> Vec<MessageReceipt> {
+        let receipts = self.receipts.lock().unwrap();
+        messages
+            .iter()
+            .filter_map(|m| receipts.get(&m.id).cloned())
+            .collect()
+    }
+
+    // Registra la conferma di `username`. Ritorna lo stato aggiornato solo se è cambiato;
+    // le conferme di messaggi sconosciuti o di chi non ne è destinatario sono rifiutate.
+    // La lettura implica la consegna. Un messaggio letto da tutti non viene più tracciato.
+    pub fn acknowledge(
+        &self,
+        message_id: &Uuid,
+        username: &str,
+        kind: ReceiptKind,
+    ) -> Result<Option<MessageReceipt>, ChatErrorReason> {
+        let mut receipts = self.receipts.lock().unwrap();
+        // Stesso errore nei due casi: non si rivela l'esistenza di messaggi altrui
+        let receipt = receipts
+            .get_mut(message_id)
+            .ok_or(ChatErrorReason::UnknownMessage)?;
+        let recipient = receipt
+            .recipients
+            .iter_mut()
+            .find(|r| r.username == username)
+            .ok_or(ChatErrorReason::UnknownMessage)?;
+
+        let now = chrono::Utc::now();
+        let mut changed = false;
+        if recipient.delivered_at.is_none() {
+            recipient.delivered_at = Some(now);
+            changed = true;
+        }
+        if kind == ReceiptKind::Read && recipient.read_at.is_none() {
+            recipient.read_at = Some(now);
+            changed = true;
+        }
+        if !changed {
+            return Ok(None);
+        }
+        receipt.refresh_status();
+        let receipt = receipt.clone();
+        if receipt.status == ReceiptStatus::Read {
+            receipts.remove(message_id);
+        }
+        Ok(Some(receipt))
+    }
+
+    // Smette di tracciare i messaggi di una chat chiusa
+    pub fn forget_chat(&self, chat_id: &str) {
+        self.receipts
+            .lock()
+            .unwrap()
+            .retain(|_, receipt| receipt.chat_id != chat_id);
+    }
+}
+
+// Gestisce una conferma Delivered/Read e notifica il mittente con lo stato aggiornato
+pub async fn handle_receipt(
+    state: &AppState,
+    username: &str,
+    ack: &ReceiptAck,
+    kind: ReceiptKind,
+) -> Result<(), ChatError> {
+    let start = Instant::now();
+    let updated = state.receipts.acknowledge(&ack.message_id, username, kind);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    if let Some(receipt) = updated.map_err(|reason| ChatError::new(reason, None))? {
+        if kind == ReceiptKind::Read {
+            mark_read_up_to(state, &receipt.chat_id, username, receipt.message_id);
+        }
+        let sender = receipt.sender.clone();
+        state
+            .users
+            .send_to(vec![sender], Frame::new(ServerMessage::ReceiptUpdate(receipt)));
+    }
+    Ok(())
+}
@@ -0,0 +1,788 @@
+use crate::frame::Frame;
+use crate::offline::OfflineQueues;
+use crate::presence::{Outbox, UserRegistry};
+use crate::storage::{InMemoryRoomStore, RoomStore};
+use crate::types::{
+    ChatErrorReason, ChatRole, ChatType, ChatUsersCount, ModerationAction, Room, RoomKind,
+    RoomStatus,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::{mpsc, oneshot};
+
+impl Room {
+    // Vista del conteggio utenti inviata ai client con MessageType::ChatUsersCount
+    pub fn users_count(&self) -> ChatUsersCount {
+        ChatUsersCount {
+            chat_id: self.id.clone(),
+            invited_users: self.invited_users.clone(),
+            users_in_chat: self.users_in_chat.clone(),
+            invited_count: self.invited_users.len(),
+            in_chat_count: self.users_in_chat.len(),
+        }
+    }
+
+    pub fn is_invited(&self, username: &str) -> bool {
+        self.invited_users.iter().any(|u| u == username)
+    }
+
+    pub fn is_member(&self, username: &str) -> bool {
+        self.members.iter().any(|u| u == username)
+    }
+
+    pub fn is_banned(&self, username: &str) -> bool {
+        self.banned.iter().any(|u| u == username)
+    }
+
+    // Ruolo di un membro (None per chi non ha accettato l'invito)
+    pub fn role_of(&self, username: &str) -> Option<ChatRole> {
+        if self.owner == username {
+            Some(ChatRole::Owner)
+        } else if self.admins.iter().any(|u| u == username) {
+            Some(ChatRole::Admin)
+        } else if self.is_member(username) {
+            Some(ChatRole::Member)
+        } else {
+            None
+        }
+    }
+
+    // Tipo di chat dei messaggi inviati da `sender`, ricavato dalla chat e non dal client
+    pub fn chat_type_for(&self, sender: &str) -> ChatType {
+        match self.kind {
+            RoomKind::Private => ChatType::Private {
+                target: self
+                    .invited_users
+                    .iter()
+                    .find(|u| *u != sender)
+                    .cloned()
+                    .unwrap_or_default(),
+            },
+            RoomKind::Group => ChatType::Group {
+                members: self.members.clone(),
+            },
+        }
+    }
+}
+
+// Esito dell'uscita di un utente da una chat
+#[derive(Debug, Default)]
+pub struct LeaveOutcome {
+    // Chat privata abbandonata definitivamente: utente rimasto da avvisare
+    pub abandoned_remaining: Option<String>,
+}
+
+// A chi consegnare un messaggio inviato nella chat
+pub enum Delivery {
+    // Solo agli utenti presenti e connessi (es. indicatori di digitazione)
+    Present,
+    // A tutti i membri, anche se stanno guardando un'altra chat (nella coda offline se disconnessi)
+    Members {
+        users: UserRegistry,
+        offline: OfflineQueues,
+    },
+}
+
+enum RoomCommand {
+    Get {
+        reply: oneshot::Sender<Room>,
+    },
+    Authorize {
+        username: String,
+        reply: oneshot::Sender<Result<Room, ChatErrorReason>>,
+    },
+    Accept {
+        username: String,
+        reply: oneshot::Sender<bool>,
+    },
+    Invite {
+        invited_by: String,
+        usernames: Vec<String>,
+        reply: oneshot::Sender<Result<(Room, Vec<String>), ChatErrorReason>>,
+    },
+    Decline {
+        username: String,
+        reply: oneshot::Sender<bool>,
+    },
+    Moderate {
+        by: String,
+        action: ModerationAction,
+        target: String,
+        reply: oneshot::Sender<Result<Room, ChatErrorReason>>,
+    },
+    Enter {
+        username: String,
+        outbox: Outbox,
+        reply: oneshot::Sender<bool>,
+    },
+    Leave {
+        username: String,
+        reply: oneshot::Sender<Option<LeaveOutcome>>,
+    },
+    Broadcast {
+        frame: Frame,
+        exclude: Option<String>,
+        delivery: Delivery,
+    },
+}
+
+// Canale verso il task che possiede una chat
+#[derive(Clone)]
+struct RoomHandle {
+    commands: mpsc::UnboundedSender<RoomCommand>,
+}
+
+// Registro delle chat: crea le chat (generando il chat_id), ne gestisce
+// invitati, membri, presenze e ciclo di vita.
+// Ogni chat è un task che possiede i propri dati e l'elenco dei presenti, e inoltra
+// i messaggi solo a loro: il costo di un invio dipende dalla dimensione della chat e non
+// dal numero totale di utenti. Il registro tiene solo i canali verso i task e, per ogni
+// utente, le chat di cui è membro.
+// Una chat chiusa e senza più presenti termina il proprio task: ne resta solo l'ultimo stato
+// in `closed`, per la cronologia e le risposte agli invitati.
+// Ogni modifica di invitati, membri, ruoli o stato viene registrata nel `RoomStore`.
+#[derive(Clone)]
+pub struct RoomRegistry {
+    rooms: Arc<Mutex<HashMap<String, RoomHandle>>>,
+    closed: Arc<Mutex<HashMap<String, Room>>>,
+    memberships: Arc<Mutex<HashMap<String, BTreeSet<String>>>>, // username -> chat_id
+    store: Arc<dyn RoomStore>,
+}
+
+impl Default for RoomRegistry {
+    fn default() -> Self {
+        Self::with_store(Arc::new(InMemoryRoomStore::new()))
+    }
+}
+
+impl RoomRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Registro che riprende le chat salvate in `store` (es. dopo un riavvio), senza presenze.
+    // Va creato all'interno del runtime tokio.
+    pub fn with_store(store: Arc<dyn RoomStore>) -> Self {
+        let registry = RoomRegistry {
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            closed: Arc::new(Mutex::new(HashMap::new())),
+            memberships: Arc::new(Mutex::new(HashMap::new())),
+            store,
+        };
+        for mut room in registry.store.rooms() {
+            room.users_in_chat.clear();
+            for member in &room.members {
+                registry.join(member, &room.id);
+            }
+            if room.status == RoomStatus::Closed {
+                registry
+                    .closed
+                    .lock()
+                    .unwrap()
+                    .insert(room.id.clone(), room);
+            } else {
+                registry.spawn(room);
+            }
+        }
+        registry
+    }
+
+    // Forza su disco le modifiche registrate nel `RoomStore` (usata all'arresto)
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.store.sync()
+    }
+
+    fn spawn(&self, room: Room) {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let id = room.id.clone();
+        tokio::spawn(run_room(
+            room,
+            receiver,
+            self.store.clone(),
+            Arc::downgrade(&self.rooms),
+            self.closed.clone(),
+        ));
+        self.rooms
+            .lock()
+            .unwrap()
+            .insert(id, RoomHandle { commands });
+    }
+
+    // Crea una nuova chat con id generato dal server. Il proprietario è sempre
+    // tra gli invitati e tra i membri.
+    pub fn create(&self, owner: &str, kind: RoomKind, invited_users: Vec<String>) -> Room {
+        self.create_with_id(uuid::Uuid::new_v4().to_string(), owner, kind, invited_users)
+    }
+
+    // Come `create`, con un id già generato dal server (es. registrato prima con l'invito)
+    pub fn create_with_id(
+        &self,
+        chat_id: String,
+        owner: &str,
+        kind: RoomKind,
+        invited_users: Vec<String>,
+    ) -> Room {
+        let mut invited = vec![owner.to_string()];
+        for user in invited_users {
+            if !invited.contains(&user) {
+                invited.push(user);
+            }
+        }
+
+        let room = Room {
+            id: chat_id,
+            kind,
+            owner: owner.to_string(),
+            admins: Vec::new(),
+            banned: Vec::new(),
+            invited_users: invited,
+            members: vec![owner.to_string()],
+            users_in_chat: Vec::new(),
+            created_at: chrono::Utc::now(),
+            status: RoomStatus::Pending,
+            had_both_users: false,
+        };
+        self.store.save(&room);
+        self.spawn(room.clone());
+        self.join(owner, &room.id);
+        room
+    }
+
+    // Chat di cui l'utente è membro, indipendentemente da quella in cui si trova
+    pub fn chats_of(&self, username: &str) -> Vec<String> {
+        self.memberships
+            .lock()
+            .unwrap()
+            .get(username)
+            .map(|chats| chats.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn join(&self, username: &str, chat_id: &str) {
+        self.memberships
+            .lock()
+            .unwrap()
+            .entry(username.to_string())
+            .or_default()
+            .insert(chat_id.to_string());
+    }
+
+    fn quit(&self, username: &str, chat_id: &str) {
+        let mut memberships = self.memberships.lock().unwrap();
+        if let Some(chats) = memberships.get_mut(username) {
+            chats.remove(chat_id);
+            if chats.is_empty() {
+                memberships.remove(username);
+            }
+        }
+    }
+
+    fn handle(&self, chat_id: &str) -> Option<RoomHandle> {
+        self.rooms.lock().unwrap().get(chat_id).cloned()
+    }
+
+    // Ultimo stato di una chat chiusa il cui task è terminato
+    fn closed(&self, chat_id: &str) -> Option<Room> {
+        self.closed.lock().unwrap().get(chat_id).cloned()
+    }
+
+    // Chat con un task attivo (le chat chiuse e senza presenti non contano)
+    pub fn open_count(&self) -> usize {
+        self.rooms.lock().unwrap().len()
+    }
+
+    // Invia una richiesta al task della chat e ne attende la risposta (None se la chat non esiste)
+    async fn request<T>(
+        &self,
+        chat_id: &str,
+        command: impl FnOnce(oneshot::Sender<T>) -> RoomCommand,
+    ) -> Option<T> {
+        let handle = self.handle(chat_id)?;
+        let (reply, response) = oneshot::channel();
+        handle.commands.send(command(reply)).ok()?;
+        response.await.ok()
+    }
+
+    pub async fn get(&self, chat_id: &str) -> Option<Room> {
+        match self
+            .request(chat_id, |reply| RoomCommand::Get { reply })
+            .await
+        {
+            Some(room) => Some(room),
+            None => self.closed(chat_id),
+        }
+    }
+
+    pub async fn users_count(&self, chat_id: &str) -> Option<ChatUsersCount> {
+        self.get(chat_id).await.map(|room| room.users_count())
+    }
+
+    pub async fn members(&self, chat_id: &str) -> Vec<String> {
+        self.get(chat_id)
+            .await
+            .map(|room| room.members)
+            .unwrap_or_default()
+    }
+
+    pub async fn is_invited(&self, chat_id: &str, username: &str) -> bool {
+        self.get(chat_id)
+            .await
+            .is_some_and(|room| room.is_invited(username))
+    }
+
+    // Verifica che l'utente sia membro (ha accettato l'invito) di una chat ancora aperta.
+    // È il controllo richiesto per inviare messaggi ed entrare nella chat.
+    pub async fn authorize_member(&self, chat_id: &str, username: &str) -> Result<Room, ChatErrorReason> {
+        let username = username.to_string();
+        match self
+            .request(chat_id, |reply| RoomCommand::Authorize {
+                username: username.clone(),
+                reply,
+            })
+            .await
+        {
+            Some(result) => result,
+            None => self
+                .closed(chat_id)
+                .map_or(Err(ChatErrorReason::UnknownChat), |room| {
+                    authorize(&room, &username)
+                }),
+        }
+    }
+
+    // Registra l'accettazione dell'invito. Ritorna false se l'utente non era invitato
+    // o la chat è chiusa.
+    pub async fn accept(&self, chat_id: &str, username: &str) -> bool {
+        let user = username.to_string();
+        let accepted = self
+            .request(chat_id, |reply| RoomCommand::Accept {
+                username: user,
+                reply,
+            })
+            .await
+            .unwrap_or(false);
+        if accepted {
+            self.join(username, chat_id);
+        }
+        accepted
+    }
+
+    // Aggiunge nuovi invitati a una chat di gruppo aperta, senza toccare membri e presenze.
+    // Solo proprietario e amministratori possono invitare; ritorna la chat aggiornata e gli utenti davvero aggiunti
+    // (quelli già invitati vengono saltati).
+    pub async fn invite(
+        &self,
+        chat_id: &str,
+        invited_by: &str,
+        usernames: Vec<String>,
+    ) -> Result<(Room, Vec<String>), ChatErrorReason> {
+        let by = invited_by.to_string();
+        match self
+            .request(chat_id, |reply| RoomCommand::Invite {
+                invited_by: by,
+                usernames: usernames.clone(),
+                reply,
+            })
+            .await
+        {
+            Some(result) => result,
+            // Su una chat chiusa l'invito fallisce sempre: serve solo l'errore giusto
+            None => self
+                .closed(chat_id)
+                .map_or(Err(ChatErrorReason::UnknownChat), |mut room| {
+                    invite(&mut room, invited_by, usernames)
+                }),
+        }
+    }
+
+    // Verifica, senza modificare la chat, che `invited_by` possa aggiungervi invitati
+    pub async fn check_inviter(&self, chat_id: &str, invited_by: &str) -> Result<(), ChatErrorReason> {
+        self.get(chat_id)
+            .await
+            .map_or(Err(ChatErrorReason::UnknownChat), |room| check_inviter(&room, invited_by))
+    }
+
+    // Rimuove un utente dagli invitati quando rifiuta l'invito (o non entrerà più).
+    // Una chat rimasta con il solo proprietario viene chiusa.
+    pub async fn decline(&self, chat_id: &str, username: &str) -> bool {
+        let user = username.to_string();
+        let declined = self
+            .request(chat_id, |reply| RoomCommand::Decline {
+                username: user,
+                reply,
+            })
+            .await
+            .unwrap_or(false);
+        if declined {
+            self.quit(username, chat_id);
+        }
+        declined
+    }
+
+    // Applica un'operazione di moderazione di `by` su `target` in una chat di gruppo.
+    // Espulsi e banditi perdono invito, iscrizione e presenza. Ritorna la chat aggiornata.
+    pub async fn moderate(
+        &self,
+        chat_id: &str,
+        by: &str,
+        action: ModerationAction,
+        target: &str,
+    ) -> Result<Room, ChatErrorReason> {
+        let by = by.to_string();
+        let removed = target.to_string();
+        let result = match self
+            .request(chat_id, |reply| RoomCommand::Moderate {
+                by: by.clone(),
+                action,
+                target: removed,
+                reply,
+            })
+            .await
+        {
+            Some(result) => result,
+            None => self
+                .closed(chat_id)
+                .map_or(Err(ChatErrorReason::UnknownChat), |mut room| {
+                    moderate(&mut room, &by, action, target)
+                }),
+        };
+        if matches!(&result, Ok(room) if !room.is_member(target)) {
+            self.quit(target, chat_id);
+        }
+        result
+    }
+
+    // Segna l'utente come presente in chat: da qui in poi riceve i messaggi tramite `outbox`.
+    // Ritorna true se la presenza è cambiata.
+    pub async fn enter(&self, chat_id: &str, username: &str, outbox: Outbox) -> bool {
+        let username = username.to_string();
+        self.request(chat_id, |reply| RoomCommand::Enter {
+            username,
+            outbox,
+            reply,
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    // Segna l'utente come uscito dalla chat. Ritorna None se non era presente.
+    // Se una chat privata già completa resta con un solo utente, l'abbandono è
+    // definitivo e la chat viene chiusa.
+    pub async fn leave(&self, chat_id: &str, username: &str) -> Option<LeaveOutcome> {
+        let username = username.to_string();
+        self.request(chat_id, |reply| RoomCommand::Leave { username, reply })
+            .await
+            .flatten()
+    }
+
+    // Inoltra un messaggio già serializzato ai presenti nella chat (escluso `exclude`).
+    // Non attende la consegna: i messaggi inviati nella stessa chat mantengono l'ordine.
+    pub fn broadcast(&self, chat_id: &str, frame: Frame, exclude: Option<&str>, delivery: Delivery) {
+        if let Some(handle) = self.handle(chat_id) {
+            let _ = handle.commands.send(RoomCommand::Broadcast {
+                frame,
+                exclude: exclude.map(str::to_string),
+                delivery,
+            });
+        }
+    }
+}
+
+// Task che possiede una chat: termina quando il registro viene rilasciato, oppure quando la
+// chat è chiusa e non ha più presenti. In quel caso lascia l'ultimo stato in `closed`, toglie il
+// proprio canale da `rooms` e serve solo i comandi già in coda.
+async fn run_room(
+    mut room: Room,
+    mut commands: mpsc::UnboundedReceiver<RoomCommand>,
+    store: Arc<dyn RoomStore>,
+    rooms: Weak<Mutex<HashMap<String, RoomHandle>>>,
+    closed: Arc<Mutex<HashMap<String, Room>>>,
+) {
+    // Presenti in chat con il canale verso la loro connessione
+    let mut present: HashMap<String, Outbox> = HashMap::new();
+    let mut retired = false;
+
+    while let Some(command) = commands.recv().await {
+        match command {
+            RoomCommand::Get { reply } => {
+                let _ = reply.send(room.clone());
+            }
+            RoomCommand::Authorize { username, reply } => {
+                let _ = reply.send(authorize(&room, &username));
+            }
+            RoomCommand::Accept { username, reply } => {
+                let accepted = room.status != RoomStatus::Closed && room.is_invited(&username);
+                if accepted {
+                    if !room.is_member(&username) {
+                        room.members.push(username);
+                    }
+                    room.status = RoomStatus::Active;
+                    store.save(&room);
+                }
+                let _ = reply.send(accepted);
+            }
+            RoomCommand::Invite {
+                invited_by,
+                usernames,
+                reply,
+            } => {
+                let result = invite(&mut room, &invited_by, usernames);
+                if result.is_ok() {
+                    store.save(&room);
+                }
+                let _ = reply.send(result);
+            }
+            RoomCommand::Moderate {
+                by,
+                action,
+                target,
+                reply,
+            } => {
+                let result = moderate(&mut room, &by, action, &target);
+                if result.is_ok() {
+                    store.save(&room);
+                    if !room.is_invited(&target) {
+                        present.remove(&target);
+                    }
+                }
+                let _ = reply.send(result);
+            }
+            RoomCommand::Decline { username, reply } => {
+                let declined = room.is_invited(&username);
+                if declined {
+                    room.invited_users.retain(|u| *u != username);
+                    room.members.retain(|u| *u != username);
+                    // Sicurezza: assicurati che non risulti in chat
+                    room.users_in_chat.retain(|u| *u != username);
+                    present.remove(&username);
+                    if room.invited_users.len() < 2 {
+                        room.status = RoomStatus::Closed;
+                    }
+                    store.save(&room);
+                }
+                let _ = reply.send(declined);
+            }
+            RoomCommand::Enter {
+                username,
+                outbox,
+                reply,
+            } => {
+                let had_both_users = room.had_both_users;
+                let entered = enter(&mut room, &mut present, username, outbox);
+                if room.had_both_users != had_both_users {
+                    store.save(&room);
+                }
+                let _ = reply.send(entered);
+            }
+            RoomCommand::Leave { username, reply } => {
+                let outcome = leave(&mut room, &username);
+                if outcome.is_some() {
+                    present.remove(&username);
+                }
+                if matches!(&outcome, Some(left) if left.abandoned_remaining.is_some()) {
+                    store.save(&room);
+                }
+                let _ = reply.send(outcome);
+            }
+            RoomCommand::Broadcast {
+                frame,
+                exclude,
+                delivery,
+            } => {
+                for (username, outbox) in &present {
+                    if exclude.as_ref() != Some(username) {
+                        match delivery {
+                            Delivery::Present => outbox.send(frame.clone()),
+                            Delivery::Members { .. } => outbox.deliver(frame.clone()),
+                        }
+                    }
+                }
+                // I membri non presenti lo ricevono comunque: subito se connessi, altrimenti in coda
+                if let Delivery::Members { users, offline } = delivery {
+                    let absent: Vec<String> = room
+                        .members
+                        .iter()
+                        .filter(|member| !present.contains_key(*member) && exclude.as_ref() != Some(*member))
+                        .cloned()
+                        .collect();
+                    if !absent.is_empty() {
+                        users.deliver_to(absent, frame, &offline);
+                    }
+                }
+            }
+        }
+
+        let finished = room.status == RoomStatus::Closed && present.is_empty();
+        if finished && !retired {
+            retired = true;
+            closed.lock().unwrap().insert(room.id.clone(), room.clone());
+            if let Some(rooms) = rooms.upgrade() {
+                rooms.lock().unwrap().remove(&room.id);
+            }
+            commands.close();
+        }
+    }
+    // Comandi arrivati dopo il ritiro: l'ultimo stato resta quello in `closed`
+    if retired {
+        closed.lock().unwrap().insert(room.id.clone(), room);
+    }
+}
+
+fn authorize(room: &Room, username: &str) -> Result<Room, ChatErrorReason> {
+    if room.is_banned(username) {
+        Err(ChatErrorReason::Banned)
+    } else if !room.is_member(username) {
+        Err(ChatErrorReason::NotMember)
+    } else if room.status == RoomStatus::Closed {
+        Err(ChatErrorReason::ChatClosed)
+    } else {
+        Ok(room.clone())
+    }
+}
+
+// Controlli su chi invita, indipendenti dagli invitati
+fn check_inviter(room: &Room, invited_by: &str) -> Result<(), ChatErrorReason> {
+    let Some(role) = room.role_of(invited_by) else {
+        return Err(ChatErrorReason::NotMember);
+    };
+    if room.status == RoomStatus::Closed {
+        return Err(ChatErrorReason::ChatClosed);
+    }
+    if room.kind != RoomKind::Group {
+        return Err(ChatErrorReason::NotGroupChat);
+    }
+    if role == ChatRole::Member {
+        return Err(ChatErrorReason::NotAllowed);
+    }
+    Ok(())
+}
+
+fn invite(
+    room: &mut Room,
+    invited_by: &str,
+    usernames: Vec<String>,
+) -> Result<(Room, Vec<String>), ChatErrorReason> {
+    check_inviter(room, invited_by)?;
+    if usernames.iter().any(|user| room.is_banned(user)) {
+        return Err(ChatErrorReason::Banned);
+    }
+    let mut added = Vec::new();
+    for user in usernames {
+        if !room.is_invited(&user) && !added.contains(&user) {
+            added.push(user);
+        }
+    }
+    if added.is_empty() {
+        return Err(ChatErrorReason::InvalidInvite);
+    }
+    room.invited_users.extend(added.iter().cloned());
+    Ok((room.clone(), added))
+}
+
+fn moderate(
+    room: &mut Room,
+    by: &str,
+    action: ModerationAction,
+    target: &str,
+) -> Result<Room, ChatErrorReason> {
+    if room.kind != RoomKind::Group {
+        return Err(ChatErrorReason::NotGroupChat);
+    }
+    let Some(role) = room.role_of(by) else {
+        return Err(ChatErrorReason::NotMember);
+    };
+    if room.status == RoomStatus::Closed {
+        return Err(ChatErrorReason::ChatClosed);
+    }
+    if by == target {
+        return Err(ChatErrorReason::InvalidTarget);
+    }
+    let target_role = room.role_of(target);
+    match action {
+        ModerationAction::Kick | ModerationAction::Ban => {
+            // Gli amministratori moderano solo i membri semplici (e chi non ha ancora accettato)
+            let allowed = match role {
+                ChatRole::Owner => true,
+                ChatRole::Admin => matches!(target_role, None | Some(ChatRole::Member)),
+                ChatRole::Member => false,
+            };
+            if !allowed {
+                return Err(ChatErrorReason::NotAllowed);
+            }
+            let applies = match action {
+                ModerationAction::Kick => room.is_invited(target),
+                _ => !room.is_banned(target),
+            };
+            if !applies {
+                return Err(ChatErrorReason::InvalidTarget);
+            }
+            room.invited_users.retain(|u| u != target);
+            room.members.retain(|u| u != target);
+            room.admins.retain(|u| u != target);
+            room.users_in_chat.retain(|u| u != target);
+            if action == ModerationAction::Ban {
+                room.banned.push(target.to_string());
+            }
+        }
+        ModerationAction::Unban => {
+            if role == ChatRole::Member {
+                return Err(ChatErrorReason::NotAllowed);
+            }
+            if !room.is_banned(target) {
+                return Err(ChatErrorReason::InvalidTarget);
+            }
+            room.banned.retain(|u| u != target);
+        }
+        ModerationAction::Promote | ModerationAction::Demote | ModerationAction::TransferOwnership => {
+            // Ruoli e proprietà sono decisi solo dal proprietario
+            if role != ChatRole::Owner {
+                return Err(ChatErrorReason::NotAllowed);
+            }
+            match (action, target_role) {
+                (ModerationAction::Promote, Some(ChatRole::Member)) => {
+                    room.admins.push(target.to_string());
+                }
+                (ModerationAction::Demote, Some(ChatRole::Admin)) => {
+                    room.admins.retain(|u| u != target);
+                }
+                (ModerationAction::TransferOwnership, Some(_)) => {
+                    room.admins.retain(|u| u != target);
+                    let previous = std::mem::replace(&mut room.owner, target.to_string());
+                    room.admins.push(previous);
+                }
+                _ => return Err(ChatErrorReason::InvalidTarget),
+            }
+        }
+    }
+    Ok(room.clone())
+}
+
+fn enter(room: &mut Room, present: &mut HashMap<String, Outbox>, username: String, outbox: Outbox) -> bool {
+    if room.status == RoomStatus::Closed {
+        return false;
+    }
+    // Già presente: aggiorna solo il canale
+    present.insert(username.clone(), outbox);
+    if room.users_in_chat.contains(&username) {
+        return false;
+    }
+    room.users_in_chat.push(username);
+
+    //CONTROLLO SPECIALE: chat privata con entrambi gli utenti presenti
+    if room.kind == RoomKind::Private && room.users_in_chat.len() == 2 {
+        room.had_both_users = true;
+    }
+    true
+}
+
+fn leave(room: &mut Room, username: &str) -> Option<LeaveOutcome> {
+    let pos = room.users_in_chat.iter().position(|u| u == username)?;
+    room.users_in_chat.remove(pos);
+
+    let mut outcome = LeaveOutcome::default();
+    if room.kind == RoomKind::Private && room.had_both_users && room.users_in_chat.len() == 1 {
+        outcome.abandoned_remaining = Some(room.users_in_chat[0].clone());
+        room.status = RoomStatus::Closed;
+    }
+    Some(outcome)
+}
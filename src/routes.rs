@@ -1,80 +1,242 @@
+use crate::accounts::{check_login, RegisterError};
+use crate::history::{load_history_page, HistoryError};
 use crate::performance::update_cpu_time;
+use crate::presence::StatusUpdate;
+use crate::protocol::{ProtocolVersion, SUBPROTOCOLS};
+use crate::schema::protocol_schema;
 use crate::state::AppState;
-use crate::types::{LoginRequest, User};
+use crate::tracking::remove_user_from_chat_tracking;
+use crate::unread::unread_counts;
+use crate::types::{HistoryRequest, LoginErrorReason, LoginRequest, RegisterRequest, User};
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    async_trait,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
+use serde::Deserialize;
 use std::time::Instant;
+use uuid::Uuid;
 
 //handlers HTTP REST del server
 
+// Token inviato come `Authorization: Bearer <token>`
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+// Utente autenticato dal token di sessione ricevuto con LoginSuccess,
+// inviato come `Authorization: Bearer <token>`
+pub struct SessionUser(pub String);
+
+#[async_trait]
+impl FromRequestParts<AppState> for SessionUser {
+    type Rejection = (StatusCode, Json<&'static str>);
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        match bearer_token(&parts.headers).map(|token| state.sessions.verify(token)) {
+            Some(Ok(claims)) => Ok(SessionUser(claims.username)),
+            _ => Err((
+                StatusCode::UNAUTHORIZED,
+                Json("Token di sessione mancante o non valido"),
+            )),
+        }
+    }
+}
+
 pub async fn root() -> &'static str {
     "Rust WebSocket Chat Server is running!"
 }
 
 //get lista utenti connessi
 pub async fn get_users(State(users): State<AppState>) -> impl IntoResponse {
-    let users_guard = users.connected_users.lock().unwrap();
-    let start = Instant::now(); 
-    let users_vec: Vec<User> = users_guard.values().map(|cu| cu.user.clone()).collect();
-    //aggiorna il tempo di CPU//
-    update_cpu_time(users.total_cpu_time.clone(), start);
+    let users_vec: Vec<User> = users.users.list().await;
     (StatusCode::OK, Json(users_vec))
 }
 
-//validazione username
+//registrazione di un nuovo account
+pub async fn register_user(
+    State(state): State<AppState>,
+    Json(register_req): Json<RegisterRequest>,
+) -> impl IntoResponse {
+    // L'hashing della password è bloccante: va eseguito fuori dal runtime async
+    let accounts = state.accounts.clone();
+    let username = register_req.username.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        accounts.register(&register_req.username, &register_req.password)
+    })
+    .await
+    .unwrap_or(Err(RegisterError::Storage));
+
+    match result {
+        Ok(()) => (
+            StatusCode::CREATED,
+            Json(format!("Account '{}' registrato", username)),
+        ),
+        Err(e) => {
+            let status = match e {
+                RegisterError::AlreadyExists => StatusCode::CONFLICT,
+                RegisterError::Storage => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            (status, Json(e.message()))
+        }
+    }
+}
+
+//validazione credenziali
 pub async fn login_user(
     State(users): State<AppState>,
     Json(login_req): Json<LoginRequest>,
 ) -> impl IntoResponse {
-    // Controlla se l'username è già in uso
-    let users_guard = users.connected_users.lock().unwrap();
-    let start = Instant::now(); 
-    if users_guard.contains_key(&login_req.username) {
-        //aggiorna il tempo di CPU
-        update_cpu_time(users.total_cpu_time.clone(), start);
-        return (
-            StatusCode::CONFLICT,
-            Json(format!(
-                "Username '{}' è già in uso. Scegli un altro nome.",
-                login_req.username
-            )),
-        );
+    match check_login(&users, &login_req).await {
+        // Credenziali valide e username disponibile
+        Ok(()) => (
+            StatusCode::OK,
+            Json(format!("Username '{}' è disponibile", login_req.username)),
+        )
+            .into_response(),
+        Err(error) => {
+            let status = match error.reason {
+                LoginErrorReason::AlreadyConnected => StatusCode::CONFLICT,
+                _ => StatusCode::UNAUTHORIZED,
+            };
+            (status, Json(error)).into_response()
+        }
     }
-
-    //aggiorna il tempo di CPU
-    update_cpu_time(users.total_cpu_time.clone(), start);
-    // Username disponibile
-    (
-        StatusCode::OK,
-        Json(format!("Username '{}' è disponibile", login_req.username)),
-    )
 }
 
-//aggiorna stato utente
+//aggiorna stato utente: solo l'utente del token di sessione può cambiare il proprio
 pub async fn update_user_availability(
     State(users): State<AppState>,
+    SessionUser(session_user): SessionUser,
     Path(username): Path<String>,
     Json(available): Json<bool>,
 ) -> impl IntoResponse {
-    let mut users_map = users.connected_users.lock().unwrap();
-    let start = Instant::now();
+    if session_user != username {
+        return (StatusCode::FORBIDDEN, Json("Non puoi modificare la disponibilità di un altro utente"));
+    }
+    // Un utente di nuovo disponibile non è più in nessuna chat
+    let update = StatusUpdate {
+        available: Some(available),
+        in_chat: available.then_some(false),
+        ..StatusUpdate::default()
+    };
 
-    if let Some(connected_user) = users_map.get_mut(&username) {
-        connected_user.user.is_available = available;
+    match users.users.update_status(&username, update).await {
+        Some(change) => {
+            if let Some(chat_id) = change.left_chat {
+                remove_user_from_chat_tracking(&users, &chat_id, &username).await;
+            }
+            (StatusCode::OK, Json("Disponibilità aggiornata"))
+        }
+        None => (StatusCode::NOT_FOUND, Json("Utente non trovato")),
+    }
+}
 
-        if available {
-            connected_user.user.chat_id = None;
+// Parametri di /api/chats/:chat_id/messages
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    pub before: Option<Uuid>,
+    pub limit: Option<usize>,
+}
+
+//cronologia paginata di una chat, per l'utente del token di sessione
+pub async fn get_chat_messages(
+    State(state): State<AppState>,
+    SessionUser(username): SessionUser,
+    Path(chat_id): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> impl IntoResponse {
+    let request = HistoryRequest {
+        chat_id,
+        before: query.before,
+        limit: query.limit,
+    };
+    match load_history_page(&state, &username, &request).await {
+        Ok(page) => (StatusCode::OK, Json(page)).into_response(),
+        Err(e) => {
+            let status = match e {
+                HistoryError::UnknownChat => StatusCode::NOT_FOUND,
+                HistoryError::Forbidden => StatusCode::FORBIDDEN,
+                HistoryError::InvalidCursor => StatusCode::BAD_REQUEST,
+            };
+            (status, Json(e.message())).into_response()
         }
-        //aggiorna il tempo di CPU//
-        update_cpu_time(users.total_cpu_time.clone(), start);
-        (StatusCode::OK, Json("Disponibilità aggiornata"))
-    } else {
-        //aggiorna il tempo di CPU//
-        update_cpu_time(users.total_cpu_time.clone(), start);
-        (StatusCode::NOT_FOUND, Json("Utente non trovato"))
     }
 }
+
+//stato di consegna e lettura di un messaggio, visibile agli invitati della chat
+pub async fn get_message_receipt(
+    State(state): State<AppState>,
+    SessionUser(username): SessionUser,
+    Path((chat_id, message_id)): Path<(String, Uuid)>,
+) -> impl IntoResponse {
+    if !state.rooms.is_invited(&chat_id, &username).await {
+        return (StatusCode::FORBIDDEN, Json(HistoryError::Forbidden.message())).into_response();
+    }
+    let start = Instant::now();
+    let receipt = state
+        .receipts
+        .get(&message_id)
+        .filter(|receipt| receipt.chat_id == chat_id);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    match receipt {
+        Some(receipt) => (StatusCode::OK, Json(receipt)).into_response(),
+        None => (StatusCode::NOT_FOUND, Json("Messaggio non trovato")).into_response(),
+    }
+}
+
+//inviti ancora in attesa di risposta da parte dell'utente autenticato, con la loro scadenza
+pub async fn get_pending_invites(
+    State(state): State<AppState>,
+    SessionUser(username): SessionUser,
+) -> impl IntoResponse {
+    let start = Instant::now();
+    let invites = state.invites.pending_for(&username);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    (StatusCode::OK, Json(invites))
+}
+
+//messaggi non letti e ultimo messaggio visto in ogni chat di cui l'utente è membro,
+//visibili solo all'utente stesso
+pub async fn get_unread(
+    State(state): State<AppState>,
+    SessionUser(session_user): SessionUser,
+    Path(username): Path<String>,
+) -> Response {
+    if session_user != username {
+        return (StatusCode::FORBIDDEN, Json("Non puoi leggere i non letti di un altro utente")).into_response();
+    }
+    (StatusCode::OK, Json(unread_counts(&state, &username))).into_response()
+}
+
+//code in uscita di ogni utente connesso: messaggi in attesa, picco, presenze scartate, espulsioni.
+//Elenca gli utenti connessi: risponde solo se è configurato `metrics_token` e va chiamata con quel token
+pub async fn get_queue_metrics(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let Some(expected) = &state.metrics_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if bearer_token(&headers) != Some(expected.as_str()) {
+        return (StatusCode::UNAUTHORIZED, Json("Token delle metriche mancante o non valido")).into_response();
+    }
+    (StatusCode::OK, Json(state.users.queue_stats().await)).into_response()
+}
+
+//versioni supportate del protocollo WebSocket e JSON Schema dei frame, per tenere allineato il frontend
+pub async fn get_protocol() -> impl IntoResponse {
+    let versions = [ProtocolVersion::V1, ProtocolVersion::V2].map(ProtocolVersion::number);
+    let body = serde_json::json!({
+        "versions": versions,
+        "subprotocols": SUBPROTOCOLS,
+        "schema": protocol_schema(),
+    });
+    (StatusCode::OK, Json(body))
+}
@@ -0,0 +1,576 @@
+use crate::protocol::{ClientMessage, ServerMessage};
+use crate::types::{
+    AddChatMembers, AloneInChatNotification, ChatAbandonedNotification, ChatCreated, ChatError,
+    ChatErrorReason, ChatInvalidated, ChatInvite, ChatInviteClosed, ChatInviteResponse,
+    ChatInviteResponseNotify, ChatMessage, ChatMessageSent, ChatReady, ChatRole, ChatType,
+    ChatUnread, ChatUsersCount, HistoryPage, HistoryRequest, InviteCancel, InviteState, LoginError,
+    LoginErrorReason, LoginRequest, LoginSuccess, MessageReceipt, MessageType, ModerationAction,
+    ModerationNotice, ModerationRequest, ReceiptAck, ReceiptStatus, RecipientReceipt, RequestAck,
+    ResumeRequest, Room, RoomKind, RoomStatus, SendChatMessage, ServerShutdown, StatusChangeRequest,
+    TypingIndicator, TypingRequest, UnreadCounts, User,
+};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+// JSON Schema del protocollo WebSocket, generato dai tipi di types.rs e protocol.rs.
+// Ogni descrizione elenca i campi o le varianti del tipo in un `check` che il compilatore
+// verifica: aggiungere o rinominare un campo senza aggiornare lo schema non compila.
+
+// Tipi con nome, raccolti in `$defs` e referenziati con `$ref`
+#[derive(Default)]
+pub struct Definitions(BTreeMap<String, Value>);
+
+impl Definitions {
+    // Registra il tipo `name` una sola volta e ne ritorna il riferimento
+    fn define(&mut self, name: &str, schema: impl FnOnce(&mut Definitions) -> Value) -> Value {
+        if !self.0.contains_key(name) {
+            self.0.insert(name.to_string(), Value::Null);
+            let schema = schema(self);
+            self.0.insert(name.to_string(), schema);
+        }
+        json!({ "$ref": format!("#/$defs/{}", name) })
+    }
+}
+
+pub trait JsonSchema {
+    fn schema(defs: &mut Definitions) -> Value;
+
+    // Un campo di questo tipo può mancare (Option)
+    fn optional() -> bool {
+        false
+    }
+}
+
+impl JsonSchema for String {
+    fn schema(_: &mut Definitions) -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+impl JsonSchema for bool {
+    fn schema(_: &mut Definitions) -> Value {
+        json!({ "type": "boolean" })
+    }
+}
+
+impl JsonSchema for u64 {
+    fn schema(_: &mut Definitions) -> Value {
+        json!({ "type": "integer", "minimum": 0 })
+    }
+}
+
+impl JsonSchema for usize {
+    fn schema(_: &mut Definitions) -> Value {
+        json!({ "type": "integer", "minimum": 0 })
+    }
+}
+
+impl JsonSchema for Uuid {
+    fn schema(_: &mut Definitions) -> Value {
+        json!({ "type": "string", "format": "uuid" })
+    }
+}
+
+impl JsonSchema for chrono::DateTime<chrono::Utc> {
+    fn schema(_: &mut Definitions) -> Value {
+        json!({ "type": "string", "format": "date-time" })
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Option<T> {
+    fn schema(defs: &mut Definitions) -> Value {
+        json!({ "anyOf": [T::schema(defs), { "type": "null" }] })
+    }
+
+    fn optional() -> bool {
+        true
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Vec<T> {
+    fn schema(defs: &mut Definitions) -> Value {
+        json!({ "type": "array", "items": T::schema(defs) })
+    }
+}
+
+// Nome JSON di un campo: quello indicato con `as` (serde rename) o il nome Rust
+macro_rules! field_name {
+    ($field:ident) => {
+        stringify!($field)
+    };
+    ($field:ident, $json:literal) => {
+        $json
+    };
+}
+
+// Struct serializzata come oggetto JSON
+macro_rules! object_schema {
+    ($ty:ident { $($field:ident $(as $json:literal)? : $field_ty:ty),* $(,)? }) => {
+        impl JsonSchema for $ty {
+            fn schema(defs: &mut Definitions) -> Value {
+                #[allow(dead_code)]
+                fn check(value: &$ty) {
+                    let $ty { $($field),* } = value;
+                    $(let _: &$field_ty = $field;)*
+                }
+                defs.define(stringify!($ty), |defs| {
+                    let mut properties = Map::new();
+                    let mut required = Vec::new();
+                    $(
+                        let name = field_name!($field $(, $json)?);
+                        properties.insert(name.to_string(), <$field_ty>::schema(defs));
+                        if !<$field_ty>::optional() {
+                            required.push(Value::from(name));
+                        }
+                    )*
+                    json!({ "type": "object", "properties": properties, "required": required })
+                })
+            }
+        }
+    };
+}
+
+// Enum con sole varianti unitarie, serializzata come stringa
+macro_rules! string_enum_schema {
+    ($ty:ident [$($variant:ident),* $(,)?]) => {
+        impl JsonSchema for $ty {
+            fn schema(defs: &mut Definitions) -> Value {
+                #[allow(dead_code)]
+                fn check(value: &$ty) {
+                    match value {
+                        $($ty::$variant => {})*
+                    }
+                }
+                defs.define(stringify!($ty), |_| {
+                    json!({ "type": "string", "enum": [$(stringify!($variant)),*] })
+                })
+            }
+        }
+    };
+}
+
+// Messaggi del protocollo: `{"message_type": "<variante>", "data": <payload>}`, con il
+// `request_id` facoltativo del client (ripetuto dal server nelle risposte)
+macro_rules! tagged_schema {
+    ($ty:ident [$($variant:ident($payload:ty)),* $(,)?]) => {
+        impl JsonSchema for $ty {
+            fn schema(defs: &mut Definitions) -> Value {
+                #[allow(dead_code)]
+                fn check(value: &$ty) {
+                    match value {
+                        $($ty::$variant(payload) => {
+                            let _: &$payload = payload;
+                        })*
+                    }
+                }
+                defs.define(stringify!($ty), |defs| {
+                    let variants: Vec<Value> = vec![$(
+                        json!({
+                            "type": "object",
+                            "properties": {
+                                "message_type": { "const": stringify!($variant) },
+                                "data": <$payload>::schema(defs),
+                                "request_id": { "type": "string" },
+                            },
+                            "required": ["message_type", "data"],
+                        })
+                    ),*];
+                    json!({ "oneOf": variants })
+                })
+            }
+        }
+    };
+}
+
+object_schema!(User {
+    username: String,
+    is_available: bool,
+    chat_id: Option<String>,
+});
+object_schema!(LoginRequest {
+    username: String,
+    password: String,
+});
+object_schema!(LoginSuccess {
+    username: String,
+    session_id: String,
+    token: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+    resumed: bool,
+    chat_id: Option<String>,
+});
+object_schema!(ResumeRequest { token: String });
+string_enum_schema!(LoginErrorReason [
+    InvalidCredentials,
+    AlreadyConnected,
+    InvalidSession,
+    SessionExpired,
+]);
+object_schema!(LoginError {
+    reason: LoginErrorReason,
+    message: String,
+});
+object_schema!(ChatMessage {
+    id: Uuid,
+    chat_id: Option<String>,
+    username: String,
+    content: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    chat_type: ChatType,
+});
+object_schema!(SendChatMessage {
+    chat_id: Option<String>,
+    content: String,
+    client_nonce: Option<String>,
+});
+object_schema!(ChatMessageSent {
+    client_nonce: Option<String>,
+    message: ChatMessage,
+});
+object_schema!(StatusChangeRequest {
+    available: Option<bool>,
+    in_chat as "inChat": Option<bool>,
+    chat_id as "chatId": Option<Option<String>>,
+});
+object_schema!(ChatInvite {
+    id: String,
+    chat_id: Option<String>,
+    from: String,
+    from_session_id: String,
+    chat_type: ChatType,
+    message: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+});
+object_schema!(ChatInviteResponse {
+    invite_id: String,
+    chat_id: Option<String>,
+    accepted: bool,
+    from_user: String,
+    from_session_id: String,
+    chat_type: ChatType,
+});
+object_schema!(ChatInviteResponseNotify {
+    invite_id: String,
+    chat_id: Option<String>,
+    accepted: bool,
+    from_user: String,
+    from_session_id: String,
+    chat_type: ChatType,
+    responding_user: String,
+});
+string_enum_schema!(InviteState [
+    Pending,
+    Accepted,
+    Declined,
+    Expired,
+    Cancelled,
+]);
+object_schema!(InviteCancel {
+    invite_id: String,
+});
+object_schema!(AddChatMembers {
+    invite_id: String,
+    chat_id: String,
+    members: Vec<String>,
+    message: String,
+});
+object_schema!(ChatInviteClosed {
+    invite_id: String,
+    chat_id: String,
+    state: InviteState,
+});
+object_schema!(ChatReady {
+    chat_id: String,
+    inviter: String,
+    inviter_session_id: String,
+    chat_type: ChatType,
+    accepted_by: String,
+});
+object_schema!(AloneInChatNotification {
+    chat_id: String,
+    message: String,
+    is_alone: bool,
+});
+object_schema!(ChatUsersCount {
+    chat_id: String,
+    invited_users: Vec<String>,
+    users_in_chat: Vec<String>,
+    invited_count: usize,
+    in_chat_count: usize,
+});
+string_enum_schema!(RoomKind [Private, Group]);
+string_enum_schema!(RoomStatus [Pending, Active, Closed]);
+object_schema!(Room {
+    id: String,
+    kind: RoomKind,
+    owner: String,
+    admins: Vec<String>,
+    banned: Vec<String>,
+    invited_users: Vec<String>,
+    members: Vec<String>,
+    users_in_chat: Vec<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    status: RoomStatus,
+    had_both_users: bool,
+});
+string_enum_schema!(ChatRole [
+    Owner,
+    Admin,
+    Member,
+]);
+string_enum_schema!(ModerationAction [
+    Kick,
+    Ban,
+    Unban,
+    Promote,
+    Demote,
+    TransferOwnership,
+]);
+object_schema!(ModerationRequest {
+    chat_id: String,
+    action: ModerationAction,
+    target: String,
+});
+object_schema!(ModerationNotice {
+    chat_id: String,
+    action: ModerationAction,
+    target: String,
+    by: String,
+    owner: String,
+    admins: Vec<String>,
+    banned: Vec<String>,
+});
+object_schema!(ChatCreated {
+    invite_id: String,
+    room: Room,
+});
+string_enum_schema!(ChatErrorReason [
+    UnknownChat,
+    NotMember,
+    NotInvited,
+    ChatClosed,
+    InvalidInvite,
+    UnknownMessage,
+    Unauthenticated,
+    AlreadyAuthenticated,
+    Malformed,
+    UnknownType,
+    RateLimited,
+    UnknownInvite,
+    InviteAnswered,
+    InviteExpired,
+    InviteCancelled,
+    DuplicateInvite,
+    NotGroupChat,
+    NotAllowed,
+    Banned,
+    InvalidTarget,
+    UnknownUser,
+    InvalidCursor,
+]);
+object_schema!(ChatError {
+    reason: ChatErrorReason,
+    code: String,
+    chat_id: Option<String>,
+    message: String,
+});
+object_schema!(ChatAbandonedNotification {
+    chat_id: String,
+    abandoned_by: String,
+    remaining_user: String,
+    message: String,
+    is_private_chat: bool,
+});
+object_schema!(ChatInvalidated {
+    chat_id: String,
+    reason: String,
+});
+object_schema!(TypingRequest { chat_id: String });
+object_schema!(TypingIndicator {
+    chat_id: String,
+    username: String,
+});
+object_schema!(ReceiptAck { message_id: Uuid });
+string_enum_schema!(ReceiptStatus [Sent, Delivered, Read]);
+object_schema!(RecipientReceipt {
+    username: String,
+    delivered_at: Option<chrono::DateTime<chrono::Utc>>,
+    read_at: Option<chrono::DateTime<chrono::Utc>>,
+});
+object_schema!(MessageReceipt {
+    message_id: Uuid,
+    chat_id: String,
+    sender: String,
+    recipients: Vec<RecipientReceipt>,
+    status: ReceiptStatus,
+});
+object_schema!(HistoryRequest {
+    chat_id: String,
+    before: Option<Uuid>,
+    limit: Option<usize>,
+});
+object_schema!(HistoryPage {
+    chat_id: String,
+    messages: Vec<ChatMessage>,
+    has_more: bool,
+    receipts: Vec<MessageReceipt>,
+});
+object_schema!(ServerShutdown {
+    message: String,
+    reconnect_after_secs: Option<u64>,
+});
+string_enum_schema!(MessageType [
+    Login,
+    ResumeSession,
+    LoginSuccess,
+    LoginError,
+    ChatMessage,
+    SendChatMessage,
+    ChatMessageSent,
+    Delivered,
+    Read,
+    ReceiptUpdate,
+    TypingStarted,
+    TypingStopped,
+    UserJoined,
+    UserLeft,
+    UserStatusChanged,
+    UsersList,
+    ChatInvite,
+    ChatInviteResponse,
+    ChatReady,
+    AloneInChat,
+    ChatUsersCount,
+    ChatAbandoned,
+    ChatInvalidated,
+    ChatCreated,
+    HistoryRequest,
+    HistoryPage,
+    Error,
+    ServerShutdown,
+    ChatInviteCancel,
+    ChatInviteClosed,
+    AddChatMembers,
+    ChatModeration,
+    UnreadCounts,
+    Ack,
+]);
+object_schema!(ChatUnread {
+    chat_id: String,
+    unread: usize,
+    last_read: Option<Uuid>,
+});
+object_schema!(UnreadCounts {
+    chats: Vec<ChatUnread>,
+});
+object_schema!(RequestAck {
+    message_type: MessageType,
+});
+
+// Enum esterna di serde: `{"Private": {"target": ...}}`, `{"Group": {"members": [...]}}` o "System"
+impl JsonSchema for ChatType {
+    fn schema(defs: &mut Definitions) -> Value {
+        #[allow(dead_code)]
+        fn check(value: &ChatType) {
+            match value {
+                ChatType::Private { target } => {
+                    let _: &String = target;
+                }
+                ChatType::Group { members } => {
+                    let _: &Vec<String> = members;
+                }
+                ChatType::System => {}
+            }
+        }
+        defs.define("ChatType", |defs| {
+            let target = String::schema(defs);
+            let members = Vec::<String>::schema(defs);
+            json!({ "oneOf": [
+                {
+                    "type": "object",
+                    "properties": { "Private": {
+                        "type": "object",
+                        "properties": { "target": target },
+                        "required": ["target"],
+                    } },
+                    "required": ["Private"],
+                    "additionalProperties": false,
+                },
+                {
+                    "type": "object",
+                    "properties": { "Group": {
+                        "type": "object",
+                        "properties": { "members": members },
+                        "required": ["members"],
+                    } },
+                    "required": ["Group"],
+                    "additionalProperties": false,
+                },
+                { "const": "System" },
+            ] })
+        })
+    }
+}
+
+tagged_schema!(ClientMessage [
+    Login(LoginRequest),
+    ResumeSession(ResumeRequest),
+    SendChatMessage(SendChatMessage),
+    ChatMessage(ChatMessage),
+    UserStatusChanged(StatusChangeRequest),
+    ChatInvite(ChatInvite),
+    ChatInviteResponse(ChatInviteResponse),
+    ChatInviteCancel(InviteCancel),
+    AddChatMembers(AddChatMembers),
+    ChatModeration(ModerationRequest),
+    Delivered(ReceiptAck),
+    Read(ReceiptAck),
+    TypingStarted(TypingRequest),
+    TypingStopped(TypingRequest),
+    HistoryRequest(HistoryRequest),
+]);
+
+tagged_schema!(ServerMessage [
+    LoginSuccess(LoginSuccess),
+    LoginError(LoginError),
+    ChatMessage(ChatMessage),
+    ChatMessageSent(ChatMessageSent),
+    ReceiptUpdate(MessageReceipt),
+    TypingStarted(TypingIndicator),
+    TypingStopped(TypingIndicator),
+    UserJoined(User),
+    UserLeft(String),
+    UserStatusChanged(User),
+    UsersList(Vec<User>),
+    ChatInvite(ChatInvite),
+    ChatInviteResponse(ChatInviteResponseNotify),
+    ChatInviteClosed(ChatInviteClosed),
+    ChatModeration(ModerationNotice),
+    UnreadCounts(UnreadCounts),
+    ChatReady(ChatReady),
+    AloneInChat(AloneInChatNotification),
+    ChatUsersCount(ChatUsersCount),
+    ChatAbandoned(ChatAbandonedNotification),
+    ChatInvalidated(ChatInvalidated),
+    ChatCreated(ChatCreated),
+    HistoryPage(HistoryPage),
+    Error(ChatError),
+    ServerShutdown(ServerShutdown),
+    Ack(RequestAck),
+]);
+
+// JSON Schema di tutti i frame del protocollo (versione 2), servito da GET /api/protocol
+// e salvato in client/src/API/protocol.schema.json
+pub fn protocol_schema() -> Value {
+    let mut defs = Definitions::default();
+    let client = ClientMessage::schema(&mut defs);
+    let server = ServerMessage::schema(&mut defs);
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Ruggine WebSocket protocol",
+        "description": "Frame scambiati su /ws. Con il protocollo 2 `data` è il payload descritto qui; con il protocollo 1 è lo stesso payload serializzato in una stringa (per UserLeft lo username stesso).",
+        "anyOf": [client, server],
+        "$defs": defs.0,
+    })
+}
@@ -0,0 +1,199 @@
+use crate::chat_routes;
+use crate::config::Config;
+use crate::shutdown;
+use crate::state::AppState;
+use crate::tls::{serve_tls, TlsCertificates};
+use crate::types::ChatMessage;
+use axum::routing::MethodRouter;
+use axum::Router;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type UserHook = Arc<dyn Fn(&str) + Send + Sync>;
+type MessageHook = Arc<dyn Fn(&ChatMessage) + Send + Sync>;
+
+// Callback registrate da chi incorpora il server, invocate dopo l'evento corrispondente.
+// Devono essere brevi: vengono eseguite nel task della connessione.
+#[derive(Clone, Default)]
+pub struct ServerHooks {
+    on_login: Vec<UserHook>,
+    on_logout: Vec<UserHook>,
+    on_message: Vec<MessageHook>,
+}
+
+impl ServerHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Nuova sessione (la ripresa di una sessione nel periodo di grazia non conta)
+    pub fn login(&self, username: &str) {
+        for hook in &self.on_login {
+            hook(username);
+        }
+    }
+
+    // Sessione conclusa alla scadenza del periodo di grazia
+    pub fn logout(&self, username: &str) {
+        for hook in &self.on_logout {
+            hook(username);
+        }
+    }
+
+    // Messaggio di un utente accettato e inoltrato ai membri della chat
+    pub fn message(&self, message: &ChatMessage) {
+        for hook in &self.on_message {
+            hook(message);
+        }
+    }
+
+    fn extend(&mut self, other: ServerHooks) {
+        self.on_login.extend(other.on_login);
+        self.on_logout.extend(other.on_logout);
+        self.on_message.extend(other.on_message);
+    }
+}
+
+// Costruisce il server di chat: usato dal binario e da chi lo incorpora nella propria
+// applicazione axum (eventualmente sotto un prefisso, es. `/chat/ws`).
+pub struct ServerBuilder {
+    config: Config,
+    state: Option<AppState>,
+    routes: Router<AppState>, // rotte aggiuntive, con accesso allo stato del server
+    prefix: Option<String>,
+    hooks: ServerHooks,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> Self {
+        ServerBuilder {
+            config,
+            state: None,
+            routes: Router::new(),
+            prefix: None,
+            hooks: ServerHooks::new(),
+        }
+    }
+
+    // Stato da usare al posto di quello creato da `AppState::from_config`
+    pub fn state(mut self, state: AppState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    pub fn route(mut self, path: &str, method_router: MethodRouter<AppState>) -> Self {
+        self.routes = self.routes.route(path, method_router);
+        self
+    }
+
+    pub fn merge(mut self, router: Router<AppState>) -> Self {
+        self.routes = self.routes.merge(router);
+        self
+    }
+
+    // Monta tutte le rotte sotto `prefix` (es. "/chat")
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.to_string());
+        self
+    }
+
+    pub fn on_login(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.hooks.on_login.push(Arc::new(hook));
+        self
+    }
+
+    pub fn on_logout(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.hooks.on_logout.push(Arc::new(hook));
+        self
+    }
+
+    pub fn on_message(mut self, hook: impl Fn(&ChatMessage) + Send + Sync + 'static) -> Self {
+        self.hooks.on_message.push(Arc::new(hook));
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // Router completo, pronto per `axum::serve` o per essere unito a un'altra applicazione.
+    // Senza uno stato esplicito apre gli archivi su disco indicati nella configurazione.
+    // Per l'arresto ordinato chi incorpora il server usa `shutdown::begin` e `shutdown::finish`.
+    pub fn build(self) -> std::io::Result<Router> {
+        self.into_parts().map(|(_, app)| app)
+    }
+
+    fn into_parts(self) -> std::io::Result<(AppState, Router)> {
+        let mut state = match self.state {
+            Some(state) => state,
+            None => AppState::from_config(Arc::new(Mutex::new(Duration::ZERO)), &self.config)?,
+        };
+        state.hooks.extend(self.hooks);
+
+        let app = chat_routes()
+            .merge(self.routes)
+            .with_state(state.clone())
+            .layer(self.config.cors_layer());
+        let app = match self.prefix {
+            Some(prefix) => Router::new().nest(&prefix, app),
+            None => app,
+        };
+        Ok((state, app))
+    }
+
+    // Avvia il server su `bind_address` (porta 0: porta libera scelta dal sistema)
+    // fino a Ctrl+C o SIGTERM
+    pub async fn serve(self) -> std::io::Result<()> {
+        let listener = tokio::net::TcpListener::bind(self.config.bind_address).await?;
+        self.serve_with_shutdown(listener, shutdown::os_signal()).await
+    }
+
+    // Serve le connessioni di `listener` finché `signal` non si completa, poi arresta il
+    // server in modo ordinato: nessuna nuova connessione, ServerShutdown a tutti gli utenti,
+    // attesa delle connessioni aperte e salvataggio su disco.
+    // Con certificato e chiave configurati le connessioni sono HTTPS/WSS.
+    pub async fn serve_with_shutdown(
+        self,
+        listener: tokio::net::TcpListener,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> std::io::Result<()> {
+        let addr = listener.local_addr()?;
+        let prefix = self.prefix.clone().unwrap_or_default();
+        let config = self.config.clone();
+        let certificates = match config.tls_paths() {
+            Some((cert_path, key_path)) => Some(TlsCertificates::load(cert_path, key_path)?),
+            None => None,
+        };
+        let (state, app) = self.into_parts()?;
+        let ws_scheme = if certificates.is_some() { "wss" } else { "ws" };
+        println!("Server listening on {}", addr);
+        println!("WebSocket endpoint: {}://{}{}/ws", ws_scheme, addr, prefix);
+
+        let notify_state = state.clone();
+        let reconnect_after = config.shutdown_reconnect_after();
+        let signal = async move {
+            signal.await;
+            println!("Arresto del server in corso...");
+            shutdown::begin(&notify_state, reconnect_after).await;
+        };
+        match certificates {
+            Some(certificates) => {
+                let watch_state = state.clone();
+                certificates.watch(config.tls_reload_interval(), async move {
+                    watch_state.shutdown.triggered().await;
+                });
+                serve_tls(listener, app, certificates, signal, config.shutdown_timeout()).await?;
+            }
+            None => {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(signal)
+                    .await?;
+            }
+        }
+
+        shutdown::finish(&state, config.shutdown_timeout(), &config.cpu_log_path).await;
+        println!("Server arrestato");
+        Ok(())
+    }
+}
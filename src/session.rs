@@ -0,0 +1,140 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Contenuto firmato del token di sessione
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SessionClaims {
+    pub username: String,
+    pub session_id: String,
+    pub expires_at: i64, // secondi Unix
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+// Emette e verifica token di sessione firmati con HMAC-SHA256.
+// Formato: `base64url(claims JSON).base64url(firma)`.
+pub struct SessionSigner {
+    key: Vec<u8>,
+    ttl: Duration,
+}
+
+impl SessionSigner {
+    pub fn new(key: impl Into<Vec<u8>>, ttl: Duration) -> Self {
+        SessionSigner {
+            key: key.into(),
+            ttl,
+        }
+    }
+
+    // Chiave casuale: i token emessi non restano validi dopo un riavvio del server
+    pub fn random(ttl: Duration) -> Self {
+        Self::new(random_key(), ttl)
+    }
+
+    // Chiave letta dal file (base64url), generata e salvata al primo avvio:
+    // i token emessi restano validi anche dopo un riavvio del server
+    pub fn open(path: impl AsRef<Path>, ttl: Duration) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            let encoded = std::fs::read_to_string(path)?;
+            let key = URL_SAFE_NO_PAD
+                .decode(encoded.trim())
+                .ok()
+                .filter(|key| !key.is_empty())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("chiave di sessione non valida in {}", path.display()),
+                    )
+                })?;
+            return Ok(Self::new(key, ttl));
+        }
+
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        let key = random_key();
+        write_private(path, &URL_SAFE_NO_PAD.encode(&key))?;
+        Ok(Self::new(key, ttl))
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC accetta chiavi di qualsiasi lunghezza")
+    }
+
+    // Emette un token per la sessione indicata, valido per la durata configurata
+    pub fn issue(&self, username: &str, session_id: &str) -> (String, chrono::DateTime<chrono::Utc>) {
+        let expires = chrono::Utc::now()
+            + chrono::Duration::from_std(self.ttl).unwrap_or(chrono::Duration::zero());
+        let claims = SessionClaims {
+            username: username.to_string(),
+            session_id: session_id.to_string(),
+            expires_at: expires.timestamp(),
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+        (format!("{}.{}", payload, signature), expires)
+    }
+
+    // Verifica firma e scadenza del token e ne restituisce il contenuto
+    pub fn verify(&self, token: &str) -> Result<SessionClaims, TokenError> {
+        let (payload, signature) = token.split_once('.').ok_or(TokenError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+
+        // Confronto a tempo costante
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| TokenError::BadSignature)?;
+
+        let claims_json = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| TokenError::Malformed)?;
+        let claims: SessionClaims =
+            serde_json::from_slice(&claims_json).map_err(|_| TokenError::Malformed)?;
+
+        if claims.expires_at <= chrono::Utc::now().timestamp() {
+            return Err(TokenError::Expired);
+        }
+        Ok(claims)
+    }
+}
+
+// 32 byte dal generatore casuale del sistema operativo
+fn random_key() -> Vec<u8> {
+    let mut key = vec![0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+// Il file della chiave è leggibile solo dal proprietario (su Unix)
+fn write_private(path: &Path, content: &str) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(path)?, content.as_bytes())
+}
@@ -0,0 +1,155 @@
+use crate::cpu_log;
+use crate::frame::Frame;
+use crate::protocol::ServerMessage;
+use crate::state::AppState;
+use crate::types::ServerShutdown;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+// Segnale di arresto condiviso dalle connessioni WebSocket. Le connessioni aperte
+// vengono contate per poter attendere la loro chiusura prima di uscire.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    triggered: watch::Sender<bool>,
+    active: Arc<AtomicUsize>,
+    closed: Arc<Notify>,
+}
+
+// Tiene aperta una connessione finché non viene rilasciata
+pub struct ConnectionGuard {
+    active: Arc<AtomicUsize>,
+    closed: Arc<Notify>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.closed.notify_waiters();
+        }
+    }
+}
+
+impl Default for ShutdownSignal {
+    fn default() -> Self {
+        ShutdownSignal {
+            triggered: watch::Sender::new(false),
+            active: Arc::new(AtomicUsize::new(0)),
+            closed: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl ShutdownSignal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+
+    // Si completa quando viene richiesto l'arresto (subito, se è già stato richiesto)
+    pub async fn triggered(&self) {
+        let mut receiver = self.triggered.subscribe();
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+
+    pub fn connection(&self) -> ConnectionGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard {
+            active: self.active.clone(),
+            closed: self.closed.clone(),
+        }
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    // Attende la chiusura di tutte le connessioni, al massimo per `timeout`.
+    // Ritorna false se allo scadere ne restano di aperte.
+    pub async fn wait_closed(&self, timeout: Duration) -> bool {
+        let wait = async {
+            loop {
+                let closed = self.closed.notified();
+                tokio::pin!(closed);
+                closed.as_mut().enable();
+                if self.active_connections() == 0 {
+                    return;
+                }
+                closed.await;
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+}
+
+// Segnali del sistema operativo che avviano l'arresto (Ctrl+C, e SIGTERM su Unix)
+pub async fn os_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// Avvisa tutti gli utenti connessi con ServerShutdown e chiede alle connessioni di chiudersi.
+// L'avviso viene accodato prima del segnale, quindi precede la chiusura del WebSocket.
+pub async fn begin(state: &AppState, reconnect_after: Option<Duration>) {
+    let notice = ServerShutdown {
+        message: "Il server si sta arrestando.".to_string(),
+        reconnect_after_secs: reconnect_after.map(|d| d.as_secs()),
+    };
+    let frame = Frame::new(ServerMessage::ServerShutdown(notice));
+
+    state.users.broadcast(frame).await;
+    state.shutdown.trigger();
+}
+
+// Conclude l'arresto: attende le connessioni ancora aperte (al massimo `timeout`),
+// poi salva su disco cronologia, chat, segnalibri dei non letti e tempo di CPU.
+// Account e chiave di sessione sono già scritti al momento della modifica.
+// Restano solo in memoria, e si perdono al riavvio: inviti in attesa, code offline,
+// conferme di consegna/lettura, presenze e digitazione.
+pub async fn finish(state: &AppState, timeout: Duration, cpu_log_path: &Path) {
+    if !state.shutdown.wait_closed(timeout).await {
+        eprintln!(
+            "Arresto: {} connessioni ancora aperte dopo {:?}",
+            state.shutdown.active_connections(),
+            timeout
+        );
+    }
+    if let Err(e) = state.message_store.sync() {
+        eprintln!("Errore salvataggio cronologia: {}", e);
+    }
+    if let Err(e) = state.rooms.sync() {
+        eprintln!("Errore salvataggio chat: {}", e);
+    }
+    if let Err(e) = state.read_markers.sync() {
+        eprintln!("Errore salvataggio segnalibri: {}", e);
+    }
+    if let Err(e) = cpu_log::write_log(&state.total_cpu_time, cpu_log_path).await {
+        eprintln!("Errore scrittura log CPU: {}", e);
+    }
+}
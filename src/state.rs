@@ -1,34 +1,113 @@
-use crate::types::{ChatUsersCount, User};
-use std::collections::{HashMap, HashSet};
+use crate::accounts::AccountStore;
+use crate::config::Config;
+use crate::invites::{InviteRegistry, DEFAULT_INVITE_TTL};
+use crate::offline::OfflineQueues;
+use crate::outbound::DEFAULT_OUTBOUND_QUEUE_LIMIT;
+use crate::presence::UserRegistry;
+use crate::receipts::ReceiptRegistry;
+use crate::rooms::RoomRegistry;
+use crate::server::ServerHooks;
+use crate::session::SessionSigner;
+use crate::shutdown::ShutdownSignal;
+use crate::storage::{
+    FileMessageStore, FileReadMarkerStore, FileRoomStore, InMemoryMessageStore, InMemoryReadMarkerStore,
+    MessageStore,
+};
+use crate::typing::{TypingTracker, DEFAULT_TYPING_TIMEOUT};
+use crate::unread::ReadMarkers;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-// Stato globale per memorizzare utenti connessi e loro WebSocket
-#[derive(Debug)]
-pub struct ConnectedUser {
-    pub user: User,
-    pub sender: tokio::sync::mpsc::UnboundedSender<String>, //canale websocket dell'utente
-    pub session_id: String,                                 // Identificatore univoco della sessione
-}
+// Durata predefinita del periodo di grazia dopo una disconnessione
+pub const DEFAULT_SESSION_GRACE: Duration = Duration::from_secs(30);
+// Validità predefinita dei token di sessione
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(12 * 60 * 60);
 
 //struttura di condivisione dello stato tra tutti i thread, connessioni websocket e operazioni http
 #[derive(Clone)]
 pub struct AppState {
-    pub connected_users: Arc<Mutex<HashMap<String, ConnectedUser>>>,
+    pub users: UserRegistry, // utenti connessi (presenze e sessioni), posseduti da un unico task
     pub total_cpu_time: Arc<Mutex<Duration>>,
-    pub chat_tracking: Arc<Mutex<HashMap<String, ChatUsersCount>>>, // traccia utenti attivi in chat
-    pub private_chats_with_both_users: Arc<Mutex<HashSet<String>>>, // Set di chat_id private dove entrambi gli utenti sono stati presenti almeno una volta. Utilizzato per evitare notifiche di abbandono premature in chat incomplete.
+    pub rooms: RoomRegistry, // chat create dal server: invitati, membri, presenze e ciclo di vita
+    pub message_store: Arc<dyn MessageStore>, // cronologia dei messaggi per chat_id
+    pub receipts: ReceiptRegistry, // consegne e letture dei messaggi per destinatario
+    pub offline: OfflineQueues,    // messaggi in attesa per gli account disconnessi
+    pub read_markers: ReadMarkers, // ultimo messaggio visto per utente e chat
+    pub accounts: Arc<AccountStore>,          // account registrati (username + hash password)
+    pub sessions: Arc<SessionSigner>,         // firma e verifica dei token di sessione
+    pub session_grace: Duration, // tempo entro cui una sessione disconnessa può essere ripresa
+    pub typing: TypingTracker,   // utenti che stanno scrivendo, per chat
+    pub typing_timeout: Duration, // scadenza di un indicatore di digitazione non rinnovato
+    pub invites: InviteRegistry,  // inviti inviati, con lo stato di ciascun destinatario
+    pub invite_ttl: Duration,     // validità di un invito senza risposta
+    pub outbound_queue_limit: usize, // messaggi in attesa di invio per connessione
+    pub metrics_token: Option<String>, // token richiesto da GET /api/metrics/queues (None: rotta disattivata)
+    pub hooks: ServerHooks,       // callback di chi incorpora il server (login, logout, messaggi)
+    pub shutdown: ShutdownSignal, // arresto richiesto e connessioni WebSocket ancora aperte
 }
 
+// Il registro degli utenti e le chat sono task tokio: lo stato va creato all'interno del runtime
 impl AppState {
     pub fn new(total_cpu_time: Arc<Mutex<Duration>>) -> Self {
-        let connected_users = Arc::new(Mutex::new(HashMap::new()));
+        Self::with_stores(
+            total_cpu_time,
+            Arc::new(InMemoryMessageStore::new()),
+            Arc::new(AccountStore::new()),
+        )
+    }
+
+    // Stato del server avviato con `config`: archivi su disco e limiti configurati
+    pub fn from_config(
+        total_cpu_time: Arc<Mutex<Duration>>,
+        config: &Config,
+    ) -> std::io::Result<Self> {
+        let message_store = FileMessageStore::open(&config.messages_path)?;
+        let accounts = AccountStore::open(&config.accounts_path)?;
+
+        let mut state = Self::with_stores(total_cpu_time, Arc::new(message_store), Arc::new(accounts));
+        let room_store = FileRoomStore::open(&config.rooms_path)?;
+        state.rooms = RoomRegistry::with_store(Arc::new(room_store));
+        let marker_store = FileReadMarkerStore::open(&config.read_markers_path)?;
+        state.read_markers = ReadMarkers::with_stores(state.message_store.clone(), Arc::new(marker_store));
+        let sessions = SessionSigner::open(&config.session_key_path, config.session_ttl())?;
+        state.sessions = Arc::new(sessions);
+        state.session_grace = config.session_grace();
+        state.typing_timeout = config.typing_timeout();
+        state.invite_ttl = config.invite_ttl();
+        state.offline = OfflineQueues::with_limit(config.offline_queue_limit);
+        state.outbound_queue_limit = config.outbound_queue_limit;
+        state.metrics_token = config.metrics_token.clone();
+        Ok(state)
+    }
 
+    // Come `new`, ma con archivi scelti dal chiamante (es. su disco)
+    pub fn with_stores(
+        total_cpu_time: Arc<Mutex<Duration>>,
+        message_store: Arc<dyn MessageStore>,
+        accounts: Arc<AccountStore>,
+    ) -> Self {
+        // I segnalibri numerano la cronologia già salvata la prima volta che serve
+        let read_markers =
+            ReadMarkers::with_stores(message_store.clone(), Arc::new(InMemoryReadMarkerStore::new()));
         AppState {
-            connected_users: connected_users.clone(),
+            users: UserRegistry::new(),
             total_cpu_time: total_cpu_time.clone(),
-            chat_tracking: Arc::new(Mutex::new(HashMap::new())),
-            private_chats_with_both_users: Arc::new(Mutex::new(HashSet::new())),
+            rooms: RoomRegistry::new(),
+            message_store,
+            receipts: ReceiptRegistry::new(),
+            offline: OfflineQueues::new(),
+            read_markers,
+            accounts,
+            sessions: Arc::new(SessionSigner::random(DEFAULT_SESSION_TTL)), // senza file: non sopravvive al riavvio
+            session_grace: DEFAULT_SESSION_GRACE,
+            typing: TypingTracker::new(),
+            typing_timeout: DEFAULT_TYPING_TIMEOUT,
+            invites: InviteRegistry::new(),
+            invite_ttl: DEFAULT_INVITE_TTL,
+            outbound_queue_limit: DEFAULT_OUTBOUND_QUEUE_LIMIT,
+            metrics_token: None,
+            hooks: ServerHooks::new(),
+            shutdown: ShutdownSignal::new(),
         }
     }
 }
@@ -0,0 +1,449 @@
+use crate::types::{ChatMessage, Room};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+// Archivio della cronologia dei messaggi, indicizzato per chat_id.
+// Ogni implementazione deve restituire i messaggi nell'ordine in cui sono stati registrati.
+pub trait MessageStore: Send + Sync {
+    // Registra un messaggio nella cronologia della chat indicata
+    fn append(&self, chat_id: &str, message: &ChatMessage);
+
+    // Restituisce tutti i messaggi della chat, dal più vecchio al più recente
+    fn messages(&self, chat_id: &str) -> Vec<ChatMessage>;
+
+    // Restituisce al massimo `limit` messaggi precedenti a `before` (o gli ultimi, se assente),
+    // sempre dal più vecchio al più recente, e indica se esistono messaggi ancora più vecchi.
+    // None se `before` non è un messaggio della chat.
+    fn page(&self, chat_id: &str, before: Option<Uuid>, limit: usize) -> Option<(Vec<ChatMessage>, bool)> {
+        slice_page(&self.messages(chat_id), before, limit)
+    }
+
+    // Porta su disco i messaggi già registrati (chiamata all'arresto del server)
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Estrae una pagina da una cronologia ordinata. Un `before` sconosciuto non produce alcuna pagina.
+fn slice_page(
+    messages: &[ChatMessage],
+    before: Option<Uuid>,
+    limit: usize,
+) -> Option<(Vec<ChatMessage>, bool)> {
+    let end = match before {
+        Some(before_id) => messages.iter().position(|m| m.id == before_id)?,
+        None => messages.len(),
+    };
+    let start = end.saturating_sub(limit);
+    Some((messages[start..end].to_vec(), start > 0))
+}
+
+// Archivio volatile: la cronologia vive solo finché il server è attivo
+#[derive(Default)]
+pub struct InMemoryMessageStore {
+    chats: Mutex<HashMap<String, Vec<ChatMessage>>>,
+}
+
+impl InMemoryMessageStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MessageStore for InMemoryMessageStore {
+    fn append(&self, chat_id: &str, message: &ChatMessage) {
+        let mut chats = self.chats.lock().unwrap();
+        chats
+            .entry(chat_id.to_string())
+            .or_default()
+            .push(message.clone());
+    }
+
+    fn messages(&self, chat_id: &str) -> Vec<ChatMessage> {
+        let chats = self.chats.lock().unwrap();
+        chats.get(chat_id).cloned().unwrap_or_default()
+    }
+
+    fn page(&self, chat_id: &str, before: Option<Uuid>, limit: usize) -> Option<(Vec<ChatMessage>, bool)> {
+        // Evita di clonare l'intera cronologia per restituirne solo una pagina
+        let chats = self.chats.lock().unwrap();
+        let messages = chats.get(chat_id).map_or(&[][..], Vec::as_slice);
+        slice_page(messages, before, limit)
+    }
+}
+
+// Riga del file di cronologia: il chat_id è quello risolto dal server,
+// non quello (opzionale) contenuto nel messaggio
+#[derive(Serialize, Deserialize)]
+struct StoredMessage {
+    chat_id: String,
+    message: ChatMessage,
+}
+
+// Archivio su disco in formato append-only (una riga JSON per messaggio).
+// All'apertura il file viene riletto per ricostruire la cronologia in memoria,
+// così i messaggi sopravvivono al riavvio del server.
+pub struct FileMessageStore {
+    file: Mutex<File>,
+    cache: InMemoryMessageStore,
+}
+
+impl FileMessageStore {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        // Crea la directory se non esiste
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+
+        let cache = InMemoryMessageStore::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (line_number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // Una riga corrotta (es. scrittura interrotta) non deve impedire l'avvio
+                match serde_json::from_str::<StoredMessage>(&line) {
+                    Ok(stored) => cache.append(&stored.chat_id, &stored.message),
+                    Err(e) => eprintln!(
+                        "Riga {} di {} ignorata: {}",
+                        line_number + 1,
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(FileMessageStore {
+            file: Mutex::new(file),
+            cache,
+        })
+    }
+}
+
+impl MessageStore for FileMessageStore {
+    fn append(&self, chat_id: &str, message: &ChatMessage) {
+        let stored = StoredMessage {
+            chat_id: chat_id.to_string(),
+            message: message.clone(),
+        };
+        match serde_json::to_string(&stored) {
+            Ok(mut line) => {
+                line.push('\n');
+                let mut file = self.file.lock().unwrap();
+                if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+                    eprintln!("Errore scrittura cronologia: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Errore serializzazione messaggio: {}", e),
+        }
+        self.cache.append(chat_id, message);
+    }
+
+    fn messages(&self, chat_id: &str) -> Vec<ChatMessage> {
+        self.cache.messages(chat_id)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.flush()?;
+        file.sync_all()
+    }
+
+    fn page(&self, chat_id: &str, before: Option<Uuid>, limit: usize) -> Option<(Vec<ChatMessage>, bool)> {
+        self.cache.page(chat_id, before, limit)
+    }
+}
+
+// Archivio delle chat (invitati, membri, ruoli e stato), per ritrovarle dopo un riavvio
+// insieme alla loro cronologia. Le presenze in chat non vengono conservate.
+pub trait RoomStore: Send + Sync {
+    // Registra lo stato attuale della chat, al posto di quello salvato in precedenza
+    fn save(&self, room: &Room);
+
+    // Ultimo stato salvato di ogni chat
+    fn rooms(&self) -> Vec<Room>;
+
+    // Porta su disco le chat già registrate (chiamata all'arresto del server)
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Archivio volatile: le chat vivono solo finché il server è attivo
+#[derive(Default)]
+pub struct InMemoryRoomStore {
+    rooms: Mutex<HashMap<String, Room>>,
+}
+
+impl InMemoryRoomStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl RoomStore for InMemoryRoomStore {
+    fn save(&self, room: &Room) {
+        let mut room = room.clone();
+        room.users_in_chat.clear();
+        self.rooms.lock().unwrap().insert(room.id.clone(), room);
+    }
+
+    fn rooms(&self) -> Vec<Room> {
+        self.rooms.lock().unwrap().values().cloned().collect()
+    }
+}
+
+// Archivio su disco in formato append-only (una riga JSON per ogni modifica di una chat).
+// All'apertura vale l'ultima riga di ogni chat e il file viene riscritto con solo quelle.
+pub struct FileRoomStore {
+    file: Mutex<File>,
+    cache: InMemoryRoomStore,
+}
+
+impl FileRoomStore {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        // Crea la directory se non esiste
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+
+        let cache = InMemoryRoomStore::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (line_number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // Una riga corrotta (es. scrittura interrotta) non deve impedire l'avvio
+                match serde_json::from_str::<Room>(&line) {
+                    Ok(room) => cache.save(&room),
+                    Err(e) => eprintln!(
+                        "Riga {} di {} ignorata: {}",
+                        line_number + 1,
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+
+            // Compattazione: una sola riga per chat
+            let compacted = path.with_extension("tmp");
+            let mut content = String::new();
+            for room in cache.rooms() {
+                content.push_str(&serde_json::to_string(&room).map_err(io::Error::from)?);
+                content.push('\n');
+            }
+            std::fs::write(&compacted, content)?;
+            std::fs::rename(&compacted, path)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(FileRoomStore {
+            file: Mutex::new(file),
+            cache,
+        })
+    }
+}
+
+impl RoomStore for FileRoomStore {
+    fn save(&self, room: &Room) {
+        self.cache.save(room);
+        let mut stored = room.clone();
+        stored.users_in_chat.clear();
+        match serde_json::to_string(&stored) {
+            Ok(mut line) => {
+                line.push('\n');
+                let mut file = self.file.lock().unwrap();
+                if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+                    eprintln!("Errore scrittura chat: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Errore serializzazione chat: {}", e),
+        }
+    }
+
+    fn rooms(&self) -> Vec<Room> {
+        self.cache.rooms()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.flush()?;
+        file.sync_all()
+    }
+}
+
+// Segnalibro di lettura salvato: ultimo messaggio visto da un utente in una chat
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredReadMarker {
+    pub username: String,
+    pub chat_id: String,
+    pub message_id: Uuid,
+}
+
+// Archivio dei segnalibri di lettura, per non far tornare non letti i messaggi già visti
+// dopo un riavvio
+pub trait ReadMarkerStore: Send + Sync {
+    // Registra il segnalibro, al posto di quello salvato in precedenza per lo stesso utente e chat
+    fn save(&self, marker: &StoredReadMarker);
+
+    // Ultimo segnalibro salvato di ogni utente nella chat indicata
+    fn markers(&self, chat_id: &str) -> Vec<StoredReadMarker>;
+
+    // Porta su disco i segnalibri già registrati (chiamata all'arresto del server)
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Archivio volatile: i segnalibri vivono solo finché il server è attivo
+#[derive(Default)]
+pub struct InMemoryReadMarkerStore {
+    markers: Mutex<HashMap<String, HashMap<String, Uuid>>>, // chat_id -> username -> message_id
+}
+
+impl InMemoryReadMarkerStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn all(&self) -> Vec<StoredReadMarker> {
+        let markers = self.markers.lock().unwrap();
+        markers
+            .keys()
+            .flat_map(|chat_id| Self::of_chat(&markers, chat_id))
+            .collect()
+    }
+
+    fn of_chat(markers: &HashMap<String, HashMap<String, Uuid>>, chat_id: &str) -> Vec<StoredReadMarker> {
+        markers
+            .get(chat_id)
+            .into_iter()
+            .flatten()
+            .map(|(username, message_id)| StoredReadMarker {
+                username: username.clone(),
+                chat_id: chat_id.to_string(),
+                message_id: *message_id,
+            })
+            .collect()
+    }
+}
+
+impl ReadMarkerStore for InMemoryReadMarkerStore {
+    fn save(&self, marker: &StoredReadMarker) {
+        self.markers
+            .lock()
+            .unwrap()
+            .entry(marker.chat_id.clone())
+            .or_default()
+            .insert(marker.username.clone(), marker.message_id);
+    }
+
+    fn markers(&self, chat_id: &str) -> Vec<StoredReadMarker> {
+        Self::of_chat(&self.markers.lock().unwrap(), chat_id)
+    }
+}
+
+// Archivio su disco in formato append-only (una riga JSON per ogni segnalibro spostato).
+// All'apertura vale l'ultima riga di ogni utente e chat e il file viene riscritto con solo quelle.
+pub struct FileReadMarkerStore {
+    file: Mutex<File>,
+    cache: InMemoryReadMarkerStore,
+}
+
+impl FileReadMarkerStore {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        // Crea la directory se non esiste
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+
+        let cache = InMemoryReadMarkerStore::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (line_number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // Una riga corrotta (es. scrittura interrotta) non deve impedire l'avvio
+                match serde_json::from_str::<StoredReadMarker>(&line) {
+                    Ok(marker) => cache.save(&marker),
+                    Err(e) => eprintln!(
+                        "Riga {} di {} ignorata: {}",
+                        line_number + 1,
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+
+            // Compattazione: una sola riga per utente e chat
+            let compacted = path.with_extension("tmp");
+            let mut content = String::new();
+            for marker in cache.all() {
+                content.push_str(&serde_json::to_string(&marker).map_err(io::Error::from)?);
+                content.push('\n');
+            }
+            std::fs::write(&compacted, content)?;
+            std::fs::rename(&compacted, path)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(FileReadMarkerStore {
+            file: Mutex::new(file),
+            cache,
+        })
+    }
+}
+
+impl ReadMarkerStore for FileReadMarkerStore {
+    fn save(&self, marker: &StoredReadMarker) {
+        self.cache.save(marker);
+        match serde_json::to_string(marker) {
+            Ok(mut line) => {
+                line.push('\n');
+                let mut file = self.file.lock().unwrap();
+                if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+                    eprintln!("Errore scrittura segnalibri: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Errore serializzazione segnalibro: {}", e),
+        }
+    }
+
+    fn markers(&self, chat_id: &str) -> Vec<StoredReadMarker> {
+        self.cache.markers(chat_id)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.flush()?;
+        file.sync_all()
+    }
+}
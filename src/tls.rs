@@ -0,0 +1,153 @@
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use rustls::ServerConfig;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+// Tempo concesso a un client per completare l'handshake TLS
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Certificato e chiave del server, riletti dai file PEM quando cambiano su disco.
+// Le connessioni già aperte mantengono il certificato con cui sono state accettate.
+#[derive(Clone)]
+pub struct TlsCertificates {
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl TlsCertificates {
+    pub fn load(cert_path: &Path, key_path: &Path) -> io::Result<Self> {
+        let config = load_server_config(cert_path, key_path)?;
+        Ok(TlsCertificates {
+            current: Arc::new(RwLock::new(config)),
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+        })
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Rilegge certificato e chiave. In caso di errore resta in uso la configurazione precedente.
+    pub fn reload(&self) -> io::Result<()> {
+        let config = load_server_config(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = config;
+        Ok(())
+    }
+
+    // Controlla i file ogni `interval` e li ricarica quando cambiano, finché `stop` non si completa
+    pub fn watch(&self, interval: Duration, stop: impl Future<Output = ()> + Send + 'static) {
+        let certificates = self.clone();
+        tokio::spawn(async move {
+            tokio::pin!(stop);
+            let mut last_seen = certificates.modified();
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = &mut stop => break,
+                }
+                let modified = certificates.modified();
+                if modified == last_seen {
+                    continue;
+                }
+                match certificates.reload() {
+                    Ok(()) => {
+                        println!("Certificati TLS ricaricati da {}", certificates.cert_path.display());
+                        last_seen = modified;
+                    }
+                    // Ad es. file scritto solo in parte: si riprova al prossimo controllo
+                    Err(e) => eprintln!("Errore ricaricamento certificati TLS: {}", e),
+                }
+            }
+        });
+    }
+
+    // Data di modifica dei due file (None se non leggibile)
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(&self.cert_path), modified(&self.key_path))
+    }
+}
+
+// Legge la catena di certificati e la chiave privata (PEM) e prepara la configurazione rustls
+fn load_server_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let invalid = |path: &Path, e: &dyn std::fmt::Display| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+    };
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid(cert_path, &"nessun certificato trovato"));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| invalid(key_path, &"nessuna chiave privata trovata"))?;
+
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| invalid(cert_path, &e))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| invalid(cert_path, &e))?;
+    // Il WebSocket richiede HTTP/1.1 (upgrade della connessione)
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+// Come `axum::serve`, ma su TLS: serve `app` su HTTPS e WSS finché `signal` non si completa,
+// poi smette di accettare connessioni e attende (al massimo `drain_timeout`) quelle HTTP in corso.
+pub async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    certificates: TlsCertificates,
+    signal: impl Future<Output = ()> + Send + 'static,
+    drain_timeout: Duration,
+) -> io::Result<()> {
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(signal);
+
+    loop {
+        let (tcp, _) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    eprintln!("Errore accettazione connessione: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+        };
+
+        let acceptor = TlsAcceptor::from(certificates.current());
+        let service = TowerToHyperService::new(app.clone());
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                Ok(Ok(stream)) => stream,
+                // Handshake fallito o troppo lento: la connessione viene chiusa
+                _ => return,
+            };
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            let _ = watcher.watch(connection.into_owned()).await;
+        });
+    }
+
+    drop(listener);
+    if tokio::time::timeout(drain_timeout, graceful.shutdown()).await.is_err() {
+        eprintln!("Arresto: connessioni HTTPS ancora aperte dopo {:?}", drain_timeout);
+    }
+    Ok(())
+}
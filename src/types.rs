@@ -11,6 +11,62 @@ pub struct User {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LoginRequest {
     pub username: String,
+    #[serde(default)]
+    pub password: String,
+}
+
+// Payload di MessageType::LoginSuccess
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LoginSuccess {
+    pub username: String,
+    pub session_id: String,
+    pub token: String, // token firmato da presentare con ResumeSession su una nuova connessione
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub resumed: bool, // true se è stata ripresa una sessione ancora attiva (stessa chat)
+    pub chat_id: Option<String>,
+}
+
+// Payload di MessageType::ResumeSession
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResumeRequest {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegisterRequest {
+    pub username: String,
+    pub password: String,
+}
+
+// Motivo del rifiuto di un login
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum LoginErrorReason {
+    InvalidCredentials, // username inesistente o password errata (indistinguibili)
+    AlreadyConnected,   // credenziali corrette ma l'utente è già connesso
+    InvalidSession,     // token di sessione non valido (firma o formato)
+    SessionExpired,     // token di sessione scaduto: serve un nuovo login
+}
+
+// Payload di MessageType::LoginError (e delle risposte di errore di /api/login)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LoginError {
+    pub reason: LoginErrorReason,
+    pub message: String,
+}
+
+impl LoginError {
+    pub fn new(reason: LoginErrorReason, username: &str) -> Self {
+        let message = match reason {
+            LoginErrorReason::InvalidCredentials => "Username o password non validi.".to_string(),
+            LoginErrorReason::AlreadyConnected => format!(
+                "Username '{}' è già in uso. Scegli un altro nome.",
+                username
+            ),
+            LoginErrorReason::InvalidSession => "Sessione non valida, effettua di nuovo il login.".to_string(),
+            LoginErrorReason::SessionExpired => "Sessione scaduta, effettua di nuovo il login.".to_string(),
+        };
+        LoginError { reason, message }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -23,6 +79,24 @@ pub struct ChatMessage {
     pub chat_type: ChatType,
 }
 
+// Payload di MessageType::SendChatMessage: il client indica solo chat e testo,
+// autore, id e timestamp vengono assegnati dal server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SendChatMessage {
+    #[serde(default)]
+    pub chat_id: Option<String>, // assente: la chat in cui si trova il mittente
+    pub content: String,
+    #[serde(default)]
+    pub client_nonce: Option<String>, // id provvisorio del client, restituito in ChatMessageSent
+}
+
+// Payload di MessageType::ChatMessageSent: conferma al mittente il messaggio registrato
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatMessageSent {
+    pub client_nonce: Option<String>,
+    pub message: ChatMessage,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ChatType {
     Private { target: String },
@@ -51,6 +125,49 @@ pub struct ChatInviteResponse {
     pub chat_type: ChatType,
 }
 
+// Stato di un invito per ciascun destinatario
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum InviteState {
+    Pending,   // in attesa di risposta
+    Accepted,  // accettato dal destinatario
+    Declined,  // rifiutato dal destinatario
+    Expired,   // nessuna risposta entro la scadenza
+    Cancelled, // ritirato dall'invitante prima della risposta
+}
+
+// Payload di MessageType::ChatInviteCancel inviato dall'invitante
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InviteCancel {
+    pub invite_id: String,
+}
+
+// Payload di MessageType::ChatInviteClosed: l'invito non può più essere accettato
+// (state è Expired o Cancelled). Inviato ai destinatari che non avevano risposto
+// e, alla scadenza, all'invitante
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatInviteClosed {
+    pub invite_id: String,
+    pub chat_id: String,
+    pub state: InviteState,
+}
+
+// Payload di MessageType::AddChatMembers: un membro di una chat di gruppo già creata
+// invita altre persone. `invite_id` identifica l'invito inviato ai nuovi invitati
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AddChatMembers {
+    pub invite_id: String,
+    pub chat_id: String,
+    pub members: Vec<String>,
+    pub message: String,
+}
+
+// Elemento di GET /api/invites: invito in attesa di risposta con la sua scadenza
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingInvite {
+    pub invite: ChatInvite,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ChatReady {
     pub chat_id: String,
@@ -76,6 +193,198 @@ pub struct ChatUsersCount {
     pub in_chat_count: usize,       // Numero effettivamente in chat
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum RoomKind {
+    Private,
+    Group,
+}
+
+// Ciclo di vita di una chat
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum RoomStatus {
+    Pending, // creata, nessun invitato ha ancora accettato
+    Active,  // almeno un invitato ha accettato
+    Closed,  // chiusa (abbandono definitivo o nessuno rimasto): non si può più entrare
+}
+
+// Chat registrata sul server: l'id è sempre generato dal server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Room {
+    pub id: String,
+    pub kind: RoomKind,
+    pub owner: String,              // chi ha creato la chat con il primo invito (o a cui è stata ceduta)
+    pub admins: Vec<String>,        // membri che possono invitare ed espellere (chat di gruppo)
+    pub banned: Vec<String>,        // utenti espulsi che non possono essere invitati di nuovo
+    pub invited_users: Vec<String>, // Tutti gli utenti invitati (incluso il proprietario)
+    pub members: Vec<String>,       // Invitati che hanno accettato (incluso il proprietario)
+    pub users_in_chat: Vec<String>, // Solo utenti effettivamente in chat
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub status: RoomStatus,
+    pub had_both_users: bool, // chat privata in cui entrambi sono stati presenti almeno una volta
+}
+
+// Ruolo di un membro in una chat di gruppo
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatRole {
+    Owner,  // tutte le operazioni, incluse nomina degli amministratori e cessione della chat
+    Admin,  // invita, espelle e banna i membri semplici
+    Member, // scrive nella chat
+}
+
+// Operazione di moderazione su una chat di gruppo
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModerationAction {
+    Kick,              // rimuove l'utente dalla chat (può essere invitato di nuovo)
+    Ban,               // rimuove l'utente e impedisce nuovi inviti
+    Unban,             // revoca il ban
+    Promote,           // membro -> amministratore
+    Demote,            // amministratore -> membro
+    TransferOwnership, // cede la chat: il vecchio proprietario resta amministratore
+}
+
+// Payload di MessageType::ChatModeration inviato dal client
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ModerationRequest {
+    pub chat_id: String,
+    pub action: ModerationAction,
+    pub target: String,
+}
+
+// Payload di MessageType::ChatModeration inviato dal server agli invitati della chat
+// e all'utente coinvolto, con i ruoli aggiornati
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ModerationNotice {
+    pub chat_id: String,
+    pub action: ModerationAction,
+    pub target: String,
+    pub by: String,
+    pub owner: String,
+    pub admins: Vec<String>,
+    pub banned: Vec<String>,
+}
+
+// Payload di MessageType::ChatCreated: la chat creata dal server per l'invito `invite_id`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatCreated {
+    pub invite_id: String,
+    pub room: Room,
+}
+
+// Payload di MessageType::Ack: conferma una richiesta del client che non ha un'altra risposta
+// (es. UserStatusChanged, Read); inviato solo se la richiesta indicava un request_id
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RequestAck {
+    pub message_type: MessageType, // tipo della richiesta confermata
+}
+
+// Motivo del rifiuto di un frame del client
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ChatErrorReason {
+    UnknownChat,          // chat inesistente o non indicata
+    NotMember,            // l'utente non ha accettato l'invito (o non è mai stato invitato)
+    NotInvited,           // risposta a un invito che l'utente non ha ricevuto
+    ChatClosed,           // chat chiusa: non accetta più messaggi né ingressi
+    InvalidInvite,        // invito senza destinatari validi
+    UnknownMessage,       // conferma di un messaggio inesistente o non destinato all'utente
+    Unauthenticated,      // richiesta inviata prima del login
+    AlreadyAuthenticated, // Login o ResumeSession su una connessione che ha già effettuato il login
+    Malformed,            // frame non JSON o payload che non corrisponde al tipo dichiarato
+    UnknownType,          // message_type che il client non può inviare
+    RateLimited,          // troppe richieste dello stesso tipo in poco tempo
+    UnknownInvite,        // invito inesistente o di un altro utente (ChatInviteCancel)
+    InviteAnswered,       // l'invito ha già ricevuto una risposta
+    InviteExpired,        // l'invito è scaduto
+    InviteCancelled,      // l'invito è stato ritirato dall'invitante
+    DuplicateInvite,      // esiste già un invito con lo stesso id
+    NotGroupChat,         // nuovi membri o moderazione in una chat che non è di gruppo
+    NotAllowed,           // operazione riservata al proprietario o agli amministratori
+    Banned,               // utente bandito dalla chat
+    InvalidTarget,        // utente a cui l'operazione di moderazione non si applica
+    UnknownUser,          // invito rivolto a uno username non registrato
+    InvalidCursor,        // HistoryRequest con un `before` che non è un messaggio della chat
+}
+
+impl ChatErrorReason {
+    // Codice stabile inviato ai client in ChatError::code
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChatErrorReason::UnknownChat => "unknown_chat",
+            ChatErrorReason::NotMember => "not_member",
+            ChatErrorReason::NotInvited => "not_invited",
+            ChatErrorReason::ChatClosed => "chat_closed",
+            ChatErrorReason::InvalidInvite => "invalid_invite",
+            ChatErrorReason::UnknownMessage => "unknown_message",
+            ChatErrorReason::Unauthenticated => "unauthenticated",
+            ChatErrorReason::AlreadyAuthenticated => "already_authenticated",
+            ChatErrorReason::Malformed => "malformed",
+            ChatErrorReason::UnknownType => "unknown_type",
+            ChatErrorReason::RateLimited => "rate_limited",
+            ChatErrorReason::UnknownInvite => "unknown_invite",
+            ChatErrorReason::InviteAnswered => "invite_answered",
+            ChatErrorReason::InviteExpired => "invite_expired",
+            ChatErrorReason::InviteCancelled => "invite_cancelled",
+            ChatErrorReason::DuplicateInvite => "duplicate_invite",
+            ChatErrorReason::NotGroupChat => "not_group_chat",
+            ChatErrorReason::NotAllowed => "not_allowed",
+            ChatErrorReason::Banned => "banned",
+            ChatErrorReason::InvalidTarget => "invalid_target",
+            ChatErrorReason::UnknownUser => "unknown_user",
+            ChatErrorReason::InvalidCursor => "invalid_cursor",
+        }
+    }
+}
+
+// Payload di MessageType::Error, inviato per ogni frame del client rifiutato.
+// L'eventuale request_id del frame rifiutato viene ripetuto nella busta della risposta
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatError {
+    pub reason: ChatErrorReason,
+    pub code: String, // ChatErrorReason::code, es. "not_member"
+    pub chat_id: Option<String>,
+    pub message: String,
+}
+
+impl ChatError {
+    pub fn new(reason: ChatErrorReason, chat_id: Option<&str>) -> Self {
+        let message = match reason {
+            ChatErrorReason::UnknownChat => "La chat indicata non esiste.".to_string(),
+            ChatErrorReason::NotMember => "Non fai parte di questa chat.".to_string(),
+            ChatErrorReason::NotInvited => "Non hai ricevuto un invito per questa chat.".to_string(),
+            ChatErrorReason::ChatClosed => "La chat è stata chiusa.".to_string(),
+            ChatErrorReason::InvalidInvite => "L'invito non contiene destinatari validi.".to_string(),
+            ChatErrorReason::UnknownMessage => "Il messaggio indicato non esiste.".to_string(),
+            ChatErrorReason::Unauthenticated => "Effettua il login prima di inviare richieste.".to_string(),
+            ChatErrorReason::AlreadyAuthenticated => "Hai già effettuato il login su questa connessione.".to_string(),
+            ChatErrorReason::Malformed => "Il messaggio non è valido.".to_string(),
+            ChatErrorReason::UnknownType => "Tipo di messaggio non supportato.".to_string(),
+            ChatErrorReason::RateLimited => "Troppe richieste: riprova tra poco.".to_string(),
+            ChatErrorReason::UnknownInvite => "L'invito indicato non esiste.".to_string(),
+            ChatErrorReason::InviteAnswered => "Hai già risposto a questo invito.".to_string(),
+            ChatErrorReason::InviteExpired => "L'invito è scaduto.".to_string(),
+            ChatErrorReason::InviteCancelled => "L'invito è stato ritirato.".to_string(),
+            ChatErrorReason::DuplicateInvite => "Esiste già un invito con questo id.".to_string(),
+            ChatErrorReason::NotGroupChat => {
+                "Operazione disponibile solo nelle chat di gruppo.".to_string()
+            }
+            ChatErrorReason::NotAllowed => "Non hai i permessi per questa operazione.".to_string(),
+            ChatErrorReason::Banned => "L'utente è stato bandito da questa chat.".to_string(),
+            ChatErrorReason::InvalidTarget => {
+                "L'operazione non si applica all'utente indicato.".to_string()
+            }
+            ChatErrorReason::UnknownUser => "Uno o più utenti invitati non sono registrati.".to_string(),
+            ChatErrorReason::InvalidCursor => {
+                "Il messaggio indicato non fa parte della cronologia della chat.".to_string()
+            }
+        };
+        ChatError {
+            code: reason.code().to_string(),
+            reason,
+            chat_id: chat_id.map(str::to_string),
+            message,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ChatAbandonedNotification {
     pub chat_id: String,
@@ -104,18 +413,155 @@ pub struct ChatInviteResponseNotify {
     pub responding_user: String,
 }
 
+// Payload di TypingStarted/TypingStopped inviati dal client
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TypingRequest {
+    pub chat_id: String,
+}
+
+// Payload di TypingStarted/TypingStopped inoltrati agli altri utenti della chat
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TypingIndicator {
+    pub chat_id: String,
+    pub username: String,
+}
+
+// Messaggi non letti in una chat di cui l'utente è membro
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChatUnread {
+    pub chat_id: String,
+    pub unread: usize,
+    pub last_read: Option<Uuid>, // ultimo messaggio visto, assente se l'utente non ne ha mai visti
+}
+
+// Payload di MessageType::UnreadCounts: tutte le chat dell'utente con i messaggi non letti
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UnreadCounts {
+    pub chats: Vec<ChatUnread>,
+}
+
+// Payload di MessageType::Delivered e MessageType::Read inviati dal destinatario di un messaggio
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReceiptAck {
+    pub message_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ReceiptStatus {
+    Sent,      // non ancora consegnato a tutti i destinatari
+    Delivered, // consegnato a tutti i destinatari
+    Read,      // letto da tutti i destinatari
+}
+
+// Conferme di un singolo destinatario
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecipientReceipt {
+    pub username: String,
+    pub delivered_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub read_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// Stato di consegna/lettura di un messaggio (payload di MessageType::ReceiptUpdate)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MessageReceipt {
+    pub message_id: Uuid,
+    pub chat_id: String,
+    pub sender: String,
+    pub recipients: Vec<RecipientReceipt>, // membri della chat al momento dell'invio, mittente escluso
+    pub status: ReceiptStatus,
+}
+
+// Richiesta di una pagina di cronologia: `before` è l'id del messaggio più vecchio già noto
+// al client (assente per ottenere gli ultimi messaggi)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryRequest {
+    pub chat_id: String,
+    #[serde(default)]
+    pub before: Option<Uuid>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+// Pagina di cronologia, ordinata dal messaggio più vecchio al più recente
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryPage {
+    pub chat_id: String,
+    pub messages: Vec<ChatMessage>,
+    pub has_more: bool, // esistono messaggi più vecchi di quelli restituiti
+    #[serde(default)]
+    pub receipts: Vec<MessageReceipt>, // stato di consegna/lettura dei messaggi della pagina
+}
+
+// Payload di MessageType::ServerShutdown, inviato a tutti gli utenti prima dell'arresto del server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerShutdown {
+    pub message: String,
+    pub reconnect_after_secs: Option<u64>, // quando conviene riprovare a connettersi, se noto
+}
+
+// Payload di MessageType::UserStatusChanged inviato dal client
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StatusChangeRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub available: Option<bool>,
+    #[serde(rename = "inChat", default, skip_serializing_if = "Option::is_none")]
+    pub in_chat: Option<bool>,
+    // assente: la chat non cambia; null: esce dalla chat attuale
+    #[serde(
+        rename = "chatId",
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub chat_id: Option<Option<String>>,
+}
+
+// Distingue un campo presente con valore null (Some(None)) da un campo assente (None)
+fn present<'de, D>(deserializer: D) -> Result<Option<Option<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer).map(Some)
+}
+
+// Busta non tipizzata di un frame con il protocollo 1 (`data` è il payload serializzato).
+// Il server usa ClientMessage e ServerMessage (protocol.rs)
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WebSocketMessage {
     pub message_type: MessageType,
+    #[serde(deserialize_with = "data_from_either_protocol")]
     pub data: String, // JSON serialized data
+    // Id di correlazione scelto dal client, ripetuto dal server nella risposta
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// `data` può arrivare come stringa (protocollo 1) o come valore JSON (protocollo 2):
+// in entrambi i casi i gestori ricevono il payload serializzato
+fn data_from_either_protocol<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(data) => data,
+        value => value.to_string(),
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum MessageType {
     Login,
+    ResumeSession, // ripresa di una sessione tramite token dopo una disconnessione
     LoginSuccess,
     LoginError,
     ChatMessage,
+    SendChatMessage, // invio di un messaggio (payload SendChatMessage)
+    ChatMessageSent, // conferma al mittente con id e timestamp assegnati dal server
+    Delivered,       // il destinatario ha ricevuto il messaggio (payload ReceiptAck)
+    Read,            // il destinatario ha letto il messaggio (payload ReceiptAck)
+    ReceiptUpdate,   // stato di consegna/lettura aggiornato, inviato al mittente
+    TypingStarted,   // un utente ha iniziato a scrivere in una chat
+    TypingStopped,   // un utente ha smesso di scrivere (o l'indicatore è scaduto)
     UserJoined,
     UserLeft,
     UserStatusChanged,
@@ -127,5 +573,15 @@ pub enum MessageType {
     ChatUsersCount,  //aggiornamenti conteggio utenti chat
     ChatAbandoned,   // notifica abbandono definitivo chat privata
     ChatInvalidated, // invalida ChatReady obsolete
-    Error,
+    ChatCreated,     // conferma all'invitante la chat creata dal server (con il suo chat_id)
+    HistoryRequest,  // richiesta di una pagina di cronologia
+    HistoryPage,     // pagina di cronologia in risposta a HistoryRequest
+    Error,           // operazione rifiutata (payload ChatError)
+    ServerShutdown,  // il server si sta arrestando: la connessione verrà chiusa
+    ChatInviteCancel, // l'invitante ritira un invito ancora in attesa (payload InviteCancel)
+    ChatInviteClosed, // invito non più accettabile perché ritirato o scaduto
+    AddChatMembers,   // proprietario o amministratore invita altre persone in una chat di gruppo esistente
+    ChatModeration,   // espulsione, ban e ruoli (client: ModerationRequest, server: ModerationNotice)
+    UnreadCounts,     // chat dell'utente con i messaggi non letti, inviato quando cambiano
+    Ack,             // richiesta con request_id eseguita, senza altra risposta (payload RequestAck)
 }
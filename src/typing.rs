@@ -0,0 +1,186 @@
+use crate::frame::Frame;
+use crate::performance::update_cpu_time;
+use crate::protocol::ServerMessage;
+use crate::rooms::Delivery;
+use crate::state::AppState;
+use crate::types::{ChatError, TypingIndicator, TypingRequest};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Dopo questo tempo senza un nuovo TypingStarted l'indicatore viene rimosso dal server
+pub const DEFAULT_TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+// Eventi di digitazione accettati per connessione in ogni finestra: gli altri vengono scartati
+pub const TYPING_EVENTS_PER_WINDOW: u32 = 5;
+pub const TYPING_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// Digitazione in corso di un utente in una chat
+struct TypingEntry {
+    deadline: Instant,
+    generation: Uuid, // distingue una nuova digitazione da una precedente già conclusa
+}
+
+// Utenti che stanno scrivendo, per chat. Ogni voce scade se non viene rinnovata.
+#[derive(Clone, Default)]
+pub struct TypingTracker {
+    typing: Arc<Mutex<HashMap<(String, String), TypingEntry>>>,
+}
+
+impl TypingTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Segna l'utente come "sta scrivendo" fino a `now + timeout`. Ritorna la generazione
+    // se la digitazione è appena iniziata, None se è solo un rinnovo.
+    pub fn start(&self, chat_id: &str, username: &str, timeout: Duration) -> Option<Uuid> {
+        let mut typing = self.typing.lock().unwrap();
+        let deadline = Instant::now() + timeout;
+        match typing.get_mut(&(chat_id.to_string(), username.to_string())) {
+            Some(entry) => {
+                entry.deadline = deadline;
+                None
+            }
+            None => {
+                let generation = Uuid::new_v4();
+                typing.insert(
+                    (chat_id.to_string(), username.to_string()),
+                    TypingEntry {
+                        deadline,
+                        generation,
+                    },
+                );
+                Some(generation)
+            }
+        }
+    }
+
+    // Conclude la digitazione. Ritorna true se l'utente stava scrivendo.
+    pub fn stop(&self, chat_id: &str, username: &str) -> bool {
+        let mut typing = self.typing.lock().unwrap();
+        typing
+            .remove(&(chat_id.to_string(), username.to_string()))
+            .is_some()
+    }
+
+    // Scadenza attuale della digitazione `generation`, se è ancora in corso
+    fn deadline(&self, chat_id: &str, username: &str, generation: Uuid) -> Option<Instant> {
+        let typing = self.typing.lock().unwrap();
+        typing
+            .get(&(chat_id.to_string(), username.to_string()))
+            .filter(|entry| entry.generation == generation)
+            .map(|entry| entry.deadline)
+    }
+
+    // Rimuove la digitazione `generation` se è scaduta
+    fn expire(&self, chat_id: &str, username: &str, generation: Uuid) -> bool {
+        let mut typing = self.typing.lock().unwrap();
+        let key = (chat_id.to_string(), username.to_string());
+        let expired = typing
+            .get(&key)
+            .is_some_and(|entry| entry.generation == generation && entry.deadline <= Instant::now());
+        if expired {
+            typing.remove(&key);
+        }
+        expired
+    }
+}
+
+// Limite di frequenza degli eventi di digitazione di una singola connessione (finestra fissa).
+pub struct TypingRateLimit {
+    window_start: Instant,
+    count: u32,
+}
+
+impl TypingRateLimit {
+    pub fn new() -> Self {
+        TypingRateLimit {
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    pub fn allow(&mut self) -> bool {
+        if self.window_start.elapsed() >= TYPING_RATE_WINDOW {
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count <= TYPING_EVENTS_PER_WINDOW
+    }
+}
+
+impl Default for TypingRateLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub async fn handle_typing_started(
+    state: &AppState,
+    username: &str,
+    request: &TypingRequest,
+) -> Result<(), ChatError> {
+    let start = Instant::now();
+    if let Err(reason) = state.rooms.authorize_member(&request.chat_id, username).await {
+        update_cpu_time(state.total_cpu_time.clone(), start);
+        return Err(ChatError::new(reason, Some(&request.chat_id)));
+    }
+    let started = state
+        .typing
+        .start(&request.chat_id, username, state.typing_timeout);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    // Solo l'inizio viene notificato: i rinnovi spostano in avanti la scadenza
+    if let Some(generation) = started {
+        broadcast_typing(state, &request.chat_id, username, ServerMessage::TypingStarted).await;
+
+        // Scadenza lato server: un client che cade mentre scrive non lascia l'indicatore attivo
+        let state_clone = state.clone();
+        let chat_id = request.chat_id.clone();
+        let username = username.to_string();
+        tokio::spawn(async move {
+            while let Some(deadline) = state_clone.typing.deadline(&chat_id, &username, generation) {
+                tokio::time::sleep_until(deadline.into()).await;
+                if state_clone.typing.expire(&chat_id, &username, generation) {
+                    broadcast_typing(&state_clone, &chat_id, &username, ServerMessage::TypingStopped)
+                        .await;
+                    break;
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+pub async fn handle_typing_stopped(state: &AppState, username: &str, request: &TypingRequest) {
+    let start = Instant::now();
+    let was_typing = state.typing.stop(&request.chat_id, username);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    if was_typing {
+        broadcast_typing(state, &request.chat_id, username, ServerMessage::TypingStopped).await;
+    }
+}
+
+// Invia l'indicatore agli utenti presenti nella chat, escluso chi sta scrivendo
+async fn broadcast_typing(
+    state: &AppState,
+    chat_id: &str,
+    username: &str,
+    message: fn(TypingIndicator) -> ServerMessage,
+) {
+    let start = Instant::now();
+    let frame = Frame::new(message(TypingIndicator {
+        chat_id: chat_id.to_string(),
+        username: username.to_string(),
+    }));
+    state
+        .rooms
+        .broadcast(chat_id, frame, Some(username), Delivery::Present);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+}
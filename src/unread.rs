@@ -0,0 +1,251 @@
+use crate::frame::Frame;
+use crate::outbound::OutboundSender;
+use crate::performance::update_cpu_time;
+use crate::protocol::ServerMessage;
+use crate::state::AppState;
+use crate::storage::{MessageStore, ReadMarkerStore, StoredReadMarker};
+use crate::types::{ChatMessage, ChatType, ChatUnread, Room, UnreadCounts};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use uuid::Uuid;
+
+// Segnalibro di un utente in una chat: numero di sequenza e id dell'ultimo messaggio visto
+#[derive(Clone, Copy)]
+struct Marker {
+    seq: u64,
+    message_id: Uuid,
+}
+
+// Numera a partire da 1 i messaggi degli utenti (non di "Sistema") di una chat
+#[derive(Default)]
+struct ChatSequence {
+    head: u64,                     // sequenza dell'ultimo messaggio
+    last: Option<Uuid>,            // id dell'ultimo messaggio
+    positions: HashMap<Uuid, u64>, // message_id -> sequenza, per le conferme Read
+}
+
+impl ChatSequence {
+    // Ritorna la sequenza del messaggio, numerandolo se è nuovo
+    fn push(&mut self, message_id: Uuid) -> u64 {
+        if let Some(&seq) = self.positions.get(&message_id) {
+            return seq;
+        }
+        self.head += 1;
+        self.last = Some(message_id);
+        self.positions.insert(message_id, self.head);
+        self.head
+    }
+
+    fn marker(&self) -> Option<Marker> {
+        self.last.map(|message_id| Marker { seq: self.head, message_id })
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    chats: HashMap<String, ChatSequence>,             // chat_id -> sequenza dei messaggi
+    markers: HashMap<String, HashMap<String, Marker>>, // username -> chat_id -> segnalibro
+}
+
+impl Counters {
+    // Numera la cronologia già salvata della chat e ne riprende i segnalibri salvati.
+    // Chi ha scritto un messaggio ha comunque visto tutti quelli precedenti.
+    fn load(&mut self, chat_id: &str, messages: &dyn MessageStore, saved: &dyn ReadMarkerStore) {
+        let mut sequence = ChatSequence::default();
+        for message in messages.messages(chat_id) {
+            if matches!(message.chat_type, ChatType::System) {
+                continue;
+            }
+            let marker = Marker { seq: sequence.push(message.id), message_id: message.id };
+            self.set(&message.username, chat_id, marker);
+        }
+        for stored in saved.markers(chat_id) {
+            if let Some(&seq) = sequence.positions.get(&stored.message_id) {
+                let marker = Marker { seq, message_id: stored.message_id };
+                self.set(&stored.username, chat_id, marker);
+            }
+        }
+        self.chats.insert(chat_id.to_string(), sequence);
+    }
+
+    fn chat(&mut self, chat_id: &str) -> &mut ChatSequence {
+        self.chats.entry(chat_id.to_string()).or_default()
+    }
+
+    fn get(&self, username: &str, chat_id: &str) -> Option<Marker> {
+        self.markers.get(username).and_then(|chats| chats.get(chat_id)).copied()
+    }
+
+    // Sposta il segnalibro solo in avanti. Ritorna true se è cambiato.
+    fn set(&mut self, username: &str, chat_id: &str, marker: Marker) -> bool {
+        if self.get(username, chat_id).is_some_and(|current| current.seq >= marker.seq) {
+            return false;
+        }
+        self.markers
+            .entry(username.to_string())
+            .or_default()
+            .insert(chat_id.to_string(), marker);
+        true
+    }
+}
+
+// Ultimo messaggio visto da ogni utente in ogni chat di cui è membro.
+// I non letti sono la differenza tra la sequenza dell'ultimo messaggio della chat e quella
+// del segnalibro: il mittente ha sempre visto il proprio messaggio, quindi dopo il suo
+// segnalibro ci sono solo messaggi degli altri utenti.
+// I segnalibri spostati vengono salvati, così sopravvivono al riavvio.
+#[derive(Clone)]
+pub struct ReadMarkers {
+    counters: Arc<Mutex<Counters>>,
+    messages: Arc<dyn MessageStore>, // cronologia da numerare al primo uso di ogni chat
+    saved: Arc<dyn ReadMarkerStore>,
+}
+
+impl ReadMarkers {
+    pub fn with_stores(messages: Arc<dyn MessageStore>, saved: Arc<dyn ReadMarkerStore>) -> Self {
+        Self {
+            counters: Arc::new(Mutex::new(Counters::default())),
+            messages,
+            saved,
+        }
+    }
+
+    // Contatori pronti per la chat indicata, caricati dagli archivi al suo primo uso
+    fn lock(&self, chat_id: &str) -> MutexGuard<'_, Counters> {
+        let mut counters = self.counters.lock().unwrap();
+        if !counters.chats.contains_key(chat_id) {
+            counters.load(chat_id, self.messages.as_ref(), self.saved.as_ref());
+        }
+        counters
+    }
+
+    // Sposta il segnalibro e, se è avanzato, lo salva
+    fn set(&self, counters: &mut Counters, username: &str, chat_id: &str, marker: Marker) -> bool {
+        if !counters.set(username, chat_id, marker) {
+            return false;
+        }
+        self.saved.save(&StoredReadMarker {
+            username: username.to_string(),
+            chat_id: chat_id.to_string(),
+            message_id: marker.message_id,
+        });
+        true
+    }
+
+    // Non letti e ultimo messaggio visto da `username` nella chat
+    pub fn unread(&self, username: &str, chat_id: &str) -> (usize, Option<Uuid>) {
+        let mut counters = self.lock(chat_id);
+        let head = counters.chat(chat_id).head;
+        let marker = counters.get(username, chat_id);
+        let read = marker.map_or(0, |marker| marker.seq);
+        ((head - read) as usize, marker.map(|marker| marker.message_id))
+    }
+
+    // Numera un nuovo messaggio della chat: chi lo sta già guardando (`readers`) l'ha visto
+    pub fn record(&self, chat_id: &str, message_id: Uuid, readers: &[&String]) {
+        let mut counters = self.lock(chat_id);
+        let seq = counters.chat(chat_id).push(message_id);
+        for reader in readers {
+            self.set(&mut counters, reader, chat_id, Marker { seq, message_id });
+        }
+    }
+
+    // Segna come visti tutti i messaggi della chat. Ritorna true se c'erano non letti.
+    pub fn mark_all(&self, username: &str, chat_id: &str) -> bool {
+        let mut counters = self.lock(chat_id);
+        match counters.chat(chat_id).marker() {
+            Some(marker) => self.set(&mut counters, username, chat_id, marker),
+            None => false,
+        }
+    }
+
+    // Segna come visti i messaggi fino a `message_id`, mai all'indietro. Ritorna true se il segnalibro è avanzato.
+    pub fn mark_up_to(&self, username: &str, chat_id: &str, message_id: Uuid) -> bool {
+        let mut counters = self.lock(chat_id);
+        match counters.chat(chat_id).positions.get(&message_id) {
+            Some(&seq) => self.set(&mut counters, username, chat_id, Marker { seq, message_id }),
+            None => false,
+        }
+    }
+
+    // Porta su disco i segnalibri salvati (chiamata all'arresto del server)
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.saved.sync()
+    }
+}
+
+fn chat_unread(state: &AppState, username: &str, chat_id: &str) -> ChatUnread {
+    let (unread, last_read) = state.read_markers.unread(username, chat_id);
+    ChatUnread {
+        chat_id: chat_id.to_string(),
+        unread,
+        last_read,
+    }
+}
+
+// Riepilogo dei non letti in tutte le chat di cui l'utente è membro
+pub fn unread_counts(state: &AppState, username: &str) -> UnreadCounts {
+    let start = Instant::now();
+    let chats = state
+        .rooms
+        .chats_of(username)
+        .iter()
+        .map(|chat_id| chat_unread(state, username, chat_id))
+        .collect();
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    UnreadCounts { chats }
+}
+
+// Invia il riepilogo aggiornato all'utente, se è connesso
+fn push_unread_counts(state: &AppState, username: &str) {
+    let frame = Frame::new(ServerMessage::UnreadCounts(unread_counts(state, username)));
+    state.users.send_to(vec![username.to_string()], frame);
+}
+
+// Riepilogo inviato al login, dopo i messaggi arrivati mentre l'utente era disconnesso
+pub fn send_unread_counts(tx: &OutboundSender, state: &AppState, username: &str) {
+    let _ = tx.send(Frame::new(ServerMessage::UnreadCounts(unread_counts(
+        state, username,
+    ))));
+}
+
+// Messaggio di un utente appena consegnato alla chat: chi la sta guardando (e il mittente)
+// l'ha visto, gli altri membri ricevono i non letti aggiornati
+pub fn record_unread(state: &AppState, room: &Room, message: &ChatMessage) {
+    let start = Instant::now();
+    let (focused, unfocused): (Vec<&String>, Vec<&String>) = room
+        .members
+        .iter()
+        .partition(|member| **member == message.username || room.users_in_chat.contains(member));
+    state.read_markers.record(&room.id, message.id, &focused);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    for member in unfocused {
+        push_unread_counts(state, member);
+    }
+}
+
+// L'utente è entrato nella chat: tutti i suoi messaggi risultano visti
+pub fn mark_chat_read(state: &AppState, chat_id: &str, username: &str) {
+    let start = Instant::now();
+    let had_unread = state.read_markers.mark_all(username, chat_id);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    if had_unread {
+        push_unread_counts(state, username);
+    }
+}
+
+// Conferma di lettura (Read) di un messaggio: il segnalibro avanza fino a lì,
+// mai all'indietro
+pub fn mark_read_up_to(state: &AppState, chat_id: &str, username: &str, message_id: Uuid) {
+    let start = Instant::now();
+    let advanced = state.read_markers.mark_up_to(username, chat_id, message_id);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    if advanced {
+        push_unread_counts(state, username);
+    }
+}
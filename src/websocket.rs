@@ -1,420 +1,733 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, State,
     },
-    response::Response,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
 };
 use futures_util::{sink::SinkExt, stream::StreamExt};
+use serde::Deserialize;
 use std::time::Instant;
 
-use crate::chat::{broadcast_chat_message, broadcast_user_left};
-use crate::invites::{handle_invite_response, send_chat_invite};
+use crate::accounts::check_login;
+use crate::chat::{broadcast_user_left, send_chat_message};
+use crate::frame::Frame;
+use crate::history::load_history_page;
+use crate::invites::{
+    add_chat_members, cancel_chat_invite, handle_invite_response, send_chat_invite,
+};
+use crate::moderation::moderate_chat;
 use crate::notifications::invalidate_chat_ready_notifications;
+use crate::outbound::{self, OutboundSender};
 use crate::performance::update_cpu_time;
-use crate::state::{AppState, ConnectedUser};
+use crate::receipts::{handle_receipt, ReceiptKind};
+use crate::session::TokenError;
+use crate::presence::{AttachOutcome, AttachRequest, StatusUpdate, Welcome};
+use crate::protocol::{ClientFrame, ClientMessage, ProtocolVersion, ServerMessage, SUBPROTOCOLS};
+use crate::state::AppState;
 use crate::tracking::{
     add_user_to_chat_tracking, check_and_notify_alone_in_chat, remove_user_from_chat_tracking,
 };
 use crate::types::{
-    ChatInvite, ChatInviteResponse, ChatMessage, LoginRequest, MessageType, User, WebSocketMessage,
+    AddChatMembers, ChatError, ChatErrorReason, ChatInvite, ChatInviteResponse, ChatMessageSent,
+    HistoryRequest, InviteCancel, LoginError, LoginErrorReason, LoginRequest, LoginSuccess,
+    MessageType, ModerationRequest, ReceiptAck, RequestAck, ResumeRequest, SendChatMessage,
+    StatusChangeRequest, TypingRequest, User,
 };
+use crate::typing::{handle_typing_started, handle_typing_stopped, TypingRateLimit};
+use crate::unread::{mark_chat_read, send_unread_counts};
 use crate::user::{
     broadcast_user_joined, broadcast_user_status_changed, send_users_list, send_users_list_to_all,
 };
 
+// Attesa massima per l'invio del Close a un client espulso perché troppo lento
+const EVICTION_CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Parametri di /ws
+#[derive(Deserialize)]
+pub struct WsQuery {
+    pub protocol: Option<u8>, // versione del protocollo per i client senza sottoprotocolli
+}
+
 //WebSocket handler principale
-pub async fn websocket_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+pub async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(query): Query<WsQuery>,
+) -> Response {
+    let requested = match query.protocol.map(ProtocolVersion::from_number) {
+        Some(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json("Versione del protocollo non supportata"),
+            )
+                .into_response();
+        }
+        Some(version) => version,
+        None => None,
+    };
+    ws.protocols(SUBPROTOCOLS).on_upgrade(move |socket| {
+        // Versione: sottoprotocollo concordato nell'handshake, poi ?protocol=N, altrimenti 1
+        let version = socket
+            .protocol()
+            .and_then(|subprotocol| subprotocol.to_str().ok())
+            .and_then(ProtocolVersion::from_subprotocol)
+            .or(requested)
+            .unwrap_or_default();
+        handle_socket(socket, state, version)
+    })
 }
 
 //Gestisce una singola connessione WebSocket toclean
-pub async fn handle_socket(socket: WebSocket, state: AppState) {
+pub async fn handle_socket(socket: WebSocket, state: AppState, version: ProtocolVersion) {
     let (mut sender, mut receiver) = socket.split();
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    // Coda limitata: un client che non legge non può far crescere la memoria del server
+    let (tx, mut rx) = outbound::channel(state.outbound_queue_limit);
 
     let mut username: Option<String> = None;
     let state_clone = state.clone();
+    // Identifica questa connessione: una sessione ripresa altrove ne avrà un'altra
+    let connection_id = uuid::Uuid::new_v4();
+    // Limita gli eventi di digitazione di questa connessione
+    let mut typing_limit = TypingRateLimit::new();
+    // L'arresto del server attende la chiusura di questa connessione
+    let _connection = state.shutdown.connection();
+
+    // Client espulso perché troppo lento
+    let mut evicted = false;
 
     // Loop unico: gestisce sia invii che ricezioni senza spawn
-    let _ws_closed = false;
     loop {
         tokio::select! {
             maybe_out = rx.recv() => {
-                match maybe_out {
-                    Some(msg) => {
-                        if sender.send(Message::Text(msg)).await.is_err() {
+                // None: coda chiusa perché il client non smaltiva i messaggi
+                let Some(frame) = maybe_out else {
+                    evicted = true;
+                    break;
+                };
+                // Un client che non legge più blocca la scrittura: la si interrompe se nel
+                // frattempo la coda viene chiusa
+                tokio::select! {
+                    sent = sender.send(text_message(&frame, version)) => {
+                        if sent.is_err() {
                             break;
                         }
-                    },
-                    None => { /* canale chiuso */ }
+                    }
+                    _ = rx.closed() => {
+                        evicted = true;
+                        break;
+                    }
+                }
+            }
+            _ = state.shutdown.triggered() => {
+                // Arresto del server: consegna i messaggi già accodati (incluso ServerShutdown) e chiude
+                while let Some(frame) = rx.try_recv() {
+                    if sender.send(text_message(&frame, version)).await.is_err() {
+                        break;
+                    }
                 }
-            },
+                let _ = sender.send(Message::Close(None)).await;
+                break;
+            }
             incoming = receiver.next() => {
-                if let Some(msg) = incoming {
-            let start = Instant::now();
-            match msg {
-                Ok(Message::Text(text)) => {
-                    match serde_json::from_str::<WebSocketMessage>(&text) {
-                        Ok(ws_msg) => {
-                            match ws_msg.message_type {
-                                MessageType::Login => {
-                                    handle_login_message(&state_clone, &tx, &ws_msg, &mut username, start).await;
-                                }
-                                MessageType::ChatMessage => {
-                                    handle_chat_message(&state_clone, &username, &ws_msg, start).await;
-                                }
-                                MessageType::UserStatusChanged => {
-                                    handle_user_status_changed(&state_clone, &username, &ws_msg, start).await;
-                                }
-                                MessageType::ChatInvite => {
-                                    handle_chat_invite(&state_clone, &username, &ws_msg, start).await;
-                                }
-                                MessageType::ChatInviteResponse => {
-                                    handle_chat_invite_response(&state_clone, &username, &ws_msg, start).await;
+                let Some(msg) = incoming else {
+                    break;
+                };
+                let start = Instant::now();
+                match msg {
+                    Ok(Message::Text(text)) => {
+                        match ClientFrame::decode(&text) {
+                            // Prima del login si accettano solo Login e ResumeSession
+                            Ok(ClientFrame { request_id, message }) if username.is_none() && message.requires_login() => {
+                                //aggiorna il tempo di CPU//
+                                update_cpu_time(state.total_cpu_time.clone(), start);
+                                let reply = Reply { tx: &tx, request_id: request_id.as_deref() };
+                                reply.error(&ChatError::new(ChatErrorReason::Unauthenticated, None));
+                            }
+                            Ok(ClientFrame { request_id, message }) => {
+                                let reply = Reply { tx: &tx, request_id: request_id.as_deref() };
+                                match message {
+                                    ClientMessage::Login(login_req) => {
+                                        handle_login_message(&state_clone, &reply, connection_id, login_req, &mut username, start).await;
+                                    }
+                                    ClientMessage::ResumeSession(resume_req) => {
+                                        handle_resume_message(&state_clone, &reply, connection_id, resume_req, &mut username, start).await;
+                                    }
+                                    ClientMessage::SendChatMessage(request) => {
+                                        handle_chat_message(&state_clone, &reply, &username, request, true, start).await;
+                                    }
+                                    // Formato precedente: del ChatMessage completo si usano solo chat e testo
+                                    ClientMessage::ChatMessage(chat_msg) => {
+                                        let request = SendChatMessage {
+                                            chat_id: chat_msg.chat_id,
+                                            content: chat_msg.content,
+                                            client_nonce: None,
+                                        };
+                                        handle_chat_message(&state_clone, &reply, &username, request, false, start).await;
+                                    }
+                                    ClientMessage::UserStatusChanged(request) => {
+                                        handle_user_status_changed(&state_clone, &reply, &username, request, start).await;
+                                    }
+                                    ClientMessage::ChatInvite(invite) => {
+                                        handle_chat_invite(&state_clone, &reply, &username, invite, start).await;
+                                    }
+                                    ClientMessage::ChatInviteResponse(response) => {
+                                        handle_chat_invite_response(&state_clone, &reply, &username, response, start).await;
+                                    }
+                                    ClientMessage::ChatInviteCancel(cancel) => {
+                                        handle_chat_invite_cancel(&state_clone, &reply, &username, cancel, start).await;
+                                    }
+                                    ClientMessage::AddChatMembers(request) => {
+                                        handle_add_chat_members(&state_clone, &reply, &username, request, start).await;
+                                    }
+                                    ClientMessage::ChatModeration(request) => {
+                                        handle_chat_moderation(&state_clone, &reply, &username, request, start).await;
+                                    }
+                                    ClientMessage::Delivered(ack) => {
+                                        handle_receipt_message(&state_clone, &reply, &username, ack, ReceiptKind::Delivered, start).await;
+                                    }
+                                    ClientMessage::Read(ack) => {
+                                        handle_receipt_message(&state_clone, &reply, &username, ack, ReceiptKind::Read, start).await;
+                                    }
+                                    ClientMessage::TypingStarted(request) => {
+                                        handle_typing_message(&state_clone, &reply, &username, &mut typing_limit, request, true, start).await;
+                                    }
+                                    ClientMessage::TypingStopped(request) => {
+                                        handle_typing_message(&state_clone, &reply, &username, &mut typing_limit, request, false, start).await;
+                                    }
+                                    ClientMessage::HistoryRequest(request) => {
+                                        handle_history_request(&state_clone, &reply, &username, request, start).await;
+                                    }
                                 }
-                                _ => {}
                             }
-                        }
-                        Err(e) => {
-                            println!("Error parsing WebSocket message: {}", e);
-                            //aggiorna il tempo di CPU//
-                            update_cpu_time(state.total_cpu_time.clone(), start);
+                            // Il client riceve il motivo del rifiuto invece di un silenzio
+                            Err(rejected) => {
+                                println!("Error parsing WebSocket message: {}", rejected.error.message);
+                                //aggiorna il tempo di CPU//
+                                update_cpu_time(state.total_cpu_time.clone(), start);
+                                let reply = Reply { tx: &tx, request_id: rejected.request_id.as_deref() };
+                                reply.error(&rejected.error);
+                            }
                         }
                     }
-                }
-                Ok(Message::Close(_)) => {
-                    //aggiorna il tempo di CPU//
-                    update_cpu_time(state.total_cpu_time.clone(), start);
-                    break;
-                }
-                Err(e) => {
-                    println!("WebSocket error: {}", e);
-                    //aggiorna il tempo di CPU//
-                    update_cpu_time(state.total_cpu_time.clone(), start);
-                    break;
-                }
-                _ => {}
-            }
-                } else {
-                    let _ = _ws_closed;
-                    break;
+                    Ok(Message::Binary(_)) => {
+                        //aggiorna il tempo di CPU//
+                        update_cpu_time(state.total_cpu_time.clone(), start);
+                        let chat_error = ChatError {
+                            message: "Sono accettati solo frame testuali.".to_string(),
+                            ..ChatError::new(ChatErrorReason::Malformed, None)
+                        };
+                        Reply { tx: &tx, request_id: None }.error(&chat_error);
+                    }
+                    Ok(Message::Close(_)) => {
+                        //aggiorna il tempo di CPU//
+                        update_cpu_time(state.total_cpu_time.clone(), start);
+                        break;
+                    }
+                    Err(e) => {
+                        println!("WebSocket error: {}", e);
+                        //aggiorna il tempo di CPU//
+                        update_cpu_time(state.total_cpu_time.clone(), start);
+                        break;
+                    }
+                    _ => {}
                 }
             }
         }
     }
 
-    //misura l'inizio di uso di cpu//
-    let mut start = Instant::now();
-
-    // Cleanup: rimuovi utente quando si disconnette (LOGOUT AUTOMATICO)
-    let mut chat_id: Option<String> = None;
-    if let Some(disconnected_username) = username {
-        // Prima di rimuovere l'utente, assicurati che sia disponibile e fai il broadcast
-        let updated_user = {
-            //aggiorna il tempo di CPU//
-            update_cpu_time(state.total_cpu_time.clone(), start);
-            let mut users = state_clone.connected_users.lock().unwrap();
-            start = Instant::now();
-            if let Some(connected_user) = users.get_mut(&disconnected_username) {
-                // Imposta l'utente come disponibile prima di rimuoverlo
-                connected_user.user.is_available = true;
-                let user_to_broadcast = connected_user.user.clone();
-
-                if connected_user.user.chat_id.is_some() {
-                    chat_id = connected_user.user.chat_id.clone();
-                    connected_user.user.chat_id = None;
-                }
-
-                // Ora rimuovi l'utente
-                users.remove(&disconnected_username);
-                Some(user_to_broadcast)
-            } else {
-                // L'utente non esiste più, rimuovi solo per sicurezza
-                users.remove(&disconnected_username);
-                None
-            }
+    if evicted {
+        println!("Connessione chiusa: client troppo lento ({:?})", username);
+        let close = CloseFrame {
+            code: close_code::POLICY,
+            reason: "Client troppo lento".into(),
         };
-        //aggiorna il tempo di CPU//
-        update_cpu_time(state.total_cpu_time.clone(), start);
+        // Il client potrebbe non leggere affatto: non si attende oltre
+        let _ = tokio::time::timeout(
+            EVICTION_CLOSE_TIMEOUT,
+            sender.send(Message::Close(Some(close))),
+        )
+        .await;
+    }
 
-        // Fai il broadcast dell'aggiornamento di stato prima di notificare la disconnessione
-        if let Some(user_to_update) = updated_user {
-            broadcast_user_status_changed(&state_clone, &user_to_update).await;
+    // Alla disconnessione l'utente non viene rimosso subito: per `session_grace`
+    // la sessione può essere ripresa (ResumeSession o nuovo login) senza notificare l'uscita.
+    // Durante l'arresto del server non c'è nulla da riprendere
+    if state_clone.shutdown.is_triggered() {
+        return;
+    }
+    if let Some(disconnected_username) = username {
+        // Falso se la sessione è già stata ripresa da un'altra connessione
+        let still_attached = state_clone
+            .users
+            .detach(&disconnected_username, connection_id)
+            .await;
+
+        if still_attached {
+            let grace = state_clone.session_grace;
+            tokio::spawn(async move {
+                tokio::time::sleep(grace).await;
+                expire_session(&state_clone, &disconnected_username, connection_id).await;
+            });
         }
-
-        // Notifica tutti dell'uscita dell'utente
-        broadcast_user_left(&state_clone, &disconnected_username, chat_id).await;
     }
 }
 
+// Il frame è codificato una sola volta per versione e condiviso tra le connessioni;
+// axum richiede comunque una String propria per ogni invio
+fn text_message(frame: &Frame, version: ProtocolVersion) -> Message {
+    Message::Text(frame.encode(version).to_string())
+}
+
+// Conclude una sessione non ripresa entro il periodo di grazia (LOGOUT AUTOMATICO)
+async fn expire_session(state: &AppState, disconnected_username: &str, connection_id: uuid::Uuid) {
+    //misura l'inizio di uso di cpu//
+    let start = Instant::now();
+
+    // Sessione ripresa (o già rimossa): nulla da fare
+    let Some(mut updated_user) = state.users.expire(disconnected_username, connection_id).await
+    else {
+        return;
+    };
+    // Prima del broadcast l'utente risulta disponibile e fuori dalle chat
+    updated_user.is_available = true;
+    let chat_id = updated_user.chat_id.take();
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    // Fai il broadcast dell'aggiornamento di stato prima di notificare la disconnessione
+    broadcast_user_status_changed(state, &updated_user).await;
+
+    // Notifica tutti dell'uscita dell'utente
+    broadcast_user_left(state, disconnected_username, chat_id).await;
+    state.hooks.logout(disconnected_username);
+}
+
 async fn handle_login_message(
     state: &AppState,
-    tx: &tokio::sync::mpsc::UnboundedSender<String>,
-    ws_msg: &WebSocketMessage,
+    reply: &Reply<'_>,
+    connection_id: uuid::Uuid,
+    login_req: LoginRequest,
     username: &mut Option<String>,
-    mut start: Instant,
+    start: Instant,
 ) {
-    if let Ok(login_req) = serde_json::from_str::<LoginRequest>(&ws_msg.data) {
-        //CONTROLLO DUPLICATI - Verifica se l'username è già in uso
-        {
-            //aggiorna il tempo di CPU//
-            update_cpu_time(state.total_cpu_time.clone(), start);
-
-            let users = state.connected_users.lock().unwrap();
-            start = Instant::now();
-            if users.contains_key(&login_req.username) {
-                // Invia errore al client
-                let error_msg = WebSocketMessage {
-                    message_type: MessageType::LoginError,
-                    data: format!(
-                        "Username '{}' è già in uso. Scegli un altro nome.",
-                        login_req.username
-                    ),
-                };
-                if let Ok(error_json) = serde_json::to_string(&error_msg) {
-                    let _ = tx.send(error_json);
-                }
-                return;
-            }
-        }
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    // Il socket ha già effettuato il login
+    if username.is_some() {
+        let chat_error = ChatError::new(ChatErrorReason::AlreadyAuthenticated, None);
+        reply.error(&chat_error);
+        return;
+    }
 
-        // Username disponibile - procedi con il login
-        *username = Some(login_req.username.clone());
+    //CONTROLLO CREDENZIALI E DUPLICATI
+    if let Err(login_error) = check_login(state, &login_req).await {
+        reply.login_error(&login_error);
+        return;
+    }
 
-        // Genera session_id qui
-        let session_id = uuid::Uuid::new_v4().to_string();
+    // Credenziali valide - procedi con il login
+    attach_session(state, reply, connection_id, &login_req.username, None, username).await;
+}
 
-        let user = User {
-            username: login_req.username.clone(),
-            is_available: true,
-            chat_id: None,
-        };
+async fn handle_resume_message(
+    state: &AppState,
+    reply: &Reply<'_>,
+    connection_id: uuid::Uuid,
+    resume_req: ResumeRequest,
+    username: &mut Option<String>,
+    start: Instant,
+) {
+    if username.is_some() {
         //aggiorna il tempo di CPU//
         update_cpu_time(state.total_cpu_time.clone(), start);
-        // Registra l'utente
-        {
-            let mut users = state.connected_users.lock().unwrap();
-            start = Instant::now();
-            users.insert(
-                login_req.username.clone(),
-                ConnectedUser {
-                    user: user.clone(),
-                    sender: tx.clone(),
-                    session_id: session_id.clone(),
-                },
-            );
+        let chat_error = ChatError::new(ChatErrorReason::AlreadyAuthenticated, None);
+        reply.error(&chat_error);
+        return;
+    }
+    let verified = state.sessions.verify(&resume_req.token);
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    match verified {
+        Ok(claims) => {
+            attach_session(
+                state,
+                reply,
+                connection_id,
+                &claims.username,
+                Some(&claims.session_id),
+                username,
+            )
+            .await;
+        }
+        Err(e) => {
+            let reason = match e {
+                TokenError::Expired => LoginErrorReason::SessionExpired,
+                TokenError::Malformed | TokenError::BadSignature => {
+                    LoginErrorReason::InvalidSession
+                }
+            };
+            reply.login_error(&LoginError::new(reason, ""));
         }
+    }
+}
 
-        // Conferma login riuscito
-        let success_msg = WebSocketMessage {
-            message_type: MessageType::LoginSuccess,
-            data: format!(
-                "Username '{}' è disponibile; session_id: {}",
-                login_req.username, session_id
-            ),
+// Associa questa connessione all'utente `login_username`. Se esiste già una sessione
+// nel periodo di grazia (o quella indicata da `resume_session`) viene ripresa con la stessa
+// chat; altrimenti se ne crea una nuova.
+async fn attach_session(
+    state: &AppState,
+    reply: &Reply<'_>,
+    connection_id: uuid::Uuid,
+    login_username: &str,
+    resume_session: Option<&str>,
+    username: &mut Option<String>,
+) {
+    let start = Instant::now();
+    // Conferma login riuscito con il token per riprendere la sessione; il registro degli utenti
+    // consegna poi i messaggi arrivati mentre l'utente era disconnesso, prima di ogni altro
+    let sessions = state.sessions.clone();
+    let request_id = reply.request_id.map(str::to_string);
+    let welcome: Welcome = Box::new(move |user: &User, session_id: &str, resumed: bool| {
+        let (token, expires_at) = sessions.issue(&user.username, session_id);
+        let login_success = LoginSuccess {
+            username: user.username.clone(),
+            session_id: session_id.to_string(),
+            token,
+            expires_at,
+            resumed,
+            chat_id: user.chat_id.clone(),
         };
-        if let Ok(success_json) = serde_json::to_string(&success_msg) {
-            let _ = tx.send(success_json);
+        Frame::reply(ServerMessage::LoginSuccess(login_success), request_id.as_deref())
+    });
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+
+    let outcome = state
+        .users
+        .attach(AttachRequest {
+            username: login_username.to_string(),
+            connection_id,
+            resume_session: resume_session.map(str::to_string),
+            sender: reply.tx.clone(),
+            offline: state.offline.clone(),
+            welcome,
+        })
+        .await;
+
+    let Some(AttachOutcome { user, resumed, .. }) = outcome else {
+        reply.login_error(
+            &LoginError::new(LoginErrorReason::AlreadyConnected, login_username),
+        );
+        return;
+    };
+    *username = Some(user.username.clone());
+
+    // Notifica tutti dell'ingresso del nuovo utente (chi riprende la sessione non era mai uscito)
+    if !resumed {
+        broadcast_user_joined(state, &user).await;
+        state.hooks.login(&user.username);
+    }
+
+    // Invia lista utenti al nuovo utente
+    send_users_list(reply.tx, state).await;
+
+    // Riepilogo dei non letti, compresi i messaggi arrivati mentre era disconnesso
+    send_unread_counts(reply.tx, state, &user.username);
+}
+
+// Risposte a un frame del client: vanno alla sua connessione e ne ripetono il request_id
+struct Reply<'a> {
+    tx: &'a OutboundSender,
+    request_id: Option<&'a str>,
+}
+
+impl Reply<'_> {
+    fn send(&self, message: ServerMessage) {
+        let _ = self.tx.send(Frame::reply(message, self.request_id));
+    }
+
+    // Conferma una richiesta senza altra risposta, se il client può riconoscerla
+    fn ack(&self, message_type: MessageType) {
+        if self.request_id.is_some() {
+            self.send(ServerMessage::Ack(RequestAck { message_type }));
         }
-        //aggiorna il tempo di CPU//
-        update_cpu_time(state.total_cpu_time.clone(), start);
+    }
 
-        // Notifica tutti dell'ingresso del nuovo utente
-        broadcast_user_joined(state, &user).await;
+    fn login_error(&self, login_error: &LoginError) {
+        self.send(ServerMessage::LoginError(login_error.clone()));
+    }
 
-        // Invia lista utenti al nuovo utente
-        send_users_list(tx, state).await;
+    // Risponde al client con il motivo per cui la sua richiesta è stata rifiutata
+    fn error(&self, chat_error: &ChatError) {
+        self.send(ServerMessage::Error(chat_error.clone()));
     }
 }
 
 async fn handle_chat_message(
     state: &AppState,
+    reply: &Reply<'_>,
     username: &Option<String>,
-    ws_msg: &WebSocketMessage,
+    request: SendChatMessage,
+    confirm: bool, // solo chi usa SendChatMessage riceve ChatMessageSent
     start: Instant,
 ) {
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
     if let Some(ref current_username) = username {
-        if let Ok(chat_msg) = serde_json::from_str::<ChatMessage>(&ws_msg.data) {
-            //aggiorna il tempo di CPU//
-            update_cpu_time(state.total_cpu_time.clone(), start);
-            broadcast_chat_message(state, current_username, &chat_msg).await;
+        match send_chat_message(state, current_username, &request).await {
+            // La conferma permette al client di riconciliare il messaggio provvisorio
+            Ok(message) if confirm => {
+                let sent = ChatMessageSent {
+                    client_nonce: request.client_nonce,
+                    message,
+                };
+                reply.send(ServerMessage::ChatMessageSent(sent));
+            }
+            Ok(_) => reply.ack(MessageType::ChatMessage),
+            Err(chat_error) => reply.error(&chat_error),
         }
     }
 }
 
 async fn handle_user_status_changed(
     state: &AppState,
+    reply: &Reply<'_>,
     username: &Option<String>,
-    ws_msg: &WebSocketMessage,
-    mut start: Instant,
+    request: StatusChangeRequest,
+    start: Instant,
 ) {
     if let Some(ref current_username) = username {
-        //Gestione stati avanzata con JSON
-        if let Ok(status_data) = serde_json::from_str::<serde_json::Value>(&ws_msg.data) {
-            let updated_user = {
+        // Si può entrare solo in una chat di cui si è membri: altrimenti l'intero
+        // aggiornamento viene rifiutato
+        if let Some(Some(chat_id_str)) = &request.chat_id {
+            if let Err(reason) = state.rooms.authorize_member(chat_id_str, current_username).await {
                 //aggiorna il tempo di CPU//
                 update_cpu_time(state.total_cpu_time.clone(), start);
+                reply.error(&ChatError::new(reason, Some(chat_id_str)));
+                return;
+            }
+        }
 
-                let mut users = state.connected_users.lock().unwrap();
-                start = Instant::now();
-                if let Some(connected_user) = users.get_mut(current_username) {
-                    //Gestione stato disponibilità
-                    if let Some(available) = status_data.get("available").and_then(|v| v.as_bool())
-                    {
-                        connected_user.user.is_available = available;
-                    }
-
-                    if let Some(chat_id) = status_data.get("chatId") {
-                        if chat_id.is_null() {
-                            connected_user.user.chat_id = None;
-                        } else if let Some(chat_id_str) = chat_id.as_str() {
-                            connected_user.user.chat_id = Some(chat_id_str.to_string());
-                        }
-                    }
-
-                    // Gestione uscita da chat (torna disponibile)
-                    if let Some(in_chat) = status_data.get("inChat").and_then(|v| v.as_bool()) {
-                        if !in_chat {
-                            let old_chat_id = connected_user.user.chat_id.clone();
-                            connected_user.user.is_available = true;
-                            connected_user.user.chat_id = None;
-
-                            // Invalida ChatReady esistenti per questa chat
-                            if let Some(chat_id_str) = &old_chat_id {
-                                let chat_id_for_invalidation = chat_id_str.clone();
-                                let username_for_invalidation = current_username.clone();
-                                tokio::spawn({
-                                    let state_clone = state.clone();
-                                    async move {
-                                        let reason =
-                                            format!("User {} left chat", username_for_invalidation);
-                                        invalidate_chat_ready_notifications(
-                                            &state_clone,
-                                            &chat_id_for_invalidation,
-                                            &reason,
-                                        )
-                                        .await;
-                                    }
-                                });
-                            }
-
-                            // NUOVO: Rimuovi utente dal tracking della chat
-                            if let Some(chat_id_str) = &old_chat_id {
-                                let chat_id_for_tracking = chat_id_str.clone();
-                                let username_for_tracking = current_username.clone();
-                                tokio::spawn({
-                                    let state_clone = state.clone();
-                                    async move {
-                                        remove_user_from_chat_tracking(
-                                            &state_clone,
-                                            &chat_id_for_tracking,
-                                            &username_for_tracking,
-                                        )
-                                        .await;
-                                    }
-                                });
-                            }
+        let update = StatusUpdate {
+            available: request.available,
+            chat_id: request.chat_id,
+            in_chat: request.in_chat,
+        };
+        //aggiorna il tempo di CPU//
+        update_cpu_time(state.total_cpu_time.clone(), start);
 
-                            // CONTROLLO: Controlla solitudine quando qualcuno esce
-                            if let Some(chat_id_str) = old_chat_id {
-                                //aggiorna il tempo di CPU//
-                                update_cpu_time(state.total_cpu_time.clone(), start);
-                                // Chiamata asincrona alla fine del blocco
-                                tokio::spawn({
-                                    let state_clone = state.clone();
-                                    let chat_id_str = chat_id_str.clone();
-                                    async move {
-                                        check_and_notify_alone_in_chat(&state_clone, &chat_id_str)
-                                            .await;
-                                    }
-                                });
-                                start = Instant::now();
-                            }
-                        } else {
-                            connected_user.user.is_available = false;
-
-                            //NUOVO: Aggiungi utente al tracking della chat
-                            if let Some(chat_id_str) = &connected_user.user.chat_id {
-                                let chat_id_for_tracking = chat_id_str.clone();
-                                let username_for_tracking = current_username.clone();
-                                tokio::spawn({
-                                    let state_clone = state.clone();
-                                    async move {
-                                        add_user_to_chat_tracking(
-                                            &state_clone,
-                                            &chat_id_for_tracking,
-                                            &username_for_tracking,
-                                        )
-                                        .await;
-                                    }
-                                });
-                            }
+        let Some(change) = state.users.update_status(current_username, update).await else {
+            return; // Utente non trovato
+        };
 
-                            // CONTROLLO: Controlla solitudine quando qualcuno entra
-                            if let Some(chat_id_str) = &connected_user.user.chat_id {
-                                let chat_id_for_check = chat_id_str.clone();
-                                //aggiorna il tempo di CPU//
-                                update_cpu_time(state.total_cpu_time.clone(), start);
-                                // Chiamata asincrona alla fine del blocco
-                                tokio::spawn({
-                                    let state_clone = state.clone();
-                                    async move {
-                                        check_and_notify_alone_in_chat(
-                                            &state_clone,
-                                            &chat_id_for_check,
-                                        )
-                                        .await;
-                                    }
-                                });
-                                start = Instant::now();
-                            }
-                        }
-                    }
-                    //aggiorna il tempo di CPU//
-                    update_cpu_time(state.total_cpu_time.clone(), start);
-                    connected_user.user.clone()
-                } else {
-                    return; // Utente non trovato
+        if let Some(old_chat_id) = change.left_chat {
+            // Invalida ChatReady esistenti per questa chat
+            let chat_id_for_invalidation = old_chat_id.clone();
+            let username_for_invalidation = current_username.clone();
+            tokio::spawn({
+                let state_clone = state.clone();
+                async move {
+                    let reason = format!("User {} left chat", username_for_invalidation);
+                    invalidate_chat_ready_notifications(
+                        &state_clone,
+                        &chat_id_for_invalidation,
+                        &reason,
+                    )
+                    .await;
                 }
-            };
+            });
 
-            // Broadcast aggiornamento stato a tutti
-            broadcast_user_status_changed(state, &updated_user).await;
-            send_users_list_to_all(state).await;
+            // Rimuovi utente dal tracking della chat, poi controlla solitudine
+            remove_user_from_chat_tracking(state, &old_chat_id, current_username).await;
+            check_and_notify_alone_in_chat(state, &old_chat_id).await;
         }
-        // Aggiungi qui la gestione degli altri casi per status_data...
+
+        // Aggiungi utente al tracking della chat prima di rispondere ad altre richieste:
+        // da qui in poi riceve i messaggi della chat
+        if let Some(chat_id_str) = change.entered_chat {
+            add_user_to_chat_tracking(state, &chat_id_str, current_username, change.outbox)
+                .await;
+            mark_chat_read(state, &chat_id_str, current_username);
+            check_and_notify_alone_in_chat(state, &chat_id_str).await;
+        }
+        let updated_user = change.user;
+
+        // Broadcast aggiornamento stato a tutti
+        broadcast_user_status_changed(state, &updated_user).await;
+        send_users_list_to_all(state).await;
+        reply.ack(MessageType::UserStatusChanged);
     }
 }
 
 async fn handle_chat_invite(
     state: &AppState,
+    reply: &Reply<'_>,
     username: &Option<String>,
-    ws_msg: &WebSocketMessage,
+    invite: ChatInvite,
     start: Instant,
 ) {
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
     if let Some(ref current_username) = username {
-        if let Ok(invite) = serde_json::from_str::<ChatInvite>(&ws_msg.data) {
-            //aggiorna il tempo di CPU//
-            update_cpu_time(state.total_cpu_time.clone(), start);
-            // Invia l'invito ai destinatari
-            send_chat_invite(state, current_username, &invite).await;
+        // Invia l'invito ai destinatari; l'invitante riceve ChatCreated
+        if let Err(chat_error) =
+            send_chat_invite(state, current_username, &invite, reply.request_id).await
+        {
+            reply.error(&chat_error);
         }
     }
 }
 
 async fn handle_chat_invite_response(
     state: &AppState,
+    reply: &Reply<'_>,
+    username: &Option<String>,
+    response: ChatInviteResponse,
+    start: Instant,
+) {
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    if let Some(ref current_username) = username {
+        // Gestisce la risposta all'invito: chi accetta riceve la conferma, chi rifiuta un Ack
+        match handle_invite_response(state, current_username, &response, reply.request_id).await {
+            Ok(()) if !response.accepted => reply.ack(MessageType::ChatInviteResponse),
+            Ok(()) => {}
+            Err(chat_error) => reply.error(&chat_error),
+        }
+    }
+}
+
+async fn handle_chat_invite_cancel(
+    state: &AppState,
+    reply: &Reply<'_>,
     username: &Option<String>,
-    ws_msg: &WebSocketMessage,
+    cancel: InviteCancel,
     start: Instant,
 ) {
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
     if let Some(ref current_username) = username {
-        if let Ok(response) = serde_json::from_str::<ChatInviteResponse>(&ws_msg.data) {
-            //aggiorna il tempo di CPU//
-            update_cpu_time(state.total_cpu_time.clone(), start);
-            // Gestisce la risposta all'invito
-            handle_invite_response(state, current_username, &response).await;
+        // I destinatari in attesa ricevono ChatInviteClosed, l'invitante un Ack
+        match cancel_chat_invite(state, current_username, &cancel).await {
+            Ok(()) => reply.ack(MessageType::ChatInviteCancel),
+            Err(chat_error) => reply.error(&chat_error),
+        }
+    }
+}
+
+async fn handle_add_chat_members(
+    state: &AppState,
+    reply: &Reply<'_>,
+    username: &Option<String>,
+    request: AddChatMembers,
+    start: Instant,
+) {
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    if let Some(ref current_username) = username {
+        // I nuovi invitati ricevono ChatInvite, la chat il conteggio aggiornato, chi invita un Ack
+        match add_chat_members(state, current_username, &request).await {
+            Ok(()) => reply.ack(MessageType::AddChatMembers),
+            Err(chat_error) => reply.error(&chat_error),
+        }
+    }
+}
+
+async fn handle_chat_moderation(
+    state: &AppState,
+    reply: &Reply<'_>,
+    username: &Option<String>,
+    request: ModerationRequest,
+    start: Instant,
+) {
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    if let Some(ref current_username) = username {
+        // Gli invitati ricevono ChatModeration con i ruoli aggiornati, chi la richiede un Ack
+        match moderate_chat(state, current_username, &request).await {
+            Ok(()) => reply.ack(MessageType::ChatModeration),
+            Err(chat_error) => reply.error(&chat_error),
+        }
+    }
+}
+
+async fn handle_receipt_message(
+    state: &AppState,
+    reply: &Reply<'_>,
+    username: &Option<String>,
+    ack: ReceiptAck,
+    kind: ReceiptKind,
+    start: Instant,
+) {
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    if let Some(ref current_username) = username {
+        let message_type = match kind {
+            ReceiptKind::Delivered => MessageType::Delivered,
+            ReceiptKind::Read => MessageType::Read,
+        };
+        match handle_receipt(state, current_username, &ack, kind).await {
+            Ok(()) => reply.ack(message_type),
+            Err(chat_error) => reply.error(&chat_error),
+        }
+    }
+}
+
+async fn handle_typing_message(
+    state: &AppState,
+    reply: &Reply<'_>,
+    username: &Option<String>,
+    typing_limit: &mut TypingRateLimit,
+    request: TypingRequest,
+    started: bool,
+    start: Instant,
+) {
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    // Oltre il limite gli indicatori vengono scartati
+    if !typing_limit.allow() {
+        let chat_error = ChatError::new(ChatErrorReason::RateLimited, Some(&request.chat_id));
+        reply.error(&chat_error);
+        return;
+    }
+    if let Some(ref current_username) = username {
+        if started {
+            match handle_typing_started(state, current_username, &request).await {
+                Ok(()) => reply.ack(MessageType::TypingStarted),
+                Err(chat_error) => reply.error(&chat_error),
+            }
+        } else {
+            handle_typing_stopped(state, current_username, &request).await;
+            reply.ack(MessageType::TypingStopped);
+        }
+    }
+}
+
+async fn handle_history_request(
+    state: &AppState,
+    reply: &Reply<'_>,
+    username: &Option<String>,
+    request: HistoryRequest,
+    start: Instant,
+) {
+    //aggiorna il tempo di CPU//
+    update_cpu_time(state.total_cpu_time.clone(), start);
+    if let Some(ref current_username) = username {
+        match load_history_page(state, current_username, &request).await {
+            Ok(page) => {
+                reply.send(ServerMessage::HistoryPage(page));
+            }
+            Err(e) => {
+                let chat_error = ChatError {
+                    message: e.message().to_string(),
+                    ..ChatError::new(e.reason(), Some(&request.chat_id))
+                };
+                reply.error(&chat_error);
+            }
         }
     }
 }
@@ -6,8 +6,9 @@
 // - Verificare il broadcast di un messaggio di gruppo ai membri
 //
 // Strategia:
-// - Avvio di un processo reale del server (`fullstack-app`) con PORT=0 (porta effimera)
-// - Lettura dello stdout per ricavare l'endpoint WebSocket effettivo (es. ws://127.0.0.1:12345/ws)
+// - Avvio del server in-process con `create_app` e la stessa `Config` del binario,
+//   ma con `bind_address` sulla porta 0 (porta effimera)
+// - Lettura dell'indirizzo effettivo dal listener (es. ws://127.0.0.1:12345/ws)
 // - Connessione di client WebSocket reali (tokio-tungstenite) per scambiare messaggi come farebbe il frontend
 //
 // Note su Windows:
@@ -15,12 +16,18 @@
 // - Se un processo server rimane attivo tra un run e l'altro, può bloccare l'aggiornamento del binario: terminare i processi residui
 
 use futures_util::{SinkExt, StreamExt};
-use std::{clone, net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::{sync::mpsc, net::TcpListener};
 use tokio_tungstenite::tungstenite::Message as WsMessage;
 use axum::Router;
-use tower_http::cors::CorsLayer;
-use fullstack_app::{create_app, AppState};
+use fullstack_app::{create_app, AppState, Config, ServerBuilder};
+use fullstack_app::config::ConfigError;
+use fullstack_app::frame::Frame;
+use fullstack_app::outbound;
+use fullstack_app::presence::{AttachRequest, StatusUpdate};
+use fullstack_app::protocol::{ClientMessage, ProtocolVersion, ServerMessage};
+use fullstack_app::storage::{FileMessageStore, MessageStore};
 use fullstack_app::types; // importiamo i tipi dal crate invece di duplicarli
 
 // Semplice wrapper per ogni client connesso ai fini del test:
@@ -33,6 +40,7 @@ struct TestClient {
         WsMessage,
     >,
     rx: mpsc::UnboundedReceiver<types::WebSocketMessage>,
+    token: String, // token di sessione dell'ultimo login, per le API REST
 }
 
 // Avvia un server Axum in-process su porta effimera e ritorna l'URL WS.
@@ -40,30 +48,20 @@ async fn start_test_server() -> (String, tokio::task::JoinHandle<()>) {
     // Stato condiviso con timer CPU inizializzato a ZERO
     let total_cpu_time: Arc<Mutex<Duration>> = Arc::new(Mutex::new(Duration::ZERO));
     let state = AppState::new(total_cpu_time.clone());
+    start_test_server_with_state(state).await
+}
 
-    // CORS minimale, come nel main
-    let cors = CorsLayer::new()
-        .allow_origin(
-            "http://localhost:5173"
-                .parse::<axum::http::HeaderValue>()
-                .unwrap(),
-        )
-        .allow_methods([
-            axum::http::Method::GET,
-            axum::http::Method::POST,
-            axum::http::Method::PUT,
-            axum::http::Method::DELETE,
-            axum::http::Method::OPTIONS,
-        ])
-        .allow_headers([
-            axum::http::header::CONTENT_TYPE,
-            axum::http::header::AUTHORIZATION,
-            axum::http::header::ACCEPT,
-        ])
-        .allow_credentials(true);
-
-    let app: Router = create_app(state, cors);
-    let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+// Come `start_test_server`, ma con uno stato fornito dal test (che può tenerne una copia
+// per ispezionarlo durante l'esecuzione).
+async fn start_test_server_with_state(state: AppState) -> (String, tokio::task::JoinHandle<()>) {
+    // Stessa configurazione del binario, su una porta libera
+    let config = Config {
+        bind_address: SocketAddr::from(([127, 0, 0, 1], 0)),
+        ..Config::default()
+    };
+
+    let app: Router = create_app(state, &config);
+    let listener = TcpListener::bind(config.bind_address).await.unwrap();
     let addr: SocketAddr = listener.local_addr().unwrap();
     let ws_url = format!("ws://{}/ws", addr);
     let handle = tokio::spawn(async move {
@@ -72,6 +70,11 @@ async fn start_test_server() -> (String, tokio::task::JoinHandle<()>) {
     (ws_url, handle)
 }
 
+// Ricava l'URL base HTTP (per le API REST) dall'URL WS restituito da `start_test_server`.
+fn http_url(ws_url: &str) -> String {
+    ws_url.replacen("ws://", "http://", 1).trim_end_matches("/ws").to_string()
+}
+
 // Apre una connessione WebSocket al server, splitta lettura/scrittura e
 // inoltra i frame testuali in un canale tipizzato con `WebSocketMessage`.
 async fn connect_client(ws_url: &str) -> TestClient {
@@ -87,17 +90,51 @@ async fn connect_client(ws_url: &str) -> TestClient {
             }
         }
     });
-    TestClient { sender: write, rx }
+    TestClient { sender: write, rx, token: String::new() }
 }
 
 // Helper per inviare un messaggio WS serializzato come
 // { message_type, data: json(payload) }.
 async fn send_ws<T: serde::Serialize>(client: &mut TestClient, message_type: types::MessageType, payload: T) {
-    let msg = types::WebSocketMessage { message_type, data: serde_json::to_string(&payload).unwrap() };
+    let msg = types::WebSocketMessage { message_type, data: serde_json::to_string(&payload).unwrap(), request_id: None };
     let txt = serde_json::to_string(&msg).unwrap();
     client.sender.send(WsMessage::Text(txt)).await.unwrap();
 }
 
+// Come `send_ws`, con un request_id che il server ripete nella risposta
+async fn send_request<T: serde::Serialize>(client: &mut TestClient, message_type: types::MessageType, payload: T, request_id: &str) {
+    let msg = types::WebSocketMessage { message_type, data: serde_json::to_string(&payload).unwrap(), request_id: Some(request_id.into()) };
+    let txt = serde_json::to_string(&msg).unwrap();
+    client.sender.send(WsMessage::Text(txt)).await.unwrap();
+}
+
+// Password usata per tutti gli account creati dai test
+const TEST_PASSWORD: &str = "password-di-prova";
+
+// Registra l'account `username` tramite POST /api/register e ritorna lo status HTTP.
+async fn register(ws_url: &str, username: &str) -> reqwest::StatusCode {
+    reqwest::Client::new()
+        .post(format!("{}/api/register", http_url(ws_url)))
+        .json(&types::RegisterRequest { username: username.into(), password: TEST_PASSWORD.into() })
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+// Registra l'account (se non esiste già) ed effettua il login via WebSocket,
+// attendendo il LoginSuccess (che contiene il token per riprendere la sessione
+// e autenticare le richieste REST, conservato anche in `client.token`).
+async fn login(client: &mut TestClient, ws_url: &str, username: &str) -> types::LoginSuccess {
+    register(ws_url, username).await;
+    send_ws(client, types::MessageType::Login, types::LoginRequest { username: username.into(), password: TEST_PASSWORD.into() }).await;
+    let got = recv_until(&mut client.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await
+        .unwrap_or_else(|| panic!("{} should log in", username));
+    let success: types::LoginSuccess = serde_json::from_str(&got.data).unwrap();
+    client.token = success.token.clone();
+    success
+}
+
 /// Riceve in modo asincrono i messaggi dal canale `rx` e restituisce il primo
 /// che soddisfa il predicato `pred`, oppure `None` se scade `timeout_ms` o se
 /// il canale viene chiuso prima di trovare una corrispondenza.
@@ -127,203 +164,2970 @@ where
             } else { return None; }
         }
     };
-    tokio::time::timeout(deadline, fut).await.ok().flatten()
+    tokio::time::timeout(deadline, fut).await.ok().flatten()
+}
+
+// Attende che il server confermi (tramite `UserStatusChanged`) che l'utente
+// `username` sia effettivamente nella chat con `chat_id` e non disponibile.
+async fn wait_user_in_chat(
+    rx: &mut mpsc::UnboundedReceiver<types::WebSocketMessage>,
+    username: &str,
+    chat_id: &str,
+) {
+    let got = recv_until(
+        rx,
+        |m| {
+            if let types::MessageType::UserStatusChanged = m.message_type {
+                if let Ok(u) = serde_json::from_str::<types::User>(&m.data) {
+                    return u.username == username && u.chat_id.as_deref() == Some(chat_id) && !u.is_available;
+                }
+            }
+            false
+        },
+        3000,
+    ).await;
+    assert!(got.is_some(), "{} should be marked in chat {}", username, chat_id);
+}
+
+// Crea una chat tramite invito: `owner` invita con `chat_type`, ogni client in `guests`
+// accetta l'invito ricevuto e `owner` attende un ChatReady per ciascuno.
+// Ritorna il chat_id generato dal server.
+async fn create_chat(owner: &mut TestClient, guests: Vec<&mut TestClient>, chat_type: types::ChatType) -> String {
+    let invite = types::ChatInvite {
+        id: uuid::Uuid::new_v4().to_string(),
+        chat_id: None,
+        from: String::new(),
+        from_session_id: String::new(),
+        chat_type,
+        message: "Unisciti".into(),
+        timestamp: chrono::Utc::now(),
+    };
+    send_ws(owner, types::MessageType::ChatInvite, invite).await;
+    let created = recv_until(&mut owner.rx, |m| matches!(m.message_type, types::MessageType::ChatCreated), 3000).await
+        .expect("the inviter should receive ChatCreated");
+    let chat_id = serde_json::from_str::<types::ChatCreated>(&created.data).unwrap().room.id;
+
+    let n_guests = guests.len();
+    for guest in guests {
+        let got = recv_until(&mut guest.rx, |m| matches!(m.message_type, types::MessageType::ChatInvite), 3000).await
+            .expect("guest should receive the invite");
+        let received: types::ChatInvite = serde_json::from_str(&got.data).unwrap();
+        send_ws(guest, types::MessageType::ChatInviteResponse, types::ChatInviteResponse {
+            invite_id: received.id,
+            chat_id: received.chat_id,
+            accepted: true,
+            from_user: received.from,
+            from_session_id: received.from_session_id,
+            chat_type: received.chat_type,
+        }).await;
+    }
+    for _ in 0..n_guests {
+        recv_until(&mut owner.rx, |m| matches!(m.message_type, types::MessageType::ChatReady), 3000).await
+            .expect("the inviter should receive ChatReady");
+    }
+    chat_id
+}
+
+// Test 1: rifiuto del login con username duplicato
+// Passi:
+// - Avvio server e connessione di due client
+// - Il primo fa login con "mario" e riceve LoginSuccess
+// - Il secondo tenta lo stesso username (con la password corretta) e deve ricevere LoginError(AlreadyConnected)
+#[tokio::test]
+async fn test_duplicate_login_rejected() {
+    let (ws_url, _handle) = start_test_server().await;
+    let mut c1 = connect_client(&ws_url).await;
+    let mut c2 = connect_client(&ws_url).await;
+
+    login(&mut c1, &ws_url, "mario").await;
+
+    send_ws(&mut c2, types::MessageType::Login, types::LoginRequest { username: "mario".into(), password: TEST_PASSWORD.into() }).await;
+    let got_error = recv_until(&mut c2.rx, |m| matches!(m.message_type, types::MessageType::LoginError), 2000).await
+        .expect("second login should be rejected");
+    let error: types::LoginError = serde_json::from_str(&got_error.data).unwrap();
+    assert_eq!(error.reason, types::LoginErrorReason::AlreadyConnected);
+}
+
+// Test 1b: credenziali errate su WebSocket
+// Passi:
+// - "mario" si registra; un client prova a entrare con password sbagliata, un altro con un utente inesistente
+// - Entrambi ricevono lo stesso LoginError (InvalidCredentials, senza rivelare quale username esiste)
+//   e restano non autenticati
+#[tokio::test]
+async fn test_login_with_wrong_credentials_rejected() {
+    let (ws_url, _handle) = start_test_server().await;
+    assert_eq!(register(&ws_url, "mario").await, reqwest::StatusCode::CREATED);
+
+    let mut c1 = connect_client(&ws_url).await;
+    send_ws(&mut c1, types::MessageType::Login, types::LoginRequest { username: "mario".into(), password: "sbagliata".into() }).await;
+    let got_error = recv_until(&mut c1.rx, |m| matches!(m.message_type, types::MessageType::LoginError), 2000).await
+        .expect("wrong password should be rejected");
+    let wrong_password: types::LoginError = serde_json::from_str(&got_error.data).unwrap();
+    assert_eq!(wrong_password.reason, types::LoginErrorReason::InvalidCredentials);
+
+    let mut c2 = connect_client(&ws_url).await;
+    send_ws(&mut c2, types::MessageType::Login, types::LoginRequest { username: "luigi".into(), password: TEST_PASSWORD.into() }).await;
+    let got_error = recv_until(&mut c2.rx, |m| matches!(m.message_type, types::MessageType::LoginError), 2000).await
+        .expect("unknown user should be rejected");
+    let unknown_user: types::LoginError = serde_json::from_str(&got_error.data).unwrap();
+    assert_eq!(unknown_user.reason, types::LoginErrorReason::InvalidCredentials);
+    assert_eq!(unknown_user.message, wrong_password.message);
+
+    // Dopo un login fallito le credenziali corrette funzionano ancora
+    login(&mut c1, &ws_url, "mario").await;
+}
+
+// Test 1c: registrazione e login via REST
+// Passi:
+// - Registrazione di "mario" (201), seconda registrazione (409), "Sistema" è riservato (400)
+// - /api/login risponde 401 con password errata e 200 con quella corretta
+// - Dopo il login via WebSocket, /api/login risponde 409 (già connesso)
+// - La disponibilità di mario si aggiorna solo con il suo token: 401 senza token, 403 con quello di luigi
+#[tokio::test]
+async fn test_register_and_login_rest() {
+    let (ws_url, _handle) = start_test_server().await;
+    let base = http_url(&ws_url);
+    let client = reqwest::Client::new();
+
+    assert_eq!(register(&ws_url, "mario").await, reqwest::StatusCode::CREATED);
+    assert_eq!(register(&ws_url, "mario").await, reqwest::StatusCode::CONFLICT);
+    assert_eq!(register(&ws_url, "Sistema").await, reqwest::StatusCode::BAD_REQUEST);
+
+    let wrong = client.post(format!("{}/api/login", base))
+        .json(&serde_json::json!({ "username": "mario", "password": "sbagliata" }))
+        .send().await.unwrap();
+    assert_eq!(wrong.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let error: types::LoginError = wrong.json().await.unwrap();
+    assert_eq!(error.reason, types::LoginErrorReason::InvalidCredentials);
+
+    let ok = client.post(format!("{}/api/login", base))
+        .json(&serde_json::json!({ "username": "mario", "password": TEST_PASSWORD }))
+        .send().await.unwrap();
+    assert_eq!(ok.status(), reqwest::StatusCode::OK);
+
+    let mut c1 = connect_client(&ws_url).await;
+    login(&mut c1, &ws_url, "mario").await;
+    let busy = client.post(format!("{}/api/login", base))
+        .json(&serde_json::json!({ "username": "mario", "password": TEST_PASSWORD }))
+        .send().await.unwrap();
+    assert_eq!(busy.status(), reqwest::StatusCode::CONFLICT);
+    let error: types::LoginError = busy.json().await.unwrap();
+    assert_eq!(error.reason, types::LoginErrorReason::AlreadyConnected);
+
+    let mut c2 = connect_client(&ws_url).await;
+    login(&mut c2, &ws_url, "luigi").await;
+    let availability = format!("{}/api/users/mario/availability", base);
+    let anonymous = client.post(&availability).json(&false).send().await.unwrap();
+    assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let other = client.post(&availability).bearer_auth(&c2.token).json(&false).send().await.unwrap();
+    assert_eq!(other.status(), reqwest::StatusCode::FORBIDDEN);
+    let own = client.post(&availability).bearer_auth(&c1.token).json(&false).send().await.unwrap();
+    assert_eq!(own.status(), reqwest::StatusCode::OK);
+}
+
+// Test 2: invio e ricezione di un invito privato
+// Passi:
+// - Avvio server e login di alice e bob
+// - alice invia ChatInvite(private) a bob
+// - bob deve ricevere un ChatInvite con lo stesso id
+#[tokio::test]
+async fn test_invite_delivery_private() {
+    let (ws_url, _handle) = start_test_server().await;
+    let mut alice = connect_client(&ws_url).await;
+    let mut bob = connect_client(&ws_url).await;
+
+    login(&mut alice, &ws_url, "alice").await;
+    login(&mut bob, &ws_url, "bob").await;
+
+    let invite = types::ChatInvite {
+        id: "inv1".into(),
+        chat_id: Some("chat-1".into()),
+        from: "alice".into(),
+        from_session_id: "dummy".into(),
+        chat_type: types::ChatType::Private { target: "bob".into() },
+        message: "Join me".into(),
+        timestamp: chrono::Utc::now(),
+    };
+    send_ws(&mut alice, types::MessageType::ChatInvite, invite.clone()).await;
+
+    let got_invite = recv_until(&mut bob.rx, |m| matches!(m.message_type, types::MessageType::ChatInvite), 2000).await
+        .expect("bob should receive invite");
+    let payload: types::ChatInvite = serde_json::from_str(&got_invite.data).unwrap();
+    assert_eq!(payload.id, invite.id);
+}
+
+// Test 2b: il server crea la chat e ne genera l'id
+// Passi:
+// - alice invita bob proponendo un chat_id: il server lo ignora e conferma ad alice la chat creata (ChatCreated)
+// - bob riceve l'invito con lo stesso chat_id e con mittente/sessione reali di alice
+// - bob accetta: alice riceve ChatReady per la stessa chat
+// - una risposta per una chat inesistente viene ignorata
+#[tokio::test]
+async fn test_server_assigns_chat_ids() {
+    let (ws_url, _handle) = start_test_server().await;
+    let mut alice = connect_client(&ws_url).await;
+    let mut bob = connect_client(&ws_url).await;
+    let alice_session = login(&mut alice, &ws_url, "alice").await;
+    login(&mut bob, &ws_url, "bob").await;
+
+    let invite = types::ChatInvite {
+        id: "inv-room".into(),
+        chat_id: Some("scelto-dal-client".into()),
+        from: "mallory".into(),
+        from_session_id: "dummy".into(),
+        chat_type: types::ChatType::Private { target: "bob".into() },
+        message: "Join me".into(),
+        timestamp: chrono::Utc::now(),
+    };
+    send_ws(&mut alice, types::MessageType::ChatInvite, invite.clone()).await;
+
+    let created = recv_until(&mut alice.rx, |m| matches!(m.message_type, types::MessageType::ChatCreated), 2000).await
+        .expect("alice should be told which chat was created");
+    let created: types::ChatCreated = serde_json::from_str(&created.data).unwrap();
+    assert_eq!(created.invite_id, "inv-room");
+    assert_ne!(created.room.id, "scelto-dal-client");
+    assert_eq!(created.room.owner, "alice");
+    assert_eq!(created.room.kind, types::RoomKind::Private);
+    assert_eq!(created.room.status, types::RoomStatus::Pending);
+    assert_eq!(created.room.invited_users, vec!["alice".to_string(), "bob".to_string()]);
+
+    let got_invite = recv_until(&mut bob.rx, |m| matches!(m.message_type, types::MessageType::ChatInvite), 2000).await
+        .expect("bob should receive invite");
+    let received: types::ChatInvite = serde_json::from_str(&got_invite.data).unwrap();
+    assert_eq!(received.chat_id.as_deref(), Some(created.room.id.as_str()));
+    assert_eq!(received.from, "alice");
+    assert_eq!(received.from_session_id, alice_session.session_id);
+
+    // Risposta a una chat mai creata: nessun ChatReady
+    send_ws(&mut bob, types::MessageType::ChatInviteResponse, types::ChatInviteResponse {
+        invite_id: "inv-room".into(),
+        chat_id: Some("inesistente".into()),
+        accepted: true,
+        from_user: "alice".into(),
+        from_session_id: received.from_session_id.clone(),
+        chat_type: received.chat_type.clone(),
+    }).await;
+    let ready = recv_until(&mut alice.rx, |m| matches!(m.message_type, types::MessageType::ChatReady), 500).await;
+    assert!(ready.is_none(), "responses to unknown chats must be ignored");
+
+    send_ws(&mut bob, types::MessageType::ChatInviteResponse, types::ChatInviteResponse {
+        invite_id: "inv-room".into(),
+        chat_id: received.chat_id.clone(),
+        accepted: true,
+        from_user: "alice".into(),
+        from_session_id: received.from_session_id.clone(),
+        chat_type: received.chat_type.clone(),
+    }).await;
+    let ready = recv_until(&mut alice.rx, |m| matches!(m.message_type, types::MessageType::ChatReady), 2000).await
+        .expect("alice should be told the chat is ready");
+    let ready: types::ChatReady = serde_json::from_str(&ready.data).unwrap();
+    assert_eq!(ready.chat_id, created.room.id);
+    assert_eq!(ready.accepted_by, "bob");
+}
+
+// Attende un MessageType::Error e ne restituisce il payload strutturato
+async fn expect_chat_error(client: &mut TestClient) -> types::ChatError {
+    let got = recv_until(&mut client.rx, |m| matches!(m.message_type, types::MessageType::Error), 2000).await
+        .expect("the server should reply with an Error");
+    let error: types::ChatError = serde_json::from_str(&got.data).unwrap();
+    assert_eq!(error.code, error.reason.code());
+    error
+}
+
+// Test 2c: solo i membri di una chat possono scriverci, entrarci o rispondere ai suoi inviti
+// Passi:
+// - alice crea una chat privata con bob, che accetta ed entra
+// - carol (non invitata) prova a entrare, a scrivere (con chat_id o con un Group forgiato)
+//   e a rispondere all'invito: ogni tentativo riceve un Error con il motivo, bob non riceve nulla
+// - un invito senza altri destinatari viene rifiutato, uno a un utente non registrato riceve UnknownUser
+#[tokio::test]
+async fn test_non_members_are_rejected() {
+    let (ws_url, _handle) = start_test_server().await;
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+    let mut c = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+    login(&mut c, &ws_url, "carol").await;
+
+    let chat_id = create_chat(&mut a, vec![&mut b], types::ChatType::Private { target: "bob".into() }).await;
+    let status = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+    send_ws(&mut b, types::MessageType::UserStatusChanged, status.clone()).await;
+    wait_user_in_chat(&mut b.rx, "bob", &chat_id).await;
+
+    // Ingresso nella chat
+    send_ws(&mut c, types::MessageType::UserStatusChanged, status.clone()).await;
+    let error = expect_chat_error(&mut c).await;
+    assert_eq!(error.reason, types::ChatErrorReason::NotMember);
+    assert_eq!(error.chat_id.as_deref(), Some(chat_id.as_str()));
+
+    // Messaggio con il chat_id della chat
+    let forged = types::ChatMessage {
+        id: uuid::Uuid::new_v4(),
+        chat_id: Some(chat_id.clone()),
+        username: "carol".into(),
+        content: "intrusa".into(),
+        timestamp: chrono::Utc::now(),
+        chat_type: types::ChatType::Private { target: "bob".into() },
+    };
+    send_ws(&mut c, types::MessageType::ChatMessage, forged.clone()).await;
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::NotMember);
+
+    // Chat inesistente
+    send_ws(&mut c, types::MessageType::ChatMessage, types::ChatMessage { chat_id: Some("inesistente".into()), ..forged.clone() }).await;
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::UnknownChat);
+
+    // Nessuna chat: la lista di membri dichiarata dal client non basta
+    send_ws(&mut c, types::MessageType::ChatMessage, types::ChatMessage {
+        chat_id: None,
+        chat_type: types::ChatType::Group { members: vec!["bob".into()] },
+        ..forged.clone()
+    }).await;
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::UnknownChat);
+
+    let leaked = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 500).await;
+    assert!(leaked.is_none(), "bob should not receive messages from non-members");
+
+    // Risposta a un invito mai ricevuto
+    send_ws(&mut c, types::MessageType::ChatInviteResponse, types::ChatInviteResponse {
+        invite_id: "x".into(),
+        chat_id: Some(chat_id.clone()),
+        accepted: true,
+        from_user: "alice".into(),
+        from_session_id: String::new(),
+        chat_type: types::ChatType::Private { target: "carol".into() },
+    }).await;
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::NotInvited);
+
+    // Invito rivolto solo a se stessi
+    send_ws(&mut c, types::MessageType::ChatInvite, types::ChatInvite {
+        id: "self".into(),
+        chat_id: None,
+        from: "carol".into(),
+        from_session_id: String::new(),
+        chat_type: types::ChatType::Private { target: "carol".into() },
+        message: String::new(),
+        timestamp: chrono::Utc::now(),
+    }).await;
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::InvalidInvite);
+
+    // Invito a un utente mai registrato
+    send_ws(&mut c, types::MessageType::ChatInvite, types::ChatInvite {
+        id: "ghost".into(),
+        chat_id: None,
+        from: "carol".into(),
+        from_session_id: String::new(),
+        chat_type: types::ChatType::Private { target: "ghost".into() },
+        message: String::new(),
+        timestamp: chrono::Utc::now(),
+    }).await;
+    let error = expect_chat_error(&mut c).await;
+    assert_eq!(error.reason, types::ChatErrorReason::UnknownUser);
+    assert!(error.message.contains("ghost"));
+}
+
+// Test 3: broadcast di un messaggio di gruppo a tutti i membri
+// Passi:
+// - Avvio server, login di alice, bob, carol
+// - alice crea una chat di gruppo invitando bob e carol, che accettano
+// - Tutti segnalano `UserStatusChanged` con il `chatId` generato dal server
+// - Attendo conferma per ciascuno (UserStatusChanged coerente)
+// - alice invia ChatMessage(Group) con quel chatId
+// - bob e carol devono ricevere il medesimo ChatMessage
+#[tokio::test]
+async fn test_group_message_broadcast_to_all_members() {
+    let (ws_url, _handle) = start_test_server().await;
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+    let mut c = connect_client(&ws_url).await;
+
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+    login(&mut c, &ws_url, "carol").await;
+
+    let group = types::ChatType::Group { members: vec!["bob".into(), "carol".into()] };
+    let chat_id = create_chat(&mut a, vec![&mut b, &mut c], group).await;
+
+    let status = serde_json::json!({
+        "available": false,
+        "inChat": true,
+        "chatId": chat_id,
+        "members": ["alice", "bob", "carol"]
+    });
+    send_ws(&mut a, types::MessageType::UserStatusChanged, status.clone()).await;
+    send_ws(&mut b, types::MessageType::UserStatusChanged, status.clone()).await;
+    send_ws(&mut c, types::MessageType::UserStatusChanged, status.clone()).await;
+
+    wait_user_in_chat(&mut a.rx, "alice", &chat_id).await;
+    wait_user_in_chat(&mut b.rx, "bob", &chat_id).await;
+    wait_user_in_chat(&mut c.rx, "carol", &chat_id).await;
+
+    let chat_msg = types::ChatMessage {
+        id: uuid::Uuid::new_v4(),
+        chat_id: Some(chat_id.clone()),
+        username: "alice".into(),
+        content: "ciao gruppo".into(),
+        timestamp: chrono::Utc::now(),
+        chat_type: types::ChatType::Group { members: vec!["alice".into(), "bob".into(), "carol".into()] },
+    };
+    send_ws(&mut a, types::MessageType::ChatMessage, chat_msg.clone()).await;
+
+    let msg_b = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 3000).await
+        .expect("bob should receive group message");
+    let parsed_b: types::ChatMessage = serde_json::from_str(&msg_b.data).unwrap();
+    assert_eq!(parsed_b.content, chat_msg.content);
+
+    let msg_c = recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 3000).await
+        .expect("carol should receive group message");
+    let parsed_c: types::ChatMessage = serde_json::from_str(&msg_c.data).unwrap();
+    assert_eq!(parsed_c.content, chat_msg.content);
+}
+
+//Test 4: ricezione di un messaggio solo dai componenti della stessa chat
+// Passi:
+// - Avvio server, login di alice, bob, carol, dave
+// - alice crea una chat con bob (chat 1), carol una con dave (chat 2)
+// - alice e bob segnalano `UserStatusChanged` con il `chatId` della chat 1
+// - carol e dave segnalano `UserStatusChanged` con il `chatId` della chat 2
+// - Attendo conferma per ciascuno (UserStatusChanged coerente)
+// - alice invia ChatMessage(Group) nella chat 1
+// - bob deve ricevere il messaggio
+// - carol e dave non devono ricevere il messaggio
+#[tokio::test]
+async fn test_multiple_chats_correct_deliver() {
+    let (ws_url, _handle) = start_test_server().await;
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+    let mut c = connect_client(&ws_url).await;
+    let mut d = connect_client(&ws_url).await;
+
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+    login(&mut c, &ws_url, "carol").await;
+    login(&mut d, &ws_url, "dave").await;
+
+    let chat_1 = create_chat(&mut a, vec![&mut b], types::ChatType::Group { members: vec!["bob".into()] }).await;
+    let chat_2 = create_chat(&mut c, vec![&mut d], types::ChatType::Group { members: vec!["dave".into()] }).await;
+
+    let status1 = serde_json::json!({
+        "available": false,
+        "inChat": true,
+        "chatId": chat_1,
+        "members": ["alice", "bob"]
+    });
+    send_ws(&mut a, types::MessageType::UserStatusChanged, status1.clone()).await;
+    send_ws(&mut b, types::MessageType::UserStatusChanged, status1.clone()).await;
+
+    let status2 = serde_json::json!({
+        "available": false,
+        "inChat": true,
+        "chatId": chat_2,
+        "members": ["carol", "dave"]
+    });
+    send_ws(&mut c, types::MessageType::UserStatusChanged, status2.clone()).await;
+    send_ws(&mut d, types::MessageType::UserStatusChanged, status2.clone()).await;
+    wait_user_in_chat(&mut a.rx, "alice", &chat_1).await;
+    wait_user_in_chat(&mut b.rx, "bob", &chat_1).await;
+    wait_user_in_chat(&mut c.rx, "carol", &chat_2).await;
+    wait_user_in_chat(&mut d.rx, "dave", &chat_2).await;
+    let chat_msg1 = types::ChatMessage {
+        id: uuid::Uuid::new_v4(),
+        chat_id: Some(chat_1.clone()),
+        username: "alice".into(),
+        content: "ciao gruppo 1".into(),
+        timestamp: chrono::Utc::now(),
+        chat_type: types::ChatType::Group { members: vec!["alice".into(), "bob".into()] },
+    };
+    send_ws(&mut a, types::MessageType::ChatMessage, chat_msg1.clone()).await;
+    let msg_b = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 3000).await
+        .expect("bob should receive group message");
+    let parsed_b: types::ChatMessage = serde_json::from_str(&msg_b.data).unwrap();
+    assert_eq!(parsed_b.content, chat_msg1.content);
+    let no_msg_c = recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 1000).await;
+    assert!(no_msg_c.is_none(), "carol should NOT receive chat 1 message");
+    let no_msg_d = recv_until(&mut d.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 1000).await;
+    assert!(no_msg_d.is_none(), "dave should NOT receive chat 1 message");
+}
+
+//Test 4b: autore, id e timestamp dei messaggi sono assegnati dal server
+// Passi:
+// - alice e bob sono in una chat privata
+// - alice invia SendChatMessage con un client_nonce: riceve ChatMessageSent con lo stesso nonce
+//   e il messaggio registrato, che bob riceve con lo stesso id
+// - alice invia un ChatMessage (formato precedente) spacciandosi per "Sistema" con id e timestamp
+//   scelti da lei: bob lo riceve con l'autore, l'id e il timestamp assegnati dal server
+#[tokio::test]
+async fn test_server_stamps_message_fields() {
+    let (ws_url, _handle) = start_test_server().await;
+    let (mut a, mut b, _session, chat_id) = alice_and_bob_in_chat(&ws_url).await;
+
+    send_ws(&mut a, types::MessageType::SendChatMessage, types::SendChatMessage {
+        chat_id: Some(chat_id.clone()),
+        content: "ciao bob".into(),
+        client_nonce: Some("tmp-1".into()),
+    }).await;
+    let ack = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatMessageSent), 2000).await
+        .expect("alice should receive ChatMessageSent");
+    let ack: types::ChatMessageSent = serde_json::from_str(&ack.data).unwrap();
+    assert_eq!(ack.client_nonce.as_deref(), Some("tmp-1"));
+    assert_eq!(ack.message.username, "alice");
+    assert_eq!(ack.message.chat_id, Some(chat_id.clone()));
+    assert!(matches!(ack.message.chat_type, types::ChatType::Private { ref target } if target == "bob"));
+
+    let got = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 2000).await
+        .expect("bob should receive alice's message");
+    let received: types::ChatMessage = serde_json::from_str(&got.data).unwrap();
+    assert_eq!(received.id, ack.message.id);
+    assert_eq!(received.content, "ciao bob");
+
+    let spoofed = types::ChatMessage {
+        id: uuid::Uuid::nil(),
+        chat_id: Some(chat_id.clone()),
+        username: "Sistema".into(),
+        content: "messaggio falso".into(),
+        timestamp: chrono::Utc::now() - chrono::Duration::days(365),
+        chat_type: types::ChatType::System,
+    };
+    send_ws(&mut a, types::MessageType::ChatMessage, spoofed.clone()).await;
+    let got = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 2000).await
+        .expect("bob should receive the re-stamped message");
+    let received: types::ChatMessage = serde_json::from_str(&got.data).unwrap();
+    assert_eq!(received.username, "alice");
+    assert_eq!(received.content, "messaggio falso");
+    assert_ne!(received.id, spoofed.id);
+    assert!(received.timestamp > chrono::Utc::now() - chrono::Duration::minutes(1));
+    assert!(matches!(received.chat_type, types::ChatType::Private { .. }));
+}
+
+//Test 4c: conferme di consegna e lettura in una chat di gruppo
+// Passi:
+// - alice crea un gruppo con bob e carol e invia un messaggio
+// - bob conferma la consegna, carol la lettura, poi bob la lettura: alice riceve un ReceiptUpdate
+//   per ogni cambiamento (Sent -> Delivered -> Read)
+// - una conferma da chi non è destinatario (dave) viene ignorata
+// - prima della lettura di bob lo stato è consultabile nella cronologia e tramite REST (solo dagli invitati,
+//   autenticati con il token di sessione)
+// - letto da tutti, il messaggio non è più tracciato: REST risponde 404 e la cronologia non ne riporta lo stato
+#[tokio::test]
+async fn test_delivery_and_read_receipts() {
+    let (ws_url, _handle) = start_test_server().await;
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+    let mut c = connect_client(&ws_url).await;
+    let mut d = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+    login(&mut c, &ws_url, "carol").await;
+    login(&mut d, &ws_url, "dave").await;
+
+    let group = types::ChatType::Group { members: vec!["bob".into(), "carol".into()] };
+    let chat_id = create_chat(&mut a, vec![&mut b, &mut c], group).await;
+    send_ws(&mut a, types::MessageType::SendChatMessage, types::SendChatMessage {
+        chat_id: Some(chat_id.clone()),
+        content: "avete letto?".into(),
+        client_nonce: None,
+    }).await;
+    let ack = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatMessageSent), 2000).await
+        .expect("alice should receive ChatMessageSent");
+    let message_id = serde_json::from_str::<types::ChatMessageSent>(&ack.data).unwrap().message.id;
+    let receipt_ack = types::ReceiptAck { message_id };
+
+    async fn next_receipt(client: &mut TestClient) -> types::MessageReceipt {
+        let got = recv_until(&mut client.rx, |m| matches!(m.message_type, types::MessageType::ReceiptUpdate), 2000).await
+            .expect("the sender should receive a ReceiptUpdate");
+        serde_json::from_str(&got.data).unwrap()
+    }
+
+    send_ws(&mut b, types::MessageType::Delivered, receipt_ack.clone()).await;
+    let receipt = next_receipt(&mut a).await;
+    assert_eq!(receipt.message_id, message_id);
+    assert_eq!(receipt.status, types::ReceiptStatus::Sent);
+    let bob = receipt.recipients.iter().find(|r| r.username == "bob").unwrap();
+    assert!(bob.delivered_at.is_some() && bob.read_at.is_none());
+    assert_eq!(receipt.recipients.len(), 2);
+
+    send_ws(&mut c, types::MessageType::Read, receipt_ack.clone()).await;
+    assert_eq!(next_receipt(&mut a).await.status, types::ReceiptStatus::Delivered);
+
+    let base = http_url(&ws_url);
+    let client = reqwest::Client::new();
+    let page: types::HistoryPage = client
+        .get(format!("{}/api/chats/{}/messages", base, chat_id))
+        .bearer_auth(&b.token)
+        .send().await.unwrap()
+        .json().await.unwrap();
+    let in_history = page.receipts.iter().find(|r| r.message_id == message_id)
+        .expect("history should include the receipt");
+    assert_eq!(in_history.status, types::ReceiptStatus::Delivered);
+
+    let url = format!("{}/api/chats/{}/messages/{}/receipts", base, chat_id, message_id);
+    let receipt: types::MessageReceipt = client.get(&url)
+        .bearer_auth(&a.token)
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert_eq!(receipt.sender, "alice");
+    assert_eq!(receipt.status, types::ReceiptStatus::Delivered);
+    let forbidden = client.get(&url).bearer_auth(&d.token).send().await.unwrap();
+    assert_eq!(forbidden.status(), reqwest::StatusCode::FORBIDDEN);
+    // Il nome nella query non autentica nessuno
+    let anonymous = client.get(format!("{}?username=alice", url)).send().await.unwrap();
+    assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    send_ws(&mut d, types::MessageType::Read, receipt_ack.clone()).await;
+    send_ws(&mut b, types::MessageType::Read, receipt_ack.clone()).await;
+    let receipt = next_receipt(&mut a).await;
+    assert_eq!(receipt.status, types::ReceiptStatus::Read);
+    assert!(receipt.recipients.iter().all(|r| r.username != "dave"));
+
+    // Letto da tutti: il messaggio non è più tracciato
+    let pruned = client.get(&url).bearer_auth(&a.token).send().await.unwrap();
+    assert_eq!(pruned.status(), reqwest::StatusCode::NOT_FOUND);
+    let page: types::HistoryPage = client
+        .get(format!("{}/api/chats/{}/messages", base, chat_id))
+        .bearer_auth(&b.token)
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert!(page.receipts.is_empty());
+}
+
+//Test 4d: indicatori di digitazione limitati alla chat, con scadenza e limite di frequenza
+// Passi:
+// - alice e bob sono in una chat privata, carol è collegata ma non è nella chat
+// - alice inizia a scrivere: bob riceve TypingStarted, carol no; carol non può segnalare digitazione nella chat
+// - alice non invia TypingStopped: allo scadere del timeout bob riceve TypingStopped
+// - una raffica di eventi viene limitata: bob ne riceve solo una parte
+#[tokio::test]
+async fn test_typing_indicators() {
+    let mut state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    state.typing_timeout = Duration::from_millis(300);
+    let (ws_url, _handle) = start_test_server_with_state(state).await;
+    let (mut a, mut b, _session, chat_id) = alice_and_bob_in_chat(&ws_url).await;
+    let mut c = connect_client(&ws_url).await;
+    login(&mut c, &ws_url, "carol").await;
+
+    let typing = types::TypingRequest { chat_id: chat_id.clone() };
+    send_ws(&mut a, types::MessageType::TypingStarted, typing.clone()).await;
+    let got = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::TypingStarted), 2000).await
+        .expect("bob should see alice typing");
+    let indicator: types::TypingIndicator = serde_json::from_str(&got.data).unwrap();
+    assert_eq!(indicator.username, "alice");
+    assert_eq!(indicator.chat_id, chat_id);
+
+    send_ws(&mut c, types::MessageType::TypingStarted, typing.clone()).await;
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::NotMember);
+
+    let started = std::time::Instant::now();
+    recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::TypingStopped), 2000).await
+        .expect("the indicator should expire on the server");
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    let own = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::TypingStarted | types::MessageType::TypingStopped), 200).await;
+    assert!(own.is_none(), "alice should not receive her own typing events");
+    let leaked = recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::TypingStarted | types::MessageType::TypingStopped), 200).await;
+    assert!(leaked.is_none(), "carol is not in the chat");
+
+    // Raffica: start/stop alternati, ciascuno produrrebbe un evento per bob.
+    // La raffica può cadere a cavallo di due finestre: al massimo il doppio del limite
+    for i in 0..40 {
+        let message_type = if i % 2 == 0 { types::MessageType::TypingStarted } else { types::MessageType::TypingStopped };
+        send_ws(&mut a, message_type, typing.clone()).await;
+    }
+    let mut received = 0;
+    while recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::TypingStarted | types::MessageType::TypingStopped), 300).await.is_some() {
+        received += 1;
+    }
+    assert!(received > 0);
+    assert!(received <= 2 * fullstack_app::typing::TYPING_EVENTS_PER_WINDOW as usize, "received {} typing events", received);
+}
+
+//Test 4e: code dei messaggi per gli account registrati ma disconnessi
+// Passi (limite di conservazione: 2 messaggi per utente):
+// - alice invita bob e carol, bob non è connesso: al login riceve l'invito e accetta
+// - bob si disconnette; alice invia 3 messaggi: al nuovo login bob riceve, in ordine, solo gli ultimi 2
+// - alice si disconnette e carol rifiuta l'invito: alice riceve la risposta al login successivo
+#[tokio::test]
+async fn test_offline_queue_flushed_on_login() {
+    let mut state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    state.session_grace = Duration::from_millis(200);
+    state.offline = fullstack_app::offline::OfflineQueues::with_limit(2);
+    let (ws_url, _handle) = start_test_server_with_state(state).await;
+    assert_eq!(register(&ws_url, "bob").await, reqwest::StatusCode::CREATED);
+    let mut a = connect_client(&ws_url).await;
+    let mut c = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut c, &ws_url, "carol").await;
+
+    send_ws(&mut a, types::MessageType::ChatInvite, types::ChatInvite {
+        id: "inv-offline".into(),
+        chat_id: None,
+        from: "alice".into(),
+        from_session_id: String::new(),
+        chat_type: types::ChatType::Group { members: vec!["bob".into(), "carol".into()] },
+        message: "Unisciti".into(),
+        timestamp: chrono::Utc::now(),
+    }).await;
+    let created = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatCreated), 2000).await
+        .expect("alice should receive ChatCreated");
+    let chat_id = serde_json::from_str::<types::ChatCreated>(&created.data).unwrap().room.id;
+
+    let mut b = connect_client(&ws_url).await;
+    login(&mut b, &ws_url, "bob").await;
+    let queued = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatInvite), 2000).await
+        .expect("bob should receive the invite sent while he was offline");
+    let invite: types::ChatInvite = serde_json::from_str(&queued.data).unwrap();
+    assert_eq!(invite.chat_id.as_deref(), Some(chat_id.as_str()));
+    send_ws(&mut b, types::MessageType::ChatInviteResponse, types::ChatInviteResponse {
+        invite_id: invite.id.clone(),
+        chat_id: invite.chat_id.clone(),
+        accepted: true,
+        from_user: invite.from.clone(),
+        from_session_id: invite.from_session_id.clone(),
+        chat_type: invite.chat_type.clone(),
+    }).await;
+    recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatReady), 2000).await
+        .expect("alice should receive ChatReady");
+
+    b.sender.close().await.unwrap();
+    recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::UserLeft) && m.data == "bob", 2000).await
+        .expect("bob's session should expire");
+    for i in 0..3 {
+        send_ws(&mut a, types::MessageType::SendChatMessage, types::SendChatMessage {
+            chat_id: Some(chat_id.clone()),
+            content: format!("offline {}", i),
+            client_nonce: None,
+        }).await;
+        recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatMessageSent), 2000).await
+            .expect("alice's message should be accepted");
+    }
+
+    let mut b = connect_client(&ws_url).await;
+    login(&mut b, &ws_url, "bob").await;
+    for expected in ["offline 1", "offline 2"] {
+        let got = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 2000).await
+            .expect("bob should receive queued messages");
+        let message: types::ChatMessage = serde_json::from_str(&got.data).unwrap();
+        assert_eq!(message.content, expected);
+    }
+    let extra = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 300).await;
+    assert!(extra.is_none(), "the oldest message exceeds the retention limit");
+
+    a.sender.close().await.unwrap();
+    recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::UserLeft) && m.data == "alice", 2000).await
+        .expect("alice's session should expire");
+    send_ws(&mut c, types::MessageType::ChatInviteResponse, types::ChatInviteResponse {
+        invite_id: "inv-offline".into(),
+        chat_id: Some(chat_id.clone()),
+        accepted: false,
+        from_user: "alice".into(),
+        from_session_id: String::new(),
+        chat_type: types::ChatType::Group { members: vec!["bob".into(), "carol".into()] },
+    }).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut a = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    let got = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatInviteResponse), 2000).await
+        .expect("alice should receive the response sent while she was offline");
+    let response: types::ChatInviteResponseNotify = serde_json::from_str(&got.data).unwrap();
+    assert!(!response.accepted);
+    assert_eq!(response.responding_user, "carol");
+}
+
+//Test 4d: le conferme dei messaggi di una chat chiusa non vengono più tracciate
+// Passi:
+// - alice e bob sono in una chat privata; alice invia un messaggio che bob non conferma
+// - bob esce dalla chat: l'abbandono definitivo la chiude e alice riceve ChatAbandoned
+// - il registro delle conferme non contiene più il messaggio
+#[tokio::test]
+async fn test_receipts_pruned_when_chat_closes() {
+    let state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    let (ws_url, _handle) = start_test_server_with_state(state.clone()).await;
+    let (mut a, mut b, _session, chat_id) = alice_and_bob_in_chat(&ws_url).await;
+
+    send_ws(&mut a, types::MessageType::SendChatMessage, types::SendChatMessage {
+        chat_id: Some(chat_id.clone()),
+        content: "ci sei?".into(),
+        client_nonce: None,
+    }).await;
+    let ack = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatMessageSent), 2000).await
+        .expect("alice should receive ChatMessageSent");
+    let message_id = serde_json::from_str::<types::ChatMessageSent>(&ack.data).unwrap().message.id;
+    assert!(state.receipts.get(&message_id).is_some());
+
+    send_ws(&mut b, types::MessageType::UserStatusChanged, serde_json::json!({ "available": true, "inChat": false })).await;
+    recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatAbandoned), 2000).await
+        .expect("alice should be told that bob abandoned the chat");
+    assert!(state.receipts.get(&message_id).is_none());
+}
+
+//Test 5: i messaggi di chat (inclusi quelli di "Sistema") vengono registrati nella cronologia
+// Passi:
+// - Avvio server con stato condiviso, login di alice e bob, chat di gruppo creata tramite invito
+// - alice invia un messaggio, poi bob si disconnette
+// - La cronologia della chat deve contenere l'avviso di ingresso di bob, il messaggio di alice
+//   e l'avviso di abbandono di bob
+#[tokio::test]
+async fn test_chat_messages_recorded_in_store() {
+    let mut state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    // Periodo di grazia breve: l'uscita di bob viene notificata quasi subito
+    state.session_grace = Duration::from_millis(200);
+    let (ws_url, _handle) = start_test_server_with_state(state.clone()).await;
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+    let chat_id = create_chat(&mut a, vec![&mut b], types::ChatType::Group { members: vec!["bob".into()] }).await;
+
+    let status = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+    send_ws(&mut a, types::MessageType::UserStatusChanged, status.clone()).await;
+    send_ws(&mut b, types::MessageType::UserStatusChanged, status.clone()).await;
+    wait_user_in_chat(&mut a.rx, "alice", &chat_id).await;
+    wait_user_in_chat(&mut b.rx, "bob", &chat_id).await;
+
+    send_ws(&mut a, types::MessageType::SendChatMessage, types::SendChatMessage {
+        chat_id: Some(chat_id.clone()),
+        content: "da ricordare".into(),
+        client_nonce: None,
+    }).await;
+    let got = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 3000).await
+        .expect("bob should receive group message");
+    let chat_msg: types::ChatMessage = serde_json::from_str(&got.data).unwrap();
+
+    // bob chiude la connessione: il server genera il messaggio di sistema
+    b.sender.close().await.unwrap();
+    let got_system = recv_until(&mut a.rx, |m| {
+        matches!(m.message_type, types::MessageType::ChatMessage)
+            && serde_json::from_str::<types::ChatMessage>(&m.data).map(|c| c.username == "Sistema").unwrap_or(false)
+    }, 3000).await;
+    assert!(got_system.is_some(), "alice should be told that bob left");
+
+    let history = state.message_store.messages(&chat_id);
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[0].username, "Sistema");
+    assert_eq!(history[1].id, chat_msg.id);
+    assert_eq!(history[1].content, "da ricordare");
+    assert_eq!(history[2].username, "Sistema");
+}
+
+//Test 6: la cronologia su file sopravvive alla riapertura (riavvio del server)
+#[tokio::test]
+async fn test_file_store_survives_restart() {
+    let path = std::env::temp_dir().join(format!("ruggine-history-{}.jsonl", uuid::Uuid::new_v4()));
+    let chat_msg = types::ChatMessage {
+        id: uuid::Uuid::new_v4(),
+        chat_id: Some("chat-1".into()),
+        username: "alice".into(),
+        content: "persistente".into(),
+        timestamp: chrono::Utc::now(),
+        chat_type: types::ChatType::Private { target: "bob".into() },
+    };
+
+    {
+        let store = FileMessageStore::open(&path).unwrap();
+        store.append("chat-1", &chat_msg);
+        store.append("chat-2", &types::ChatMessage { content: "altra chat".into(), ..chat_msg.clone() });
+    }
+
+    let reopened = FileMessageStore::open(&path).unwrap();
+    let history = reopened.messages("chat-1");
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].id, chat_msg.id);
+    assert_eq!(history[0].content, "persistente");
+    assert_eq!(reopened.messages("chat-2").len(), 1);
+    assert!(reopened.messages("chat-3").is_empty());
+
+    let _ = std::fs::remove_file(&path);
+}
+
+//Test 6b: dopo un riavvio le chat salvate restano accessibili ai loro invitati
+// Passi:
+// - server avviato con archivi su file: alice e bob creano una chat privata e alice scrive un messaggio
+// - uno stato ricreato dalla stessa configurazione ritrova la chat, bob tra i suoi membri e la cronologia
+// - carol, non invitata, resta esclusa
+// - i segnalibri sono salvati: il messaggio, visto da bob in chat e scritto da alice, non risulta non letto
+#[tokio::test]
+async fn test_rooms_survive_restart() {
+    let dir = std::env::temp_dir().join(format!("ruggine-rooms-{}", uuid::Uuid::new_v4()));
+    let config = Config {
+        messages_path: dir.join("messages.jsonl"),
+        rooms_path: dir.join("rooms.jsonl"),
+        read_markers_path: dir.join("read_markers.jsonl"),
+        accounts_path: dir.join("accounts.json"),
+        session_key_path: dir.join("session.key"),
+        ..Config::default()
+    };
+    let state = AppState::from_config(Arc::new(Mutex::new(Duration::ZERO)), &config).unwrap();
+    let (ws_url, _handle) = start_test_server_with_state(state).await;
+    let (mut a, _b, _, chat_id) = alice_and_bob_in_chat(&ws_url).await;
+    send_ws(&mut a, types::MessageType::SendChatMessage, types::SendChatMessage {
+        chat_id: Some(chat_id.clone()),
+        content: "prima del riavvio".into(),
+        client_nonce: None,
+    }).await;
+    recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatMessageSent), 2000).await
+        .expect("alice's message should be accepted");
+
+    let restarted = AppState::from_config(Arc::new(Mutex::new(Duration::ZERO)), &config).unwrap();
+    let room = restarted.rooms.get(&chat_id).await.expect("the chat should be restored");
+    assert_eq!(room.members, vec!["alice", "bob"]);
+    assert!(room.users_in_chat.is_empty(), "presence is not restored");
+    assert_eq!(restarted.rooms.chats_of("bob"), vec![chat_id.clone()]);
+
+    let request = types::HistoryRequest { chat_id: chat_id.clone(), before: None, limit: None };
+    let page = fullstack_app::history::load_history_page(&restarted, "bob", &request).await
+        .expect("bob should still read the history");
+    assert!(page.messages.iter().any(|m| m.content == "prima del riavvio"));
+    assert!(fullstack_app::history::load_history_page(&restarted, "carol", &request).await.is_err());
+
+    let sent = page.messages.iter().find(|m| m.content == "prima del riavvio").unwrap();
+    let unread = |username: &str| fullstack_app::unread::unread_counts(&restarted, username).chats;
+    assert_eq!(unread("bob"), vec![types::ChatUnread { chat_id: chat_id.clone(), unread: 0, last_read: Some(sent.id) }]);
+    assert_eq!(unread("alice"), vec![types::ChatUnread { chat_id: chat_id.clone(), unread: 0, last_read: Some(sent.id) }]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+//Test 6c: una chat chiusa termina il proprio task quando non ha più presenti
+// Passi:
+// - alice e bob sono in una chat privata; alice esce e la chat viene chiusa, ma bob è ancora presente
+// - quando esce anche bob il task della chat termina
+// - la chat chiusa resta leggibile: stato, membri e cronologia per bob, rifiuto dei nuovi messaggi
+#[tokio::test]
+async fn test_closed_room_task_ends() {
+    let state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    let (ws_url, _handle) = start_test_server_with_state(state.clone()).await;
+    let (mut a, mut b, _, chat_id) = alice_and_bob_in_chat(&ws_url).await;
+    assert_eq!(state.rooms.open_count(), 1);
+
+    let out = serde_json::json!({ "available": true, "inChat": false });
+    send_ws(&mut a, types::MessageType::UserStatusChanged, out.clone()).await;
+    recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatAbandoned), 2000).await
+        .expect("bob should be told the chat was abandoned");
+    assert_eq!(state.rooms.open_count(), 1, "bob is still in the chat");
+
+    send_ws(&mut b, types::MessageType::UserStatusChanged, out).await;
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while state.rooms.open_count() > 0 {
+        assert!(std::time::Instant::now() < deadline, "the closed chat's task should end");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let room = state.rooms.get(&chat_id).await.expect("the closed chat is still known");
+    assert_eq!(room.status, types::RoomStatus::Closed);
+    assert!(room.users_in_chat.is_empty());
+    assert!(state.rooms.is_invited(&chat_id, "bob").await);
+    assert!(matches!(
+        state.rooms.authorize_member(&chat_id, "bob").await,
+        Err(types::ChatErrorReason::ChatClosed)
+    ));
+    let request = types::HistoryRequest { chat_id: chat_id.clone(), before: None, limit: None };
+    assert!(fullstack_app::history::load_history_page(&state, "bob", &request).await.is_ok());
+}
+
+// Prepara una chat privata tra alice e bob (invito accettato, entrambi in chat)
+// e vi invia `n` messaggi da parte di alice. Ritorna i client, il chat_id generato dal server
+// e gli id dei messaggi in cronologia: il primo è l'avviso di sistema dell'ingresso di bob.
+async fn setup_private_chat_with_history(ws_url: &str, n: usize) -> (TestClient, TestClient, String, Vec<uuid::Uuid>) {
+    let mut alice = connect_client(ws_url).await;
+    let mut bob = connect_client(ws_url).await;
+    login(&mut alice, ws_url, "alice").await;
+    login(&mut bob, ws_url, "bob").await;
+
+    let invite = types::ChatInvite {
+        id: "inv-h".into(),
+        chat_id: None,
+        from: "alice".into(),
+        from_session_id: "dummy".into(),
+        chat_type: types::ChatType::Private { target: "bob".into() },
+        message: "Join me".into(),
+        timestamp: chrono::Utc::now(),
+    };
+    send_ws(&mut alice, types::MessageType::ChatInvite, invite.clone()).await;
+    let got_invite = recv_until(&mut bob.rx, |m| matches!(m.message_type, types::MessageType::ChatInvite), 2000).await
+        .expect("bob should receive invite");
+    let received: types::ChatInvite = serde_json::from_str(&got_invite.data).unwrap();
+    let chat_id = received.chat_id.clone().expect("the server assigns the chat id");
+
+    // alice (proprietaria) entra subito, così riceve l'avviso di ingresso di bob
+    let status = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+    send_ws(&mut alice, types::MessageType::UserStatusChanged, status.clone()).await;
+    wait_user_in_chat(&mut alice.rx, "alice", &chat_id).await;
+
+    send_ws(&mut bob, types::MessageType::ChatInviteResponse, types::ChatInviteResponse {
+        invite_id: received.id,
+        chat_id: received.chat_id,
+        accepted: true,
+        from_user: received.from,
+        from_session_id: received.from_session_id,
+        chat_type: received.chat_type,
+    }).await;
+    let joined = recv_until(&mut alice.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 2000).await
+        .expect("alice should see bob join");
+    let joined: types::ChatMessage = serde_json::from_str(&joined.data).unwrap();
+    assert_eq!(joined.username, "Sistema");
+
+    send_ws(&mut bob, types::MessageType::UserStatusChanged, status.clone()).await;
+    wait_user_in_chat(&mut bob.rx, "bob", &chat_id).await;
+
+    let mut ids = vec![joined.id];
+    for i in 0..n {
+        send_ws(&mut alice, types::MessageType::SendChatMessage, types::SendChatMessage {
+            chat_id: Some(chat_id.clone()),
+            content: format!("msg {}", i),
+            client_nonce: None,
+        }).await;
+        let got = recv_until(&mut bob.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 2000).await
+            .expect("bob should receive message");
+        ids.push(serde_json::from_str::<types::ChatMessage>(&got.data).unwrap().id);
+    }
+    (alice, bob, chat_id, ids)
+}
+
+//Test 7: cronologia paginata via REST, accessibile solo agli invitati
+// Passi:
+// - alice invita bob in una chat privata e invia 5 messaggi (6 in cronologia con l'avviso di ingresso)
+// - bob chiede gli ultimi 3 messaggi, poi i precedenti usando `before`, autenticandosi con il token di sessione
+// - un `before` che non è un messaggio della chat riceve 400, una chat inesistente 404
+// - carol (non invitata) riceve 403; senza token, o con un token non valido, la risposta è 401
+#[tokio::test]
+async fn test_history_rest_pagination() {
+    let (ws_url, _handle) = start_test_server().await;
+    let (_alice, bob, chat_id, ids) = setup_private_chat_with_history(&ws_url, 5).await;
+    let base = http_url(&ws_url);
+    let client = reqwest::Client::new();
+
+    let page: types::HistoryPage = client
+        .get(format!("{}/api/chats/{}/messages?limit=3", base, chat_id))
+        .bearer_auth(&bob.token)
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert_eq!(page.messages.iter().map(|m| m.id).collect::<Vec<_>>(), ids[3..].to_vec());
+    assert!(page.has_more);
+
+    let older: types::HistoryPage = client
+        .get(format!("{}/api/chats/{}/messages?limit=3&before={}", base, chat_id, ids[3]))
+        .bearer_auth(&bob.token)
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert_eq!(older.messages.iter().map(|m| m.id).collect::<Vec<_>>(), ids[..3].to_vec());
+    assert!(!older.has_more);
+
+    let bad_cursor = client
+        .get(format!("{}/api/chats/{}/messages?before={}", base, chat_id, uuid::Uuid::new_v4()))
+        .bearer_auth(&bob.token)
+        .send().await.unwrap();
+    assert_eq!(bad_cursor.status(), reqwest::StatusCode::BAD_REQUEST);
+    let unknown = client
+        .get(format!("{}/api/chats/inesistente/messages", base))
+        .bearer_auth(&bob.token)
+        .send().await.unwrap();
+    assert_eq!(unknown.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let mut carol = connect_client(&ws_url).await;
+    login(&mut carol, &ws_url, "carol").await;
+    let forbidden = client
+        .get(format!("{}/api/chats/{}/messages", base, chat_id))
+        .bearer_auth(&carol.token)
+        .send().await.unwrap();
+    assert_eq!(forbidden.status(), reqwest::StatusCode::FORBIDDEN);
+
+    // Il nome utente nella query non conta più: serve il token
+    let anonymous = client
+        .get(format!("{}/api/chats/{}/messages?username=bob", base, chat_id))
+        .send().await.unwrap();
+    assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let forged = client
+        .get(format!("{}/api/chats/{}/messages", base, chat_id))
+        .bearer_auth("non-un-token")
+        .send().await.unwrap();
+    assert_eq!(forged.status(), reqwest::StatusCode::UNAUTHORIZED);
+}
+
+//Test 8: cronologia via WebSocket (HistoryRequest -> HistoryPage) e rifiuto per i non invitati
+// Passi:
+// - bob riceve l'intera cronologia; con un `before` sconosciuto riceve l'Error `invalid_cursor`
+// - carol (non invitata) riceve `not_member`, una chat inesistente `unknown_chat`
+#[tokio::test]
+async fn test_history_websocket_backfill() {
+    let (ws_url, _handle) = start_test_server().await;
+    let (_alice, mut bob, chat_id, ids) = setup_private_chat_with_history(&ws_url, 4).await;
+
+    send_ws(&mut bob, types::MessageType::HistoryRequest, types::HistoryRequest {
+        chat_id: chat_id.clone(),
+        before: None,
+        limit: Some(10),
+    }).await;
+    let msg = recv_until(&mut bob.rx, |m| matches!(m.message_type, types::MessageType::HistoryPage), 2000).await
+        .expect("bob should receive a history page");
+    let page: types::HistoryPage = serde_json::from_str(&msg.data).unwrap();
+    assert_eq!(page.chat_id, chat_id);
+    assert_eq!(page.messages.iter().map(|m| m.id).collect::<Vec<_>>(), ids);
+    assert!(!page.has_more);
+
+    send_ws(&mut bob, types::MessageType::HistoryRequest, types::HistoryRequest {
+        chat_id: chat_id.clone(),
+        before: Some(uuid::Uuid::new_v4()),
+        limit: None,
+    }).await;
+    let got_error = recv_until(&mut bob.rx, |m| matches!(m.message_type, types::MessageType::Error), 2000).await
+        .expect("an unknown cursor should be rejected");
+    let error: types::ChatError = serde_json::from_str(&got_error.data).unwrap();
+    assert_eq!(error.reason, types::ChatErrorReason::InvalidCursor);
+    assert_eq!(error.chat_id.as_deref(), Some(chat_id.as_str()));
+
+    let mut carol = connect_client(&ws_url).await;
+    login(&mut carol, &ws_url, "carol").await;
+    send_ws(&mut carol, types::MessageType::HistoryRequest, types::HistoryRequest {
+        chat_id: chat_id.clone(),
+        before: None,
+        limit: None,
+    }).await;
+    let got_error = recv_until(&mut carol.rx, |m| matches!(m.message_type, types::MessageType::Error), 2000).await
+        .expect("carol should not read the chat history");
+    let error: types::ChatError = serde_json::from_str(&got_error.data).unwrap();
+    assert_eq!(error.reason, types::ChatErrorReason::NotMember);
+    assert_eq!(error.chat_id, Some(chat_id));
+
+    send_ws(&mut carol, types::MessageType::HistoryRequest, types::HistoryRequest {
+        chat_id: "inesistente".into(),
+        before: None,
+        limit: None,
+    }).await;
+    let got_error = recv_until(&mut carol.rx, |m| matches!(m.message_type, types::MessageType::Error), 2000).await
+        .expect("an unknown chat should be rejected");
+    let error: types::ChatError = serde_json::from_str(&got_error.data).unwrap();
+    assert_eq!(error.reason, types::ChatErrorReason::UnknownChat);
+}
+
+// Porta alice e bob in una nuova chat privata (invito accettato, entrambi in chat).
+// Ritorna i client, il LoginSuccess di alice e il chat_id.
+async fn alice_and_bob_in_chat(ws_url: &str) -> (TestClient, TestClient, types::LoginSuccess, String) {
+    let mut a = connect_client(ws_url).await;
+    let mut b = connect_client(ws_url).await;
+    let session = login(&mut a, ws_url, "alice").await;
+    login(&mut b, ws_url, "bob").await;
+    let chat_id = create_chat(&mut a, vec![&mut b], types::ChatType::Private { target: "bob".into() }).await;
+
+    let status = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+    send_ws(&mut a, types::MessageType::UserStatusChanged, status.clone()).await;
+    send_ws(&mut b, types::MessageType::UserStatusChanged, status.clone()).await;
+    wait_user_in_chat(&mut a.rx, "alice", &chat_id).await;
+    wait_user_in_chat(&mut b.rx, "bob", &chat_id).await;
+    (a, b, session, chat_id)
+}
+
+//Test 9: ripresa della sessione entro il periodo di grazia
+// Passi:
+// - alice e bob sono in una chat privata; alice chiude la connessione e si riconnette con ResumeSession(token)
+// - alice riceve LoginSuccess con resumed=true e la stessa chat
+// - bob non riceve né UserLeft né il messaggio "ha abbandonato la chat"
+// - i messaggi di alice continuano ad arrivare a bob
+#[tokio::test]
+async fn test_session_resume_within_grace() {
+    let (ws_url, _handle) = start_test_server().await;
+    let (mut a, mut b, session, chat_id) = alice_and_bob_in_chat(&ws_url).await;
+    assert!(!session.resumed);
+
+    a.sender.close().await.unwrap();
+    let mut a2 = connect_client(&ws_url).await;
+    send_ws(&mut a2, types::MessageType::ResumeSession, types::ResumeRequest { token: session.token.clone() }).await;
+    let msg = recv_until(&mut a2.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await
+        .expect("alice should resume her session");
+    let resumed: types::LoginSuccess = serde_json::from_str(&msg.data).unwrap();
+    assert!(resumed.resumed);
+    assert_eq!(resumed.username, "alice");
+    assert_eq!(resumed.session_id, session.session_id);
+    assert_eq!(resumed.chat_id, Some(chat_id.clone()));
+
+    let left = recv_until(&mut b.rx, |m| {
+        matches!(m.message_type, types::MessageType::UserLeft | types::MessageType::ChatMessage | types::MessageType::ChatAbandoned)
+    }, 500).await;
+    assert!(left.is_none(), "bob should not see alice leave");
+
+    send_ws(&mut a2, types::MessageType::ChatMessage, types::ChatMessage {
+        id: uuid::Uuid::new_v4(),
+        chat_id: Some(chat_id.clone()),
+        username: "alice".into(),
+        content: "sono tornata".into(),
+        timestamp: chrono::Utc::now(),
+        chat_type: types::ChatType::Private { target: "bob".into() },
+    }).await;
+    let got = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 2000).await
+        .expect("bob should receive alice's message after resume");
+    let parsed: types::ChatMessage = serde_json::from_str(&got.data).unwrap();
+    assert_eq!(parsed.content, "sono tornata");
+}
+
+//Test 10: scadenza del periodo di grazia e token non validi
+// Passi:
+// - con grazia breve, alice si disconnette e bob riceve UserLeft
+// - un token alterato viene rifiutato con InvalidSession
+// - il token originale (ancora valido) permette un nuovo login, senza la vecchia chat
+#[tokio::test]
+async fn test_session_expires_after_grace() {
+    let mut state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    state.session_grace = Duration::from_millis(200);
+    let (ws_url, _handle) = start_test_server_with_state(state).await;
+    let (mut a, mut b, session, _chat_id) = alice_and_bob_in_chat(&ws_url).await;
+
+    a.sender.close().await.unwrap();
+    let left = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::UserLeft), 2000).await
+        .expect("bob should see alice leave after the grace period");
+    assert_eq!(left.data, "alice");
+
+    let mut a2 = connect_client(&ws_url).await;
+    let mut forged = session.token.clone();
+    forged.push('x');
+    send_ws(&mut a2, types::MessageType::ResumeSession, types::ResumeRequest { token: forged }).await;
+    let msg = recv_until(&mut a2.rx, |m| matches!(m.message_type, types::MessageType::LoginError), 2000).await
+        .expect("a tampered token should be rejected");
+    let error: types::LoginError = serde_json::from_str(&msg.data).unwrap();
+    assert_eq!(error.reason, types::LoginErrorReason::InvalidSession);
+
+    send_ws(&mut a2, types::MessageType::ResumeSession, types::ResumeRequest { token: session.token.clone() }).await;
+    let msg = recv_until(&mut a2.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await
+        .expect("a valid token should still log alice in");
+    let fresh: types::LoginSuccess = serde_json::from_str(&msg.data).unwrap();
+    assert!(!fresh.resumed);
+    assert_eq!(fresh.chat_id, None);
+}
+
+//Test 10c: gli avvisi di sistema arrivano anche ai membri che non stanno guardando la chat
+// Passi:
+// - alice e bob sono in una chat privata; bob esce dalla vista della chat ma resta connesso
+// - alice si disconnette e il periodo di grazia scade
+// - bob riceve comunque il messaggio di "Sistema" "alice ha abbandonato la chat"
+#[tokio::test]
+async fn test_system_messages_reach_unfocused_members() {
+    let mut state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    state.session_grace = Duration::from_millis(200);
+    let (ws_url, _handle) = start_test_server_with_state(state).await;
+    let (mut a, mut b, _session, chat_id) = alice_and_bob_in_chat(&ws_url).await;
+
+    send_ws(&mut b, types::MessageType::UserStatusChanged, serde_json::json!({ "available": true, "inChat": false })).await;
+    recv_until(&mut a.rx, |m| {
+        matches!(m.message_type, types::MessageType::UserStatusChanged)
+            && serde_json::from_str::<types::User>(&m.data).is_ok_and(|u| u.username == "bob" && u.chat_id.is_none())
+    }, 2000).await
+        .expect("alice should see bob leave the chat view");
+    a.sender.close().await.unwrap();
+
+    let left = recv_until(&mut b.rx, |m| {
+        matches!(m.message_type, types::MessageType::ChatMessage)
+            && serde_json::from_str::<types::ChatMessage>(&m.data).is_ok_and(|msg| msg.username == "Sistema" && msg.content.contains("alice"))
+    }, 2000).await
+        .expect("bob should be told that alice left even when not focused on the chat");
+    let left: types::ChatMessage = serde_json::from_str(&left.data).unwrap();
+    assert_eq!(left.chat_id, Some(chat_id));
+}
+
+//Test 10b: la chiave dei token di sessione è salvata su file e sopravvive al riavvio
+// Passi:
+// - il primo avvio crea la chiave; un token emesso allora è valido per lo stato ricreato con la stessa configurazione
+// - uno stato con un'altra chiave rifiuta il token (firma non valida)
+#[tokio::test]
+async fn test_session_key_survives_restart() {
+    let dir = std::env::temp_dir().join(format!("ruggine-session-{}", uuid::Uuid::new_v4()));
+    let config = Config {
+        messages_path: dir.join("messages.jsonl"),
+        rooms_path: dir.join("rooms.jsonl"),
+        read_markers_path: dir.join("read_markers.jsonl"),
+        accounts_path: dir.join("accounts.json"),
+        session_key_path: dir.join("session.key"),
+        ..Config::default()
+    };
+    let before = AppState::from_config(Arc::new(Mutex::new(Duration::ZERO)), &config).unwrap();
+    assert!(config.session_key_path.exists());
+    let (token, _) = before.sessions.issue("alice", "sessione-1");
+
+    let after = AppState::from_config(Arc::new(Mutex::new(Duration::ZERO)), &config).unwrap();
+    let claims = after.sessions.verify(&token).expect("the token should survive a restart");
+    assert_eq!(claims.username, "alice");
+
+    let other = Config { session_key_path: dir.join("altra.key"), ..config.clone() };
+    let other = AppState::from_config(Arc::new(Mutex::new(Duration::ZERO)), &other).unwrap();
+    assert_eq!(other.sessions.verify(&token), Err(fullstack_app::session::TokenError::BadSignature));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+//Test 11: configurazione da file, variabili d'ambiente e riga di comando (in ordine di priorità)
+#[test]
+fn test_config_precedence() {
+    let path = std::env::temp_dir().join(format!("ruggine-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &path,
+        "bind_address = \"0.0.0.0:4000\"\ntyping_timeout_secs = 9\noffline_queue_limit = 10\n",
+    )
+    .unwrap();
+    let args = |extra: &[&str]| {
+        let mut args = vec!["--config".to_string(), path.display().to_string()];
+        args.extend(extra.iter().map(|a| a.to_string()));
+        args
+    };
+    let env = |key: &str| match key {
+        "RUGGINE_TYPING_TIMEOUT_SECS" => Some("7".to_string()),
+        "RUGGINE_ALLOWED_ORIGINS" => Some("http://a.example, http://b.example".to_string()),
+        _ => None,
+    };
+
+    let config = Config::load_from(args(&["--offline-queue-limit", "3", "--bind-address=127.0.0.1:0"]), env).unwrap();
+    assert_eq!(config.bind_address, SocketAddr::from(([127, 0, 0, 1], 0))); // CLI > file
+    assert_eq!(config.typing_timeout(), Duration::from_secs(7)); // env > file
+    assert_eq!(config.offline_queue_limit, 3); // CLI > file
+    assert_eq!(config.allowed_origins, vec!["http://a.example", "http://b.example"]);
+    assert_eq!(config.session_grace_secs, Config::default().session_grace_secs); // default
+
+    assert!(matches!(
+        Config::load_from(args(&["--porta", "80"]), |_| None),
+        Err(ConfigError::UnknownOption(_))
+    ));
+    assert!(matches!(
+        Config::load_from(args(&["--allowed-origins", "http://bad\norigin"]), |_| None),
+        Err(ConfigError::InvalidValue { .. })
+    ));
+    assert!(matches!(
+        Config::load_from(args(&["--session-grace-secs", "molti"]), |_| None),
+        Err(ConfigError::InvalidValue { .. })
+    ));
+
+    // Certificato e chiave TLS vanno indicati insieme
+    assert!(matches!(
+        Config::load_from(args(&["--tls-cert-path", "cert.pem"]), |_| None),
+        Err(ConfigError::MissingValue(key)) if key == "tls_key_path"
+    ));
+
+    std::fs::write(&path, "porta = 80\n").unwrap();
+    assert!(matches!(Config::load_from(args(&[]), |_| None), Err(ConfigError::Parse(..))));
+    let _ = std::fs::remove_file(&path);
+}
+
+//Test 12: server incorporato in un'applicazione axum sotto un prefisso, con rotte aggiuntive e hook
+#[tokio::test]
+async fn test_embedded_server_under_prefix() {
+    let logins = Arc::new(Mutex::new(Vec::<String>::new()));
+    let messages = Arc::new(Mutex::new(Vec::<String>::new()));
+    let (logins_hook, messages_hook) = (logins.clone(), messages.clone());
+
+    let state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    let chat = ServerBuilder::new(Config::default())
+        .state(state)
+        .prefix("/chat")
+        .route(
+            "/api/online",
+            axum::routing::get(|axum::extract::State(state): axum::extract::State<AppState>| async move {
+                state.users.list().await.len().to_string()
+            }),
+        )
+        .on_login(move |username| logins_hook.lock().unwrap().push(username.to_string()))
+        .on_message(move |message| messages_hook.lock().unwrap().push(message.content.clone()))
+        .build()
+        .unwrap();
+    let app = Router::new()
+        .route("/health", axum::routing::get(|| async { "ok" }))
+        .merge(chat);
+
+    let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    let ws_url = format!("ws://{}/chat/ws", addr);
+
+    let (mut a, mut b, _, chat_id) = alice_and_bob_in_chat(&ws_url).await;
+    assert_eq!(*logins.lock().unwrap(), vec!["alice", "bob"]);
+
+    send_ws(&mut a, types::MessageType::SendChatMessage, types::SendChatMessage {
+        chat_id: Some(chat_id.clone()),
+        content: "dall'host".into(),
+        client_nonce: None,
+    }).await;
+    recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage) && m.data.contains("dall'host"), 2000).await
+        .expect("bob should receive the message through the prefixed endpoint");
+    assert_eq!(*messages.lock().unwrap(), vec!["dall'host"]);
+
+    // Rotte dell'host, rotte aggiuntive e REST del server convivono
+    let http = format!("http://{}", addr);
+    let health = reqwest::get(format!("{}/health", http)).await.unwrap();
+    assert_eq!(health.text().await.unwrap(), "ok");
+    let online = reqwest::get(format!("{}/chat/api/online", http)).await.unwrap();
+    assert_eq!(online.text().await.unwrap(), "2");
+    let users = reqwest::get(format!("{}/chat/api/users", http)).await.unwrap();
+    assert!(users.status().is_success());
+    let unprefixed = reqwest::get(format!("{}/api/users", http)).await.unwrap();
+    assert_eq!(unprefixed.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+//Test 13: arresto ordinato: avviso ServerShutdown, chiusura delle connessioni e salvataggio su disco
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    let dir = std::env::temp_dir().join(format!("ruggine-shutdown-{}", uuid::Uuid::new_v4()));
+    let config = Config {
+        bind_address: SocketAddr::from(([127, 0, 0, 1], 0)),
+        messages_path: dir.join("messages.jsonl"),
+        rooms_path: dir.join("rooms.jsonl"),
+        read_markers_path: dir.join("read_markers.jsonl"),
+        accounts_path: dir.join("accounts.json"),
+        session_key_path: dir.join("session.key"),
+        cpu_log_path: dir.join("cpu_log.txt"),
+        shutdown_timeout_secs: 5,
+        shutdown_reconnect_after_secs: 3,
+        ..Config::default()
+    };
+    let state = AppState::from_config(Arc::new(Mutex::new(Duration::ZERO)), &config).unwrap();
+    let listener = TcpListener::bind(config.bind_address).await.unwrap();
+    let ws_url = format!("ws://{}/ws", listener.local_addr().unwrap());
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(
+        ServerBuilder::new(config.clone())
+            .state(state.clone())
+            .serve_with_shutdown(listener, async {
+                let _ = stop_rx.await;
+            }),
+    );
+
+    let (mut a, mut b, _, chat_id) = alice_and_bob_in_chat(&ws_url).await;
+    send_ws(&mut a, types::MessageType::SendChatMessage, types::SendChatMessage {
+        chat_id: Some(chat_id.clone()),
+        content: "prima dell'arresto".into(),
+        client_nonce: None,
+    }).await;
+    recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage) && m.data.contains("prima dell'arresto"), 2000).await
+        .expect("bob should receive the message before shutdown");
+
+    stop_tx.send(()).unwrap();
+    for client in [&mut a, &mut b] {
+        let msg = recv_until(&mut client.rx, |m| matches!(m.message_type, types::MessageType::ServerShutdown), 2000).await
+            .expect("every user should be told about the shutdown");
+        let notice: types::ServerShutdown = serde_json::from_str(&msg.data).unwrap();
+        assert_eq!(notice.reconnect_after_secs, Some(3));
+        // Dopo l'avviso il server chiude il WebSocket
+        let closed = tokio::time::timeout(Duration::from_secs(2), async {
+            while client.rx.recv().await.is_some() {}
+        }).await;
+        assert!(closed.is_ok(), "the server should close the connection");
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), server).await
+        .expect("shutdown should finish within the timeout")
+        .unwrap()
+        .unwrap();
+    assert_eq!(state.shutdown.active_connections(), 0);
+    assert!(tokio_tungstenite::connect_async(&ws_url).await.is_err());
+
+    // Cronologia e log CPU sono su disco
+    let reopened = FileMessageStore::open(&config.messages_path).unwrap();
+    assert!(reopened.messages(&chat_id).iter().any(|m| m.content == "prima dell'arresto"));
+    let cpu_log = std::fs::read_to_string(&config.cpu_log_path).unwrap();
+    assert!(cpu_log.starts_with("Durata corrente:"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// Certificato per "localhost" firmato dalla CA di test: (PEM, chiave PEM, DER)
+fn localhost_cert(ca: &rcgen::Certificate, ca_key: &rcgen::KeyPair) -> (String, String, Vec<u8>) {
+    let key = rcgen::KeyPair::generate().unwrap();
+    let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap()
+        .signed_by(&key, ca, ca_key).unwrap();
+    (cert.pem(), key.serialize_pem(), cert.der().to_vec())
+}
+
+// Apre una connessione TLS verso il server, fidandosi solo della CA di test
+async fn tls_connect(
+    addr: SocketAddr,
+    connector: &tokio_rustls::TlsConnector,
+) -> tokio_rustls::client::TlsStream<tokio::net::TcpStream> {
+    let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+    connector.connect(name, tcp).await.unwrap()
+}
+
+//Test 14: HTTPS e WSS con certificati ricaricati quando i file cambiano
+#[tokio::test]
+async fn test_tls_with_certificate_reload() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let ca_key = rcgen::KeyPair::generate().unwrap();
+    let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let (cert_pem, key_pem, first_der) = localhost_cert(&ca, &ca_key);
+
+    let dir = std::env::temp_dir().join(format!("ruggine-tls-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, &cert_pem).unwrap();
+    std::fs::write(&key_path, &key_pem).unwrap();
+
+    let config = Config {
+        bind_address: SocketAddr::from(([127, 0, 0, 1], 0)),
+        cpu_log_path: dir.join("cpu_log.txt"),
+        tls_cert_path: Some(cert_path.clone()),
+        tls_key_path: Some(key_path.clone()),
+        tls_reload_interval_secs: 1,
+        ..Config::default()
+    };
+    let listener = TcpListener::bind(config.bind_address).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(
+        ServerBuilder::new(config)
+            .state(AppState::new(Arc::new(Mutex::new(Duration::ZERO))))
+            .serve_with_shutdown(listener, async {
+                let _ = stop_rx.await;
+            }),
+    );
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(ca.der().clone()).unwrap();
+    let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions().unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+
+    // HTTPS: le rotte REST rispondono sulla connessione cifrata
+    let mut https = tls_connect(addr, &connector).await;
+    assert_eq!(https.get_ref().1.peer_certificates().unwrap()[0].as_ref(), first_der.as_slice());
+    https.write_all(b"GET /api/users HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    https.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {}", response);
+
+    // WSS: il WebSocket funziona sopra TLS
+    let wss = tls_connect(addr, &connector).await;
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("wss://localhost:{}/ws", addr.port()), wss).await.unwrap();
+    let login = types::WebSocketMessage {
+        message_type: types::MessageType::Login,
+        data: serde_json::to_string(&types::LoginRequest { username: "sconosciuto".into(), password: TEST_PASSWORD.into() }).unwrap(),
+        request_id: None,
+    };
+    ws.send(WsMessage::Text(serde_json::to_string(&login).unwrap())).await.unwrap();
+    let reply = tokio::time::timeout(Duration::from_secs(2), ws.next()).await.unwrap().unwrap().unwrap();
+    let reply: types::WebSocketMessage = serde_json::from_str(reply.to_text().unwrap()).unwrap();
+    assert!(matches!(reply.message_type, types::MessageType::LoginError));
+
+    // Nuovo certificato sugli stessi file: le nuove connessioni lo ricevono senza riavvio
+    let (cert_pem, key_pem, second_der) = localhost_cert(&ca, &ca_key);
+    std::fs::write(&key_path, &key_pem).unwrap();
+    std::fs::write(&cert_path, &cert_pem).unwrap();
+    let mut reloaded = false;
+    for _ in 0..40 {
+        let stream = tls_connect(addr, &connector).await;
+        if stream.get_ref().1.peer_certificates().unwrap()[0].as_ref() == second_der.as_slice() {
+            reloaded = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reloaded, "the server should pick up the new certificate");
+
+    drop(ws);
+    stop_tx.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// Come `connect_client`, ma la lettura dal socket si ferma finché `paused` vale true
+// (simula un client lento: i messaggi si accumulano lato server)
+async fn connect_pausable_client(ws_url: &str) -> (TestClient, tokio::sync::watch::Sender<bool>) {
+    let (stream, _) = tokio_tungstenite::connect_async(ws_url).await.unwrap();
+    let (write, mut read) = stream.split();
+    let (tx, rx) = mpsc::unbounded_channel::<types::WebSocketMessage>();
+    let (pause_tx, mut paused) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        loop {
+            if paused.wait_for(|p| !*p).await.is_err() {
+                break;
+            }
+            let Some(Ok(msg)) = read.next().await else { break };
+            if let WsMessage::Text(txt) = msg {
+                if let Ok(parsed) = serde_json::from_str::<types::WebSocketMessage>(&txt) {
+                    let _ = tx.send(parsed);
+                }
+            }
+        }
+    });
+    (TestClient { sender: write, rx, token: String::new() }, pause_tx)
+}
+
+// Aggiornamento di presenza (scartabile) per l'utente indicato
+fn presence(username: &str) -> Frame {
+    Frame::new(ServerMessage::UserLeft(username.to_string()))
+}
+
+// Messaggio di chat (mai scartato) con il testo indicato; id e orario fissi per confrontarli
+fn chat_frame(content: &str) -> Frame {
+    Frame::new(ServerMessage::ChatMessage(types::ChatMessage {
+        id: uuid::Uuid::nil(),
+        chat_id: Some("c".into()),
+        username: "alice".into(),
+        content: content.into(),
+        timestamp: chrono::DateTime::default(),
+        chat_type: types::ChatType::System,
+    }))
+}
+
+//Test 15: code in uscita limitate e client lenti
+// Passi:
+// - coda da 3: gli aggiornamenti di presenza più vecchi vengono scartati, i messaggi di chat mai;
+//   con la coda piena di messaggi di chat la connessione viene chiusa e i messaggi restano recuperabili,
+//   insieme agli altri messaggi destinati all'utente, ma senza le risposte alla connessione
+// - bob smette di leggere mentre alice invia molti messaggi: il server lo espelle (GET /api/metrics/queues,
+//   solo con il token delle metriche; senza token configurato la rotta non esiste)
+// - bob riprende la sessione e riceve dalla coda offline i messaggi non scritti sul socket, fino all'ultimo
+#[tokio::test]
+async fn test_slow_consumer_eviction() {
+    let (tx, mut rx) = outbound::channel(3);
+    tx.send(presence("p1")).unwrap();
+    tx.send(chat_frame("c1")).unwrap();
+    tx.send(presence("p2")).unwrap();
+    tx.send(chat_frame("c2")).unwrap();
+    tx.send(chat_frame("c3")).unwrap();
+    tx.send(presence("p3")).unwrap();
+    assert_eq!(rx.try_recv(), Some(chat_frame("c1")));
+    tx.send(chat_frame("c4")).unwrap();
+    let stats = tx.stats();
+    assert_eq!((stats.queued, stats.peak, stats.dropped_presence, stats.evicted), (3, 3, 3, false));
+
+    assert_eq!(tx.send(chat_frame("c5")), Err(outbound::Closed(chat_frame("c5"))));
+    assert!(tx.stats().evicted);
+    assert_eq!(rx.recv().await, None);
+    assert_eq!(tx.take_pending(), vec![chat_frame("c2"), chat_frame("c3"), chat_frame("c4")]);
+
+    // Alla chiusura si recuperano anche gli altri messaggi per l'utente, non le risposte alla connessione
+    let closed = Frame::new(ServerMessage::ChatInviteClosed(types::ChatInviteClosed {
+        invite_id: "inv".into(),
+        chat_id: "c".into(),
+        state: types::InviteState::Cancelled,
+    }));
+    let ack = Frame::reply(ServerMessage::Ack(types::RequestAck { message_type: types::MessageType::TypingStopped }), Some("r1"));
+    let notice = Frame::new(ServerMessage::ServerShutdown(types::ServerShutdown {
+        message: "arresto".into(),
+        reconnect_after_secs: None,
+    }));
+    let (tx, _rx) = outbound::channel(8);
+    tx.send(closed.clone()).unwrap();
+    tx.send(ack).unwrap();
+    tx.send(notice).unwrap();
+    tx.send(presence("p4")).unwrap();
+    tx.send(chat_frame("c6")).unwrap();
+    assert_eq!(tx.take_pending(), vec![closed, chat_frame("c6")]);
+
+    // Senza token configurato le metriche non sono esposte
+    let (default_url, _default_handle) = start_test_server().await;
+    let hidden = reqwest::get(format!("{}/api/metrics/queues", http_url(&default_url))).await.unwrap();
+    assert_eq!(hidden.status(), reqwest::StatusCode::NOT_FOUND);
+
+    // Lato server con un client che non legge più
+    const N_MESSAGES: usize = 600;
+    let mut state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    state.outbound_queue_limit = 16;
+    state.metrics_token = Some("metriche".into());
+    let (ws_url, _handle) = start_test_server_with_state(state).await;
+    let mut a = connect_client(&ws_url).await;
+    let (mut b, pause_b) = connect_pausable_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    let session_b = login(&mut b, &ws_url, "bob").await;
+    let chat_id = create_chat(&mut a, vec![&mut b], types::ChatType::Private { target: "bob".into() }).await;
+    let status = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+    send_ws(&mut a, types::MessageType::UserStatusChanged, status.clone()).await;
+    send_ws(&mut b, types::MessageType::UserStatusChanged, status).await;
+    wait_user_in_chat(&mut a.rx, "bob", &chat_id).await;
+
+    // Alice attende l'eco di ogni messaggio, così la sua coda non si riempie mai
+    pause_b.send(true).unwrap();
+    let big = "X".repeat(32 * 1024);
+    for i in 0..N_MESSAGES {
+        let content = format!("{}-{}", big, i);
+        send_ws(&mut a, types::MessageType::SendChatMessage, types::SendChatMessage {
+            chat_id: Some(chat_id.clone()),
+            content: content.clone(),
+            client_nonce: None,
+        }).await;
+        recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage)
+            && serde_json::from_str::<types::ChatMessage>(&m.data).is_ok_and(|c| c.content == content), 20000).await
+            .expect("alice should keep receiving messages");
+    }
+
+    // Le metriche elencano gli utenti connessi: servono il token configurato
+    let metrics_url = format!("{}/api/metrics/queues", http_url(&ws_url));
+    let http = reqwest::Client::new();
+    let denied = http.get(&metrics_url).bearer_auth("sbagliato").send().await.unwrap();
+    assert_eq!(denied.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let mut bob_evicted = false;
+    for _ in 0..100 {
+        let queues: Vec<serde_json::Value> = http.get(&metrics_url).bearer_auth("metriche").send().await.unwrap()
+            .json().await.unwrap();
+        let bob = queues.iter().find(|q| q["username"] == "bob").expect("bob should be listed");
+        if bob["evicted"] == true {
+            assert!(bob["peak"].as_u64().unwrap() <= 16, "the queue should stay bounded: {}", bob);
+            let alice = queues.iter().find(|q| q["username"] == "alice").expect("alice should be listed");
+            assert_eq!(alice["evicted"], false);
+            bob_evicted = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert!(bob_evicted, "bob should be evicted as a slow consumer");
+
+    let last = format!("{}-{}", big, N_MESSAGES - 1);
+    let mut b2 = connect_client(&ws_url).await;
+    send_ws(&mut b2, types::MessageType::ResumeSession, types::ResumeRequest { token: session_b.token }).await;
+    let resumed = recv_until(&mut b2.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await
+        .expect("bob should resume his session");
+    assert!(serde_json::from_str::<types::LoginSuccess>(&resumed.data).unwrap().resumed);
+    recv_until(&mut b2.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage)
+        && serde_json::from_str::<types::ChatMessage>(&m.data).is_ok_and(|c| c.content == last), 5000).await
+        .expect("bob should get the messages queued after the eviction");
+}
+
+//Test 15b: le risposte a una connessione chiusa non vengono riconsegnate al login successivo
+// Passi:
+// - bob si collega ma non legge: in coda restano il suo LoginSuccess, un Ack, un Error e un messaggio di chat
+// - la connessione si chiude: solo il messaggio di chat passa nella coda offline
+// - bob riprende la sessione: riceve il nuovo LoginSuccess e poi il messaggio di chat, nient'altro
+#[tokio::test]
+async fn test_pending_replies_not_replayed() {
+    let users = fullstack_app::presence::UserRegistry::new();
+    let offline = fullstack_app::offline::OfflineQueues::new();
+    let welcome = |resumed: bool| -> fullstack_app::presence::Welcome {
+        Box::new(move |user: &types::User, session_id: &str, _| {
+            Frame::new(ServerMessage::LoginSuccess(types::LoginSuccess {
+                username: user.username.clone(),
+                session_id: session_id.to_string(),
+                token: format!("token-{}", resumed),
+                expires_at: chrono::Utc::now(),
+                resumed,
+                chat_id: None,
+            }))
+        })
+    };
+    let attach = |sender: outbound::OutboundSender, connection_id: uuid::Uuid, resume_session: Option<String>| {
+        fullstack_app::presence::AttachRequest {
+            username: "bob".into(),
+            connection_id,
+            welcome: welcome(resume_session.is_some()),
+            resume_session,
+            sender,
+            offline: offline.clone(),
+        }
+    };
+
+    let first = uuid::Uuid::new_v4();
+    let (tx, _rx) = outbound::channel(16);
+    let session = users.attach(attach(tx.clone(), first, None)).await.expect("bob should log in");
+    tx.send(Frame::reply(ServerMessage::Ack(types::RequestAck { message_type: types::MessageType::TypingStopped }), Some("fermo"))).unwrap();
+    tx.send(Frame::reply(ServerMessage::Error(types::ChatError::new(types::ChatErrorReason::Malformed, None)), Some("rotto"))).unwrap();
+    users.deliver_to(vec!["bob".into()], chat_frame("arretrato"), &offline);
+    assert!(users.detach("bob", first).await);
+
+    let (tx, mut rx) = outbound::channel(16);
+    let resumed = users.attach(attach(tx, uuid::Uuid::new_v4(), Some(session.session_id))).await
+        .expect("bob should resume his session");
+    assert!(resumed.resumed);
+    let welcome = rx.try_recv().expect("the new LoginSuccess comes first");
+    assert_eq!(welcome.message_type(), &types::MessageType::LoginSuccess);
+    assert!(welcome.encode(ProtocolVersion::V2).contains("token-true"));
+    assert_eq!(rx.try_recv(), Some(chat_frame("arretrato")));
+    assert_eq!(rx.try_recv(), None, "replies to the closed connection should not be replayed");
+}
+
+//Test 16: protocollo 2 con il payload come valore JSON, accanto al protocollo 1
+// Passi:
+// - un Frame viene codificato una sola volta per versione; la versione 1 è identica a WebSocketMessage
+// - una versione del protocollo sconosciuta viene rifiutata
+// - bob si collega con ?protocol=2, invia il login con il payload incorporato e riceve
+//   LoginSuccess e UserJoined (di alice, che usa il protocollo 1) con `data` come oggetto
+#[tokio::test]
+async fn test_protocol_v2_embedded_payload() {
+    let user = types::User { username: "alice".into(), is_available: true, chat_id: None };
+    let joined = Frame::new(ServerMessage::UserJoined(user.clone()));
+    let v1 = joined.encode(ProtocolVersion::V1);
+    assert_eq!(&*v1, serde_json::to_string(&types::WebSocketMessage {
+        message_type: types::MessageType::UserJoined,
+        data: serde_json::to_string(&user).unwrap(),
+        request_id: None,
+    }).unwrap());
+    assert!(Arc::ptr_eq(&v1, &joined.encode(ProtocolVersion::V1)), "the encoding should be shared");
+    let v2: serde_json::Value = serde_json::from_str(&joined.encode(ProtocolVersion::V2)).unwrap();
+    assert_eq!(v2, serde_json::json!({ "message_type": "UserJoined", "data": serde_json::to_value(&user).unwrap() }));
+    let left: serde_json::Value = serde_json::from_str(&presence("alice").encode(ProtocolVersion::V2)).unwrap();
+    assert_eq!(left["data"], "alice");
+
+    let (ws_url, _handle) = start_test_server().await;
+    assert!(tokio_tungstenite::connect_async(format!("{}?protocol=7", ws_url)).await.is_err());
+
+    let (mut bob, _) = tokio_tungstenite::connect_async(format!("{}?protocol=2", ws_url)).await.unwrap();
+    register(&ws_url, "bob").await;
+    let login_frame = serde_json::json!({
+        "message_type": "Login",
+        "data": { "username": "bob", "password": TEST_PASSWORD },
+    });
+    bob.send(WsMessage::Text(login_frame.to_string())).await.unwrap();
+
+    // Legge i frame di bob fino al primo del tipo indicato
+    async fn next_of_type(
+        bob: &mut tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+        message_type: &str,
+    ) -> serde_json::Value {
+        let fut = async {
+            while let Some(Ok(msg)) = bob.next().await {
+                if let WsMessage::Text(txt) = msg {
+                    let value: serde_json::Value = serde_json::from_str(&txt).unwrap();
+                    if value["message_type"] == message_type {
+                        return value;
+                    }
+                }
+            }
+            panic!("connection closed before {}", message_type);
+        };
+        tokio::time::timeout(Duration::from_secs(3), fut).await
+            .unwrap_or_else(|_| panic!("bob should receive {}", message_type))
+    }
+
+    let success = next_of_type(&mut bob, "LoginSuccess").await;
+    assert_eq!(success["data"]["username"], "bob");
+    assert!(success["data"]["token"].is_string());
+
+    let mut a = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    // Il primo UserJoined può essere quello di bob stesso
+    let mut joined = next_of_type(&mut bob, "UserJoined").await;
+    if joined["data"]["username"] == "bob" {
+        joined = next_of_type(&mut bob, "UserJoined").await;
+    }
+    assert_eq!(joined["data"]["username"], "alice");
+}
+
+//Test 17: messaggi tipizzati, sottoprotocollo WebSocket e JSON Schema pubblicato
+// Passi:
+// - ClientMessage decodifica lo stesso messaggio con `data` come stringa (protocollo 1) o come oggetto (protocollo 2)
+// - lo schema generato coincide con client/src/API/protocol.schema.json
+//   (UPDATE_PROTOCOL_SCHEMA=1 cargo test lo rigenera) ed è servito da GET /api/protocol
+// - bob propone i sottoprotocolli ruggine.v1 e ruggine.v2: il server sceglie ruggine.v2 e invia frame del protocollo 2
+#[tokio::test]
+async fn test_typed_protocol_and_schema() {
+    let v1 = r#"{"message_type":"TypingStarted","data":"{\"chat_id\":\"c1\"}"}"#;
+    let v2 = r#"{"message_type":"TypingStarted","data":{"chat_id":"c1"}}"#;
+    for text in [v1, v2] {
+        match ClientMessage::decode(text).unwrap() {
+            ClientMessage::TypingStarted(request) => assert_eq!(request.chat_id, "c1"),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+    // I messaggi del server non sono accettati dal client e viceversa
+    assert!(ClientMessage::decode(r#"{"message_type":"UsersList","data":[]}"#).is_err());
+    assert!(ClientMessage::decode(r#"{"message_type":"SendChatMessage","data":{"chat_id":"c1"}}"#).is_err());
+
+    let schema = fullstack_app::schema::protocol_schema();
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/client/src/API/protocol.schema.json");
+    let generated = serde_json::to_string_pretty(&schema).unwrap() + "\n";
+    if std::env::var_os("UPDATE_PROTOCOL_SCHEMA").is_some() {
+        std::fs::write(path, &generated).unwrap();
+    }
+    assert_eq!(std::fs::read_to_string(path).unwrap(), generated,
+        "protocol.schema.json is out of date: run UPDATE_PROTOCOL_SCHEMA=1 cargo test");
+
+    let (ws_url, _handle) = start_test_server().await;
+    let protocol: serde_json::Value = reqwest::get(format!("{}/api/protocol", http_url(&ws_url)))
+        .await.unwrap().json().await.unwrap();
+    assert_eq!(protocol["versions"], serde_json::json!([1, 2]));
+    assert_eq!(protocol["schema"], schema);
+
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    let mut request = ws_url.as_str().into_client_request().unwrap();
+    request.headers_mut().insert("Sec-WebSocket-Protocol", "ruggine.v1, ruggine.v2".parse().unwrap());
+    let (mut bob, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+    assert_eq!(response.headers()["Sec-WebSocket-Protocol"], "ruggine.v2");
+
+    register(&ws_url, "bob").await;
+    let login_frame = serde_json::json!({
+        "message_type": "Login",
+        "data": { "username": "bob", "password": TEST_PASSWORD },
+    });
+    bob.send(WsMessage::Text(login_frame.to_string())).await.unwrap();
+    let success = tokio::time::timeout(Duration::from_secs(3), async {
+        while let Some(Ok(msg)) = bob.next().await {
+            if let WsMessage::Text(txt) = msg {
+                let value: serde_json::Value = serde_json::from_str(&txt).unwrap();
+                if value["message_type"] == "LoginSuccess" {
+                    return value;
+                }
+            }
+        }
+        panic!("connection closed before LoginSuccess");
+    }).await.expect("bob should receive LoginSuccess");
+    assert_eq!(success["data"]["username"], "bob");
+}
+
+//Test 17b: lo schema pubblicato descrive davvero i frame serializzati da serde
+// Passi:
+// - un esempio per ogni variante di ClientMessage e ServerMessage (l'elenco delle varianti è preso dallo schema,
+//   così una variante nuova senza esempio fa fallire il test)
+// - ogni esempio serializzato viene validato contro protocol_schema(): tipi, campi obbligatori, valori ammessi
+//   e nessun campo che lo schema non conosca (es. un rename in types.rs non riportato in schema.rs)
+#[test]
+fn test_schema_matches_serialized_frames() {
+    let schema = fullstack_app::schema::protocol_schema();
+    let now = chrono::Utc::now();
+    let id = uuid::Uuid::new_v4();
+    let private = types::ChatType::Private { target: "bob".into() };
+    let group = types::ChatType::Group { members: vec!["alice".into(), "bob".into()] };
+    let message = types::ChatMessage {
+        id,
+        chat_id: Some("c1".into()),
+        username: "alice".into(),
+        content: "ciao".into(),
+        timestamp: now,
+        chat_type: group.clone(),
+    };
+    let system = types::ChatMessage { chat_id: None, chat_type: types::ChatType::System, ..message.clone() };
+    let user = types::User { username: "alice".into(), is_available: true, chat_id: None };
+    let receipt = types::MessageReceipt {
+        message_id: id,
+        chat_id: "c1".into(),
+        sender: "alice".into(),
+        recipients: vec![types::RecipientReceipt { username: "bob".into(), delivered_at: Some(now), read_at: None }],
+        status: types::ReceiptStatus::Delivered,
+    };
+    let room = types::Room {
+        id: "c1".into(),
+        kind: types::RoomKind::Group,
+        owner: "alice".into(),
+        admins: vec!["bob".into()],
+        banned: Vec::new(),
+        invited_users: vec!["alice".into(), "bob".into()],
+        members: vec!["alice".into()],
+        users_in_chat: Vec::new(),
+        created_at: now,
+        status: types::RoomStatus::Pending,
+        had_both_users: false,
+    };
+    let invite = types::ChatInvite {
+        id: "inv".into(),
+        chat_id: Some("c1".into()),
+        from: "alice".into(),
+        from_session_id: "s1".into(),
+        chat_type: private.clone(),
+        message: "Join me".into(),
+        timestamp: now,
+    };
+    let typing = types::TypingIndicator { chat_id: "c1".into(), username: "alice".into() };
+
+    let client = vec![
+        ClientMessage::Login(types::LoginRequest { username: "alice".into(), password: TEST_PASSWORD.into() }),
+        ClientMessage::ResumeSession(types::ResumeRequest { token: "t".into() }),
+        ClientMessage::SendChatMessage(types::SendChatMessage {
+            chat_id: Some("c1".into()),
+            content: "ciao".into(),
+            client_nonce: Some("n1".into()),
+        }),
+        ClientMessage::ChatMessage(message.clone()),
+        ClientMessage::UserStatusChanged(types::StatusChangeRequest {
+            available: Some(false),
+            in_chat: Some(true),
+            chat_id: Some(Some("c1".into())),
+        }),
+        ClientMessage::ChatInvite(invite.clone()),
+        ClientMessage::ChatInviteResponse(types::ChatInviteResponse {
+            invite_id: "inv".into(),
+            chat_id: Some("c1".into()),
+            accepted: true,
+            from_user: "alice".into(),
+            from_session_id: "s1".into(),
+            chat_type: private.clone(),
+        }),
+        ClientMessage::ChatInviteCancel(types::InviteCancel { invite_id: "inv".into() }),
+        ClientMessage::AddChatMembers(types::AddChatMembers {
+            invite_id: "inv".into(),
+            chat_id: "c1".into(),
+            members: vec!["carol".into()],
+            message: "Benvenuta".into(),
+        }),
+        ClientMessage::ChatModeration(types::ModerationRequest {
+            chat_id: "c1".into(),
+            action: types::ModerationAction::Ban,
+            target: "bob".into(),
+        }),
+        ClientMessage::Delivered(types::ReceiptAck { message_id: id }),
+        ClientMessage::Read(types::ReceiptAck { message_id: id }),
+        ClientMessage::TypingStarted(types::TypingRequest { chat_id: "c1".into() }),
+        ClientMessage::TypingStopped(types::TypingRequest { chat_id: "c1".into() }),
+        ClientMessage::HistoryRequest(types::HistoryRequest { chat_id: "c1".into(), before: Some(id), limit: None }),
+    ];
+    let server = vec![
+        ServerMessage::LoginSuccess(types::LoginSuccess {
+            username: "alice".into(),
+            session_id: "s1".into(),
+            token: "t".into(),
+            expires_at: now,
+            resumed: false,
+            chat_id: None,
+        }),
+        ServerMessage::LoginError(types::LoginError {
+            reason: types::LoginErrorReason::InvalidCredentials,
+            message: "no".into(),
+        }),
+        ServerMessage::ChatMessage(system.clone()),
+        ServerMessage::ChatMessageSent(types::ChatMessageSent { client_nonce: None, message: message.clone() }),
+        ServerMessage::ReceiptUpdate(receipt.clone()),
+        ServerMessage::TypingStarted(typing.clone()),
+        ServerMessage::TypingStopped(typing),
+        ServerMessage::UserJoined(user.clone()),
+        ServerMessage::UserLeft("alice".into()),
+        ServerMessage::UserStatusChanged(types::User { chat_id: Some("c1".into()), ..user.clone() }),
+        ServerMessage::UsersList(vec![user]),
+        ServerMessage::ChatInvite(invite),
+        ServerMessage::ChatInviteResponse(types::ChatInviteResponseNotify {
+            invite_id: "inv".into(),
+            chat_id: Some("c1".into()),
+            accepted: false,
+            from_user: "alice".into(),
+            from_session_id: "s1".into(),
+            chat_type: private.clone(),
+            responding_user: "bob".into(),
+        }),
+        ServerMessage::ChatInviteClosed(types::ChatInviteClosed {
+            invite_id: "inv".into(),
+            chat_id: "c1".into(),
+            state: types::InviteState::Expired,
+        }),
+        ServerMessage::ChatModeration(types::ModerationNotice {
+            chat_id: "c1".into(),
+            action: types::ModerationAction::Promote,
+            target: "bob".into(),
+            by: "alice".into(),
+            owner: "alice".into(),
+            admins: vec!["bob".into()],
+            banned: Vec::new(),
+        }),
+        ServerMessage::UnreadCounts(types::UnreadCounts {
+            chats: vec![types::ChatUnread { chat_id: "c1".into(), unread: 2, last_read: Some(id) }],
+        }),
+        ServerMessage::ChatReady(types::ChatReady {
+            chat_id: "c1".into(),
+            inviter: "alice".into(),
+            inviter_session_id: "s1".into(),
+            chat_type: private,
+            accepted_by: "bob".into(),
+        }),
+        ServerMessage::AloneInChat(types::AloneInChatNotification {
+            chat_id: "c1".into(),
+            message: "sola".into(),
+            is_alone: true,
+        }),
+        ServerMessage::ChatUsersCount(room.users_count()),
+        ServerMessage::ChatAbandoned(types::ChatAbandonedNotification {
+            chat_id: "c1".into(),
+            abandoned_by: "alice".into(),
+            remaining_user: "bob".into(),
+            message: "uscita".into(),
+            is_private_chat: true,
+        }),
+        ServerMessage::ChatInvalidated(types::ChatInvalidated { chat_id: "c1".into(), reason: "chiusa".into() }),
+        ServerMessage::ChatCreated(types::ChatCreated { invite_id: "inv".into(), room }),
+        ServerMessage::HistoryPage(types::HistoryPage {
+            chat_id: "c1".into(),
+            messages: vec![message, system],
+            has_more: true,
+            receipts: vec![receipt],
+        }),
+        ServerMessage::Error(types::ChatError::new(types::ChatErrorReason::NotMember, Some("c1"))),
+        ServerMessage::ServerShutdown(types::ServerShutdown { message: "arresto".into(), reconnect_after_secs: Some(5) }),
+        ServerMessage::Ack(types::RequestAck { message_type: types::MessageType::ChatInviteCancel }),
+    ];
+
+    let client_frames: Vec<serde_json::Value> = client.iter().map(|m| serde_json::to_value(m).unwrap()).collect();
+    let mut server_frames: Vec<serde_json::Value> = server.into_iter()
+        .map(|m| serde_json::from_str(&Frame::new(m).encode(ProtocolVersion::V2)).unwrap())
+        .collect();
+    let reply = Frame::reply(ServerMessage::Ack(types::RequestAck { message_type: types::MessageType::Read }), Some("r1"));
+    server_frames.push(serde_json::from_str(&reply.encode(ProtocolVersion::V2)).unwrap());
+
+    for (def, frames) in [("ClientMessage", &client_frames), ("ServerMessage", &server_frames)] {
+        let definition = schema_def(&schema, &format!("#/$defs/{}", def));
+        let variants: std::collections::BTreeSet<&str> = definition["oneOf"].as_array().unwrap().iter()
+            .map(|v| v["properties"]["message_type"]["const"].as_str().unwrap())
+            .collect();
+        let sampled: std::collections::BTreeSet<&str> = frames.iter()
+            .map(|f| f["message_type"].as_str().unwrap())
+            .collect();
+        assert_eq!(sampled, variants, "every {} variant needs a sample", def);
+        for frame in frames {
+            if let Err(e) = validate_schema(&schema, definition, frame, "$") {
+                panic!("{} frame does not match the schema: {}\n{}", def, e, frame);
+            }
+        }
+    }
+    // Il validatore rifiuta davvero i frame sbagliati
+    let definition = schema_def(&schema, "#/$defs/ClientMessage");
+    for wrong in [
+        serde_json::json!({ "message_type": "TypingStarted", "data": { "chatId": "c1" } }),
+        serde_json::json!({ "message_type": "TypingStarted", "data": { "chat_id": 1 } }),
+        serde_json::json!({ "message_type": "Login", "data": { "username": "alice" } }),
+    ] {
+        assert!(validate_schema(&schema, definition, &wrong, "$").is_err(), "{} should be rejected", wrong);
+    }
 }
 
-// Attende che il server confermi (tramite `UserStatusChanged`) che l'utente
-// `username` sia effettivamente nella chat con `chat_id` e non disponibile.
-async fn wait_user_in_chat(
-    rx: &mut mpsc::UnboundedReceiver<types::WebSocketMessage>,
-    username: &str,
-    chat_id: &str,
-) {
-    let got = recv_until(
-        rx,
-        |m| {
-            if let types::MessageType::UserStatusChanged = m.message_type {
-                if let Ok(u) = serde_json::from_str::<types::User>(&m.data) {
-                    return u.username == username && u.chat_id.as_deref() == Some(chat_id) && !u.is_available;
-                }
+fn schema_def<'a>(root: &'a serde_json::Value, reference: &str) -> &'a serde_json::Value {
+    let name = reference.strip_prefix("#/$defs/").expect("only local references are used");
+    &root["$defs"][name]
+}
+
+// Validatore minimo per il sottoinsieme di JSON Schema usato da schema.rs ($ref, type, properties,
+// required, enum, const, oneOf, anyOf, items, minimum). Più severo dello schema: un campo presente
+// nel valore ma non tra le `properties` è un errore
+fn validate_schema(root: &serde_json::Value, schema: &serde_json::Value, value: &serde_json::Value, path: &str) -> Result<(), String> {
+    use serde_json::Value;
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        return validate_schema(root, schema_def(root, reference), value, path);
+    }
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            return Err(format!("{}: expected {}, found {}", path, expected, value));
+        }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            return Err(format!("{}: {} is not one of {:?}", path, value, allowed));
+        }
+    }
+    if let Some(options) = schema.get("oneOf").and_then(Value::as_array) {
+        let matching = options.iter().filter(|o| validate_schema(root, o, value, path).is_ok()).count();
+        if matching != 1 {
+            return Err(format!("{}: {} matches {} oneOf options", path, value, matching));
+        }
+    }
+    if let Some(options) = schema.get("anyOf").and_then(Value::as_array) {
+        if !options.iter().any(|o| validate_schema(root, o, value, path).is_ok()) {
+            return Err(format!("{}: {} matches no anyOf option", path, value));
+        }
+    }
+    let type_ok = match schema.get("type").and_then(Value::as_str) {
+        None => true,
+        Some("string") => value.is_string(),
+        Some("boolean") => value.is_boolean(),
+        Some("integer") => value.is_u64() || value.is_i64(),
+        Some("array") => value.is_array(),
+        Some("object") => value.is_object(),
+        Some("null") => value.is_null(),
+        Some(other) => return Err(format!("{}: unsupported type {}", path, other)),
+    };
+    if !type_ok {
+        return Err(format!("{}: {} is not of type {}", path, value, schema["type"]));
+    }
+    if let (Some(minimum), Some(n)) = (schema.get("minimum").and_then(Value::as_i64), value.as_i64()) {
+        if n < minimum {
+            return Err(format!("{}: {} is below {}", path, n, minimum));
+        }
+    }
+    if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+        for (i, item) in array.iter().enumerate() {
+            validate_schema(root, items, item, &format!("{}[{}]", path, i))?;
+        }
+    }
+    if let Some(object) = value.as_object().filter(|_| schema.get("properties").is_some()) {
+        let properties = schema["properties"].as_object().unwrap();
+        for (key, field) in object {
+            let Some(field_schema) = properties.get(key) else {
+                return Err(format!("{}: unexpected field {}", path, key));
+            };
+            validate_schema(root, field_schema, field, &format!("{}.{}", path, key))?;
+        }
+        for required in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+            let key = required.as_str().unwrap();
+            if !object.contains_key(key) {
+                return Err(format!("{}: missing field {}", path, key));
             }
-            false
-        },
-        3000,
-    ).await;
-    assert!(got.is_some(), "{} should be marked in chat {}", username, chat_id);
+        }
+    }
+    Ok(())
 }
 
-// Test 1: rifiuto del login con username duplicato
+//Test 18: ogni frame rifiutato riceve un Error con codice, request_id e messaggio
 // Passi:
-// - Avvio server e connessione di due client
-// - Il primo fa login con "mario" e riceve LoginSuccess
-// - Il secondo tenta lo stesso username e deve ricevere LoginError
+// - prima del login: una richiesta diversa da Login/ResumeSession riceve `unauthenticated`
+// - JSON non valido, payload che non corrisponde al tipo e frame binari ricevono `malformed`;
+//   un message_type sconosciuto o riservato al server riceve `unknown_type`
+// - dopo il login: un secondo Login riceve `already_authenticated`, la conferma di un messaggio
+//   inesistente `unknown_message`, la cronologia di una chat altrui `not_member`
+// - oltre il limite di eventi di digitazione si riceve `rate_limited`
+// - ogni errore ripete il request_id del frame rifiutato, se il client lo ha indicato
 #[tokio::test]
-async fn test_duplicate_login_rejected() {
+async fn test_structured_error_replies() {
     let (ws_url, _handle) = start_test_server().await;
-    let mut c1 = connect_client(&ws_url).await;
-    let mut c2 = connect_client(&ws_url).await;
+    let mut c = connect_client(&ws_url).await;
 
-    send_ws(&mut c1, types::MessageType::Login, types::LoginRequest { username: "mario".into() }).await;
-    let _ = recv_until(&mut c1.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
+    // Invia un frame testuale e attende l'Error corrispondente
+    async fn rejected(client: &mut TestClient, text: String) -> (types::ChatError, Option<String>) {
+        client.sender.send(WsMessage::Text(text)).await.unwrap();
+        let got = recv_until(&mut client.rx, |m| matches!(m.message_type, types::MessageType::Error), 2000).await
+            .expect("the server should reply with an Error");
+        let error: types::ChatError = serde_json::from_str(&got.data).unwrap();
+        assert!(!error.message.is_empty());
+        (error, got.request_id)
+    }
+
+    let send = serde_json::json!({
+        "message_type": "SendChatMessage",
+        "data": serde_json::json!({ "content": "ciao" }).to_string(),
+        "request_id": "r1",
+    });
+    let (error, request_id) = rejected(&mut c, send.to_string()).await;
+    assert_eq!((error.code.as_str(), request_id.as_deref()), ("unauthenticated", Some("r1")));
+    assert_eq!(error.reason, types::ChatErrorReason::Unauthenticated);
+
+    let (error, request_id) = rejected(&mut c, "non è JSON".into()).await;
+    assert_eq!((error.code.as_str(), request_id), ("malformed", None));
+    let missing_content = serde_json::json!({ "message_type": "SendChatMessage", "data": {}, "request_id": "r2" });
+    let (error, request_id) = rejected(&mut c, missing_content.to_string()).await;
+    assert_eq!((error.code.as_str(), request_id.as_deref()), ("malformed", Some("r2")));
+    assert!(error.message.contains("content"), "the message should name the missing field: {}", error.message);
+    c.sender.send(WsMessage::Binary(vec![1, 2, 3])).await.unwrap();
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::Malformed);
+
+    for message_type in ["UsersList", "Sconosciuto"] {
+        let frame = serde_json::json!({ "message_type": message_type, "data": [], "request_id": message_type });
+        let (error, request_id) = rejected(&mut c, frame.to_string()).await;
+        assert_eq!((error.code.as_str(), request_id.as_deref()), ("unknown_type", Some(message_type)));
+    }
 
-    send_ws(&mut c2, types::MessageType::Login, types::LoginRequest { username: "mario".into() }).await;
-    let got_error = recv_until(&mut c2.rx, |m| matches!(m.message_type, types::MessageType::LoginError), 2000).await;
-    assert!(got_error.is_some());
+    login(&mut c, &ws_url, "alice").await;
+    let login_again = serde_json::json!({
+        "message_type": "Login",
+        "data": { "username": "alice", "password": TEST_PASSWORD },
+        "request_id": "r3",
+    });
+    let (error, request_id) = rejected(&mut c, login_again.to_string()).await;
+    assert_eq!((error.code.as_str(), request_id.as_deref()), ("already_authenticated", Some("r3")));
+
+    let read = serde_json::json!({ "message_type": "Read", "data": { "message_id": uuid::Uuid::new_v4() }, "request_id": "r4" });
+    let (error, request_id) = rejected(&mut c, read.to_string()).await;
+    assert_eq!((error.code.as_str(), request_id.as_deref()), ("unknown_message", Some("r4")));
+
+    let history = serde_json::json!({ "message_type": "HistoryRequest", "data": { "chat_id": "altrui" }, "request_id": "r5" });
+    let (error, request_id) = rejected(&mut c, history.to_string()).await;
+    assert_eq!((error.code.as_str(), request_id.as_deref()), ("unknown_chat", Some("r5")));
+    assert_eq!(error.chat_id.as_deref(), Some("altrui"));
+
+    // Gli eventi entro il limite vengono rifiutati perché la chat non esiste, quello in più per la frequenza
+    for i in 0..=fullstack_app::typing::TYPING_EVENTS_PER_WINDOW {
+        let typing = serde_json::json!({ "message_type": "TypingStarted", "data": { "chat_id": "c1" }, "request_id": format!("t{}", i) });
+        c.sender.send(WsMessage::Text(typing.to_string())).await.unwrap();
+    }
+    let got = recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::Error)
+        && serde_json::from_str::<types::ChatError>(&m.data).is_ok_and(|e| e.code == "rate_limited"), 2000).await
+        .expect("the extra typing event should be rate limited");
+    assert_eq!(got.request_id, Some(format!("t{}", fullstack_app::typing::TYPING_EVENTS_PER_WINDOW)));
 }
 
-// Test 2: invio e ricezione di un invito privato
+//Test 19: request_id ripetuto sulle risposte, per associarle alle richieste
 // Passi:
-// - Avvio server e login di alice e bob
-// - alice invia ChatInvite(private) a bob
-// - bob deve ricevere un ChatInvite con lo stesso id
+// - Frame::reply ripete il request_id con entrambe le versioni del protocollo
+// - alice fa login e invita bob: LoginSuccess e ChatCreated riportano i request_id delle richieste
+// - bob accetta (ChatInviteResponse) ed entra nella chat (Ack di UserStatusChanged)
+// - alice invia un messaggio: ChatMessageSent ha il request_id, il ChatMessage ricevuto da bob no
+// - bob conferma la lettura (Ack di Read) e chiede la cronologia (HistoryPage)
+// - senza request_id non viene inviato alcun Ack
 #[tokio::test]
-async fn test_invite_delivery_private() {
-    let (ws_url, _handle) = start_test_server().await;
-    let mut alice = connect_client(&ws_url).await;
-    let mut bob = connect_client(&ws_url).await;
+async fn test_request_id_correlation() {
+    let reply = Frame::reply(ServerMessage::UserLeft("alice".into()), Some("r0"));
+    for version in [ProtocolVersion::V1, ProtocolVersion::V2] {
+        let encoded: serde_json::Value = serde_json::from_str(&reply.encode(version)).unwrap();
+        assert_eq!(encoded["request_id"], "r0");
+    }
+
+    // Attende il primo frame del tipo indicato e ne restituisce il request_id
+    async fn reply_to(client: &mut TestClient, message_type: types::MessageType) -> (types::WebSocketMessage, Option<String>) {
+        let got = recv_until(&mut client.rx, |m| m.message_type == message_type, 3000).await
+            .unwrap_or_else(|| panic!("the client should receive {:?}", message_type));
+        let request_id = got.request_id.clone();
+        (got, request_id)
+    }
 
-    send_ws(&mut alice, types::MessageType::Login, types::LoginRequest { username: "alice".into() }).await;
-    let _ = recv_until(&mut alice.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
-    send_ws(&mut bob, types::MessageType::Login, types::LoginRequest { username: "bob".into() }).await;
-    let _ = recv_until(&mut bob.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
+    let (ws_url, _handle) = start_test_server().await;
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+    register(&ws_url, "alice").await;
+    send_request(&mut a, types::MessageType::Login, types::LoginRequest { username: "alice".into(), password: TEST_PASSWORD.into() }, "login").await;
+    assert_eq!(reply_to(&mut a, types::MessageType::LoginSuccess).await.1.as_deref(), Some("login"));
+    login(&mut b, &ws_url, "bob").await;
 
     let invite = types::ChatInvite {
-        id: "inv1".into(),
-        chat_id: Some("chat-1".into()),
-        from: "alice".into(),
-        from_session_id: "dummy".into(),
+        id: uuid::Uuid::new_v4().to_string(),
+        chat_id: None,
+        from: String::new(),
+        from_session_id: String::new(),
         chat_type: types::ChatType::Private { target: "bob".into() },
-        message: "Join me".into(),
+        message: "Unisciti".into(),
         timestamp: chrono::Utc::now(),
     };
-    send_ws(&mut alice, types::MessageType::ChatInvite, invite.clone()).await;
-
-    let got_invite = recv_until(&mut bob.rx, |m| matches!(m.message_type, types::MessageType::ChatInvite), 2000).await
-        .expect("bob should receive invite");
-    let payload: types::ChatInvite = serde_json::from_str(&got_invite.data).unwrap();
-    assert_eq!(payload.id, invite.id);
+    send_request(&mut a, types::MessageType::ChatInvite, invite, "invite").await;
+    let (created, request_id) = reply_to(&mut a, types::MessageType::ChatCreated).await;
+    assert_eq!(request_id.as_deref(), Some("invite"));
+    let chat_id = serde_json::from_str::<types::ChatCreated>(&created.data).unwrap().room.id;
+
+    let (got, request_id) = reply_to(&mut b, types::MessageType::ChatInvite).await;
+    assert_eq!(request_id, None, "the invite is not a reply to bob");
+    let received: types::ChatInvite = serde_json::from_str(&got.data).unwrap();
+    send_request(&mut b, types::MessageType::ChatInviteResponse, types::ChatInviteResponse {
+        invite_id: received.id,
+        chat_id: received.chat_id,
+        accepted: true,
+        from_user: received.from,
+        from_session_id: received.from_session_id,
+        chat_type: received.chat_type,
+    }, "accept").await;
+    assert_eq!(reply_to(&mut b, types::MessageType::ChatInviteResponse).await.1.as_deref(), Some("accept"));
+
+    let enter = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+    send_request(&mut b, types::MessageType::UserStatusChanged, enter, "enter").await;
+    let (ack, request_id) = reply_to(&mut b, types::MessageType::Ack).await;
+    assert_eq!(request_id.as_deref(), Some("enter"));
+    assert_eq!(serde_json::from_str::<types::RequestAck>(&ack.data).unwrap().message_type, types::MessageType::UserStatusChanged);
+
+    let message = types::SendChatMessage { chat_id: Some(chat_id.clone()), content: "ciao".into(), client_nonce: None };
+    send_request(&mut a, types::MessageType::SendChatMessage, message, "send").await;
+    assert_eq!(reply_to(&mut a, types::MessageType::ChatMessageSent).await.1.as_deref(), Some("send"));
+    let (got, request_id) = reply_to(&mut b, types::MessageType::ChatMessage).await;
+    assert_eq!(request_id, None);
+    let message_id = serde_json::from_str::<types::ChatMessage>(&got.data).unwrap().id;
+
+    // Senza request_id nessun Ack: il primo Ack che arriva è quello della seconda conferma
+    send_ws(&mut b, types::MessageType::Delivered, types::ReceiptAck { message_id }).await;
+    send_request(&mut b, types::MessageType::Read, types::ReceiptAck { message_id }, "read").await;
+    let (ack, request_id) = reply_to(&mut b, types::MessageType::Ack).await;
+    assert_eq!(request_id.as_deref(), Some("read"));
+    assert_eq!(serde_json::from_str::<types::RequestAck>(&ack.data).unwrap().message_type, types::MessageType::Read);
+
+    let history = types::HistoryRequest { chat_id: chat_id.clone(), before: None, limit: None };
+    send_request(&mut b, types::MessageType::HistoryRequest, history, "history").await;
+    assert_eq!(reply_to(&mut b, types::MessageType::HistoryPage).await.1.as_deref(), Some("history"));
 }
 
-// Test 3: broadcast di un messaggio di gruppo a tutti i membri
+//Test 20: ciclo di vita degli inviti: risposte validate, ritiro, scadenza e inviti in attesa
 // Passi:
-// - Avvio server, login di alice, bob, carol
-// - Tutti segnalano `UserStatusChanged` con lo stesso `chatId` (group-1)
-// - Attendo conferma per ciascuno (UserStatusChanged coerente)
-// - alice invia ChatMessage(Group) con chatId=group-1
-// - bob e carol devono ricevere il medesimo ChatMessage
+// - alice invita bob e carol in un gruppo: entrambi lo vedono in GET /api/invites (con il proprio token di sessione)
+// - bob accetta con mittente, sessione e tipo di chat falsificati: ChatReady e conferma usano quelli dell'invito
+// - una seconda risposta di bob riceve invite_answered e l'invito sparisce dalla sua lista
+// - un secondo invito con lo stesso id viene rifiutato, bob non può ritirare l'invito di alice
+// - alice ritira l'invito (Ack): carol riceve ChatInviteClosed(Cancelled) e non può più accettarlo
+// - con una scadenza breve bob e alice ricevono ChatInviteClosed(Expired) e la risposta tardiva è rifiutata;
+//   l'invito chiuso viene poi dimenticato
 #[tokio::test]
-async fn test_group_message_broadcast_to_all_members() {
+async fn test_invite_lifecycle() {
+    fn response(received: &types::ChatInvite, accepted: bool) -> types::ChatInviteResponse {
+        types::ChatInviteResponse {
+            invite_id: received.id.clone(),
+            chat_id: received.chat_id.clone(),
+            accepted,
+            from_user: received.from.clone(),
+            from_session_id: received.from_session_id.clone(),
+            chat_type: received.chat_type.clone(),
+        }
+    }
+    async fn received_invite(client: &mut TestClient) -> types::ChatInvite {
+        let got = recv_until(&mut client.rx, |m| matches!(m.message_type, types::MessageType::ChatInvite), 3000).await
+            .expect("the guest should receive the invite");
+        serde_json::from_str(&got.data).unwrap()
+    }
+    async fn invite_closed(client: &mut TestClient) -> types::ChatInviteClosed {
+        let got = recv_until(&mut client.rx, |m| matches!(m.message_type, types::MessageType::ChatInviteClosed), 3000).await
+            .expect("the client should receive ChatInviteClosed");
+        serde_json::from_str(&got.data).unwrap()
+    }
+    async fn pending_invites(ws_url: &str, client: &TestClient) -> Vec<types::PendingInvite> {
+        let url = format!("{}/api/invites", http_url(ws_url));
+        reqwest::Client::new().get(url).bearer_auth(&client.token).send().await.unwrap().json().await.unwrap()
+    }
+    let invite = |id: &str, chat_type: types::ChatType| types::ChatInvite {
+        id: id.into(),
+        chat_id: None,
+        from: String::new(),
+        from_session_id: String::new(),
+        chat_type,
+        message: "Unisciti".into(),
+        timestamp: chrono::Utc::now(),
+    };
+
     let (ws_url, _handle) = start_test_server().await;
     let mut a = connect_client(&ws_url).await;
     let mut b = connect_client(&ws_url).await;
     let mut c = connect_client(&ws_url).await;
+    let session_a = login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+    login(&mut c, &ws_url, "carol").await;
+
+    let group = types::ChatType::Group { members: vec!["bob".into(), "carol".into()] };
+    send_ws(&mut a, types::MessageType::ChatInvite, invite("inv-g", group.clone())).await;
+    let for_bob = received_invite(&mut b).await;
+    let for_carol = received_invite(&mut c).await;
+    let chat_id = for_bob.chat_id.clone().unwrap();
+    for client in [&b, &c] {
+        let pending = pending_invites(&ws_url, client).await;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].invite.id, "inv-g");
+        assert_eq!(pending[0].invite.chat_id.as_deref(), Some(chat_id.as_str()));
+        assert!(pending[0].expires_at > chrono::Utc::now());
+    }
 
-    send_ws(&mut a, types::MessageType::Login, types::LoginRequest { username: "alice".into() }).await;
-    let _ = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
-    send_ws(&mut b, types::MessageType::Login, types::LoginRequest { username: "bob".into() }).await;
-    let _ = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
-    send_ws(&mut c, types::MessageType::Login, types::LoginRequest { username: "carol".into() }).await;
-    let _ = recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
-
-    let status = serde_json::json!({
-        "available": false,
-        "inChat": true,
-        "chatId": "group-1",
-        "members": ["alice", "bob", "carol"]
-    });
-    send_ws(&mut a, types::MessageType::UserStatusChanged, status.clone()).await;
-    send_ws(&mut b, types::MessageType::UserStatusChanged, status.clone()).await;
-    send_ws(&mut c, types::MessageType::UserStatusChanged, status.clone()).await;
-
-    wait_user_in_chat(&mut a.rx, "alice", "group-1").await;
-    wait_user_in_chat(&mut b.rx, "bob", "group-1").await;
-    wait_user_in_chat(&mut c.rx, "carol", "group-1").await;
+    // Mittente, sessione e tipo di chat dichiarati nella risposta sono ignorati
+    let forged = types::ChatInviteResponse {
+        from_user: "mallory".into(),
+        from_session_id: "forged".into(),
+        chat_type: types::ChatType::Private { target: "mallory".into() },
+        ..response(&for_bob, true)
+    };
+    send_ws(&mut b, types::MessageType::ChatInviteResponse, forged).await;
+    let accepted = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatInviteResponse), 3000).await
+        .expect("bob should be told the invite was accepted");
+    let accepted: types::ChatInviteResponseNotify = serde_json::from_str(&accepted.data).unwrap();
+    assert_eq!((accepted.from_user.as_str(), accepted.from_session_id.as_str()), ("alice", session_a.session_id.as_str()));
+    let ready = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatReady), 3000).await
+        .expect("alice's inviting session should receive ChatReady");
+    let ready: types::ChatReady = serde_json::from_str(&ready.data).unwrap();
+    assert_eq!(ready.inviter_session_id, session_a.session_id);
+    assert!(matches!(&ready.chat_type, types::ChatType::Group { members } if members.contains(&"bob".to_string())));
+    send_ws(&mut b, types::MessageType::ChatInviteResponse, response(&for_bob, false)).await;
+    assert_eq!(expect_chat_error(&mut b).await.reason, types::ChatErrorReason::InviteAnswered);
+    assert!(pending_invites(&ws_url, &b).await.is_empty());
+    // Solo i propri inviti, e solo con il token di sessione
+    assert!(pending_invites(&ws_url, &a).await.is_empty());
+    let anonymous = reqwest::get(format!("{}/api/invites?username=carol", http_url(&ws_url))).await.unwrap();
+    assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    send_ws(&mut a, types::MessageType::ChatInvite, invite("inv-g", group)).await;
+    assert_eq!(expect_chat_error(&mut a).await.reason, types::ChatErrorReason::DuplicateInvite);
+    send_ws(&mut b, types::MessageType::ChatInviteCancel, types::InviteCancel { invite_id: "inv-g".into() }).await;
+    assert_eq!(expect_chat_error(&mut b).await.reason, types::ChatErrorReason::UnknownInvite);
+
+    send_request(&mut a, types::MessageType::ChatInviteCancel, types::InviteCancel { invite_id: "inv-g".into() }, "cancel").await;
+    let ack = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::Ack), 3000).await
+        .expect("alice should receive an Ack");
+    assert_eq!(ack.request_id.as_deref(), Some("cancel"));
+    let closed = invite_closed(&mut c).await;
+    assert_eq!((closed.invite_id.as_str(), closed.chat_id.as_str()), ("inv-g", chat_id.as_str()));
+    assert_eq!(closed.state, types::InviteState::Cancelled);
+    let notified = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatInviteClosed), 300).await;
+    assert!(notified.is_none(), "bob had already answered");
+
+    send_ws(&mut c, types::MessageType::ChatInviteResponse, response(&for_carol, true)).await;
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::InviteCancelled);
+    assert!(pending_invites(&ws_url, &c).await.is_empty());
+    send_ws(&mut a, types::MessageType::ChatInviteCancel, types::InviteCancel { invite_id: "inv-g".into() }).await;
+    assert_eq!(expect_chat_error(&mut a).await.reason, types::ChatErrorReason::InviteCancelled);
+
+    // Scadenza
+    let mut state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    state.invite_ttl = Duration::from_millis(400);
+    let (ws_url, _handle) = start_test_server_with_state(state.clone()).await;
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+
+    send_ws(&mut a, types::MessageType::ChatInvite, invite("inv-p", types::ChatType::Private { target: "bob".into() })).await;
+    let for_bob = received_invite(&mut b).await;
+    for client in [&mut b, &mut a] {
+        let closed = invite_closed(client).await;
+        assert_eq!(closed.invite_id, "inv-p");
+        assert_eq!(closed.state, types::InviteState::Expired);
+    }
+    send_ws(&mut b, types::MessageType::ChatInviteResponse, response(&for_bob, true)).await;
+    assert_eq!(expect_chat_error(&mut b).await.reason, types::ChatErrorReason::InviteExpired);
+    assert!(pending_invites(&ws_url, &b).await.is_empty());
+
+    // L'invito scaduto non è più aperto e dopo un'altra validità viene dimenticato
+    assert_eq!(state.invites.open_count(), 0);
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert!(!state.invites.contains("inv-p"), "closed invites should be pruned");
+}
 
-    let chat_msg = types::ChatMessage {
-        id: uuid::Uuid::new_v4(),
-        chat_id: Some("group-1".into()),
-        username: "alice".into(),
-        content: "ciao gruppo".into(),
+//Test 20b: un'accettazione senza effetto non lascia l'invito accettato; id di invito univoci anche in concorrenza
+// Passi:
+// - alice invita bob in una chat privata, che viene chiusa prima della risposta
+// - bob accetta: riceve ChatClosed e l'invito per lui risulta scaduto (InviteExpired riprovando)
+// - molte registrazioni concorrenti dello stesso id: ne riesce una sola, le altre ricevono DuplicateInvite
+#[tokio::test]
+async fn test_invite_accept_on_closed_chat() {
+    let state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    let (ws_url, _handle) = start_test_server_with_state(state.clone()).await;
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+
+    send_ws(&mut a, types::MessageType::ChatInvite, types::ChatInvite {
+        id: "inv-chiusa".into(),
+        chat_id: None,
+        from: String::new(),
+        from_session_id: String::new(),
+        chat_type: types::ChatType::Private { target: "bob".into() },
+        message: String::new(),
         timestamp: chrono::Utc::now(),
-        chat_type: types::ChatType::Group { members: vec!["alice".into(), "bob".into(), "carol".into()] },
+    }).await;
+    let got = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatInvite), 3000).await
+        .expect("bob should receive the invite");
+    let received: types::ChatInvite = serde_json::from_str(&got.data).unwrap();
+    let chat_id = received.chat_id.clone().unwrap();
+    // Senza la proprietaria resta un solo invitato e la chat viene chiusa
+    assert!(state.rooms.decline(&chat_id, "alice").await);
+
+    let accept = types::ChatInviteResponse {
+        invite_id: received.id.clone(),
+        chat_id: received.chat_id.clone(),
+        accepted: true,
+        from_user: received.from.clone(),
+        from_session_id: received.from_session_id.clone(),
+        chat_type: received.chat_type.clone(),
     };
-    send_ws(&mut a, types::MessageType::ChatMessage, chat_msg.clone()).await;
-
-    let msg_b = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 3000).await
-        .expect("bob should receive group message");
-    let parsed_b: types::ChatMessage = serde_json::from_str(&msg_b.data).unwrap();
-    assert_eq!(parsed_b.content, chat_msg.content);
+    send_ws(&mut b, types::MessageType::ChatInviteResponse, accept.clone()).await;
+    assert_eq!(expect_chat_error(&mut b).await.reason, types::ChatErrorReason::ChatClosed);
+    send_ws(&mut b, types::MessageType::ChatInviteResponse, accept).await;
+    assert_eq!(expect_chat_error(&mut b).await.reason, types::ChatErrorReason::InviteExpired);
+    assert!(state.invites.pending_for("bob").is_empty());
+    assert!(!state.rooms.get(&chat_id).await.unwrap().is_member("bob"));
+
+    let invite = types::ChatInvite { id: "inv-doppio".into(), ..received };
+    let registrations: Vec<_> = (0..16)
+        .map(|_| {
+            let (invites, invite) = (state.invites.clone(), invite.clone());
+            std::thread::spawn(move || invites.register(&invite, &["bob".to_string()], Duration::from_secs(60)))
+        })
+        .collect();
+    let results: Vec<_> = registrations.into_iter().map(|t| t.join().unwrap()).collect();
+    assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+    assert!(results.iter().all(|r| r.is_ok() || *r == Err(types::ChatErrorReason::DuplicateInvite)));
+}
 
-    let msg_c = recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 3000).await
-        .expect("carol should receive group message");
-    let parsed_c: types::ChatMessage = serde_json::from_str(&msg_c.data).unwrap();
-    assert_eq!(parsed_c.content, chat_msg.content);
+//Test 21: nuovi invitati in una chat di gruppo già creata
+// Passi:
+// - alice e bob sono in una chat di gruppo, bob è presente nella chat
+// - carol (non ancora membro) non può invitare altri, alice non può invitare utenti non registrati
+// - alice invita carol (Ack): carol riceve il ChatInvite della stessa chat, bob il messaggio di "Sistema"
+//   e un ChatUsersCount con tre invitati senza perdere la propria presenza
+// - carol accetta ed entra; inviti già presenti, id ripetuti e chat private vengono rifiutati
+#[tokio::test]
+async fn test_add_members_to_group() {
+    let (ws_url, _handle) = start_test_server().await;
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+    let mut c = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+    login(&mut c, &ws_url, "carol").await;
+
+    let chat_id = create_chat(&mut a, vec![&mut b], types::ChatType::Group { members: vec!["bob".into()] }).await;
+    let enter = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+    send_ws(&mut b, types::MessageType::UserStatusChanged, enter.clone()).await;
+    wait_user_in_chat(&mut b.rx, "bob", &chat_id).await;
+
+    let add = |invite_id: &str, chat_id: &str, members: &[&str]| types::AddChatMembers {
+        invite_id: invite_id.into(),
+        chat_id: chat_id.into(),
+        members: members.iter().map(|m| m.to_string()).collect(),
+        message: "Unisciti anche tu".into(),
+    };
+    send_ws(&mut c, types::MessageType::AddChatMembers, add("inv-c0", &chat_id, &["dave"])).await;
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::NotMember);
+    send_ws(&mut a, types::MessageType::AddChatMembers, add("inv-g", &chat_id, &["carol", "ghost"])).await;
+    let error = expect_chat_error(&mut a).await;
+    assert_eq!(error.reason, types::ChatErrorReason::UnknownUser);
+    assert_eq!(error.chat_id.as_deref(), Some(chat_id.as_str()));
+    assert!(error.message.contains("ghost") && !error.message.contains("carol"));
+
+    send_request(&mut a, types::MessageType::AddChatMembers, add("inv-c", &chat_id, &["carol", "bob"]), "add").await;
+    let ack = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::Ack), 3000).await
+        .expect("alice should receive an Ack");
+    assert_eq!(ack.request_id.as_deref(), Some("add"));
+
+    let got = recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::ChatInvite), 3000).await
+        .expect("carol should receive the invite");
+    let received: types::ChatInvite = serde_json::from_str(&got.data).unwrap();
+    assert_eq!(received.id, "inv-c");
+    assert_eq!(received.chat_id.as_deref(), Some(chat_id.as_str()));
+    assert_eq!(received.from, "alice");
+    assert!(matches!(&received.chat_type, types::ChatType::Group { members } if members.contains(&"carol".to_string())));
+
+    let announced = recv_until(&mut b.rx, |m| {
+        m.message_type == types::MessageType::ChatMessage
+            && serde_json::from_str::<types::ChatMessage>(&m.data).is_ok_and(|msg| msg.username == "Sistema" && msg.content.contains("carol"))
+    }, 3000).await;
+    assert!(announced.is_some(), "bob should see the addition announced in the chat");
+    let count = recv_until(&mut b.rx, |m| {
+        m.message_type == types::MessageType::ChatUsersCount
+            && serde_json::from_str::<types::ChatUsersCount>(&m.data).is_ok_and(|count| count.invited_count == 3)
+    }, 3000).await.expect("bob should receive the updated count");
+    let count: types::ChatUsersCount = serde_json::from_str(&count.data).unwrap();
+    assert_eq!(count.invited_users, vec!["alice".to_string(), "bob".to_string(), "carol".to_string()]);
+    assert_eq!(count.users_in_chat, vec!["bob".to_string()]);
+
+    send_ws(&mut c, types::MessageType::ChatInviteResponse, types::ChatInviteResponse {
+        invite_id: received.id.clone(),
+        chat_id: received.chat_id.clone(),
+        accepted: true,
+        from_user: received.from.clone(),
+        from_session_id: received.from_session_id.clone(),
+        chat_type: received.chat_type.clone(),
+    }).await;
+    recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatReady), 3000).await
+        .expect("alice should be told carol accepted");
+    send_ws(&mut c, types::MessageType::UserStatusChanged, enter).await;
+    wait_user_in_chat(&mut c.rx, "carol", &chat_id).await;
+
+    send_ws(&mut a, types::MessageType::AddChatMembers, add("inv-c2", &chat_id, &["bob", "carol"])).await;
+    assert_eq!(expect_chat_error(&mut a).await.reason, types::ChatErrorReason::InvalidInvite);
+    send_ws(&mut a, types::MessageType::AddChatMembers, add("inv-c", &chat_id, &["dave"])).await;
+    assert_eq!(expect_chat_error(&mut a).await.reason, types::ChatErrorReason::DuplicateInvite);
+    let private_id = create_chat(&mut a, vec![&mut b], types::ChatType::Private { target: "bob".into() }).await;
+    send_ws(&mut a, types::MessageType::AddChatMembers, add("inv-p", &private_id, &["carol"])).await;
+    assert_eq!(expect_chat_error(&mut a).await.reason, types::ChatErrorReason::NotGroupChat);
 }
 
-//Test 4: ricezione di un messaggio solo dai componenti della stessa chat
+//Test 22: ruoli nelle chat di gruppo, espulsione e ban
 // Passi:
-// - Avvio server, login di alice, bob, carol, dave
-// - alice e bob segnalano `UserStatusChanged` con lo stesso `chatId` (group-1)
-// - carol e dave segnalano `UserStatusChanged` con lo stesso `chatId` (group-2)
-// - Attendo conferma per ciascuno (UserStatusChanged coerente)
-// - alice invia ChatMessage(Group) con chatId=group-1
-// - bob deve ricevere il messaggio
-// - carol e dave non devono ricevere il messaggio
+// - alice crea un gruppo con bob, carol e dave: i membri semplici non possono moderare né invitare
+// - alice nomina bob amministratore (Ack): gli invitati ricevono ChatModeration e un messaggio di "Sistema"
+// - bob espelle dave, che non può più scrivere; bob non può toccare alice né nominare amministratori
+// - bob banna carol: carol non può scrivere né essere invitata finché alice non revoca il ban
+// - alice cede la chat a bob, che diventa proprietario e le revoca il ruolo di amministratore
+// - chat private e operazioni su se stessi vengono rifiutate
 #[tokio::test]
-async fn test_multiple_chats_correct_deliver() {
+async fn test_group_roles_and_moderation() {
+    async fn moderate(client: &mut TestClient, chat_id: &str, action: types::ModerationAction, target: &str) {
+        let request = types::ModerationRequest { chat_id: chat_id.into(), action, target: target.into() };
+        send_ws(client, types::MessageType::ChatModeration, request).await;
+    }
+    // Attende l'avviso della prossima operazione `action` (quelli precedenti vengono saltati)
+    async fn notice(client: &mut TestClient, action: types::ModerationAction) -> types::ModerationNotice {
+        let got = recv_until(&mut client.rx, |m| {
+            m.message_type == types::MessageType::ChatModeration
+                && serde_json::from_str::<types::ModerationNotice>(&m.data).is_ok_and(|notice| notice.action == action)
+        }, 3000).await
+            .unwrap_or_else(|| panic!("the client should receive ChatModeration {:?}", action));
+        serde_json::from_str(&got.data).unwrap()
+    }
+    async fn send_message(client: &mut TestClient, chat_id: &str) {
+        let message = types::SendChatMessage { chat_id: Some(chat_id.into()), content: "ciao".into(), client_nonce: None };
+        send_ws(client, types::MessageType::SendChatMessage, message).await;
+    }
+    let add = |invite_id: &str, chat_id: &str, member: &str| types::AddChatMembers {
+        invite_id: invite_id.into(),
+        chat_id: chat_id.into(),
+        members: vec![member.into()],
+        message: "Unisciti".into(),
+    };
+
     let (ws_url, _handle) = start_test_server().await;
     let mut a = connect_client(&ws_url).await;
     let mut b = connect_client(&ws_url).await;
     let mut c = connect_client(&ws_url).await;
     let mut d = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+    login(&mut c, &ws_url, "carol").await;
+    login(&mut d, &ws_url, "dave").await;
+    let group = types::ChatType::Group { members: vec!["bob".into(), "carol".into(), "dave".into()] };
+    let chat_id = create_chat(&mut a, vec![&mut b, &mut c, &mut d], group).await;
+    let enter = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+    send_ws(&mut c, types::MessageType::UserStatusChanged, enter).await;
+    wait_user_in_chat(&mut c.rx, "carol", &chat_id).await;
+
+    moderate(&mut c, &chat_id, types::ModerationAction::Kick, "dave").await;
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::NotAllowed);
+    send_ws(&mut c, types::MessageType::AddChatMembers, add("inv-e", &chat_id, "erin")).await;
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::NotAllowed);
+
+    let promote = types::ModerationRequest { chat_id: chat_id.clone(), action: types::ModerationAction::Promote, target: "bob".into() };
+    send_request(&mut a, types::MessageType::ChatModeration, promote, "promote").await;
+    let ack = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::Ack), 3000).await
+        .expect("alice should receive an Ack");
+    assert_eq!(ack.request_id.as_deref(), Some("promote"));
+    let promoted = notice(&mut c, types::ModerationAction::Promote).await;
+    assert_eq!((promoted.target.as_str(), promoted.by.as_str()), ("bob", "alice"));
+    assert_eq!(promoted.admins, vec!["bob".to_string()]);
+    let announced = recv_until(&mut c.rx, |m| {
+        m.message_type == types::MessageType::ChatMessage
+            && serde_json::from_str::<types::ChatMessage>(&m.data).is_ok_and(|msg| msg.username == "Sistema" && msg.content.contains("amministratore"))
+    }, 3000).await;
+    assert!(announced.is_some(), "the promotion should be announced in the chat");
+
+    moderate(&mut b, &chat_id, types::ModerationAction::Kick, "dave").await;
+    let kicked = notice(&mut d, types::ModerationAction::Kick).await;
+    assert_eq!((kicked.target.as_str(), kicked.by.as_str()), ("dave", "bob"));
+    let count = recv_until(&mut c.rx, |m| {
+        m.message_type == types::MessageType::ChatUsersCount
+            && serde_json::from_str::<types::ChatUsersCount>(&m.data).is_ok_and(|count| count.invited_count == 3)
+    }, 3000).await;
+    assert!(count.is_some(), "the count should no longer include dave");
+    send_message(&mut d, &chat_id).await;
+    assert_eq!(expect_chat_error(&mut d).await.reason, types::ChatErrorReason::NotMember);
+
+    moderate(&mut b, &chat_id, types::ModerationAction::Kick, "alice").await;
+    assert_eq!(expect_chat_error(&mut b).await.reason, types::ChatErrorReason::NotAllowed);
+    moderate(&mut b, &chat_id, types::ModerationAction::Promote, "carol").await;
+    assert_eq!(expect_chat_error(&mut b).await.reason, types::ChatErrorReason::NotAllowed);
+
+    moderate(&mut b, &chat_id, types::ModerationAction::Ban, "carol").await;
+    let banned = notice(&mut c, types::ModerationAction::Ban).await;
+    assert_eq!(banned.banned, vec!["carol".to_string()]);
+    send_message(&mut c, &chat_id).await;
+    assert_eq!(expect_chat_error(&mut c).await.reason, types::ChatErrorReason::Banned);
+    send_ws(&mut a, types::MessageType::AddChatMembers, add("inv-c1", &chat_id, "carol")).await;
+    assert_eq!(expect_chat_error(&mut a).await.reason, types::ChatErrorReason::Banned);
+    moderate(&mut a, &chat_id, types::ModerationAction::Unban, "carol").await;
+    assert!(notice(&mut a, types::ModerationAction::Unban).await.banned.is_empty());
+    send_ws(&mut a, types::MessageType::AddChatMembers, add("inv-c2", &chat_id, "carol")).await;
+    recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::ChatInvite), 3000).await
+        .expect("carol can be invited again after the ban is lifted");
+
+    moderate(&mut a, &chat_id, types::ModerationAction::TransferOwnership, "bob").await;
+    let transferred = notice(&mut b, types::ModerationAction::TransferOwnership).await;
+    assert_eq!(transferred.owner, "bob");
+    assert_eq!(transferred.admins, vec!["alice".to_string()]);
+    moderate(&mut a, &chat_id, types::ModerationAction::Promote, "bob").await;
+    assert_eq!(expect_chat_error(&mut a).await.reason, types::ChatErrorReason::NotAllowed);
+    moderate(&mut b, &chat_id, types::ModerationAction::Demote, "alice").await;
+    assert!(notice(&mut a, types::ModerationAction::Demote).await.admins.is_empty());
+
+    moderate(&mut b, &chat_id, types::ModerationAction::Kick, "bob").await;
+    assert_eq!(expect_chat_error(&mut b).await.reason, types::ChatErrorReason::InvalidTarget);
+    let private_id = create_chat(&mut a, vec![&mut d], types::ChatType::Private { target: "dave".into() }).await;
+    moderate(&mut a, &private_id, types::ModerationAction::Kick, "dave").await;
+    assert_eq!(expect_chat_error(&mut a).await.reason, types::ChatErrorReason::NotGroupChat);
+}
 
-    send_ws(&mut a, types::MessageType::Login, types::LoginRequest { username: "alice".into() }).await;
-    let _ = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
-    send_ws(&mut b, types::MessageType::Login, types::LoginRequest { username: "bob".into() }).await;
-    let _ = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
-    send_ws(&mut c, types::MessageType::Login, types::LoginRequest { username: "carol".into() }).await;
-    let _ = recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
-    send_ws(&mut d, types::MessageType::Login, types::LoginRequest { username: "dave".into() }).await;
-    let _ = recv_until(&mut d.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
+//Test 23: utenti membri di più chat contemporaneamente
+// Passi:
+// - bob è membro di un gruppo con alice e di una chat privata con carol, ed è entrato nel gruppo
+// - carol scrive nella chat privata: bob riceve il messaggio e UnreadCounts con 1 non letto in quella chat
+// - alice scrive nel gruppo: bob lo riceve senza nuovi non letti
+// - bob passa alla chat privata: i non letti si azzerano, e il messaggio successivo del gruppo diventa non letto
+#[tokio::test]
+async fn test_multiple_chat_memberships() {
+    // Attende il prossimo riepilogo dei non letti, come mappa chat_id -> non letti
+    async fn unread(client: &mut TestClient) -> HashMap<String, usize> {
+        let got = recv_until(&mut client.rx, |m| matches!(m.message_type, types::MessageType::UnreadCounts), 3000).await
+            .expect("the client should receive UnreadCounts");
+        let counts: types::UnreadCounts = serde_json::from_str(&got.data).unwrap();
+        counts.chats.into_iter().map(|chat| (chat.chat_id, chat.unread)).collect()
+    }
+    async fn send_message(client: &mut TestClient, chat_id: &str, content: &str) {
+        let message = types::SendChatMessage { chat_id: Some(chat_id.into()), content: content.into(), client_nonce: None };
+        send_ws(client, types::MessageType::SendChatMessage, message).await;
+    }
+    // Attende il messaggio `content` e il riepilogo dei non letti che lo accompagna
+    // (viaggiano su canali diversi e possono arrivare in qualsiasi ordine)
+    async fn received_with_unread(client: &mut TestClient, content: &str) -> (types::ChatMessage, HashMap<String, usize>) {
+        let (mut message, mut counts) = (None, None);
+        while message.is_none() || counts.is_none() {
+            let got = recv_until(&mut client.rx, |m| {
+                matches!(m.message_type, types::MessageType::ChatMessage | types::MessageType::UnreadCounts)
+            }, 3000).await
+                .unwrap_or_else(|| panic!("the member should receive {:?} and UnreadCounts", content));
+            if got.message_type == types::MessageType::UnreadCounts {
+                let parsed: types::UnreadCounts = serde_json::from_str(&got.data).unwrap();
+                counts = Some(parsed.chats.into_iter().map(|chat| (chat.chat_id, chat.unread)).collect());
+            } else {
+                let parsed: types::ChatMessage = serde_json::from_str(&got.data).unwrap();
+                if parsed.content == content {
+                    message = Some(parsed);
+                }
+            }
+        }
+        (message.unwrap(), counts.unwrap())
+    }
 
-    let status1 = serde_json::json!({
-        "available": false,
-        "inChat": true,
-        "chatId": "group-1",
-        "members": ["alice", "bob"]
-    });
-    send_ws(&mut a, types::MessageType::UserStatusChanged, status1.clone()).await;
-    send_ws(&mut b, types::MessageType::UserStatusChanged, status1.clone()).await;
+    let (ws_url, _handle) = start_test_server().await;
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+    let mut c = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+    login(&mut c, &ws_url, "carol").await;
+    let group = create_chat(&mut a, vec![&mut b], types::ChatType::Group { members: vec!["bob".into()] }).await;
+    let private = create_chat(&mut c, vec![&mut b], types::ChatType::Private { target: "bob".into() }).await;
+    for (client, username, chat_id) in [(&mut a, "alice", &group), (&mut b, "bob", &group), (&mut c, "carol", &private)] {
+        let enter = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+        send_ws(client, types::MessageType::UserStatusChanged, enter).await;
+        wait_user_in_chat(&mut client.rx, username, chat_id).await;
+    }
 
-    let status2 = serde_json::json!({
-        "available": false,
-        "inChat": true,
-        "chatId": "group-2",
-        "members": ["carol", "dave"]
-    });
-    send_ws(&mut c, types::MessageType::UserStatusChanged, status2.clone()).await;
-    send_ws(&mut d, types::MessageType::UserStatusChanged, status2.clone()).await;
-    wait_user_in_chat(&mut a.rx, "alice", "group-1").await;
-    wait_user_in_chat(&mut b.rx, "bob", "group-1").await;
-    wait_user_in_chat(&mut c.rx, "carol", "group-2").await;
-    wait_user_in_chat(&mut d.rx, "dave", "group-2").await;
-    let chat_msg1 = types::ChatMessage {
-        id: uuid::Uuid::new_v4(),
-        chat_id: Some("group-1".into()),
-        username: "alice".into(),
-        content: "ciao gruppo 1".into(),
-        timestamp: chrono::Utc::now(),
-        chat_type: types::ChatType::Group { members: vec!["alice".into(), "bob".into()] },
+    send_message(&mut c, &private, "ciao bob").await;
+    let (message, counts) = received_with_unread(&mut b, "ciao bob").await;
+    assert_eq!(message.chat_id.as_deref(), Some(private.as_str()));
+    assert_eq!(counts, HashMap::from([(group.clone(), 0), (private.clone(), 1)]));
+
+    // Il gruppo è la chat che bob sta guardando: nessun non letto
+    send_message(&mut a, &group, "nel gruppo").await;
+    send_message(&mut c, &private, "ci sei?").await;
+    let (_, counts) = received_with_unread(&mut b, "ci sei?").await;
+    assert_eq!(counts, HashMap::from([(group.clone(), 0), (private.clone(), 2)]));
+
+    let switch = serde_json::json!({ "available": false, "inChat": true, "chatId": private });
+    send_ws(&mut b, types::MessageType::UserStatusChanged, switch).await;
+    assert_eq!(unread(&mut b).await, HashMap::from([(group.clone(), 0), (private.clone(), 0)]));
+    send_message(&mut a, &group, "torna qui").await;
+    let (message, counts) = received_with_unread(&mut b, "torna qui").await;
+    assert_eq!(message.chat_id.as_deref(), Some(group.as_str()));
+    assert_eq!(counts, HashMap::from([(group.clone(), 1), (private.clone(), 0)]));
+    let none = recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::UnreadCounts), 300).await;
+    assert!(none.is_none(), "the sender has nothing unread");
+}
+
+//Test 24: segnalibri di lettura per chat e non letti al login
+// Passi:
+// - alice e bob sono nello stesso gruppo: il messaggio "primo" è già visto da bob
+// - bob si disconnette e alice scrive altri due messaggi
+// - al nuovo login bob riceve UnreadCounts con 2 non letti e il segnalibro fermo su "primo",
+//   gli stessi dati restituiti da GET /api/users/bob/unread con il suo token (senza token 401, con quello di alice 403)
+// - la conferma Read di "secondo" sposta il segnalibro, l'ingresso nella chat azzera i non letti
+#[tokio::test]
+async fn test_read_markers_and_unread_on_login() {
+    async fn unread(client: &mut TestClient) -> types::ChatUnread {
+        let got = recv_until(&mut client.rx, |m| matches!(m.message_type, types::MessageType::UnreadCounts), 3000).await
+            .expect("bob should receive UnreadCounts");
+        let mut counts: types::UnreadCounts = serde_json::from_str(&got.data).unwrap();
+        assert_eq!(counts.chats.len(), 1);
+        counts.chats.remove(0)
+    }
+    async fn send_message(client: &mut TestClient, chat_id: &str, content: &str) -> types::ChatMessage {
+        let message = types::SendChatMessage { chat_id: Some(chat_id.into()), content: content.into(), client_nonce: None };
+        send_ws(client, types::MessageType::SendChatMessage, message).await;
+        let got = recv_until(&mut client.rx, |m| matches!(m.message_type, types::MessageType::ChatMessageSent), 2000).await
+            .expect("the message should be accepted");
+        serde_json::from_str::<types::ChatMessageSent>(&got.data).unwrap().message
+    }
+
+    let mut state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    state.session_grace = Duration::from_millis(200);
+    let (ws_url, _handle) = start_test_server_with_state(state).await;
+    let unread_url = format!("{}/api/users/bob/unread", http_url(&ws_url));
+    let http = reqwest::Client::new();
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+    let chat_id = create_chat(&mut a, vec![&mut b], types::ChatType::Group { members: vec!["bob".into()] }).await;
+    for (client, username) in [(&mut a, "alice"), (&mut b, "bob")] {
+        let enter = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+        send_ws(client, types::MessageType::UserStatusChanged, enter).await;
+        wait_user_in_chat(&mut client.rx, username, &chat_id).await;
+    }
+
+    let first = send_message(&mut a, &chat_id, "primo").await;
+    recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 2000).await
+        .expect("bob should receive the first message");
+    let anonymous = http.get(&unread_url).send().await.unwrap();
+    assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let other = http.get(&unread_url).bearer_auth(&a.token).send().await.unwrap();
+    assert_eq!(other.status(), reqwest::StatusCode::FORBIDDEN);
+    let seen: types::UnreadCounts = http.get(&unread_url).bearer_auth(&b.token).send().await.unwrap().json().await.unwrap();
+    let expected = types::ChatUnread { chat_id: chat_id.clone(), unread: 0, last_read: Some(first.id) };
+    assert_eq!(seen.chats, vec![expected]);
+
+    b.sender.close().await.unwrap();
+    recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::UserLeft) && m.data == "bob", 2000).await
+        .expect("bob's session should expire");
+    let second = send_message(&mut a, &chat_id, "secondo").await;
+    let third = send_message(&mut a, &chat_id, "terzo").await;
+
+    let mut b = connect_client(&ws_url).await;
+    login(&mut b, &ws_url, "bob").await;
+    let expected = types::ChatUnread { chat_id: chat_id.clone(), unread: 2, last_read: Some(first.id) };
+    assert_eq!(unread(&mut b).await, expected);
+    let counts: types::UnreadCounts = http.get(&unread_url).bearer_auth(&b.token).send().await.unwrap().json().await.unwrap();
+    assert_eq!(counts.chats, vec![expected]);
+
+    send_ws(&mut b, types::MessageType::Read, types::ReceiptAck { message_id: second.id }).await;
+    let expected = types::ChatUnread { chat_id: chat_id.clone(), unread: 1, last_read: Some(second.id) };
+    assert_eq!(unread(&mut b).await, expected);
+
+    let enter = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+    send_ws(&mut b, types::MessageType::UserStatusChanged, enter).await;
+    let expected = types::ChatUnread { chat_id: chat_id.clone(), unread: 0, last_read: Some(third.id) };
+    assert_eq!(unread(&mut b).await, expected);
+}
+
+
+//Test 24b: i segnalibri di lettura sopravvivono al riavvio
+// Passi:
+// - server con archivi su file: alice e bob sono in un gruppo, bob vede "uno", esce e alice scrive "due"
+// - carol viene aggiunta dopo: la cronologia precedente non risulta non letta per lei
+// - uno stato ricreato dalla stessa configurazione ritrova gli stessi non letti e segnalibri
+#[tokio::test]
+async fn test_read_markers_survive_restart() {
+    async fn send_message(client: &mut TestClient, chat_id: &str, content: &str) -> types::ChatMessage {
+        let message = types::SendChatMessage { chat_id: Some(chat_id.into()), content: content.into(), client_nonce: None };
+        send_ws(client, types::MessageType::SendChatMessage, message).await;
+        let got = recv_until(&mut client.rx, |m| matches!(m.message_type, types::MessageType::ChatMessageSent), 2000).await
+            .expect("the message should be accepted");
+        serde_json::from_str::<types::ChatMessageSent>(&got.data).unwrap().message
+    }
+
+    let dir = std::env::temp_dir().join(format!("ruggine-markers-{}", uuid::Uuid::new_v4()));
+    let config = Config {
+        messages_path: dir.join("messages.jsonl"),
+        rooms_path: dir.join("rooms.jsonl"),
+        read_markers_path: dir.join("read_markers.jsonl"),
+        accounts_path: dir.join("accounts.json"),
+        session_key_path: dir.join("session.key"),
+        ..Config::default()
     };
-    send_ws(&mut a, types::MessageType::ChatMessage, chat_msg1.clone()).await;
-    let msg_b = recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 3000).await
-        .expect("bob should receive group message");
-    let parsed_b: types::ChatMessage = serde_json::from_str(&msg_b.data).unwrap();
-    assert_eq!(parsed_b.content, chat_msg1.content);
-    let no_msg_c = recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 1000).await;
-    assert!(no_msg_c.is_none(), "carol should NOT receive group-1 message");
-    let no_msg_d = recv_until(&mut d.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 1000).await;
-    assert!(no_msg_d.is_none(), "dave should NOT receive group-1 message");
+    let state = AppState::from_config(Arc::new(Mutex::new(Duration::ZERO)), &config).unwrap();
+    let (ws_url, _handle) = start_test_server_with_state(state.clone()).await;
+    let mut a = connect_client(&ws_url).await;
+    let mut b = connect_client(&ws_url).await;
+    let mut c = connect_client(&ws_url).await;
+    login(&mut a, &ws_url, "alice").await;
+    login(&mut b, &ws_url, "bob").await;
+    login(&mut c, &ws_url, "carol").await;
+    let chat_id = create_chat(&mut a, vec![&mut b], types::ChatType::Group { members: vec!["bob".into()] }).await;
+    for (client, username) in [(&mut a, "alice"), (&mut b, "bob")] {
+        let enter = serde_json::json!({ "available": false, "inChat": true, "chatId": chat_id });
+        send_ws(client, types::MessageType::UserStatusChanged, enter).await;
+        wait_user_in_chat(&mut client.rx, username, &chat_id).await;
+    }
+
+    let first = send_message(&mut a, &chat_id, "uno").await;
+    recv_until(&mut b.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 2000).await
+        .expect("bob should receive the first message");
+    send_ws(&mut b, types::MessageType::UserStatusChanged, serde_json::json!({ "available": true, "inChat": false })).await;
+    recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::UserStatusChanged)
+        && serde_json::from_str::<types::User>(&m.data).is_ok_and(|u| u.username == "bob" && u.chat_id.is_none()), 2000).await
+        .expect("bob should leave the chat");
+    let second = send_message(&mut a, &chat_id, "due").await;
+
+    send_request(&mut a, types::MessageType::AddChatMembers, types::AddChatMembers {
+        invite_id: "inv-carol".into(),
+        chat_id: chat_id.clone(),
+        members: vec!["carol".into()],
+        message: String::new(),
+    }, "add").await;
+    let got = recv_until(&mut c.rx, |m| matches!(m.message_type, types::MessageType::ChatInvite), 3000).await
+        .expect("carol should receive the invite");
+    let received: types::ChatInvite = serde_json::from_str(&got.data).unwrap();
+    send_ws(&mut c, types::MessageType::ChatInviteResponse, types::ChatInviteResponse {
+        invite_id: received.id,
+        chat_id: received.chat_id,
+        accepted: true,
+        from_user: received.from,
+        from_session_id: received.from_session_id,
+        chat_type: received.chat_type,
+    }).await;
+    recv_until(&mut a.rx, |m| matches!(m.message_type, types::MessageType::ChatReady), 3000).await
+        .expect("alice should be told carol accepted");
+
+    let unread = |state: &AppState, username: &str| fullstack_app::unread::unread_counts(state, username).chats;
+    let expected = |unread: usize, last_read: uuid::Uuid| vec![types::ChatUnread { chat_id: chat_id.clone(), unread, last_read: Some(last_read) }];
+    assert_eq!(unread(&state, "bob"), expected(1, first.id));
+    assert_eq!(unread(&state, "carol"), expected(0, second.id));
+    state.read_markers.sync().unwrap();
+
+    let restarted = AppState::from_config(Arc::new(Mutex::new(Duration::ZERO)), &config).unwrap();
+    assert_eq!(unread(&restarted, "bob"), expected(1, first.id));
+    assert_eq!(unread(&restarted, "carol"), expected(0, second.id));
+    assert_eq!(unread(&restarted, "alice"), expected(0, second.id));
+    let _ = std::fs::remove_dir_all(&dir);
 }
 
 //* Performance test (PTest) *//
@@ -336,27 +3140,26 @@ async fn test_group_message_latency() {
     let mut receiver = connect_client(&ws_url).await;
 
     // Login
-    send_ws(&mut sender, types::MessageType::Login, types::LoginRequest { username: "alice".into() }).await;
-    let _ = recv_until(&mut sender.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
-    send_ws(&mut receiver, types::MessageType::Login, types::LoginRequest { username: "bob".into() }).await;
-    let _ = recv_until(&mut receiver.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
+    login(&mut sender, &ws_url, "alice").await;
+    login(&mut receiver, &ws_url, "bob").await;
 
     // Entrambi entrano nella stessa chat
+    let chat_id = create_chat(&mut sender, vec![&mut receiver], types::ChatType::Group { members: vec!["bob".into()] }).await;
     let status = serde_json::json!({
         "available": false,
         "inChat": true,
-        "chatId": "group-latency",
+        "chatId": chat_id,
         "members": ["alice", "bob"]
     });
     send_ws(&mut sender, types::MessageType::UserStatusChanged, status.clone()).await;
     send_ws(&mut receiver, types::MessageType::UserStatusChanged, status.clone()).await;
-    wait_user_in_chat(&mut sender.rx, "alice", "group-latency").await;
-    wait_user_in_chat(&mut receiver.rx, "bob", "group-latency").await;
+    wait_user_in_chat(&mut sender.rx, "alice", &chat_id).await;
+    wait_user_in_chat(&mut receiver.rx, "bob", &chat_id).await;
 
     // Misura tempo di invio/ricezione
     let chat_msg = types::ChatMessage {
         id: uuid::Uuid::new_v4(),
-        chat_id: Some("group-latency".into()),
+        chat_id: Some(chat_id.clone()),
         username: "alice".into(),
         content: "misura latenza".into(),
         timestamp: chrono::Utc::now(),
@@ -365,7 +3168,7 @@ async fn test_group_message_latency() {
 
     let start = std::time::Instant::now();
     send_ws(&mut sender, types::MessageType::ChatMessage, chat_msg.clone()).await;
-    let msg = recv_until(&mut receiver.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 3000).await
+    let _ = recv_until(&mut receiver.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 3000).await
         .expect("bob should receive group message");
     let elapsed_ms = start.elapsed().as_micros();
 
@@ -386,29 +3189,28 @@ async fn test_group_message_average_latency() {
     let mut receiver = connect_client(&ws_url).await;
 
     // Login
-    send_ws(&mut sender, types::MessageType::Login, types::LoginRequest { username: "alice".into() }).await;
-    let _ = recv_until(&mut sender.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
-    send_ws(&mut receiver, types::MessageType::Login, types::LoginRequest { username: "bob".into() }).await;
-    let _ = recv_until(&mut receiver.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
+    login(&mut sender, &ws_url, "alice").await;
+    login(&mut receiver, &ws_url, "bob").await;
 
     // Entrambi entrano nella stessa chat
+    let chat_id = create_chat(&mut sender, vec![&mut receiver], types::ChatType::Group { members: vec!["bob".into()] }).await;
     let status = serde_json::json!({
         "available": false,
         "inChat": true,
-        "chatId": "group-latency",
+        "chatId": chat_id,
         "members": ["alice", "bob"]
     });
     send_ws(&mut sender, types::MessageType::UserStatusChanged, status.clone()).await;
     send_ws(&mut receiver, types::MessageType::UserStatusChanged, status.clone()).await;
-    wait_user_in_chat(&mut sender.rx, "alice", "group-latency").await;
-    wait_user_in_chat(&mut receiver.rx, "bob", "group-latency").await;
+    wait_user_in_chat(&mut sender.rx, "alice", &chat_id).await;
+    wait_user_in_chat(&mut receiver.rx, "bob", &chat_id).await;
 
     // Invio multiplo e calcolo media
     let mut latencies = Vec::with_capacity(N_MESSAGES);
     for i in 0..N_MESSAGES {
         let chat_msg = types::ChatMessage {
             id: uuid::Uuid::new_v4(),
-            chat_id: Some("group-latency".into()),
+            chat_id: Some(chat_id.clone()),
             username: "alice".into(),
             content: format!("msg {}", i),
             timestamp: chrono::Utc::now(),
@@ -417,7 +3219,7 @@ async fn test_group_message_average_latency() {
 
         let start = std::time::Instant::now();
         send_ws(&mut sender, types::MessageType::ChatMessage, chat_msg.clone()).await;
-        let msg = recv_until(&mut receiver.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 3000).await
+        let _ = recv_until(&mut receiver.rx, |m| matches!(m.message_type, types::MessageType::ChatMessage), 3000).await
             .expect("bob should receive group message");
         let elapsed_ms = start.elapsed().as_micros();
         latencies.push(elapsed_ms);
@@ -425,7 +3227,7 @@ async fn test_group_message_average_latency() {
 
     let avg_latency = latencies.iter().copied().sum::<u128>() as f64 / N_MESSAGES as f64;
     println!("Tempo medio di latenza su {} messaggi: {:.2} µs", N_MESSAGES, avg_latency);
-    assert!(avg_latency < 3000_000.0, "Messaggi ricevuti troppo tardi in in media");
+    assert!(avg_latency < 3_000_000.0, "Messaggi ricevuti troppo tardi in in media");
 }
 
 //PTest 3 misura latenza media con un numero arbitrario di byte inviati
@@ -443,22 +3245,21 @@ async fn test_group_message_average_latency_with_arbitrary_size() {
     let mut receiver = connect_client(&ws_url).await;
 
     // Login
-    send_ws(&mut sender, types::MessageType::Login, types::LoginRequest { username: "alice".into() }).await;
-    let _ = recv_until(&mut sender.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
-    send_ws(&mut receiver, types::MessageType::Login, types::LoginRequest { username: "bob".into() }).await;
-    let _ = recv_until(&mut receiver.rx, |m| matches!(m.message_type, types::MessageType::LoginSuccess), 2000).await;
+    login(&mut sender, &ws_url, "alice").await;
+    login(&mut receiver, &ws_url, "bob").await;
 
     // Entrambi entrano nella stessa chat
+    let chat_id = create_chat(&mut sender, vec![&mut receiver], types::ChatType::Group { members: vec!["bob".into()] }).await;
     let status = serde_json::json!({
         "available": false,
         "inChat": true,
-        "chatId": "group-arbitrary-size",
+        "chatId": chat_id,
         "members": ["alice", "bob"]
     });
     send_ws(&mut sender, types::MessageType::UserStatusChanged, status.clone()).await;
     send_ws(&mut receiver, types::MessageType::UserStatusChanged, status.clone()).await;
-    wait_user_in_chat(&mut sender.rx, "alice", "group-arbitrary-size").await;
-    wait_user_in_chat(&mut receiver.rx, "bob", "group-arbitrary-size").await;
+    wait_user_in_chat(&mut sender.rx, "alice", &chat_id).await;
+    wait_user_in_chat(&mut receiver.rx, "bob", &chat_id).await;
 
     // Crea un payload di N_BYTES
     let big_content = "X".repeat(N_BYTES);
@@ -467,7 +3268,7 @@ async fn test_group_message_average_latency_with_arbitrary_size() {
     for _ in 0..N_ITER {
         let chat_msg = types::ChatMessage {
             id: uuid::Uuid::new_v4(),
-            chat_id: Some("group-arbitrary-size".into()),
+            chat_id: Some(chat_id.clone()),
             username: "alice".into(),
             content: big_content.clone(),
             timestamp: chrono::Utc::now(),
@@ -518,31 +3319,32 @@ Statistiche latenza con 100 utenti contemporanei:
 
 #[tokio::test]
 async fn test_multiple_concurrent_users_latency() {
-    const NUM_USERS: usize = 100;    
-    const N_BYTES: usize = 90;     
-    const N_ITER: usize = 100;        
-
     let (ws_url, _handle) = start_test_server().await;
+    concurrent_users_latency(&ws_url, 100, 100).await;
+}
+
+// Tutti gli utenti entrano nella stessa chat e a ogni iterazione inviano un messaggio ciascuno;
+// l'iterazione termina quando ognuno ha ricevuto tutti i messaggi. Ritorna la latenza media in µs
+async fn concurrent_users_latency(ws_url: &str, num_users: usize, n_iter: usize) -> f64 {
+    const N_BYTES: usize = 90;
 
     // Creazione degli utenti e login
-    let mut users = Vec::with_capacity(NUM_USERS);
-    for i in 0..NUM_USERS {
-        let mut client = connect_client(&ws_url).await;
-        send_ws(&mut client, types::MessageType::Login, types::LoginRequest { 
-            username: format!("user{}", i) 
-        }).await;
-        let _ = recv_until(&mut client.rx, |m| {
-            matches!(m.message_type, types::MessageType::LoginSuccess)
-        }, 2000).await;
+    let mut users = Vec::with_capacity(num_users);
+    for i in 0..num_users {
+        let mut client = connect_client(ws_url).await;
+        login(&mut client, ws_url, &format!("user{}", i)).await;
         users.push(client);
     }
 
-    // Tutti entrano nella stessa chat
-    let members: Vec<String> = (0..NUM_USERS).map(|i| format!("user{}", i)).collect();
+    // Tutti entrano nella stessa chat, creata da user0
+    let members: Vec<String> = (0..num_users).map(|i| format!("user{}", i)).collect();
+    let (owner, guests) = users.split_first_mut().unwrap();
+    let group = types::ChatType::Group { members: members[1..].to_vec() };
+    let chat_id = create_chat(owner, guests.iter_mut().collect(), group).await;
     let status = serde_json::json!({
         "available": false,
         "inChat": true,
-        "chatId": "concurrent-test",
+        "chatId": chat_id,
         "members": members.clone()
     });
 
@@ -552,34 +3354,33 @@ async fn test_multiple_concurrent_users_latency() {
 
     // Attendi che tutti siano nella chat
     for (i, user) in users.iter_mut().enumerate() {
-        wait_user_in_chat(&mut user.rx, &format!("user{}", i), "concurrent-test").await;
+        wait_user_in_chat(&mut user.rx, &format!("user{}", i), &chat_id).await;
     }
 
     let content = "X".repeat(N_BYTES);
-    let mut iteration_latencies = Vec::with_capacity(N_ITER);
+    let mut iteration_latencies = Vec::with_capacity(n_iter);
 
-    // Esegui N_ITER volte il test di invio contemporaneo
-    for iter in 0..N_ITER {
+    // Esegui n_iter volte il test di invio contemporaneo
+    for iter in 0..n_iter {
         let start = std::time::Instant::now();
-        
 
         // Invia messaggi in parallelo
         for (i, user) in users.iter_mut().enumerate() {
             let chat_msg = types::ChatMessage {
                 id: uuid::Uuid::new_v4(),
-                chat_id: Some("concurrent-test".into()),
+                chat_id: Some(chat_id.clone()),
                 username: format!("user{}", i),
                 content: format!("{}-iter{}", content, iter),
                 timestamp: chrono::Utc::now(),
                 chat_type: types::ChatType::Group { members: members.clone() },
             };
-            
+
             send_ws(user, types::MessageType::ChatMessage, chat_msg.clone()).await;
         }
 
-        // Attendi che ogni utente riceva NUM_USERS messaggi
+        // Attendi che ogni utente riceva num_users messaggi
         for user in users.iter_mut() {
-            for _ in 0..NUM_USERS {
+            for _ in 0..num_users {
                 let _ = recv_until(&mut user.rx, |m| {
                     matches!(m.message_type, types::MessageType::ChatMessage)
                 }, 10000).await.expect("Message not received in time");
@@ -588,22 +3389,115 @@ async fn test_multiple_concurrent_users_latency() {
 
         let elapsed = start.elapsed();
         iteration_latencies.push(elapsed.as_micros());
-        
+
         println!("Iterazione {} completata in {} µs", iter + 1, elapsed.as_micros());
     }
 
     // Calcola e mostra le statistiche
-    let avg_latency = iteration_latencies.iter().sum::<u128>() as f64 / N_ITER as f64;
+    let avg_latency = iteration_latencies.iter().sum::<u128>() as f64 / n_iter as f64;
     let min_latency = iteration_latencies.iter().min().unwrap();
     let max_latency = iteration_latencies.iter().max().unwrap();
 
-    println!("\nStatistiche latenza con {} utenti contemporanei:", NUM_USERS);
-    println!("- Messaggi per iterazione: {}", NUM_USERS);
+    println!("\nStatistiche latenza con {} utenti contemporanei:", num_users);
+    println!("- Messaggi per iterazione: {}", num_users);
     println!("- Dimensione messaggi: {} bytes", N_BYTES);
-    println!("- Numero iterazioni: {}", N_ITER);
+    println!("- Numero iterazioni: {}", n_iter);
     println!("- Latenza media: {:.2} µs", avg_latency);
     println!("- Latenza minima: {} µs", min_latency);
     println!("- Latenza massima: {} µs", max_latency);
 
     assert!(avg_latency < 5_000_000.0, "Latenza media troppo alta");
-}
\ No newline at end of file
+    avg_latency
+}
+
+// Collega `n` utenti senza passare dal WebSocket (niente hashing delle password) e li divide
+// in chat da `room_size` in cui risultano presenti. Ritorna quanti messaggi di chat hanno ricevuto
+async fn populate_idle_users(state: &AppState, n: usize, room_size: usize) -> Arc<AtomicUsize> {
+    let chat_messages = Arc::new(AtomicUsize::new(0));
+    let names: Vec<String> = (0..n).map(|i| format!("idle{}", i)).collect();
+    for name in &names {
+        let (tx, mut rx) = outbound::channel(state.outbound_queue_limit);
+        state.users.attach(AttachRequest {
+            username: name.clone(),
+            connection_id: uuid::Uuid::new_v4(),
+            resume_session: None,
+            sender: tx,
+            offline: state.offline.clone(),
+            welcome: Box::new(|_, _, _| Frame::new(ServerMessage::UsersList(Vec::new()))),
+        }).await.expect("idle user should attach");
+
+        // I messaggi vengono scartati appena arrivano: si contano solo quelli di chat
+        let counter = chat_messages.clone();
+        tokio::spawn(async move {
+            while let Some(frame) = rx.recv().await {
+                if matches!(frame.message_type(), types::MessageType::ChatMessage) {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    for group in names.chunks(room_size) {
+        let (owner, guests) = group.split_first().unwrap();
+        let room = state.rooms.create(owner, types::RoomKind::Group, guests.to_vec());
+        for name in group {
+            state.rooms.accept(&room.id, name).await;
+            let update = StatusUpdate { available: Some(false), chat_id: Some(Some(room.id.clone())), in_chat: Some(true) };
+            let change = state.users.update_status(name, update).await.unwrap();
+            state.rooms.enter(&room.id, name, change.outbox).await;
+        }
+    }
+    chat_messages
+}
+
+//PTest 5 come il PTest 4, con migliaia di altri utenti connessi e presenti in altre chat
+/*
+Con le chat come task separati il costo di un invio dipende solo dalla chat di destinazione.
+Statistiche latenza con 10 utenti contemporanei e 2000 connessi in altre chat:
+- Messaggi per iterazione: 10
+- Dimensione messaggi: 90 bytes
+- Numero iterazioni: 50
+- Latenza media: 54376.18 µs
+- Latenza minima: 43896 µs
+- Latenza massima: 415842 µs
+*/
+// Benchmark: da eseguire da solo con `cargo test --test chat_flow -- --ignored thousands --nocapture`
+#[tokio::test]
+#[ignore]
+async fn test_concurrent_users_latency_with_thousands_connected() {
+    const NUM_IDLE_USERS: usize = 2000;
+    const IDLE_ROOM_SIZE: usize = 10;
+    const NUM_ACTIVE_USERS: usize = 10;
+    const N_ITER: usize = 50;
+
+    let state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    let (ws_url, _handle) = start_test_server_with_state(state.clone()).await;
+    let idle_chat_messages = populate_idle_users(&state, NUM_IDLE_USERS, IDLE_ROOM_SIZE).await;
+    assert_eq!(state.users.list().await.len(), NUM_IDLE_USERS);
+
+    concurrent_users_latency(&ws_url, NUM_ACTIVE_USERS, N_ITER).await;
+    println!("- Utenti connessi in altre chat: {}", NUM_IDLE_USERS);
+
+    // Nessun messaggio della chat misurata raggiunge gli utenti delle altre chat
+    assert_eq!(idle_chat_messages.load(Ordering::Relaxed), 0, "idle users should not receive other chats' messages");
+}
+
+//PTest 5b: versione ridotta del PTest 5, eseguita sempre
+// Con 1000 utenti connessi e presenti in altre chat, la latenza della chat attiva resta
+// molto sotto il limite generale: gli utenti inattivi non rallentano gli invii.
+#[tokio::test]
+async fn test_concurrent_users_latency_with_idle_users() {
+    const NUM_IDLE_USERS: usize = 1000;
+    const IDLE_ROOM_SIZE: usize = 10;
+    const NUM_ACTIVE_USERS: usize = 10;
+    const N_ITER: usize = 20;
+
+    let state = AppState::new(Arc::new(Mutex::new(Duration::ZERO)));
+    let (ws_url, _handle) = start_test_server_with_state(state.clone()).await;
+    let idle_chat_messages = populate_idle_users(&state, NUM_IDLE_USERS, IDLE_ROOM_SIZE).await;
+    assert_eq!(state.users.list().await.len(), NUM_IDLE_USERS);
+
+    let avg_latency = concurrent_users_latency(&ws_url, NUM_ACTIVE_USERS, N_ITER).await;
+    assert!(avg_latency < 1_000_000.0, "idle users should not slow down the active chat: {:.0} µs", avg_latency);
+    assert_eq!(idle_chat_messages.load(Ordering::Relaxed), 0, "idle users should not receive other chats' messages");
+}